@@ -16,24 +16,57 @@
 //! t.insert(1, "foo");
 //! t.insert(42, "bar");
 //!
-//! assert_eq!(t.find(1).unwrap(), &foo);
-//! assert_eq!(t.find(42).unwrap(), &bar);
+//! assert_eq!(t.find(&1).unwrap(), &foo);
+//! assert_eq!(t.find(&42).unwrap(), &bar);
 //! ~~~
+//!
+//! The iterators and cursors of a tree borrow it, so safe code cannot modify
+//! the tree while one of them is alive. The borrows do not cover everything:
+//! the nodes of a tree are shared with its snapshots through raw pointers, see
+//! `NodeRef`, and code that gets around the borrows can still change a tree
+//! under an iterator, which then follows a stale path. The tree counts its
+//! structural changes, see `BTree::generation`, and in a build with
+//! `--cfg debug` the iterators and cursors fail when the tree changed since
+//! they were made. A scan that must run while the tree is modified can use a
+//! copy of the tree, see `BTree::snapshot`.
 
 #[feature(struct_variant)];
 #[feature(globs)];
+#[feature(macro_rules)];
 
 #[link(name="btree")];
 
 extern mod extra;
 
+use std::any::{Any, AnyRefExt};
+use std::cast;
+use std::default::Default;
+use std::hash::Hash;
+use std::io::{File, Open, Reader, ReadWrite, Seek, SeekEnd, SeekSet};
+use std::io::Writer;
+use std::io::io_error;
+use std::io::buffered::{BufferedReader, BufferedWriter};
+use std::io::fs;
+use std::io::mem::{BufReader, MemReader, MemWriter};
+use std::libc;
+use std::os::{MapFd, MapReadable, MemoryMap};
+use std::str;
+use std::unstable::atomics::{AtomicBool, AtomicUint, SeqCst};
+use std::iter::{range, Invert};
+use std::managed;
 use std::util;
+use std::vec;
+
+use extra::arc::Arc;
+use extra::flate;
+use extra::json;
+use extra::json::ToJson;
+use extra::priority_queue::PriorityQueue;
+use extra::serialize::{Decodable, Decoder, Encodable, Encoder};
 
 #[cfg(test)]
 use std::rand::{Rng, IsaacRng, SeedableRng};
 #[cfg(test)]
-use std::iter::range;
-#[cfg(test)]
 use extra::test::BenchHarness;
 
 /// The number of keys a node can contain is between a lower and upper bound.
@@ -46,748 +79,14113 @@ pub static BTREE_MIN_DEGREE : uint = 20;
 pub static BTREE_KEYS_LBOUND : uint = BTREE_MIN_DEGREE - 1;
 pub static BTREE_KEYS_UBOUND : uint = 2 * BTREE_MIN_DEGREE - 1;
 
+/// The version of the format of binary images, see `BTree::write_to`. Images
+/// with this or an older version can be read.
+pub static BTREE_IMAGE_VERSION : uint = 1;
+
+/// The size of a page of the file of a paged b-tree, see `PagedBTree::open`.
+pub static BTREE_PAGE_SIZE : uint = 4096;
+
+/// The version of the file format of paged b-trees. Files with this or an
+/// older version can be opened.
+pub static BTREE_PAGE_VERSION : uint = 3;
+
+/// The number of pages that the cache of a paged b-tree holds when it is
+/// opened, see `PagedBTree::set_cache_capacity`.
+pub static BTREE_PAGE_CACHE : uint = 256;
+
+/// The number of pairs that `BTree::build_from_unsorted_file` sorts in memory
+/// at a time, each run of which is written to a file of its own.
+pub static BTREE_RUN_PAIRS : uint = 1 << 20;
+
+/// A map of keys to values that is kept sorted by key. The keys can be of any
+/// type with an `Ord` implementation, which must order the stored keys
+/// totally, e.g. a tree of floats must not contain NaN.
 pub struct BTree<K, V> {
     priv used: uint,
+    /// The number of key-value pairs in the subtree of the node, which is the
+    /// number of leaf slots in use below it, see `BTree::select`.
+    priv length: uint,
+    /// Empty nodes that splits take before allocating new ones, see
+    /// `BTree::with_capacity`. Only maintained in the root node.
+    priv spare: ~[~BTree<K, V>],
+    /// What an insertion does with a key that is already in the tree. Only
+    /// maintained in the root node.
+    priv duplicates: Duplicates,
+    /// How the keys are ordered, see `BTree::with_comparator`. Only
+    /// maintained in the root node.
+    priv order: Order<K>,
+    /// The monoid that summarizes the pairs of every subtree, if the tree
+    /// keeps summaries, see `BTree::with_aggregate`. Only maintained in the
+    /// root node.
+    priv aggregate: Option<Aggregate<K, V>>,
+    /// The number of structural changes of the tree, see `BTree::generation`.
+    /// Only maintained in the root node.
+    priv generation: uint,
     priv keys: [Option<K>, ..BTREE_KEYS_UBOUND],
     priv nodes: [Option<TreeItem<K, V>>, ..BTREE_KEYS_UBOUND + 1],
 }
 
 pub enum TreeItem<K, V> {
-    TreeNode { value: ~BTree<K, V> },
+    TreeNode { value: NodeRef<K, V> },
     TreeLeaf { value: V },
 }
 
-impl<K: Num + Ord, V : Eq> BTree<K, V> {
-    pub fn new() -> ~BTree<K, V> {
-        // TODO: once https://github.com/mozilla/rust/issues/5244 is fixed,
-        // use the following statement:
-        //~BTree { used: 0, key: [None, ..BTREE_KEYS_UBOUND],
-        //         nodes: [None, ..BTREE_KEYS_UBOUND + 1] }
+/// A reference counted pointer to a child node. A tree and its snapshots
+/// share their unchanged nodes, and a shared node is copied before it is
+/// changed, see `BTree::snapshot`.
+pub struct NodeRef<K, V> {
+    priv ptr: *mut SharedNode<K, V>,
+}
 
-        // NB for executing the commented tests below, use this statement:
-        //~BTree { used: 0, keys: [None, None, None],
-        //         nodes: [None, None, None, None] }
+struct SharedNode<K, V> {
+    /// The number of `NodeRef`s to the node, which is changed atomically so
+    /// that frozen trees can be shared between tasks.
+    count: AtomicUint,
+    /// Copies the node when a shared node is changed, as the address of a
+    /// `fn(&BTree<K, V>) -> ~BTree<K, V>`. It is zero until the node is first
+    /// shared, as copying needs `Clone` keys and values, and then set once
+    /// by a compare-and-swap, as trees in different tasks can share the node
+    /// at the same time. It never changes after that.
+    copy: AtomicUint,
+    /// The digest of the subtree of the node, see `BTree::digest`, in two
+    /// halves of 32 bits that are set if `hashed` is. It is computed when it
+    /// is first asked for, and dropped when the node is changed. Trees in
+    /// different tasks can share the node and compute its digest at the
+    /// same time, so it is kept in atomics, in which they store equal values.
+    hashed: AtomicBool,
+    hash_low: AtomicUint,
+    hash_high: AtomicUint,
+    /// The summary of the subtree of the node, see `BTree::with_aggregate`,
+    /// without the pair of the key of its last leaf slot, as for the digest.
+    /// It is the address of a boxed `Summary`, or zero if none is kept. The
+    /// first query after the node changed keeps it by a compare-and-swap,
+    /// so a query can keep the summary of a node that other trees share and
+    /// read at the same time. It is dropped when the node changes, which
+    /// only happens once no other tree holds the node.
+    summary: AtomicUint,
+    node: ~BTree<K, V>,
+}
 
-        ~BTree { used: 0, keys: [
-                None, None, None, None, None, None, None, None, None, None,
-                None, None, None, None, None, None, None, None, None, None,
-                None, None, None, None, None, None, None, None, None, None,
-                None, None, None, None, None, None, None, None, None,
-            ],
-            nodes: [
-                None, None, None, None, None, None, None, None, None, None,
-                None, None, None, None, None, None, None, None, None, None,
-                None, None, None, None, None, None, None, None, None, None,
-                None, None, None, None, None, None, None, None, None, None,
-            ]
-        }
-    }
+/// What an insertion does with a key that is already in the tree, see
+/// `BTree::with_duplicates`.
+#[deriving(Eq, Clone)]
+pub enum Duplicates {
+    /// Replace the value of the key.
+    Replace,
+    /// Leave the tree unchanged and drop the new pair.
+    Reject,
+    /// Insert the pair before the pairs with an equal key.
+    Keep,
+}
 
-    /// Return the number of keys that can be stored in the b-tree node.
-    #[inline]
-    pub fn capacity(&self) -> uint { BTREE_KEYS_UBOUND }
+/// How a tree orders its keys, see `BTree::with_comparator`. The trees that
+/// are split off or copied from a tree share its comparator.
+enum Order<K> {
+    /// By the `Ord` implementation of the keys.
+    NaturalOrder,
+    /// By a function.
+    FnOrder(fn(&K, &K) -> Ordering),
+    /// By a comparator, which can carry state.
+    ComparatorOrder(Arc<~Comparator<K>:Send+Freeze>),
+}
 
-    /// Return a reference to the value corresponding to the key.
-    pub fn find<'a>(&'a self, key: K) -> Option<&'a V> {
-        let mut current = self;
+/// The summary of the pairs of a subtree, whose type is that of the monoid of
+/// the tree, see `Summarizer`.
+type Summary = ~Any:Send+Freeze;
 
-        loop {
-            let pos = match current.nodes[0] {
-                Some(TreeNode { value: _ }) |
-                Some(TreeLeaf { value: _ }) => find_node_pos(current, &key),
-                None => return None
-            };
+/// The monoid of a tree that keeps summaries, see `BTree::with_aggregate`.
+/// The trees that are split off or copied from a tree share its monoid.
+type Aggregate<K, V> = Arc<~Summarizer<K, V>:Send+Freeze>;
 
-            match current.nodes[pos] {
-                Some(TreeNode { value: ref tree }) => {
-                    current = &'a **tree;
-                }
-                Some(TreeLeaf { value: ref value }) => {
-                    // If the leaf's key equals the key to be found, return the
-                    // value. If the leaf is the most right leaf, also return
-                    // the value, because there is no corresponding key in the
-                    // node (the key is stored in one of the parent nodes).
-                    return if pos == current.used
-                              || current.keys[pos].get_ref() == &key {
-                        Some(value)
-                    } else {
-                        None
-                    }
-                }
-                None => return None
-            }
-        }
-    }
+/// A `Monoid` with boxed summaries, so the nodes of a tree can keep them
+/// without the tree naming their type.
+trait Summarizer<K, V> {
+    /// Return the summary of a single pair.
+    fn summarize(&self, key: &K, value: &V) -> Summary;
 
-    /// Insert a key-value pair into the b-tree. Return true if the key did not
-    /// already exist in the tree.
-    ///
-    /// TODO: return true if the key did not already exist. Determine if the
-    /// key is new is not supported at the moment.
-    pub fn insert(&mut self, key: K, value: V) -> bool {
-        if self.used == self.capacity() {
-            let mut child = BTree::new();
+    /// Return the summary of the pairs of `a` followed by the pairs of `b`.
+    fn combine(&self, a: &Summary, b: &Summary) -> Summary;
 
-            let mut i = 0;
+    /// Return a copy of a summary.
+    fn copy(&self, a: &Summary) -> Summary;
+}
 
-            while i < BTREE_KEYS_UBOUND + 1 {
-                util::swap(&mut self.nodes[i], &mut child.nodes[i]);
-                i += 1;
-            }
+/// The summarizer of a monoid with summaries of type `S`.
+struct MonoidSummarizer<M, S> {
+    monoid: M,
+}
 
-            i = 0;
+/// A view into a single key of a b-tree, which is either occupied or vacant.
+pub enum Entry<'a, K, V> {
+    Occupied(OccupiedEntry<'a, K, V>),
+    Vacant(VacantEntry<'a, K, V>),
+}
 
-            while i < BTREE_KEYS_UBOUND {
-                util::swap(&mut self.keys[i], &mut child.keys[i]);
-                i += 1;
-            }
+/// A difference between two trees, see `BTree::diff`.
+pub enum Difference<'a, K, V> {
+    /// A key that is only in the left tree, with its value.
+    LeftOnly(&'a K, &'a V),
+    /// A key that is only in the right tree, with its value.
+    RightOnly(&'a K, &'a V),
+    /// A key that is in both trees with different values, with the values
+    /// of the left and the right tree.
+    Changed(&'a K, &'a V, &'a V),
+}
 
-            util::replace(&mut self.nodes[0], Some(TreeNode { value: child }));
+/// A change of a single key, as part of a patch that turns one tree into
+/// another, see `BTree::patch`. Every change carries the value it expects
+/// to find, so a patch is only applied to a tree in the state it was made
+/// for.
+#[deriving(Eq, Clone)]
+pub enum Change<K, V> {
+    /// Insert a key that is not present.
+    Added(K, V),
+    /// Remove a key with the given value.
+    Removed(K, V),
+    /// Replace the first value of a key with the second one.
+    Updated(K, V, V),
+}
 
-            self.used = 0;
+/// An error of a paged b-tree that reads a corrupt page of its file, see
+/// `PagedBTree::get`.
+#[deriving(Eq, Clone)]
+pub enum PageError {
+    /// The checksum of the page does not match its bytes.
+    ChecksumMismatch(uint),
+    /// The page refers to bytes or pages that it cannot hold.
+    MalformedPage(uint),
+}
 
-            split_child(self, 0);
-        }
+/// An entry of a key that is stored in the tree. The entry remembers the
+/// path from the root to the node that stores the key.
+pub struct OccupiedEntry<'a, K, V> {
+    priv tree: &'a mut BTree<K, V>,
+    priv path: ~[uint],
+    priv pos: uint,
+}
 
-        insert_non_full(self, key, value)
-    }
+/// An entry of a key that is not in the tree. The entry remembers the path
+/// from the root to the bottom node where the key is inserted.
+pub struct VacantEntry<'a, K, V> {
+    priv tree: &'a mut BTree<K, V>,
+    priv path: ~[uint],
+    priv pos: uint,
+    priv key: K,
 }
 
-fn find_node_pos<K: Num + Ord, V>(tree: &BTree<K, V>, key: &K) -> uint {
-    // NB Find the position using binary search on the keys in this node. The
-    // following code performs the binary search, but it results in slower
-    // run-time. Binary search on the keys should be faster than linear search,
-    // but perhaps cache misses explain why the binary search performs poor.
-    /*
-    if tree.used == 0 {
-        return 0;
-    }
+/// A node on the path of an iterator, with the range `lo..hi` of its slots
+/// that are not visited yet. `sep` is the key of the value in the last leaf
+/// slot of the node, see `each`.
+struct Frame<'a, K, V> {
+    node: &'a BTree<K, V>,
+    sep: Option<&'a K>,
+    lo: uint,
+    hi: uint,
+}
 
-    let mut l = 0;
-    let mut u = tree.used - 1;
+/// An iterator over the key-value pairs of a b-tree in ascending key order.
+/// The iterator can be advanced from both ends: `front` and `back` are the
+/// paths of the two ends, and `front_key` and `back_key` are the keys last
+/// passed at either end. An end stops at the key last passed by the other end.
+/// `remaining` is the number of pairs left. `tree` is the root of the tree and
+/// `generation` its generation when the iterator was made, see
+/// `check_generation`.
+pub struct BTreeIterator<'a, K, V> {
+    priv tree: &'a BTree<K, V>,
+    priv generation: uint,
+    priv front: ~[Frame<'a, K, V>],
+    priv back: ~[Frame<'a, K, V>],
+    priv front_key: Option<&'a K>,
+    priv back_key: Option<&'a K>,
+    priv remaining: uint,
+}
 
-    loop {
-        if l == u {
-            if key <= tree.keys[l].get_ref() {
-                return l;
-            }
-            else {
-                return l + 1;
-            }
-        }
+/// An iterator over the key-value pairs of a b-tree in descending key order.
+pub struct BTreeRevIterator<'a, K, V> {
+    priv iter: BTreeIterator<'a, K, V>,
+}
 
-        let i = (l + u) / 2;
+/// An iterator over the key-value pairs of a b-tree within a range of keys,
+/// in ascending key order. `root` is the node the range lies in and `sep` the
+/// key of its last leaf slot, which `seek` counts the skipped keys in.
+/// `order` is the order of the tree, which only its root holds.
+pub struct BTreeRangeIterator<'a, K, V> {
+    priv iter: BTreeIterator<'a, K, V>,
+    priv root: &'a BTree<K, V>,
+    priv sep: Option<&'a K>,
+    priv order: &'a Order<K>,
+}
 
-        let cmp_key = tree.keys[i].get_ref();
+/// A bound of a range of keys, see `BTree::range`.
+pub enum Bound<'a, K> {
+    Included(&'a K),
+    Excluded(&'a K),
+    Unbounded,
+}
 
-        if key < cmp_key {
-            if i == 0 {
-                return 0;
-            }
+/// The first component of a tuple key, as a borrowed form that is equivalent
+/// to every key with that first component, see `BTree::prefix_range`. Tuple
+/// keys of two to four components are ordered lexicographically, so the keys
+/// with a given prefix are adjacent.
+pub struct Prefix<'a, A>(&'a A);
 
-            u = if i - 1 < l { l } else { i - 1 };
-        } else if key > cmp_key {
-            l = i + 1;
-        } else {
-            return i;
-        }
-    }
-    */
+/// The first two components of a tuple key, see `Prefix`.
+pub struct Prefix2<'a, A, B>(&'a A, &'a B);
 
-    // Find the position using linear search on the keys in this node.
-    for (i, k) in tree.keys.iter().enumerate() {
-        let k : &Option<K> = k;
-        match *k {
-            Some(ref k) => {
-                if key <= k {
-                    return i;
-                }
-            }
-            None => return tree.used,
-        };
-    }
+/// The first three components of a tuple key, see `Prefix`.
+pub struct Prefix3<'a, A, B, C>(&'a A, &'a B, &'a C);
 
-    tree.used
-}
+/// The natural ordering of keys, given by `TotalOrd`.
+pub struct Natural;
 
-fn find_node<'r, K: Num + Ord, V>(tree: &'r BTree<K, V>,
-                                  key: &K) -> &'r BTree<K, V> {
-    // TODO make iterative if the borrow checker allows it
-    //match tree.nodes[0] {
-    //    Some(TreeNode { value: _ }) => {
-    //        let pos = find_node_pos(tree, key);
+/// The reverse of the ordering of the comparator it holds.
+pub struct Reverse<C>(C);
 
-    //        match tree.nodes[pos] {
-    //            Some(TreeNode { value: ref mut tree }) => {
-    //                return find_node(&mut **tree, key);
-    //            }
-    //            Some(TreeLeaf { value: _ }) |
-    //            None => fail!("tree.nodes[pos] != TreeNode"),
-    //        }
-    //    }
-    //    Some(TreeLeaf { value: _ }) |
-    //    None => tree,
-    //}
-    let mut current = tree;
+/// The ordering of records by a key that the extractor `E` takes from each
+/// record, see `KeyOf`.
+pub struct ByKeyOf<E, K> {
+    priv extract: E,
+}
 
-    loop {
-        match current.nodes[0] {
-            Some(TreeNode { value: _ }) => {
-                let pos = find_node_pos(tree, key);
+/// An extracted key of a record with the extractor `E`, as a borrowed form
+/// that is equivalent to the records with that key in a tree ordered by
+/// `ByKeyOf`.
+pub struct Field<'a, K, E>(&'a K, E);
+
+/// A node on the path of a mutable iterator. The key and node slots of the
+/// node are borrowed separately, so the values can be handed out mutably
+/// while the keys stay shared. `sep` is the same as in `Frame`.
+struct MutFrame<'a, K, V> {
+    keys: vec::VecIterator<'a, Option<K>>,
+    nodes: vec::VecMutIterator<'a, Option<TreeItem<K, V>>>,
+    sep: Option<&'a K>,
+}
 
-                match tree.nodes[pos] {
-                    Some(TreeNode { value: ref tree }) => {
-                        //return find_node(&mut **tree, key);
-                        current = &**tree;
-                    }
-                    Some(TreeLeaf { value: _ }) |
-                    None => fail!("tree.nodes[pos] != TreeNode"),
-                }
-            }
-            Some(TreeLeaf { value: _ }) |
-            None => return tree,
-        }
-    }
+/// An iterator over the keys and mutable values of a b-tree in ascending key
+/// order. The stack borrows the nodes mutably, so the root is kept as a raw
+/// pointer for `check_generation`.
+pub struct BTreeMutIterator<'a, K, V> {
+    priv tree: *BTree<K, V>,
+    priv generation: uint,
+    priv stack: ~[MutFrame<'a, K, V>],
+    priv remaining: uint,
 }
 
-fn split_child<K: Num + Ord, V: Eq>(tree: &mut BTree<K, V>, pos: uint) {
-    let t = BTREE_MIN_DEGREE;
+/// A node on the path of a consuming iterator, with the next slot to visit.
+/// The keys and values are moved out of the node as they are visited.
+struct MoveFrame<K, V> {
+    node: ~BTree<K, V>,
+    sep: Option<K>,
+    pos: uint,
+}
 
-    // Make a free slot in the parent node for the to-be-inserted key.
-    // Move the median key from the left node to the parent node. The median
-    // key separates the left and right node.
-    let mut i = tree.used;
+/// An iterator that consumes a b-tree and yields its key-value pairs in
+/// ascending key order. Every node is dropped once it is visited.
+pub struct BTreeMoveIterator<K, V> {
+    priv stack: ~[MoveFrame<K, V>],
+    priv remaining: uint,
+}
 
-    while i > pos {
-        tree.nodes.swap(i, i + 1);
-        tree.keys.swap(i - 1, i);
-        i -= 1;
-    }
+/// An iterator that moves the key-value pairs out of a b-tree in ascending key
+/// order, see `BTree::drain`. The emptied nodes become spare nodes of the
+/// tree.
+pub struct BTreeDrainIterator<'a, K, V> {
+    priv spare: &'a mut ~[~BTree<K, V>],
+    priv stack: ~[MoveFrame<K, V>],
+    priv remaining: uint,
+}
 
-    let right = match tree.nodes[pos] {
-        Some(TreeNode { value: ref mut left }) => {
-            let mut right = BTree::new();
+/// An iterator that removes the key-value pairs for which a predicate returns
+/// true and moves them out in ascending key order, see `BTree::drain_filter`.
+/// The iterator walks the tree with a mutable cursor, so a pair is only
+/// tested when the iterator gets to it, and the pairs after the last one
+/// taken stay in the tree if the iterator is dropped early.
+pub struct BTreeDrainFilter<'a, K, V> {
+    priv cursor: BTreeMutCursor<'a, K, V>,
+    priv pred: 'a |&K, &V| -> bool,
+}
 
-            let mut i = 0;
+/// An iterator over the keys of a b-tree in ascending order.
+pub struct BTreeKeyIterator<'a, K, V> {
+    priv iter: BTreeIterator<'a, K, V>,
+}
 
-            // Move the larger `t - 1' keys from the left to the right node.
-            while i < t - 1 {
-                util::swap(&mut right.keys[i], &mut left.keys[i + t]);
-                i += 1;
-            }
+/// An iterator over the values of a b-tree in ascending key order.
+pub struct BTreeValueIterator<'a, K, V> {
+    priv iter: BTreeIterator<'a, K, V>,
+}
 
-            i = 0;
+/// A run of key-value pairs that are stored next to each other in a bottom
+/// node, see `BTree::chunks`. The key of the last value of a bottom node is
+/// stored in an ancestor, so that pair is a run of its own.
+pub struct Chunk<'a, K, V> {
+    priv keys: &'a [Option<K>],
+    priv nodes: &'a [Option<TreeItem<K, V>>],
+}
 
-            // Move the larger `t' nodes from the left to the right node.
-            while i < t {
-                util::swap(&mut right.nodes[i], &mut left.nodes[i + t]);
-                i += 1;
-            }
+/// A node on the path of a chunk iterator, with the next slot to visit. `sep`
+/// is the slot of the key of the value in the last leaf slot of the node, or
+/// an empty slice on the last path of the tree.
+struct ChunkFrame<'a, K, V> {
+    node: &'a BTree<K, V>,
+    sep: &'a [Option<K>],
+    pos: uint,
+}
 
-            util::swap(&mut tree.keys[pos], &mut left.keys[t - 1]);
+/// An iterator over the runs of key-value pairs of a b-tree in ascending key
+/// order.
+pub struct BTreeChunkIterator<'a, K, V> {
+    priv stack: ~[ChunkFrame<'a, K, V>],
+}
 
-            left.used = t - 1;
-            right.used = t - 1;
+/// An iterator over the keys of a chunk, which can also index them, see
+/// `Chunk::keys`.
+pub struct ChunkKeyIterator<'a, K> {
+    priv iter: vec::VecIterator<'a, Option<K>>,
+}
 
-            right
-        }
-        _ => fail!("unreachable path: tree.nodes[pos] should be a TreeNode"),
-    };
+/// An iterator over the values of a chunk, which can also index them, see
+/// `Chunk::values`.
+pub struct ChunkValueIterator<'a, K, V> {
+    priv iter: vec::VecIterator<'a, Option<TreeItem<K, V>>>,
+}
 
-    // Insert the new right node into the parent
-    tree.nodes[pos + 1] = Some(TreeNode { value: right });
-    tree.used += 1;
+/// A b-tree that maps a key to any number of values. The values of a key are
+/// kept in the order in which they were inserted.
+pub struct BTreeMultiMap<K, V> {
+    priv tree: ~BTree<K, ~[V]>,
+    /// The number of key-value bindings, which counts every value of a key.
+    priv length: uint,
 }
 
-fn is_leaf<K, V>(tree: &mut BTree<K, V>) -> bool {
-    match tree.nodes[0] {
-        Some(TreeLeaf { value: _ }) => true,
-        Some(TreeNode { value: _ }) | None => false,
-    }
+/// An iterator over the key-value bindings of a multimap in ascending key
+/// order, see `BTreeMultiMap::iter`.
+pub struct BTreeMultiIterator<'a, K, V> {
+    priv iter: BTreeIterator<'a, K, ~[V]>,
+    priv key: Option<&'a K>,
+    priv values: vec::VecIterator<'a, V>,
 }
 
-fn insert_non_full<K: Num + Ord, V: Eq>(tree: &mut BTree<K, V>, key: K,
-                                        value: V) -> bool {
-    if tree.used == 0 || is_leaf(tree) {
-        let pos = find_node_pos(tree, &key);
+/// A set of values that is kept sorted, which is a b-tree with the values as
+/// keys.
+pub struct BTreeSet<T> {
+    priv map: ~BTree<T, ()>,
+}
 
-        let new_key = tree.keys[pos].is_none()
-                      || tree.keys[pos].get_ref() != &key;
+/// A multiset of values that is kept sorted. Each distinct value is stored
+/// once with the number of times it occurs.
+pub struct BTreeMultiset<T> {
+    priv map: ~BTree<T, uint>,
+    /// The number of values, which counts every occurrence of a value.
+    priv length: uint,
+}
 
-        if new_key {
-            let mut i = tree.used;
+/// An iterator over the values of a multiset in ascending order, which yields
+/// a value as often as it occurs, see `BTreeMultiset::iter`.
+pub struct BTreeMultisetIterator<'a, T> {
+    priv iter: BTreeIterator<'a, T, uint>,
+    priv value: Option<&'a T>,
+    priv left: uint,
+}
 
-            if i > 0 {
+/// A collection of half-open intervals `start..end` with a value each, which
+/// answers stabbing and overlap queries. The intervals are kept in a tree
+/// ordered by start and end, which keeps the largest end of every subtree,
+/// see `BTree::with_aggregate`. A query skips the subtrees that end before
+/// the queried range and stops at the first interval that starts after it,
+/// so it only descends into subtrees that hold a reported interval: it takes
+/// O(log n + k) time for k reported intervals that lie close together in the
+/// tree, and never more than O(k log n).
+pub struct BTreeIntervals<T, V> {
+    priv tree: ~BTree<(T, T), V>,
+}
 
-                while i > pos {
-                    tree.keys.swap(i - 1, i);
-                    i -= 1;
-                }
+/// The monoid of the tree of `BTreeIntervals`, which summarizes intervals by
+/// the largest end among them.
+struct MaxEnd;
+
+/// An iterator over the intervals that end after `lo` and start before `hi`,
+/// or not after it if `inclusive` is true, in the order of start and end,
+/// see `BTreeIntervals::stabbing`. None stands for no bound. The top frame
+/// of the stack is the node that is visited with its next slot, where even
+/// slots are children and odd slots are pairs.
+pub struct BTreeIntervalIterator<'a, T, V> {
+    priv stack: ~[(&'a BTree<(T, T), V>, uint)],
+    priv lo: Option<T>,
+    priv hi: Option<T>,
+    priv inclusive: bool,
+}
 
-                i = tree.used + 1;
+/// A b-tree with a maximum number of pairs, which evicts the least recently
+/// used pair to make room for a new one. Every pair carries the tick of its
+/// last use, and a second tree orders the keys by that tick.
+pub struct BTreeCache<K, V> {
+    priv tree: ~BTree<K, (V, uint)>,
+    priv recency: ~BTree<uint, K>,
+    priv capacity: uint,
+    priv tick: uint,
+}
 
-                while i > pos {
-                    tree.nodes.swap(i - 1, i);
-                    i -= 1;
-                }
-            }
+/// An iterator over the pairs of a cache in ascending key order, which does
+/// not count as a use of the pairs, see `BTreeCache::iter`.
+pub struct BTreeCacheIterator<'a, K, V> {
+    priv iter: BTreeIterator<'a, K, (V, uint)>,
+}
 
-            tree.used += 1;
-        }
+/// A b-tree whose pairs carry an expiry time of type `T`. A pair is live up
+/// to and including its expiry time. Stale pairs are dropped when a lookup
+/// finds them, or in bulk by `expire_before`, which uses a second tree that
+/// orders the keys by expiry time.
+pub struct BTreeExpiring<K, T, V> {
+    priv tree: ~BTree<K, (V, T)>,
+    priv expiry: ~BTree<T, ~[K]>,
+}
 
-        util::replace(&mut tree.keys[pos], Some(key));
-        util::replace(&mut tree.nodes[pos], Some(TreeLeaf { value: value }));
+/// A b-tree that keeps the earlier values of its keys. Every change of a
+/// key is stored under the key and the version that the change created, and
+/// a removal is stored as None. The versions count the changes of the whole
+/// tree, starting at 1, so a version identifies a state of the tree.
+pub struct BTreeVersioned<K, V> {
+    priv tree: ~BTree<(K, uint), Option<V>>,
+    priv version: uint,
+}
 
-        new_key
-    } else {
-        let mut pos = find_node_pos(tree, &key);
-        let mut split = false;
+/// A b-tree with a secondary index on an attribute of its values, which is
+/// extracted by `E`, see `KeyOf`. The index is a second tree with the
+/// attribute and the primary key of every pair as its key, and it is updated
+/// on every insertion and removal. The values can only be changed by
+/// inserting them again, so the index stays in sync.
+pub struct BTreeIndexed<K, V, A, E> {
+    priv tree: ~BTree<K, V>,
+    priv index: ~BTree<(A, K), ()>,
+    priv extract: E,
+}
 
-        match tree.nodes[pos] {
-            Some(TreeNode { value: ref mut t }) => {
-                if t.used == t.capacity() {
-                    split = true;
-                }
-            }
-            Some(TreeLeaf { value: _ }) => {
-                fail!("unreachable path: leaf has same depth as a node");
-            }
-            None => fail!("todo")
-        }
+/// An iterator over the pairs of an indexed tree with a given attribute, in
+/// ascending order of the primary key, see `BTreeIndexed::find_by`.
+pub struct BTreeIndexIterator<'a, K, V, A> {
+    priv tree: &'a BTree<K, V>,
+    priv iter: BTreeRangeIterator<'a, (A, K), ()>,
+}
 
-        if split {
-            split_child(tree, pos);
+/// A map in both directions, in which the values are unique as well as the
+/// keys. A tree from the keys to the values and a tree from the values to the
+/// keys are kept in sync, so lookups in both directions take a single
+/// descent.
+pub struct BTreeBiMap<K, V> {
+    priv forward: ~BTree<K, V>,
+    priv backward: ~BTree<V, K>,
+}
 
-            match tree.keys[pos] {
-                Some(ref k) => {
-                    if key > *k {
-                        pos += 1;
-                    }
-                }
-                None => {}
-            }
-        }
+/// An immutable b-tree whose nodes are shared between versions. An insertion
+/// or removal returns a new tree that copies the nodes on the path of the key
+/// and shares all other nodes with the old tree, so every version stays
+/// valid and a copy of a tree takes constant time.
+#[deriving(Clone)]
+pub struct PersistentBTree<K, V> {
+    priv root: Option<@PersistentNode<K, V>>,
+    priv length: uint,
+}
 
-        match tree.nodes[pos] {
-            Some(TreeNode { value: ref mut t }) => {
-                insert_non_full(&mut **t, key, value)
-            }
-            Some(TreeLeaf { value: _ }) |
-            None => fail!("unreachable path: leaf has same depth as a node")
-        }
-    }
+/// A node of a persistent b-tree, which stores every key together with its
+/// value. A node without children is a bottom node.
+#[deriving(Clone)]
+struct PersistentNode<K, V> {
+    keys: ~[K],
+    values: ~[V],
+    children: ~[@PersistentNode<K, V>],
 }
 
-impl<K, V> Container for BTree<K, V> {
-    /// Return the number of keys in use in the b-tree node.
-    #[inline]
-    fn len(&self) -> uint { self.used }
+/// The result of an insertion into a subtree of a persistent tree, which
+/// either fits in a single node or has been split around a median pair.
+enum Inserted<K, V> {
+    Fit(@PersistentNode<K, V>),
+    Split(@PersistentNode<K, V>, K, V, @PersistentNode<K, V>),
+}
 
-    /// Return true if the b-tree node contains no nodes or values.
-    #[inline]
-    fn is_empty(&self) -> bool { self.nodes.head().is_none() }
+/// A b-tree that records its insertions and removals, so they can be undone
+/// and redone. An edit is recorded as the key with the value it had before,
+/// where None means that the key was not present, and undoing an edit
+/// records the value it replaced for a redo in the same way.
+pub struct BTreeHistory<K, V> {
+    priv tree: ~BTree<K, V>,
+    priv undo: ~[(K, Option<V>)],
+    priv redo: ~[(K, Option<V>)],
 }
 
-impl<K, V> Mutable for BTree<K, V> {
-    /// Clear the b-tree, removing all nodes.
-    fn clear(&mut self) {
-        for key in self.keys.mut_iter() {
-            *key = None;
-        }
+/// A b-tree that numbers its changes, so the keys that changed since a given
+/// version can be listed. Every key carries the version of its last change,
+/// and a second tree orders the keys by that version. A removed key stays as
+/// a tombstone with None as its value, until it is forgotten.
+pub struct BTreeTracked<K, V> {
+    priv tree: ~BTree<K, (Option<V>, uint)>,
+    priv changes: ~BTree<uint, K>,
+    priv version: uint,
+    /// The number of keys that are not removed.
+    priv length: uint,
+}
 
-        for node in self.nodes.mut_iter() {
-            *node = None;
+/// An iterator over the keys that changed after a version, in the order of
+/// their last change, see `BTreeTracked::changes_since`.
+pub struct BTreeChangeIterator<'a, K, V> {
+    priv tree: &'a BTree<K, (Option<V>, uint)>,
+    priv iter: BTreeRangeIterator<'a, uint, K>,
+}
+
+/// A b-tree that lives in a file of fixed-size pages, so it need not fit in
+/// memory, see `PagedBTree::open`. Every node is stored in a chain of pages,
+/// and only the nodes on the path of a key are read from the file.
+pub struct PagedBTree<K, V> {
+    priv pager: Pager,
+    /// The first page of the root node, or 0 if the tree is empty.
+    priv root: uint,
+    priv length: uint,
+}
+
+/// The pages of the file of a paged b-tree, with the list of free pages and
+/// a cache of pages.
+struct Pager {
+    file: File,
+    /// The log of the pages that a sync writes, see `PagedBTree::sync`.
+    log: File,
+    /// The number of backups that were made, followed by `changed`, see
+    /// `PagedBTree::backup_incremental`.
+    changes: File,
+    /// The number of backups that were made.
+    backups: uint,
+    /// A bitmap of the pages that were written since the last backup, with
+    /// a bit for every page, which is kept in `changes` as well.
+    changed: ~[u8],
+    pool: BufferPool,
+    /// The format version of the file, which tells whether its pages have
+    /// checksums and its nodes can be compressed.
+    version: uint,
+    /// Whether nodes are compressed when they are written.
+    compress: bool,
+    /// The number of pages of the file, including the header page.
+    pages: uint,
+    /// The first free page, or 0 if there is none.
+    free: uint,
+}
+
+/// A bounded cache of the pages of a paged b-tree, which evicts the least
+/// recently used page that is neither pinned nor dirty. Written pages stay in
+/// the cache as dirty pages until `PagedBTree::sync` writes them to the file.
+struct BufferPool {
+    frames: ~BTree<uint, PageFrame>,
+    /// The pages that can be evicted, by the tick of their last use.
+    recency: ~BTree<uint, uint>,
+    capacity: uint,
+    tick: uint,
+    stats: PageCacheStats,
+}
+
+/// A page in a buffer pool, with the number of times it is pinned and the
+/// tick of its last use. A dirty page was written since the last sync.
+struct PageFrame {
+    bytes: ~[u8],
+    pins: uint,
+    dirty: bool,
+    tick: uint,
+}
+
+/// The number of page reads of a paged b-tree that were served by its cache
+/// and that had to read the file, and the number of pages that were evicted
+/// from the cache, see `PagedBTree::cache_stats`.
+#[deriving(Eq, Clone)]
+pub struct PageCacheStats {
+    hits: uint,
+    misses: uint,
+    evictions: uint,
+}
+
+/// A read-only view of the file of a paged b-tree, which is mapped into
+/// memory, see `MappedBTree::open`. A lookup decodes the nodes on the path of
+/// the key from the mapped pages, without reading the file through a cache
+/// or loading the tree, so a tree that is built once with `PagedBTree` can be
+/// shared as a lookup table. Decoding a node builds an owned `PagedNode`
+/// with copies of all its keys and values, so a lookup allocates and copies
+/// O(t log n) keys and values, not just the ones it compares; the mapping
+/// only saves the reads and the page cache.
+pub struct MappedBTree<K, V> {
+    priv map: MemoryMap,
+    /// The first page of the root node, or 0 if the tree is empty.
+    priv root: uint,
+    priv pages: uint,
+    priv length: uint,
+    priv version: uint,
+}
+
+/// A node of a paged b-tree as it is read from its pages, which stores every
+/// key together with its value. A node without children is a bottom node.
+struct PagedNode<K, V> {
+    keys: ~[K],
+    values: ~[V],
+    /// The first pages of the child nodes.
+    children: ~[uint],
+}
+
+/// A builder of a b-tree from key-value pairs that arrive in ascending key
+/// order, see `BTreeBuilder::push`. The tree is built bottom-up by `build`,
+/// which fills the bottom nodes from left to right and builds the inner
+/// levels on top of them, so a load takes linear time and no node is split.
+pub struct BTreeBuilder<K, V> {
+    priv pairs: ~[(K, V)],
+    /// The number of pairs that were dropped for being out of order.
+    priv dropped: uint,
+    /// The order of the pairs, which the built tree keeps.
+    priv order: Order<K>,
+}
+
+/// A node on the path of a cursor, with the slot of the node on the path. The
+/// keys of the subtree of the node lie between `lo`, exclusive, and `sep`,
+/// inclusive, where None means unbounded.
+struct Step<'a, K, V> {
+    node: &'a BTree<K, V>,
+    lo: Option<&'a K>,
+    sep: Option<&'a K>,
+    pos: uint,
+}
+
+/// A read-only cursor over the key-value pairs of a b-tree. The cursor
+/// remembers the path to its current pair, so it moves to the next or previous
+/// pair in constant amortized time, and a seek only climbs as far up as the
+/// subtree that contains the key. A cursor that moves past either end of the
+/// tree is no longer positioned on a pair until it seeks again.
+pub struct BTreeCursor<'a, K, V> {
+    priv tree: &'a BTree<K, V>,
+    priv generation: uint,
+    priv path: ~[Step<'a, K, V>],
+}
+
+/// A cursor that can insert and remove key-value pairs at its position. The
+/// cursor keeps the path of child positions to the node that stores its
+/// current key, as an `OccupiedEntry` does, and the in-order position of the
+/// key. A move follows the path without comparing keys, so equal keys are
+/// visited one at a time, and a change finds the path again from the
+/// position of the key. A cursor that moves past either end of the tree is
+/// positioned at the end of the tree.
+pub struct BTreeMutCursor<'a, K, V> {
+    priv tree: &'a mut BTree<K, V>,
+    /// The generation of the tree after the last change by the cursor.
+    priv generation: uint,
+    priv path: ~[uint],
+    priv pos: uint,
+    /// The in-order position of the current pair, which is the number of
+    /// pairs at the end of the tree.
+    priv index: uint,
+}
+
+/// An iterator over the differences between two trees in ascending key
+/// order, see `BTree::diff`. `left` and `right` are the paths of the walks of
+/// both trees, as in `BTreeIterator`, and `left_next` and `right_next` the
+/// pairs they are at.
+pub struct BTreeDiffIterator<'a, K, V> {
+    priv left: ~[Frame<'a, K, V>],
+    priv right: ~[Frame<'a, K, V>],
+    priv left_next: Option<(&'a K, &'a V)>,
+    priv right_next: Option<(&'a K, &'a V)>,
+    priv order: &'a Order<K>,
+}
+
+/// A b-tree that can no longer be changed, see `BTree::freeze`. Nodes shared
+/// with snapshots are never changed and their reference counts are atomic,
+/// so a frozen tree can be shared between tasks in an `Arc`.
+pub struct FrozenBTree<K, V> {
+    priv tree: ~BTree<K, V>,
+}
+
+/// A view of a b-tree as a work queue ordered by key, see `BTree::as_queue`.
+/// The pairs stay in the tree, so the queue and map lookups share storage.
+pub struct BTreeQueue<'a, K, V> {
+    priv tree: &'a mut BTree<K, V>,
+}
+
+/// A transaction on a b-tree, see `BTree::begin`. The log holds every change
+/// with what undoes it. A transaction that is dropped without a commit is
+/// rolled back.
+pub struct BTreeTransaction<'a, K, V> {
+    priv tree: &'a mut BTree<K, V>,
+    priv log: ~[Undo<K, V>],
+}
+
+/// A change in the log of a transaction, with the pair that undoes it.
+enum Undo<K, V> {
+    /// A pair with the key was added, and is removed again.
+    UndoAdd(K),
+    /// A pair was removed, and is inserted again.
+    UndoRemove(K, V),
+    /// The value of the key was replaced by another one, and is set again.
+    UndoUpdate(K, V),
+}
+
+/// A borrowed form of a key, such as `&str` for a tree with `~str` keys. The
+/// borrowed form must be ordered consistently with the keys, so it can guide
+/// the descent of `BTree::find_equiv` without building an owned key.
+pub trait KeyEquiv<K>: Equiv<K> {
+    /// Compare the borrowed form with a key of the tree.
+    fn cmp_key(&self, key: &K) -> Ordering;
+}
+
+impl<'a> KeyEquiv<~str> for &'a str {
+    #[inline]
+    fn cmp_key(&self, key: &~str) -> Ordering {
+        self.cmp(&key.as_slice())
+    }
+}
+
+impl<'a, A: Eq, B> Equiv<(A, B)> for Prefix<'a, A> {
+    #[inline]
+    fn equiv(&self, key: &(A, B)) -> bool {
+        let Prefix(prefix) = *self;
+        let (ref first, _) = *key;
+        *prefix == *first
+    }
+}
+
+impl<'a, A: TotalOrd, B> KeyEquiv<(A, B)> for Prefix<'a, A> {
+    #[inline]
+    fn cmp_key(&self, key: &(A, B)) -> Ordering {
+        let Prefix(prefix) = *self;
+        let (ref first, _) = *key;
+        prefix.cmp(first)
+    }
+}
+
+impl<'a, A: Eq, B, C> Equiv<(A, B, C)> for Prefix<'a, A> {
+    #[inline]
+    fn equiv(&self, key: &(A, B, C)) -> bool {
+        let Prefix(prefix) = *self;
+        let (ref first, _, _) = *key;
+        *prefix == *first
+    }
+}
+
+impl<'a, A: TotalOrd, B, C> KeyEquiv<(A, B, C)> for Prefix<'a, A> {
+    #[inline]
+    fn cmp_key(&self, key: &(A, B, C)) -> Ordering {
+        let Prefix(prefix) = *self;
+        let (ref first, _, _) = *key;
+        prefix.cmp(first)
+    }
+}
+
+impl<'a, A: Eq, B, C, D> Equiv<(A, B, C, D)> for Prefix<'a, A> {
+    #[inline]
+    fn equiv(&self, key: &(A, B, C, D)) -> bool {
+        let Prefix(prefix) = *self;
+        let (ref first, _, _, _) = *key;
+        *prefix == *first
+    }
+}
+
+impl<'a, A: TotalOrd, B, C, D> KeyEquiv<(A, B, C, D)> for Prefix<'a, A> {
+    #[inline]
+    fn cmp_key(&self, key: &(A, B, C, D)) -> Ordering {
+        let Prefix(prefix) = *self;
+        let (ref first, _, _, _) = *key;
+        prefix.cmp(first)
+    }
+}
+
+impl<'a, A: Eq, B: Eq, C> Equiv<(A, B, C)> for Prefix2<'a, A, B> {
+    #[inline]
+    fn equiv(&self, key: &(A, B, C)) -> bool {
+        let Prefix2(a, b) = *self;
+        let (ref first, ref second, _) = *key;
+        *a == *first && *b == *second
+    }
+}
+
+impl<'a, A: TotalOrd, B: TotalOrd, C> KeyEquiv<(A, B, C)>
+        for Prefix2<'a, A, B> {
+    #[inline]
+    fn cmp_key(&self, key: &(A, B, C)) -> Ordering {
+        let Prefix2(a, b) = *self;
+        let (ref first, ref second, _) = *key;
+
+        match a.cmp(first) {
+            Equal => b.cmp(second),
+            order => order,
         }
+    }
+}
 
-        self.used = 0;
+impl<'a, A: Eq, B: Eq, C, D> Equiv<(A, B, C, D)> for Prefix2<'a, A, B> {
+    #[inline]
+    fn equiv(&self, key: &(A, B, C, D)) -> bool {
+        let Prefix2(a, b) = *self;
+        let (ref first, ref second, _, _) = *key;
+        *a == *first && *b == *second
     }
 }
 
-impl<K: ToStr, V> ToStr for BTree<K, V> {
-    fn to_str(&self) -> ~str { to_str(self, 0) }
+impl<'a, A: TotalOrd, B: TotalOrd, C, D> KeyEquiv<(A, B, C, D)>
+        for Prefix2<'a, A, B> {
+    #[inline]
+    fn cmp_key(&self, key: &(A, B, C, D)) -> Ordering {
+        let Prefix2(a, b) = *self;
+        let (ref first, ref second, _, _) = *key;
+
+        match a.cmp(first) {
+            Equal => b.cmp(second),
+            order => order,
+        }
+    }
 }
 
-fn to_str<K: ToStr, V>(tree: &BTree<K, V>, indent: uint) -> ~str {
-    let buf : ~[~str] = tree.nodes.iter().enumerate().map(|(i, x)| {
-        if i < tree.used {
-            let key = match tree.keys[i] {
-                Some(ref key) => key,
-                None => fail!("unreachable path"),
-            };
+impl<'a, A: Eq, B: Eq, C: Eq, D> Equiv<(A, B, C, D)>
+        for Prefix3<'a, A, B, C> {
+    #[inline]
+    fn equiv(&self, key: &(A, B, C, D)) -> bool {
+        let Prefix3(a, b, c) = *self;
+        let (ref first, ref second, ref third, _) = *key;
+        *a == *first && *b == *second && *c == *third
+    }
+}
 
-            "\t".repeat(indent) + match *x {
-                Some(TreeNode { value: ref tree }) => {
-                    ~"Node(key=" + key.to_str() + ")\n"
-                    + to_str::<K, V>(&**tree, indent + 1)
-                }
-                Some(TreeLeaf { value: _ }) => {
-                    ~"Leaf(key=" + key.to_str() + ")"
-                }
-                None => ~"None",
-            }
-        } else {
-            "\t".repeat(indent) + match *x {
-                Some(TreeNode { value: ref tree }) => {
-                    ~"Node(key=None)\n" + to_str::<K, V>(&**tree, indent + 1)
-                }
-                Some(TreeLeaf { value: _ }) => ~"Leaf(key=None)",
-                None => ~"None",
-            }
+impl<'a, A: TotalOrd, B: TotalOrd, C: TotalOrd, D> KeyEquiv<(A, B, C, D)>
+        for Prefix3<'a, A, B, C> {
+    #[inline]
+    fn cmp_key(&self, key: &(A, B, C, D)) -> Ordering {
+        let Prefix3(a, b, c) = *self;
+        let (ref first, ref second, ref third, _) = *key;
+
+        match a.cmp(first) {
+            Equal => match b.cmp(second) {
+                Equal => c.cmp(third),
+                order => order,
+            },
+            order => order,
         }
-    }).collect();
+    }
+}
 
-    buf.connect("\n")
+/// A prefix of tuple keys that also knows the component that follows it, so
+/// `BTree::suffix_range` can range over that component.
+pub trait PrefixOf<K, S>: KeyEquiv<K> {
+    /// Return the component of `key` that follows the prefix.
+    fn suffix<'a>(&self, key: &'a K) -> &'a S;
 }
 
-impl<K: Num, V: Eq> Eq for BTree<K, V> {
+impl<'a, A: TotalOrd, B> PrefixOf<(A, B), B> for Prefix<'a, A> {
     #[inline]
-    fn eq(&self, other: &BTree<K, V>) -> bool {
-        self.used == other.used
-            && self.keys == other.keys
-            && self.nodes == other.nodes
+    fn suffix<'b>(&self, key: &'b (A, B)) -> &'b B {
+        let (_, ref second) = *key;
+        second
     }
+}
 
+impl<'a, A: TotalOrd, B, C> PrefixOf<(A, B, C), B> for Prefix<'a, A> {
     #[inline]
-    fn ne(&self, other: &BTree<K, V>) -> bool { !(*self).eq(other) }
+    fn suffix<'b>(&self, key: &'b (A, B, C)) -> &'b B {
+        let (_, ref second, _) = *key;
+        second
+    }
 }
 
-impl<K: Num, V: Eq> Eq for TreeItem<K, V> {
+impl<'a, A: TotalOrd, B, C, D> PrefixOf<(A, B, C, D), B> for Prefix<'a, A> {
     #[inline]
-    fn eq(&self, other: &TreeItem<K, V>) -> bool {
+    fn suffix<'b>(&self, key: &'b (A, B, C, D)) -> &'b B {
+        let (_, ref second, _, _) = *key;
+        second
+    }
+}
+
+impl<'a, A: TotalOrd, B: TotalOrd, C> PrefixOf<(A, B, C), C>
+        for Prefix2<'a, A, B> {
+    #[inline]
+    fn suffix<'b>(&self, key: &'b (A, B, C)) -> &'b C {
+        let (_, _, ref third) = *key;
+        third
+    }
+}
+
+impl<'a, A: TotalOrd, B: TotalOrd, C, D> PrefixOf<(A, B, C, D), C>
+        for Prefix2<'a, A, B> {
+    #[inline]
+    fn suffix<'b>(&self, key: &'b (A, B, C, D)) -> &'b C {
+        let (_, _, ref third, _) = *key;
+        third
+    }
+}
+
+impl<'a, A: TotalOrd, B: TotalOrd, C: TotalOrd, D> PrefixOf<(A, B, C, D), D>
+        for Prefix3<'a, A, B, C> {
+    #[inline]
+    fn suffix<'b>(&self, key: &'b (A, B, C, D)) -> &'b D {
+        let (_, _, _, ref fourth) = *key;
+        fourth
+    }
+}
+
+/// An ordering of keys, see `BTree::with_comparator`. A tree holds a single
+/// comparator, so the comparator can carry state, such as a collation table
+/// or the values that a closure would capture.
+pub trait Comparator<K> {
+    /// Compare two keys.
+    fn compare(&self, a: &K, b: &K) -> Ordering;
+
+    /// Return true if the borrowed forms of keys compare with the keys as
+    /// this comparator orders them, see `KeyEquiv`, so a tree with this
+    /// comparator can look them up with `BTree::find_equiv`.
+    fn orders_equiv(&self) -> bool { false }
+
+    /// Return true if this comparator orders keys by their `TotalOrd`
+    /// implementation, so a tree with this comparator can select tuple keys
+    /// by their suffixes with `BTree::suffix_range`.
+    fn is_natural(&self) -> bool { false }
+}
+
+impl<K: TotalOrd> Comparator<K> for Natural {
+    #[inline]
+    fn compare(&self, a: &K, b: &K) -> Ordering { a.cmp(b) }
+
+    #[inline]
+    fn orders_equiv(&self) -> bool { true }
+
+    #[inline]
+    fn is_natural(&self) -> bool { true }
+}
+
+impl<K, C: Comparator<K>> Comparator<K> for Reverse<C> {
+    #[inline]
+    fn compare(&self, a: &K, b: &K) -> Ordering {
+        let Reverse(ref c) = *self;
+        c.compare(b, a)
+    }
+}
+
+impl<K: Ord> Order<K> {
+    /// Return true if `a` is ordered before `b`.
+    #[inline]
+    fn less(&self, a: &K, b: &K) -> bool {
         match *self {
-            TreeNode { value: ref v1 } => {
-                match *other {
-                    TreeNode { value: ref v2 } => v1 == v2,
-                    TreeLeaf { value: _ } => false,
-                }
-            }
-            TreeLeaf { value: ref v1 } => {
-                match *other {
-                    TreeLeaf { value: ref v2 } => v1 == v2,
-                    TreeNode { value: _ } => false,
-                }
-            }
+            NaturalOrder => *a < *b,
+            FnOrder(f) => f(a, b) == Less,
+            ComparatorOrder(ref c) => c.get().compare(a, b) == Less,
+        }
+    }
+
+    /// Return true if the borrowed forms of keys are ordered like the keys,
+    /// see `Comparator::orders_equiv`. The borrowed forms compare with the
+    /// keys by `KeyEquiv`, which knows nothing of the order of the tree, so a
+    /// lookup by them would otherwise descend to the wrong keys.
+    fn orders_equiv(&self) -> bool {
+        match *self {
+            NaturalOrder => true,
+            FnOrder(_) => false,
+            ComparatorOrder(ref c) => c.get().orders_equiv(),
+        }
+    }
+
+    /// Return true if the keys are ordered by their `TotalOrd`
+    /// implementation, see `Comparator::is_natural`.
+    fn is_natural(&self) -> bool {
+        match *self {
+            NaturalOrder => true,
+            FnOrder(_) => false,
+            ComparatorOrder(ref c) => c.get().is_natural(),
         }
     }
 
+    /// Return true if neither `a` nor `b` is ordered before the other.
     #[inline]
-    fn ne(&self, other: &TreeItem<K, V>) -> bool { !(*self).eq(other) }
+    fn equal(&self, a: &K, b: &K) -> bool {
+        match *self {
+            NaturalOrder => *a == *b,
+            FnOrder(f) => f(a, b) == Equal,
+            ComparatorOrder(ref c) => c.get().compare(a, b) == Equal,
+        }
+    }
 }
 
-#[bench]
-fn bench_insert_random(bh: &mut BenchHarness) {
-    let iterations = 1000;
+impl<K> Clone for Order<K> {
+    #[inline]
+    fn clone(&self) -> Order<K> {
+        match *self {
+            NaturalOrder => NaturalOrder,
+            FnOrder(f) => FnOrder(f),
+            ComparatorOrder(ref c) => ComparatorOrder(c.clone()),
+        }
+    }
+}
 
-    let mut rng = IsaacRng::new();
-    rng.reseed([42u32]);
+/// A compact binary encoding of keys and values, see `BTree::write_to`.
+/// Integers are written as 8 bytes in little-endian order, and strings and
+/// vectors as their length followed by their bytes or elements.
+pub trait Binary {
+    /// Return the name of the encoding, which an image stores for its keys
+    /// and values so it is not read back as another type. The argument is
+    /// only there to select the type, and is always None.
+    fn binary_encoding(_: Option<Self>) -> ~str;
+    /// Write the value to `w`.
+    fn write_binary(&self, w: &mut Writer);
+    /// Read a value that was written by `write_binary` from `r`.
+    fn read_binary(r: &mut Reader) -> Self;
+}
+
+impl Binary for int {
+    fn binary_encoding(_: Option<int>) -> ~str { ~"i64" }
+    fn write_binary(&self, w: &mut Writer) { w.write_le_i64(*self as i64) }
+    fn read_binary(r: &mut Reader) -> int { r.read_le_i64() as int }
+}
+
+impl Binary for uint {
+    fn binary_encoding(_: Option<uint>) -> ~str { ~"u64" }
+    fn write_binary(&self, w: &mut Writer) { w.write_le_u64(*self as u64) }
+    fn read_binary(r: &mut Reader) -> uint { r.read_le_u64() as uint }
+}
+
+impl Binary for () {
+    fn binary_encoding(_: Option<()>) -> ~str { ~"()" }
+    fn write_binary(&self, _: &mut Writer) {}
+    fn read_binary(_: &mut Reader) -> () { () }
+}
+
+impl Binary for ~str {
+    fn binary_encoding(_: Option<~str>) -> ~str { ~"str" }
+
+    fn write_binary(&self, w: &mut Writer) {
+        self.len().write_binary(w);
+        w.write(self.as_bytes());
+    }
+
+    fn read_binary(r: &mut Reader) -> ~str {
+        let len: uint = Binary::read_binary(r);
+        str::from_utf8_owned(r.read_bytes(len))
+    }
+}
+
+impl<T: Binary> Binary for ~[T] {
+    fn binary_encoding(_: Option<~[T]>) -> ~str {
+        format!("[{}]", Binary::binary_encoding(None::<T>))
+    }
+
+    fn write_binary(&self, w: &mut Writer) {
+        self.len().write_binary(w);
+
+        for x in self.iter() {
+            x.write_binary(w);
+        }
+    }
+
+    fn read_binary(r: &mut Reader) -> ~[T] {
+        let len: uint = Binary::read_binary(r);
+        vec::from_fn(len, |_| Binary::read_binary(r))
+    }
+}
+
+impl<A: Binary, B: Binary> Binary for (A, B) {
+    fn binary_encoding(_: Option<(A, B)>) -> ~str {
+        format!("({},{})", Binary::binary_encoding(None::<A>),
+                Binary::binary_encoding(None::<B>))
+    }
+
+    fn write_binary(&self, w: &mut Writer) {
+        let (ref a, ref b) = *self;
+        a.write_binary(w);
+        b.write_binary(w);
+    }
+
+    fn read_binary(r: &mut Reader) -> (A, B) {
+        let a = Binary::read_binary(r);
+        let b = Binary::read_binary(r);
+        (a, b)
+    }
+}
+
+/// The extraction of a key from a record, so records can be the keys of a
+/// tree that is ordered by one of their fields, see `ByKeyOf`. The key is
+/// borrowed from the record, so it is not stored twice.
+pub trait KeyOf<T, K> {
+    /// Return the key of a record.
+    fn key_of<'a>(&self, record: &'a T) -> &'a K;
+}
+
+/// A summary of key-value pairs, such as their sum or their largest value,
+/// which a tree can keep for every subtree, see `BTree::with_aggregate`.
+/// `combine` must be associative, as the summaries of adjacent runs of pairs
+/// are combined in ascending key order but in no fixed grouping.
+pub trait Monoid<K, V, S> {
+    /// Return the summary of a single pair.
+    fn summarize(&self, key: &K, value: &V) -> S;
+
+    /// Return the summary of the pairs of `a` followed by the pairs of `b`.
+    fn combine(&self, a: &S, b: &S) -> S;
+}
+
+impl<K, V, S: Clone + Send + Freeze + 'static, M: Monoid<K, V, S>>
+    Summarizer<K, V> for MonoidSummarizer<M, S> {
+    fn summarize(&self, key: &K, value: &V) -> Summary {
+        ~self.monoid.summarize(key, value) as Summary
+    }
+
+    fn combine(&self, a: &Summary, b: &Summary) -> Summary {
+        let (a, b) = (unbox_summary::<S>(a), unbox_summary::<S>(b));
+        ~self.monoid.combine(a, b) as Summary
+    }
+
+    fn copy(&self, a: &Summary) -> Summary {
+        ~unbox_summary::<S>(a).clone() as Summary
+    }
+}
+
+impl<E, K> ByKeyOf<E, K> {
+    /// Return the ordering of records by the key that `extract` takes from
+    /// each record.
+    #[inline]
+    pub fn new(extract: E) -> ByKeyOf<E, K> {
+        ByKeyOf { extract: extract }
+    }
+}
+
+impl<T, K: TotalOrd, E: KeyOf<T, K>> Comparator<T> for ByKeyOf<E, K> {
+    #[inline]
+    fn compare(&self, a: &T, b: &T) -> Ordering {
+        self.extract.key_of(a).cmp(self.extract.key_of(b))
+    }
+
+    #[inline]
+    fn orders_equiv(&self) -> bool { true }
+}
+
+impl<'a, T, K: TotalOrd, E: KeyOf<T, K>> Equiv<T> for Field<'a, K, E> {
+    #[inline]
+    fn equiv(&self, record: &T) -> bool {
+        self.cmp_key(record) == Equal
+    }
+}
+
+impl<'a, T, K: TotalOrd, E: KeyOf<T, K>> KeyEquiv<T> for Field<'a, K, E> {
+    #[inline]
+    fn cmp_key(&self, record: &T) -> Ordering {
+        let Field(key, ref extract) = *self;
+        key.cmp(extract.key_of(record))
+    }
+}
+
+impl<'a, K: Ord> Bound<'a, K> {
+    /// Return true if `key` is below this bound as a lower bound of a range
+    /// of keys ordered by `order`.
+    fn is_below(&self, order: &Order<K>, key: &K) -> bool {
+        match *self {
+            Included(bound) => order.less(key, bound),
+            Excluded(bound) => !order.less(bound, key),
+            Unbounded => false,
+        }
+    }
+
+    /// Return true if `key` is above this bound as an upper bound of a range
+    /// of keys ordered by `order`.
+    fn is_above(&self, order: &Order<K>, key: &K) -> bool {
+        match *self {
+            Included(bound) => order.less(bound, key),
+            Excluded(bound) => !order.less(key, bound),
+            Unbounded => false,
+        }
+    }
+}
+
+impl<K: Ord, V> BTree<K, V> {
+    pub fn new() -> ~BTree<K, V> {
+        // TODO: once https://github.com/mozilla/rust/issues/5244 is fixed,
+        // use the following statement:
+        //~BTree { used: 0, key: [None, ..BTREE_KEYS_UBOUND],
+        //         nodes: [None, ..BTREE_KEYS_UBOUND + 1] }
+
+        // NB for executing the commented tests below, use this statement:
+        //~BTree { used: 0, keys: [None, None, None],
+        //         nodes: [None, None, None, None] }
+
+        ~BTree { used: 0, length: 0, spare: ~[], duplicates: Replace,
+                 order: NaturalOrder, aggregate: None, generation: 0, keys: [
+                None, None, None, None, None, None, None, None, None, None,
+                None, None, None, None, None, None, None, None, None, None,
+                None, None, None, None, None, None, None, None, None, None,
+                None, None, None, None, None, None, None, None, None,
+            ],
+            nodes: [
+                None, None, None, None, None, None, None, None, None, None,
+                None, None, None, None, None, None, None, None, None, None,
+                None, None, None, None, None, None, None, None, None, None,
+                None, None, None, None, None, None, None, None, None, None,
+            ]
+        }
+    }
+
+    /// Return an empty tree with a pool of preallocated nodes for about `n`
+    /// entries. Splits take their new nodes from the pool while it lasts, so
+    /// loading `n` entries does not allocate nodes during the splits.
+    pub fn with_capacity(n: uint) -> ~BTree<K, V> {
+        let mut tree = BTree::new();
+
+        // Splits leave nodes about half full, so every level has about
+        // `t - 1` times fewer nodes than the level below it.
+        let mut level = n / BTREE_KEYS_LBOUND;
+        let mut count = 0;
+
+        while level > 0 {
+            count += level;
+            level /= BTREE_KEYS_LBOUND;
+        }
+
+        tree.spare = vec::from_fn(count, |_| BTree::new());
+
+        tree
+    }
+
+    /// Return an empty tree that handles insertions of keys that are already
+    /// in the tree as given by `duplicates`. With `Keep` the tree can hold
+    /// equal keys, which are yielded in the reverse order of their insertion,
+    /// and `find` and `remove` pick any one of them. The policy applies to
+    /// `insert`, `swap`, `insert_sorted` and `append`. The entry API,
+    /// `try_insert` and `find_or_insert` look the key up first and hand out
+    /// the pair that is already there, so they never add an equal key.
+    pub fn with_duplicates(duplicates: Duplicates) -> ~BTree<K, V> {
+        let mut tree = BTree::new();
+        tree.duplicates = duplicates;
+        tree
+    }
+
+    /// Return what an insertion does with a key that is already in the tree.
+    #[inline]
+    pub fn duplicates(&self) -> Duplicates { self.duplicates }
+
+    /// Return an empty tree that orders its keys by `comparator` instead of
+    /// their `Ord` implementation, which the tree then no longer uses. The
+    /// tree holds the comparator once, so it can carry state, and the trees
+    /// that are split off or copied from the tree share it.
+    pub fn with_comparator<C: Comparator<K> + Send + Freeze>(comparator: C)
+                                                            -> ~BTree<K, V> {
+        let comparator = ~comparator as ~Comparator<K>:Send+Freeze;
+        let mut tree = BTree::new();
+        tree.order = ComparatorOrder(Arc::new(comparator));
+        tree
+    }
+
+    /// Return an empty tree that orders its keys by the function `f`, which
+    /// can be chosen at runtime. A closure cannot be kept by the tree, so an
+    /// ordering that needs captured values is a `Comparator` with those values
+    /// as fields, see `with_comparator`.
+    pub fn with_order(f: fn(&K, &K) -> Ordering) -> ~BTree<K, V> {
+        let mut tree = BTree::new();
+        tree.order = FnOrder(f);
+        tree
+    }
+
+    /// Return an empty tree that keeps a summary of the pairs of every
+    /// subtree, which `monoid` computes, so the summary of a range of keys is
+    /// combined from the summaries of O(log n) subtrees instead of from every
+    /// pair in the range, see `range_aggregate`. The trees that are split off
+    /// or copied from the tree keep summaries with the same monoid.
+    pub fn with_aggregate<S: Clone + Send + Freeze + 'static,
+                          M: Monoid<K, V, S> + Send + Freeze>(monoid: M)
+                          -> ~BTree<K, V> {
+        let summarizer: MonoidSummarizer<M, S> =
+            MonoidSummarizer { monoid: monoid };
+        let summarizer = ~summarizer as ~Summarizer<K, V>:Send+Freeze;
+        let mut tree = BTree::new();
+        tree.aggregate = Some(Arc::new(summarizer));
+        tree
+    }
+
+    /// Return the number of keys that can be stored in the b-tree node.
+    #[inline]
+    pub fn capacity(&self) -> uint { BTREE_KEYS_UBOUND }
+
+    /// Return a reference to the value corresponding to the key.
+    pub fn find<'a>(&'a self, key: &K) -> Option<&'a V> {
+        find(self, &self.order, key)
+    }
+
+    /// Return true if the tree contains a value for the key. Unlike `find`,
+    /// the descent stops at the node that stores the key.
+    pub fn contains_key(&self, key: &K) -> bool {
+        let mut current = self;
+
+        loop {
+            let pos = find_node_pos(current, &self.order, key);
+
+            if pos < current.used
+               && self.order.equal(current.keys[pos].get_ref(), key) {
+                return true;
+            }
+
+            match current.nodes[pos] {
+                Some(TreeNode { value: ref tree }) => {
+                    current = tree.get();
+                }
+                Some(TreeLeaf { value: _ }) |
+                None => return false
+            }
+        }
+    }
+
+    /// Return a reference to the value corresponding to a key that is
+    /// equivalent to `key`, without converting `key` to the key type. This
+    /// returns None for a tree whose order the borrowed forms do not follow,
+    /// such as one ordered by a function or by `Reverse`, see
+    /// `Comparator::orders_equiv`.
+    pub fn find_equiv<'a, Q: KeyEquiv<K>>(&'a self, key: &Q) -> Option<&'a V> {
+        if !self.order.orders_equiv() {
+            return None;
+        }
+
+        find_equiv(self, key)
+    }
+
+    /// Return a mutable reference to the value corresponding to the key.
+    pub fn find_mut<'a>(&'a mut self, key: &K) -> Option<&'a mut V> {
+        let order = self.order.clone();
+        find_mut(self, &order, key, false)
+    }
+
+    /// Replace the value of `key` if it is in the tree and return the old
+    /// value. Unlike `swap`, a missing key is not inserted, so the tree is
+    /// never split.
+    pub fn replace_value(&mut self, key: &K, value: V) -> Option<V> {
+        let order = self.order.clone();
+
+        match find_mut(self, &order, key, false) {
+            Some(v) => Some(util::replace(v, value)),
+            None => None
+        }
+    }
+
+    /// Insert a key-value pair into the b-tree. Return true if the key did not
+    /// already exist in the tree, or if the tree keeps duplicate keys.
+    pub fn insert(&mut self, key: K, value: V) -> bool {
+        self.swap(key, value).is_none()
+    }
+
+    /// Insert a key-value pair into the b-tree. If the key already existed,
+    /// replace its value and return the old value. Otherwise return None. A
+    /// tree that rejects duplicate keys returns the new value instead, and a
+    /// tree that keeps them always returns None, see `with_duplicates`.
+    pub fn swap(&mut self, key: K, value: V) -> Option<V> {
+        self.generation += 1;
+        let mut spare = util::replace(&mut self.spare, ~[]);
+
+        if self.used == self.capacity() {
+            split_root(self, &mut spare);
+        }
+
+        let duplicates = self.duplicates;
+        let order = self.order.clone();
+        let old = insert_non_full(self, &order, key, value, duplicates,
+                                  &mut spare);
+        self.spare = spare;
+        refresh_summaries(&*self);
+
+        old
+    }
+
+    /// Insert a key-value pair if the key is not in the tree. Otherwise leave
+    /// the tree unchanged and hand the pair back, also in a tree that keeps
+    /// duplicate keys.
+    pub fn try_insert(&mut self, key: K, value: V) -> Result<(), (K, V)> {
+        self.generation += 1;
+        let mut spare = util::replace(&mut self.spare, ~[]);
+
+        if self.used == self.capacity() {
+            split_root(self, &mut spare);
+        }
+
+        let mut path = ~[];
+        let order = self.order.clone();
+        let (pos, found) = entry_path(self, &order, &key, &mut path,
+                                      &mut spare);
+        self.spare = spare;
+
+        if found {
+            return Err((key, value));
+        }
+
+        VacantEntry { tree: self, path: path, pos: pos, key: key }.set(value);
+
+        Ok(())
+    }
+
+    /// Return a mutable reference to the value of the key, inserting `value`
+    /// first if the key is not in the tree.
+    pub fn find_or_insert<'a>(&'a mut self, key: K, value: V) -> &'a mut V {
+        let mut value = Some(value);
+        self.find_or_insert_with(key, |_| value.take_unwrap())
+    }
+
+    /// Return a mutable reference to the value of the key, inserting the
+    /// result of `f` first if the key is not in the tree. The lookup and the
+    /// insertion share a single descent of the tree.
+    pub fn find_or_insert_with<'a>(&'a mut self, key: K, f: |&K| -> V)
+        -> &'a mut V {
+        match self.entry(key) {
+            Occupied(e) => e.into_mut(),
+            Vacant(e) => {
+                let value = f(e.key());
+                e.set(value)
+            }
+        }
+    }
+
+    /// Insert a key-value pair if the key is not in the tree, or otherwise
+    /// update the existing value with `f`. Both cases share a single descent
+    /// of the tree. Return a mutable reference to the value.
+    pub fn insert_or_update_with<'a>(&'a mut self, key: K, value: V,
+                                     f: |&K, &mut V|) -> &'a mut V {
+        match self.entry(key) {
+            Occupied(e) => {
+                let (k, v) = e.into_key_value();
+                f(k, v);
+                v
+            }
+            Vacant(e) => e.set(value),
+        }
+    }
+
+    /// Create the value of the key with `not_found` if the key is not in the
+    /// tree, or otherwise mutate the existing value with `found`. Either
+    /// closure receives `a`. Both cases share a single descent of the tree.
+    /// Return a mutable reference to the value.
+    pub fn mangle<'a, A>(&'a mut self, key: K, a: A, not_found: |&K, A| -> V,
+                         found: |&K, &mut V, A|) -> &'a mut V {
+        match self.entry(key) {
+            Occupied(e) => {
+                let (k, v) = e.into_key_value();
+                found(k, v, a);
+                v
+            }
+            Vacant(e) => {
+                let value = not_found(e.key(), a);
+                e.set(value)
+            }
+        }
+    }
+
+    /// Remove a key-value pair from the b-tree. Return true if the key was
+    /// present in the tree.
+    pub fn remove(&mut self, key: &K) -> bool {
+        self.pop(key).is_some()
+    }
+
+    /// Remove a key-value pair from the b-tree and return the value, or None
+    /// if the key was not present in the tree.
+    pub fn pop(&mut self, key: &K) -> Option<V> {
+        pop(self, key)
+    }
+
+    /// Return the entry of the key. The entry remembers the position of the
+    /// key in the tree, so it can be inspected, inserted, replaced or removed
+    /// without searching the tree again.
+    pub fn entry<'a>(&'a mut self, key: K) -> Entry<'a, K, V> {
+        self.generation += 1;
+        let mut spare = util::replace(&mut self.spare, ~[]);
+
+        if self.used == self.capacity() {
+            split_root(self, &mut spare);
+        }
+
+        let mut path = ~[];
+        let order = self.order.clone();
+        let (pos, found) = entry_path(self, &order, &key, &mut path,
+                                      &mut spare);
+        self.spare = spare;
+
+        if found {
+            Occupied(OccupiedEntry { tree: self, path: path, pos: pos })
+        } else {
+            Vacant(VacantEntry { tree: self, path: path, pos: pos, key: key })
+        }
+    }
+
+    /// Return an iterator over the key-value pairs in ascending key order.
+    pub fn iter<'a>(&'a self) -> BTreeIterator<'a, K, V> {
+        BTreeIterator {
+            tree: self,
+            generation: self.generation,
+            front: ~[Frame::new(self, None)],
+            back: ~[Frame::new(self, None)],
+            front_key: None,
+            back_key: None,
+            remaining: self.len(),
+        }
+    }
+
+    /// Return an iterator over the key-value pairs in descending key order.
+    pub fn rev_iter<'a>(&'a self) -> BTreeRevIterator<'a, K, V> {
+        BTreeRevIterator { iter: self.iter() }
+    }
+
+    /// Return an iterator over the key-value pairs with keys between `lo` and
+    /// `hi`, in ascending key order. Both ends of the iterator start with a
+    /// descent to the first or last key in the range. The keys just outside
+    /// the range are looked up in advance and serve as the keys last passed
+    /// at either end, so no keys outside the range are visited.
+    pub fn range<'a, 'b>(&'a self, lo: Bound<'b, K>,
+                         hi: Bound<'b, K>) -> BTreeRangeIterator<'a, K, V> {
+        range_by(self, |k| lo.is_below(&self.order, k),
+                 |k| hi.is_above(&self.order, k))
+    }
+
+    /// Return an iterator over the key-value pairs with keys that are
+    /// equivalent to `key`, in ascending key order. The keys equivalent to a
+    /// borrowed form are adjacent in the key order, such as the keys with the
+    /// same first component for a `Prefix` of tuple keys. Like `find_equiv`
+    /// this finds nothing in a tree whose order the borrowed forms do not
+    /// follow, and the iterator is empty.
+    pub fn prefix_range<'a, Q: KeyEquiv<K>>(&'a self, key: &Q)
+                                            -> BTreeRangeIterator<'a, K, V> {
+        if !self.order.orders_equiv() {
+            return empty_range(self);
+        }
+
+        range_by(self, |k| key.cmp_key(k) == Greater,
+                 |k| key.cmp_key(k) == Less)
+    }
+
+    /// Return an iterator over the pairs of tuple keys that start with
+    /// `prefix` and whose next component is within the range given by `lo`
+    /// and `hi`, in ascending key order. With tuple keys the tree works as an
+    /// index over several columns: `Prefix`, `Prefix2` and `Prefix3` fix the
+    /// first one to three columns, and the range is over the column after
+    /// them. The suffixes are compared by their `Ord` implementation, so
+    /// the keys must be ordered lexicographically, as tuples are by their
+    /// `TotalOrd` implementation, see `Comparator::is_natural`. The iterator
+    /// is empty for a tree with any other order.
+    pub fn suffix_range<'a, 'b, S: Ord, P: PrefixOf<K, S>>(
+            &'a self, prefix: &P, lo: Bound<'b, S>,
+            hi: Bound<'b, S>) -> BTreeRangeIterator<'a, K, V> {
+        if !self.order.is_natural() {
+            return empty_range(self);
+        }
+
+        range_by(self, |k| match prefix.cmp_key(k) {
+            Greater => true,
+            Equal => lo.is_below(&NaturalOrder, prefix.suffix(k)),
+            Less => false,
+        }, |k| match prefix.cmp_key(k) {
+            Less => true,
+            Equal => hi.is_above(&NaturalOrder, prefix.suffix(k)),
+            Greater => false,
+        })
+    }
+
+    /// Split the tree into iterators over the subtrees of the root, in
+    /// ascending key order. Each iterator also yields the separator key that
+    /// follows its subtree, so together they yield every pair exactly once.
+    /// The iterators are independent of each other, which allows a large
+    /// tree to be aggregated in parallel, one subtree per task.
+    pub fn split_iters<'a>(&'a self) -> ~[BTreeRangeIterator<'a, K, V>] {
+        match self.nodes[0] {
+            Some(TreeNode { value: _ }) => {}
+            _ => return ~[subtree_iter(self, self, None, self.length)],
+        }
+
+        let mut iters = vec::with_capacity(self.used + 1);
+
+        for i in range(0, self.used + 1) {
+            let sep = if i < self.used { self.keys[i].as_ref() } else { None };
+
+            match self.nodes[i] {
+                Some(TreeNode { value: ref child }) => {
+                    let child = child.get();
+                    let length = child.length;
+                    iters.push(subtree_iter(self, child, sep, length));
+                }
+                _ => fail!("inner node without a child"),
+            }
+        }
+
+        iters
+    }
+
+    /// Fold the key-value pairs with keys in the range `lo..hi`, including
+    /// `lo` and excluding `hi`, in ascending key order. `f` takes the
+    /// accumulated value and a pair, and returns the new accumulated value.
+    /// Every pair in the range is visited; a tree that keeps a summary of
+    /// every subtree answers such a fold in O(log n) time, see
+    /// `range_aggregate`.
+    pub fn fold_range<'a, A>(&'a self, lo: &K, hi: &K, init: A,
+                             f: |A, &'a K, &'a V| -> A) -> A {
+        let mut acc = init;
+
+        for (k, v) in self.range(Included(lo), Excluded(hi)) {
+            acc = f(acc, k, v);
+        }
+
+        acc
+    }
+
+    /// Return the number of keys in the range `lo..hi`, including `lo` and
+    /// excluding `hi`. The count is taken from the subtree counts of the
+    /// nodes, see `rank`, without visiting the keys in the range.
+    pub fn range_len(&self, lo: &K, hi: &K) -> uint {
+        if !self.order.less(lo, hi) {
+            return 0;
+        }
+
+        self.rank(hi) - self.rank(lo)
+    }
+
+    /// Return the summary of all pairs, or None if the tree is empty or keeps
+    /// no summaries, see `range_aggregate`.
+    pub fn aggregate<S: Clone + 'static>(&self) -> Option<S> {
+        match refresh_summaries(self) {
+            Some(aggregate) => {
+                node_summary(self, &aggregate)
+                    .map(|s| unbox_summary::<S>(&s).clone())
+            }
+            None => None,
+        }
+    }
+
+    /// Return the summary of the pairs with keys in the range `lo..hi`,
+    /// including `lo` and excluding `hi`, or None if the range holds no pair
+    /// or the tree keeps no summaries. `S` is the summary type of the monoid
+    /// of the tree, see `with_aggregate`. Every node keeps the summary of its
+    /// subtree, which is dropped when the node changes. `swap` and `pop`,
+    /// and so `insert` and `remove`, compute the summaries of the nodes they
+    /// changed right away, and a query first computes those of the nodes
+    /// changed otherwise, such as by a value changed through `find_mut`,
+    /// also for nodes shared with snapshots. The subtrees that lie within
+    /// the range contribute their summaries, so only the nodes on the paths
+    /// of `lo` and `hi` are visited, and a query takes O(log n) time once the
+    /// summaries are kept.
+    pub fn range_aggregate<S: Clone + 'static>(&self, lo: &K, hi: &K)
+                                              -> Option<S> {
+        if !self.order.less(lo, hi) {
+            return None;
+        }
+
+        match refresh_summaries(self) {
+            Some(aggregate) => {
+                summary_range(self, &aggregate, &self.order, None,
+                              Included(lo), Excluded(hi))
+                    .map(|s| unbox_summary::<S>(&s).clone())
+            }
+            None => None,
+        }
+    }
+
+    /// Return an iterator over the keys and mutable references to the values
+    /// in ascending key order.
+    pub fn mut_iter<'a>(&'a mut self) -> BTreeMutIterator<'a, K, V> {
+        let remaining = self.len();
+        let tree: *BTree<K, V> = &*self;
+        let generation = self.generation;
+        BTreeMutIterator { tree: tree, generation: generation,
+                           stack: ~[MutFrame::new(self, None)],
+                           remaining: remaining }
+    }
+
+    /// Return an iterator that consumes the tree and yields the owned
+    /// key-value pairs in ascending key order.
+    pub fn move_iter(~self) -> BTreeMoveIterator<K, V> {
+        let remaining = self.len();
+        let root = MoveFrame { node: self, sep: None, pos: 0 };
+        BTreeMoveIterator { stack: ~[root], remaining: remaining }
+    }
+
+    /// Call `f` on every key-value pair in ascending key order, until `f`
+    /// returns false. Return false if the traversal was stopped early.
+    pub fn each<'a>(&'a self, f: |&'a K, &'a V| -> bool) -> bool {
+        each(self, None, f)
+    }
+
+    /// Call `f` on every key-value pair in descending key order, until `f`
+    /// returns false. Return false if the traversal was stopped early.
+    pub fn each_reverse<'a>(&'a self, f: |&'a K, &'a V| -> bool) -> bool {
+        each_reverse(self, None, f)
+    }
+
+    /// Return a cursor positioned at the smallest key of the tree.
+    pub fn cursor<'a>(&'a self) -> BTreeCursor<'a, K, V> {
+        let mut cursor = BTreeCursor { tree: self,
+                                       generation: self.generation,
+                                       path: ~[] };
+        cursor.seek_first();
+        cursor
+    }
+
+    /// Return a mutable cursor positioned at the smallest key of the tree.
+    pub fn mut_cursor<'a>(&'a mut self) -> BTreeMutCursor<'a, K, V> {
+        let generation = self.generation;
+        let mut cursor = BTreeMutCursor { tree: self, generation: generation,
+                                          path: ~[], pos: 0, index: 0 };
+        cursor.move_to(0);
+        cursor
+    }
+
+    /// Return a view of the tree as a queue that hands out the pairs with
+    /// the smallest key first. Keys that must not replace each other can be
+    /// kept with `Keep`, see `with_duplicates`.
+    pub fn as_queue<'a>(&'a mut self) -> BTreeQueue<'a, K, V> {
+        BTreeQueue { tree: self }
+    }
+
+    /// Return an iterator that moves the key-value pairs out of the tree in
+    /// ascending key order. The tree is empty right away, and the nodes are
+    /// kept as spare nodes for later insertions once they are emptied.
+    pub fn drain<'a>(&'a mut self) -> BTreeDrainIterator<'a, K, V> {
+        let remaining = self.len();
+        let spare = util::replace(&mut self.spare, ~[]);
+        let root = ~util::replace(self, *BTree::new());
+        self.spare = spare;
+        self.duplicates = root.duplicates;
+        self.order = root.order.clone();
+        self.aggregate = root.aggregate.clone();
+        self.generation = root.generation + 1;
+
+        BTreeDrainIterator {
+            spare: &mut self.spare,
+            stack: ~[MoveFrame { node: root, sep: None, pos: 0 }],
+            remaining: remaining,
+        }
+    }
+
+    /// Return an iterator over the keys in ascending order.
+    pub fn keys<'a>(&'a self) -> BTreeKeyIterator<'a, K, V> {
+        BTreeKeyIterator { iter: self.iter() }
+    }
+
+    /// Return an iterator over the values in ascending key order.
+    pub fn values<'a>(&'a self) -> BTreeValueIterator<'a, K, V> {
+        BTreeValueIterator { iter: self.iter() }
+    }
+
+    /// Return an iterator over the key-value pairs in ascending key order,
+    /// which yields the pairs of a bottom node as a single chunk. The keys of
+    /// a chunk are a slice of the node, which avoids the per-pair work of
+    /// `iter` when the pairs are exported in bulk.
+    pub fn chunks<'a>(&'a self) -> BTreeChunkIterator<'a, K, V> {
+        BTreeChunkIterator { stack: ~[ChunkFrame { node: self, sep: &[],
+                                                   pos: 0 }] }
+    }
+
+    /// Return the smallest key and its value, or None if the tree is empty.
+    pub fn min<'a>(&'a self) -> Option<(&'a K, &'a V)> {
+        let mut current = self;
+
+        loop {
+            match current.nodes[0] {
+                Some(TreeNode { value: ref tree }) => {
+                    current = tree.get();
+                }
+                Some(TreeLeaf { value: ref value }) => {
+                    return Some((current.keys[0].get_ref(), value));
+                }
+                None => return None
+            }
+        }
+    }
+
+    /// Return the largest key and its value, or None if the tree is empty.
+    pub fn max<'a>(&'a self) -> Option<(&'a K, &'a V)> {
+        let mut current = self;
+
+        // The rightmost bottom node has no separator key to its right, so its
+        // last key is stored in the node itself.
+        loop {
+            match current.nodes[current.used] {
+                Some(TreeNode { value: ref tree }) => {
+                    current = tree.get();
+                }
+                Some(TreeLeaf { value: _ }) |
+                None => break
+            }
+        }
+
+        if current.used == 0 {
+            None
+        } else {
+            let pos = current.used - 1;
+            Some((current.keys[pos].get_ref(), value_at(current, pos)))
+        }
+    }
+
+    /// Return the smallest key, or None if the tree is empty.
+    pub fn min_key<'a>(&'a self) -> Option<&'a K> {
+        self.min().map(|(k, _)| k)
+    }
+
+    /// Return the largest key, or None if the tree is empty.
+    pub fn max_key<'a>(&'a self) -> Option<&'a K> {
+        self.max().map(|(k, _)| k)
+    }
+
+    /// Return the largest key that is less than or equal to `key`, with its
+    /// value. The lookup takes a single descent of the tree.
+    pub fn floor<'a>(&'a self, key: &K) -> Option<(&'a K, &'a V)> {
+        find_below(self, &self.order, key, true)
+    }
+
+    /// Return the smallest key that is greater than or equal to `key`, with
+    /// its value. The lookup takes a single descent of the tree.
+    pub fn ceiling<'a>(&'a self, key: &K) -> Option<(&'a K, &'a V)> {
+        find_above(self, &self.order, key, true)
+    }
+
+    /// Return the largest key that is less than `key`, with its value.
+    pub fn next_below<'a>(&'a self, key: &K) -> Option<(&'a K, &'a V)> {
+        find_below(self, &self.order, key, false)
+    }
+
+    /// Return the smallest key that is greater than `key`, with its value.
+    pub fn next_above<'a>(&'a self, key: &K) -> Option<(&'a K, &'a V)> {
+        find_above(self, &self.order, key, false)
+    }
+
+    /// Remove the smallest key from the tree and return it with its value, or
+    /// None if the tree is empty.
+    pub fn pop_min(&mut self) -> Option<(K, V)> {
+        if self.used == 0 {
+            return None;
+        }
+
+        let entry = remove_first(self);
+        collapse_root(self);
+        self.generation += 1;
+        Some(entry)
+    }
+
+    /// Remove the largest key from the tree and return it with its value, or
+    /// None if the tree is empty.
+    pub fn pop_max(&mut self) -> Option<(K, V)> {
+        if self.used == 0 {
+            return None;
+        }
+
+        let entry = remove_last(self);
+        collapse_root(self);
+        self.generation += 1;
+        Some(entry)
+    }
+
+    /// Return the generation of the tree, which counts its structural
+    /// changes. Every change that may move pairs between nodes or replace the
+    /// root increases it, such as an insertion, a removal, a split or a join;
+    /// lookups and changes of values in place leave it as it is. Iterators and
+    /// cursors remember the generation of the tree they walk, see
+    /// `check_generation`.
+    pub fn generation(&self) -> uint { self.generation }
+
+    /// Return the number of node levels of the tree, which is the number of
+    /// nodes visited by a lookup.
+    pub fn height(&self) -> uint {
+        let mut current = self;
+        let mut height = 1;
+
+        loop {
+            match current.nodes[0] {
+                Some(TreeNode { value: ref tree }) => {
+                    current = tree.get();
+                    height += 1;
+                }
+                Some(TreeLeaf { value: _ }) |
+                None => return height
+            }
+        }
+    }
+
+    /// Return the number of nodes of the tree, including the root.
+    pub fn node_count(&self) -> uint {
+        let (nodes, _) = count_items(self);
+        nodes
+    }
+
+    /// Return the number of leaf slots in use, which hold the values of the
+    /// tree.
+    pub fn leaf_count(&self) -> uint {
+        let (_, leaves) = count_items(self);
+        leaves
+    }
+
+    /// Remove all key-value pairs for which `f` returns false. The pairs are
+    /// visited once in order and the positions of those that fail are noted,
+    /// after which they are removed in place from the last to the first, so
+    /// the other nodes are left as they are.
+    pub fn retain(&mut self, f: |&K, &V| -> bool) {
+        let mut stale = ~[];
+        let mut index = 0;
+
+        self.each(|k, v| {
+            if !f(k, v) {
+                stale.push(index);
+            }
+
+            index += 1;
+            true
+        });
+
+        for &i in stale.iter().invert() {
+            remove_index(self, i);
+        }
+    }
+
+    /// Return an iterator that removes the key-value pairs for which `pred`
+    /// returns true and yields them in ascending key order. The removal is
+    /// lazy: each call to `next` tests the pairs from the last one taken up
+    /// to the next match, and removes that one through a `BTreeMutCursor`.
+    pub fn drain_filter<'a>(&'a mut self, pred: 'a |&K, &V| -> bool)
+                            -> BTreeDrainFilter<'a, K, V> {
+        let generation = self.generation;
+        let mut cursor = BTreeMutCursor { tree: self, generation: generation,
+                                          path: ~[], pos: 0, index: 0 };
+        cursor.move_to(0);
+        BTreeDrainFilter { cursor: cursor, pred: pred }
+    }
+
+    /// Keep only the `n` smallest keys of the tree and drop the others. The
+    /// tree is cut after the first `n` pairs as by `split_off`, so only the
+    /// nodes on the path of the cut are split and rebalanced, and the part
+    /// with the larger keys is dropped as a whole.
+    pub fn truncate(&mut self, n: uint) {
+        if n >= self.len() {
+            return;
+        }
+
+        if n == 0 {
+            self.clear();
+            return;
+        }
+
+        split_at(self, n);
+    }
+
+    /// Remove all key-value pairs from the tree and return them in ascending
+    /// key order. Unlike `clear`, the entries are moved out instead of
+    /// dropped.
+    pub fn drain_clear(&mut self) -> ~[(K, V)] {
+        let mut entries = vec::with_capacity(self.len());
+        take_entries(self, None, &mut entries);
+        self.generation += 1;
+
+        entries
+    }
+
+    /// Split the tree at `key`. The tree keeps the keys smaller than `key`,
+    /// and the keys greater than or equal to `key` are returned as a new tree.
+    /// Only the nodes on the path of `key` are split and rebalanced, the
+    /// other nodes move to either tree as a whole.
+    pub fn split_off(&mut self, key: &K) -> ~BTree<K, V> {
+        let index = self.rank(key);
+        split_at(self, index)
+    }
+
+    /// Move all entries of `other` into the tree. If all keys of one tree are
+    /// smaller than all keys of the other, the smaller tree is hung as a whole
+    /// below the taller one, and only the nodes along the seam are split or
+    /// rebalanced. Otherwise the entries of `other` are inserted one by one
+    /// as by `insert`, as if they were inserted after the entries of the tree.
+    pub fn append(&mut self, other: ~BTree<K, V>) {
+        let mut other = other;
+
+        if other.is_empty() {
+            return;
+        }
+
+        let duplicates = self.duplicates;
+        let order = self.order.clone();
+        let aggregate = self.aggregate.clone();
+        let generation = self.generation + 1;
+
+        if self.is_empty() {
+            *self = *other;
+            self.duplicates = duplicates;
+            self.order = order;
+            self.aggregate = aggregate;
+            self.generation = generation;
+            return;
+        }
+
+        let (before, after) = {
+            let (min, max) = (self.min_key().unwrap(), self.max_key().unwrap());
+            (order.less(max, other.min_key().unwrap()),
+             order.less(other.max_key().unwrap(), min))
+        };
+
+        if before {
+            let (key, value) = other.pop_min().unwrap();
+
+            if other.is_empty() {
+                self.insert(key, value);
+            } else {
+                join(self, key, value, other);
+            }
+        } else if after {
+            let (key, value) = other.pop_max().unwrap();
+
+            if other.is_empty() {
+                self.insert(key, value);
+            } else {
+                let right = ~util::replace(self, *other);
+                join(self, key, value, right);
+                self.duplicates = duplicates;
+                self.order = order;
+                self.aggregate = aggregate;
+                self.generation = generation;
+            }
+        } else {
+            let mut entries = ~[];
+            take_entries(&mut *other, None, &mut entries);
+
+            // Equal keys that are kept end up in the reverse order of their
+            // insertion, so they are inserted backwards to keep their order.
+            for (key, value) in entries.move_rev_iter() {
+                self.insert(key, value);
+            }
+        }
+    }
+
+    /// Remove all keys in the range `lo..hi`, including `lo` and excluding
+    /// `hi`, and return the number of removed entries. The tree is split at
+    /// both ends of the range and the outer parts are joined again, so the
+    /// subtrees inside the range are dropped as a whole.
+    pub fn remove_range(&mut self, lo: &K, hi: &K) -> uint {
+        if !self.order.less(lo, hi) {
+            return 0;
+        }
+
+        let mut band = self.split_off(lo);
+        let rest = band.split_off(hi);
+
+        self.append(rest);
+
+        band.len()
+    }
+
+    /// Look up a slice of keys sorted in ascending order, and return their
+    /// values in the same order. The keys are routed down the tree together,
+    /// so adjacent keys share the nodes on their common path.
+    pub fn get_many<'a>(&'a self, keys: &[K]) -> ~[Option<&'a V>] {
+        let mut values = vec::with_capacity(keys.len());
+        find_sorted(self, &self.order, keys, &mut values);
+        values
+    }
+
+    /// Insert key-value pairs sorted in ascending key order. The pairs are
+    /// built into a tree bottom-up, filling the bottom nodes from left to
+    /// right, which is then joined with the tree by `append`. Pairs with equal
+    /// keys are handled as if they were inserted one by one, see
+    /// `with_duplicates`. Pairs that are out of order are inserted one by one
+    /// afterwards.
+    pub fn insert_sorted(&mut self, pairs: ~[(K, V)]) {
+        let mut run = vec::with_capacity(pairs.len());
+        let mut rest = ~[];
+
+        for (key, value) in pairs.move_iter() {
+            let n = run.len();
+
+            let (after, same) = if n == 0 {
+                (true, false)
+            } else {
+                let (ref last, _) = run[n - 1];
+                (self.order.less(last, &key), self.order.equal(last, &key))
+            };
+
+            if after {
+                run.push((key, value));
+            } else if same {
+                match self.duplicates {
+                    Replace => run[n - 1] = (key, value),
+                    Reject => {}
+                    Keep => run.push((key, value)),
+                }
+            } else {
+                rest.push((key, value));
+            }
+        }
+
+        // Kept equal keys are yielded in the reverse order of their
+        // insertion, so each group of them is reversed.
+        if self.duplicates == Keep {
+            let mut i = 0;
+
+            while i < run.len() {
+                let mut j = i + 1;
+
+                while j < run.len() && {
+                    let ((ref a, _), (ref b, _)) = (&run[i], &run[j]);
+                    self.order.equal(a, b)
+                } {
+                    j += 1;
+                }
+
+                run.mut_slice(i, j).reverse();
+                i = j;
+            }
+        }
+
+        self.append(build_sorted(run));
+
+        for (key, value) in rest.move_iter() {
+            self.insert(key, value);
+        }
+    }
+
+    /// Build a tree from an iterator of key-value pairs in ascending key
+    /// order, bottom-up in linear time, see `BTreeBuilder`. Of pairs with
+    /// equal keys the last one wins. Return None if a key is smaller than
+    /// the key before it.
+    pub fn from_sorted_iter<I: Iterator<(K, V)>>(iter: I)
+                                                -> Option<~BTree<K, V>> {
+        let mut iter = iter;
+        let mut builder = BTreeBuilder::new();
+
+        for (key, value) in iter {
+            if !builder.push(key, value) {
+                return None;
+            }
+        }
+
+        Some(builder.build())
+    }
+
+    /// Return the in-order index at which `key` would be inserted, together
+    /// with whether the key is already present in the tree. The index equals
+    /// the number of keys in the tree that are smaller than `key`. Both are
+    /// found in the single descent of `rank`: a key that is in the tree is
+    /// stored in a node on the path of that descent.
+    pub fn insertion_index(&self, key: &K) -> (uint, bool) {
+        let mut current = self;
+        let mut rank = 0;
+        let mut found = false;
+
+        loop {
+            let pos = find_node_pos(current, &self.order, key);
+
+            if pos < current.used &&
+               self.order.equal(current.keys[pos].get_ref(), key) {
+                found = true;
+            }
+
+            match current.nodes[pos] {
+                Some(TreeNode { value: ref child }) => {
+                    for item in current.nodes.slice_to(pos).iter() {
+                        rank += item_length(item.get_ref());
+                    }
+
+                    current = child.get();
+                }
+                Some(TreeLeaf { value: _ }) | None => {
+                    return (rank + pos, found);
+                }
+            }
+        }
+    }
+
+    /// Return the number of keys in the tree that are smaller than `key`.
+    /// The subtree counts of the nodes left of the path of `key` are added
+    /// up, so only a single descent is needed.
+    pub fn rank(&self, key: &K) -> uint {
+        let (rank, _) = self.insertion_index(key);
+        rank
+    }
+
+    /// Return the key-value pair at in-order position `index`, or None if the
+    /// tree has `index` or fewer pairs. The descent skips over the subtrees
+    /// that hold fewer pairs than are left to pass.
+    pub fn select<'a>(&'a self, index: uint) -> Option<(&'a K, &'a V)> {
+        let mut current = self;
+        let mut sep = None;
+        let mut index = index;
+        let mut pos = 0;
+
+        if index >= self.length {
+            return None;
+        }
+
+        loop {
+            let key = if pos < current.used {
+                current.keys[pos].as_ref()
+            } else {
+                sep
+            };
+
+            match current.nodes[pos] {
+                Some(TreeNode { value: ref child }) => {
+                    if index < child.get().length {
+                        current = child.get();
+                        sep = key;
+                        pos = 0;
+                        continue;
+                    }
+
+                    index -= child.get().length;
+                }
+                Some(TreeLeaf { value: ref value }) => {
+                    if index == 0 {
+                        return Some((key.unwrap(), value));
+                    }
+
+                    index -= 1;
+                }
+                None => fail!("unreachable path: subtree counts do not match"),
+            }
+
+            pos += 1;
+        }
+    }
+}
+
+impl<K: Ord, V: Default> BTree<K, V> {
+    /// Return references to the stored key and its value, inserting the
+    /// default value first if the key is not in the tree. The lookup and the
+    /// insertion share a single descent of the tree.
+    pub fn entry_ref<'a>(&'a mut self, key: K) -> (&'a K, &'a mut V) {
+        match self.entry(key) {
+            Occupied(e) => e.into_key_value(),
+            Vacant(e) => e.insert_entry(Default::default()),
+        }
+    }
+}
+
+impl<K: Ord, V> BTree<K, V> {
+    /// Turn the tree into a read-only form that can be shared between tasks.
+    /// The spare nodes are dropped, since the tree does not grow anymore.
+    pub fn freeze(~self) -> FrozenBTree<K, V> {
+        let mut tree = self;
+        tree.spare = ~[];
+        FrozenBTree { tree: tree }
+    }
+}
+
+impl<K: Ord, V> FrozenBTree<K, V> {
+    /// Return the tree for lookups and iteration. A shared reference only
+    /// gives access to the methods that read the tree.
+    #[inline]
+    pub fn get<'a>(&'a self) -> &'a BTree<K, V> { &*self.tree }
+
+    /// Return the value of the key.
+    #[inline]
+    pub fn find<'a>(&'a self, key: &K) -> Option<&'a V> { self.tree.find(key) }
+
+    /// Return an iterator over the key-value pairs in ascending key order.
+    #[inline]
+    pub fn iter<'a>(&'a self) -> BTreeIterator<'a, K, V> { self.tree.iter() }
+
+    /// Turn the frozen tree back into a tree that can be changed.
+    pub fn thaw(self) -> ~BTree<K, V> { self.tree }
+}
+
+impl<K, V> Container for FrozenBTree<K, V> {
+    #[inline]
+    fn len(&self) -> uint { self.tree.len() }
+}
+
+impl<K: Ord, V: Eq> BTree<K, V> {
+    /// Return an iterator over the differences between this tree and
+    /// `other` in ascending key order. The trees are walked side by side, and
+    /// the keys that are in both trees with equal values are skipped. Where
+    /// both walks reach a node that the trees share, such as the nodes that
+    /// a tree and its snapshot have not changed since `snapshot`, the whole
+    /// subtree is skipped without visiting its pairs, so the diff of a tree
+    /// and a snapshot takes time in the number of changed nodes. Both trees
+    /// must order their keys alike.
+    pub fn diff<'a>(&'a self, other: &'a BTree<K, V>)
+                    -> BTreeDiffIterator<'a, K, V> {
+        let same = self as *BTree<K, V> == other as *BTree<K, V>;
+        let mut left = if same { ~[] } else { ~[Frame::new(self, None)] };
+        let mut right = if same { ~[] } else { ~[Frame::new(other, None)] };
+
+        skip_shared(&mut left, &mut right, &self.order);
+
+        let left_next = next_pair(&mut left, false);
+        let right_next = next_pair(&mut right, false);
+
+        BTreeDiffIterator { left: left, right: right, left_next: left_next,
+                            right_next: right_next, order: &self.order }
+    }
+}
+
+impl<K: Ord + Clone, V: Eq + Clone> BTree<K, V> {
+    /// Return the changes that turn this tree into `target`, in ascending
+    /// key order, see `diff`.
+    pub fn patch(&self, target: &BTree<K, V>) -> ~[Change<K, V>] {
+        self.diff(target).map(|d| {
+            match d {
+                LeftOnly(k, v) => Removed(k.clone(), v.clone()),
+                RightOnly(k, v) => Added(k.clone(), v.clone()),
+                Changed(k, old, new) => Updated(k.clone(), old.clone(),
+                                                new.clone()),
+            }
+        }).collect()
+    }
+
+    /// Apply the changes of a patch in a transaction, see `begin`. If a
+    /// change finds the tree in another state than it expects, the changes
+    /// applied so far are rolled back, and the change is handed back.
+    pub fn apply_patch(&mut self, patch: ~[Change<K, V>])
+                       -> Result<(), Change<K, V>> {
+        let mut tx = self.begin();
+
+        for change in patch.move_iter() {
+            let applies = match change {
+                Added(ref k, _) => tx.find(k).is_none(),
+                Removed(ref k, ref v) | Updated(ref k, ref v, _) => {
+                    tx.find(k) == Some(v)
+                }
+            };
+
+            if !applies {
+                tx.rollback();
+                return Err(change);
+            }
+
+            // An update replaces the value in place, as an insertion would be
+            // refused by a tree that rejects duplicate keys, and would add a
+            // second pair to a tree that keeps them.
+            match change {
+                Added(k, v) => { tx.insert(k, v); }
+                Updated(k, _, v) => { tx.replace_value(&k, v); }
+                Removed(k, _) => { tx.remove(&k); }
+            }
+        }
+
+        tx.commit();
+        Ok(())
+    }
+}
+
+impl<K: Ord + Binary, V: Binary> BTree<K, V> {
+    /// Write a binary image of the tree to `w`, which holds the nodes as
+    /// they are, see `read_from`. The image starts with a header:
+    ///
+    /// * the bytes `BTRE`,
+    /// * the format version, `BTREE_IMAGE_VERSION`, as 8 bytes,
+    /// * the minimum degree as 8 bytes,
+    /// * the names of the key and value encodings, see `Binary`,
+    /// * the duplicate-key policy as a byte: 0 replaces, 1 rejects, 2 keeps.
+    ///
+    /// The nodes follow in depth-first order. Each node holds its number of
+    /// keys and the keys, then a byte for its kind: 0 for an empty root, 1
+    /// for a bottom node and 2 for an inner node. A bottom node continues
+    /// with a byte that is 1 if its last leaf slot has a value, and the
+    /// values; an inner node with its child nodes.
+    ///
+    /// The image does not hold the order of the keys, so a tree that orders
+    /// them by a comparator or function is not written, see
+    /// `with_comparator`, and false is returned without writing anything.
+    /// The summaries of a tree are not written either, see `with_aggregate`.
+    pub fn write_to(&self, w: &mut Writer) -> bool {
+        match self.order {
+            NaturalOrder => {}
+            FnOrder(_) | ComparatorOrder(_) => return false,
+        }
+
+        w.write(IMAGE_MAGIC);
+        BTREE_IMAGE_VERSION.write_binary(w);
+        BTREE_MIN_DEGREE.write_binary(w);
+        Binary::binary_encoding(None::<K>).write_binary(w);
+        Binary::binary_encoding(None::<V>).write_binary(w);
+
+        let duplicates = match self.duplicates {
+            Replace => 0,
+            Reject => 1,
+            Keep => 2,
+        };
+
+        w.write_u8(duplicates);
+        write_node(self, w);
+        true
+    }
+
+    /// Read a tree from a binary image written by `write_to`. The nodes are
+    /// restored as they were written, and checked to be a valid tree of keys
+    /// in their `Ord` order: all bottom nodes lie at the same depth, every
+    /// node but the root is at least half full, and the keys ascend, or do
+    /// not descend if the tree keeps equal keys. Return None if the image is
+    /// not a tree image, is malformed, or is not compatible with this build:
+    /// it has a newer format version, another minimum degree, or other key
+    /// or value encodings.
+    pub fn read_from(r: &mut Reader) -> Option<~BTree<K, V>> {
+        if r.read_bytes(IMAGE_MAGIC.len()).as_slice() != IMAGE_MAGIC {
+            return None;
+        }
+
+        let version: uint = Binary::read_binary(r);
+
+        if version == 0 || version > BTREE_IMAGE_VERSION {
+            return None;
+        }
+
+        let degree: uint = Binary::read_binary(r);
+
+        if degree != BTREE_MIN_DEGREE {
+            return None;
+        }
+
+        let key: ~str = Binary::read_binary(r);
+        let value: ~str = Binary::read_binary(r);
+
+        if key != Binary::binary_encoding(None::<K>) ||
+           value != Binary::binary_encoding(None::<V>) {
+            return None;
+        }
+
+        let duplicates = match r.read_u8() {
+            0 => Replace,
+            1 => Reject,
+            2 => Keep,
+            _ => return None,
+        };
+
+        let mut tree = match read_node(r, true, true) {
+            Some((tree, _)) => tree,
+            None => return None,
+        };
+
+        tree.duplicates = duplicates;
+
+        // The keys of the image were written by another process, so their
+        // order is checked once the shape of the tree is known to be sound.
+        {
+            let mut prev = None;
+
+            for (key, _) in tree.iter() {
+                match prev {
+                    Some(p) if duplicates == Keep && *key < *p => return None,
+                    Some(p) if duplicates != Keep && *key <= *p => return None,
+                    _ => {}
+                }
+
+                prev = Some(key);
+            }
+        }
+
+        Some(tree)
+    }
+
+    /// Write the pairs of the tree to `w` in ascending key order, as a
+    /// stream that `read_sorted` loads. Each pair is written as the byte 1,
+    /// the key and the value, and the stream ends with the byte 0, so a
+    /// stream can be written without knowing the number of pairs up front.
+    pub fn write_pairs(&self, w: &mut Writer) {
+        for (key, value) in self.iter() {
+            write_pair(w, key, value);
+        }
+
+        w.write_u8(0);
+    }
+
+    /// Build a tree from a stream of pairs in ascending key order, as it is
+    /// written by `write_pairs`, bottom-up in linear time. Return None if the
+    /// stream is malformed or a key is smaller than the key before it.
+    pub fn read_sorted(r: &mut Reader) -> Option<~BTree<K, V>> {
+        let mut builder = BTreeBuilder::new();
+
+        loop {
+            match read_pair(r) {
+                Some(Some((key, value))) => {
+                    if !builder.push(key, value) {
+                        return None;
+                    }
+                }
+                Some(None) => return Some(builder.build()),
+                None => return None,
+            }
+        }
+    }
+
+    /// Build a tree from a file that holds a stream of pairs in any order,
+    /// as `write_pairs` writes them, which may be larger than memory. The
+    /// pairs are sorted in runs of `BTREE_RUN_PAIRS` in memory, and each run
+    /// is written to a file next to `path` with `-run` and its number
+    /// appended to the name. The runs are then merged by a k-way merge into
+    /// a `BTreeBuilder`, and removed. Of pairs with equal keys the last one
+    /// in the file wins. Return None if a file cannot be read or written, or
+    /// the stream is malformed.
+    pub fn build_from_unsorted_file(path: &Path) -> Option<~BTree<K, V>> {
+        build_external(path, BTREE_RUN_PAIRS)
+    }
+}
+
+impl<K: Ord + IterBytes, V: IterBytes> BTree<K, V> {
+    /// Return a hash of the key-value pairs, which is the sum of the hashes
+    /// of the pairs. Every node keeps the digest of its subtree, and a change
+    /// drops the digests of the nodes on its path, including the nodes it
+    /// splits or merges, so only those are computed again. The sum does not
+    /// depend on the grouping of the pairs into nodes, so trees with the same
+    /// pairs have the same digest, whatever their shape, and two trees are
+    /// compared by exchanging a single hash.
+    pub fn digest(&self) -> u64 {
+        digest_node(self)
+    }
+
+    /// Return a hash of the key-value pairs in the range `lo..hi`, including
+    /// `lo` and excluding `hi`, which is taken from the kept digests of the
+    /// subtrees that lie within the range, so it takes O(log n) time once
+    /// they are known. Comparing the digests of the halves of a range
+    /// localizes the keys where two trees differ, see `sync_from`.
+    pub fn range_digest(&self, lo: &K, hi: &K) -> u64 {
+        digest_range(self, &self.order, None, Included(lo), Excluded(hi))
+    }
+}
+
+impl<K: Ord + Clone + IterBytes, V: Clone + IterBytes> BTree<K, V> {
+    /// Make the tree equal to `source`, and return the number of pairs that
+    /// were copied from it. The key ranges of both trees are compared by
+    /// their digests and halved where they differ, so only the ranges of at
+    /// most `2t - 1` pairs that contain a difference are copied, or of a
+    /// longer run of equal keys that a tree with `Keep` can hold, which
+    /// cannot be halved by key. The digest of a range is taken from the
+    /// digests that the nodes keep, see `digest`, so a comparison takes
+    /// O(log n) time instead of visiting the pairs of the range, and a sync
+    /// of d differences takes O(d log^2 n).
+    pub fn sync_from(&mut self, source: &BTree<K, V>) -> uint {
+        sync_range(self, source, Unbounded, Unbounded)
+    }
+}
+
+impl<K: Ord + Clone, V> BTree<K, V> {
+    /// Start a transaction on the tree. The changes made through the
+    /// transaction are applied to the tree right away, and recorded so that
+    /// `rollback` can undo all of them. A transaction ends with `commit` or
+    /// `rollback`, and is rolled back if it goes out of scope without either,
+    /// e.g. when a task fails while it runs.
+    pub fn begin<'a>(&'a mut self) -> BTreeTransaction<'a, K, V> {
+        BTreeTransaction { tree: self, log: ~[] }
+    }
+}
+
+impl<K: Ord + Clone, V: Clone> BTree<K, V> {
+    /// Return a copy of the tree that stays unchanged while the tree is
+    /// modified, so a long scan can run over the snapshot, e.g. with
+    /// `move_iter`, while other code inserts into the tree. Only the root is
+    /// copied and the other nodes are shared, so a snapshot takes constant
+    /// time. Either tree copies a shared node on the path of a change before
+    /// changing it, so a change after a snapshot copies O(log n) nodes.
+    pub fn snapshot(&self) -> ~BTree<K, V> {
+        let mut tree = copy_node(self);
+        tree.duplicates = self.duplicates;
+        tree.order = self.order.clone();
+        tree.aggregate = self.aggregate.clone();
+        tree
+    }
+}
+
+impl<'a, K: Ord, V> BTreeMutCursor<'a, K, V> {
+    /// Move the cursor to the smallest key that is greater than or equal to
+    /// `key`, or the first of several equal keys. Return false if all keys
+    /// are smaller than `key`.
+    pub fn seek(&mut self, key: &K) -> bool {
+        let index = self.tree.rank(key);
+        self.move_to(index);
+        index < self.tree.len()
+    }
+
+    /// Move the cursor to the next pair and return it.
+    pub fn next<'b>(&'b mut self) -> Option<(&'b K, &'b mut V)> {
+        check_generation(&*self.tree, self.generation);
+
+        if self.index < self.tree.len() {
+            self.index += 1;
+
+            if self.index < self.tree.len() {
+                move_index_path(&*self.tree, &mut self.path, &mut self.pos,
+                                true);
+            }
+        }
+
+        self.get()
+    }
+
+    /// Move the cursor to the previous pair and return it. From the end of
+    /// the tree, the cursor moves to the last pair, and from the first pair
+    /// to the end of the tree.
+    pub fn prev<'b>(&'b mut self) -> Option<(&'b K, &'b mut V)> {
+        check_generation(&*self.tree, self.generation);
+        let len = self.tree.len();
+
+        if self.index == 0 {
+            self.move_to(len);
+        } else if self.index == len {
+            self.move_to(len - 1);
+        } else {
+            self.index -= 1;
+            move_index_path(&*self.tree, &mut self.path, &mut self.pos, false);
+        }
+
+        self.get()
+    }
+
+    /// Return the key and a mutable reference to the value at the cursor.
+    pub fn get<'b>(&'b mut self) -> Option<(&'b K, &'b mut V)> {
+        check_generation(&*self.tree, self.generation);
+
+        if self.index >= self.tree.len() {
+            return None;
+        }
+
+        let node = node_at_mut(&mut *self.tree, self.path);
+        Some(key_value_at_mut(node, self.pos))
+    }
+
+    /// Remove the pair at the cursor and return it. The cursor moves to the
+    /// next pair.
+    pub fn remove_current(&mut self) -> Option<(K, V)> {
+        check_generation(&*self.tree, self.generation);
+
+        if self.index >= self.tree.len() {
+            return None;
+        }
+
+        let pair = remove_index(&mut *self.tree, self.index);
+        self.generation = self.tree.generation;
+        let index = self.index;
+        self.move_to(index);
+
+        Some(pair)
+    }
+
+    /// Insert a pair just before the cursor, which stays at its pair. The key
+    /// must be between the previous key and the key at the cursor, otherwise
+    /// the pair is handed back. The keys next to the position are found from
+    /// the path of the cursor, and the pair is inserted at the in-order
+    /// position of the cursor in a single descent, see `insert_index`.
+    pub fn insert_before(&mut self, key: K, value: V) -> Result<(), (K, V)> {
+        check_generation(&*self.tree, self.generation);
+
+        let fits = {
+            let tree = &*self.tree;
+            let order = &tree.order;
+            let len = tree.len();
+
+            let after_prev = if self.index == 0 {
+                true
+            } else if self.index == len {
+                match tree.max() {
+                    Some((prev, _)) => order.less(prev, &key),
+                    None => true,
+                }
+            } else {
+                let mut path = self.path.clone();
+                let mut pos = self.pos;
+                move_index_path(tree, &mut path, &mut pos, false);
+                order.less(node_at(tree, path).keys[pos].get_ref(), &key)
+            };
+
+            after_prev && (self.index == len || {
+                let current = node_at(tree, self.path).keys[self.pos].get_ref();
+                order.less(&key, current)
+            })
+        };
+
+        if !fits {
+            return Err((key, value));
+        }
+
+        let tree = &mut *self.tree;
+        let mut spare = util::replace(&mut tree.spare, ~[]);
+
+        if tree.used == tree.capacity() {
+            split_root(tree, &mut spare);
+        }
+
+        let mut path = ~[];
+        let pos = insert_index(tree, self.index, key, value, &mut path,
+                               &mut spare);
+        tree.spare = spare;
+        tree.generation += 1;
+        self.generation = tree.generation;
+
+        self.path = path;
+        self.pos = pos;
+        self.index += 1;
+
+        if self.index < tree.len() {
+            move_index_path(&*tree, &mut self.path, &mut self.pos, true);
+        }
+
+        Ok(())
+    }
+
+    /// Move the cursor to the pair at in-order position `index`, or to the
+    /// end of the tree if there is no such pair.
+    fn move_to(&mut self, index: uint) {
+        let len = self.tree.len();
+        self.index = if index < len { index } else { len };
+
+        if self.index < len {
+            let (path, pos) = index_path(&*self.tree, self.index);
+            self.path = path;
+            self.pos = pos;
+        }
+    }
+}
+
+impl<'a, K: Ord, V> BTreeQueue<'a, K, V> {
+    /// Add a pair to the queue. Return the value that it replaced, as with
+    /// `BTree::swap`.
+    #[inline]
+    pub fn push(&mut self, key: K, value: V) -> Option<V> {
+        self.tree.swap(key, value)
+    }
+
+    /// Return the pair with the smallest key without removing it.
+    #[inline]
+    pub fn peek_min<'b>(&'b self) -> Option<(&'b K, &'b V)> {
+        self.tree.min()
+    }
+
+    /// Remove and return the pair with the smallest key.
+    #[inline]
+    pub fn pop_min(&mut self) -> Option<(K, V)> {
+        self.tree.pop_min()
+    }
+
+    /// Return an iterator over the queued pairs in the order in which they
+    /// would be popped.
+    pub fn iter<'b>(&'b self) -> BTreeIterator<'b, K, V> {
+        self.tree.iter()
+    }
+}
+
+impl<'a, K, V> Container for BTreeQueue<'a, K, V> {
+    #[inline]
+    fn len(&self) -> uint { self.tree.len() }
+}
+
+impl<'a, K: Ord + Clone, V> BTreeTransaction<'a, K, V> {
+    /// Insert a pair as `BTree::insert` does, which follows the duplicate
+    /// policy of the tree. Return true if the key was not present, or if the
+    /// tree keeps duplicate keys.
+    pub fn insert(&mut self, key: K, value: V) -> bool {
+        let duplicates = self.tree.duplicates;
+
+        match self.tree.swap(key.clone(), value) {
+            None => {
+                self.log.push(UndoAdd(key));
+                true
+            }
+            // A tree that rejects the key hands the new value back unchanged.
+            Some(_) if duplicates == Reject => false,
+            Some(old) => {
+                self.log.push(UndoUpdate(key, old));
+                false
+            }
+        }
+    }
+
+    /// Replace the value of the key, of the first one of equal keys that are
+    /// kept, whatever the duplicate policy of the tree. Return false if the
+    /// key is not present, in which case the tree is left unchanged.
+    pub fn replace_value(&mut self, key: &K, value: V) -> bool {
+        let old = self.tree.replace_value(key, value);
+
+        match old {
+            Some(old) => {
+                self.log.push(UndoUpdate(key.clone(), old));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remove the key. Return true if the key was present. Of equal keys that
+    /// are kept, see `Keep`, the first one is removed, which is where an
+    /// insertion puts a kept key, so a rollback restores every pair at its
+    /// place.
+    pub fn remove(&mut self, key: &K) -> bool {
+        let index = self.tree.rank(key);
+        let found = match self.tree.select(index) {
+            Some((k, _)) => self.tree.order.equal(k, key),
+            None => false,
+        };
+
+        if !found {
+            return false;
+        }
+
+        let (key, old) = remove_index(&mut *self.tree, index);
+        self.log.push(UndoRemove(key, old));
+        true
+    }
+
+    /// Return the value of the key, as changed by the transaction so far.
+    #[inline]
+    pub fn find<'b>(&'b self, key: &K) -> Option<&'b V> { self.tree.find(key) }
+
+    /// Keep the changes of the transaction.
+    pub fn commit(self) {
+        // With an empty log the drop of the transaction has nothing to undo.
+        let mut tx = self;
+        tx.log.clear();
+    }
+
+    /// Undo the changes of the transaction, which leaves the tree as it was
+    /// when the transaction started.
+    pub fn rollback(self) {
+        let mut tx = self;
+        tx.undo();
+    }
+
+    /// Undo the changes in the log, the last one first. An added pair is the
+    /// first of the keys that equal its key, which is removed by its index,
+    /// so a kept duplicate is removed rather than an equal key that was there
+    /// before. A replaced value is set again in place, so a tree that rejects
+    /// or keeps duplicate keys gets its old value back.
+    fn undo(&mut self) {
+        while !self.log.is_empty() {
+            match self.log.pop() {
+                UndoAdd(key) => {
+                    let index = self.tree.rank(&key);
+                    remove_index(&mut *self.tree, index);
+                }
+                UndoRemove(key, value) => {
+                    self.tree.swap(key, value);
+                }
+                UndoUpdate(key, value) => {
+                    self.tree.replace_value(&key, value);
+                }
+            }
+        }
+    }
+}
+
+#[unsafe_destructor]
+impl<'a, K: Ord + Clone, V> Drop for BTreeTransaction<'a, K, V> {
+    fn drop(&mut self) {
+        self.undo();
+    }
+}
+
+impl<'a, K, V> Container for BTreeTransaction<'a, K, V> {
+    /// Return the number of changes made by the transaction.
+    #[inline]
+    fn len(&self) -> uint { self.log.len() }
+}
+
+impl<K: Ord, V> BTreeMultiMap<K, V> {
+    /// Return an empty multimap.
+    pub fn new() -> BTreeMultiMap<K, V> {
+        BTreeMultiMap { tree: BTree::new(), length: 0 }
+    }
+
+    /// Bind another value to `key`, after the values it already has.
+    pub fn insert(&mut self, key: K, value: V) {
+        match self.tree.entry(key) {
+            Occupied(e) => e.into_mut().push(value),
+            Vacant(e) => { e.set(~[value]); }
+        }
+
+        self.length += 1;
+    }
+
+    /// Return an iterator over the values of `key`, in the order in which they
+    /// were inserted.
+    pub fn find_all<'a>(&'a self, key: &K) -> vec::VecIterator<'a, V> {
+        match self.tree.find(key) {
+            Some(values) => values.iter(),
+            None => {
+                let empty: &'a [V] = &[];
+                empty.iter()
+            }
+        }
+    }
+
+    /// Return the number of values of `key`.
+    pub fn count(&self, key: &K) -> uint {
+        match self.tree.find(key) {
+            Some(values) => values.len(),
+            None => 0,
+        }
+    }
+
+    /// Return true if `key` has at least one value.
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.tree.contains_key(key)
+    }
+
+    /// Remove every value of `key` and return them, or None if the key has no
+    /// values.
+    pub fn remove_all(&mut self, key: &K) -> Option<~[V]> {
+        let values = self.tree.pop(key);
+
+        for v in values.iter() {
+            self.length -= v.len();
+        }
+
+        values
+    }
+
+    /// Return an iterator over the key-value bindings in ascending key order.
+    /// The values of a key are yielded in the order in which they were
+    /// inserted.
+    pub fn iter<'a>(&'a self) -> BTreeMultiIterator<'a, K, V> {
+        let empty: &'a [V] = &[];
+        BTreeMultiIterator { iter: self.tree.iter(), key: None,
+                             values: empty.iter() }
+    }
+
+    /// Return an iterator over the distinct keys in ascending order.
+    pub fn keys<'a>(&'a self) -> BTreeKeyIterator<'a, K, ~[V]> {
+        self.tree.keys()
+    }
+}
+
+impl<K: Ord, V: Eq> BTreeMultiMap<K, V> {
+    /// Remove the first binding of `key` to `value`. Return true if the
+    /// binding was found. A key loses its entry with its last value.
+    pub fn remove(&mut self, key: &K, value: &V) -> bool {
+        let (found, empty) = match self.tree.find_mut(key) {
+            Some(values) => {
+                match values.iter().position(|v| *v == *value) {
+                    Some(i) => {
+                        values.remove(i);
+                        (true, values.is_empty())
+                    }
+                    None => (false, false),
+                }
+            }
+            None => (false, false),
+        };
+
+        if empty {
+            self.tree.pop(key);
+        }
+
+        if found {
+            self.length -= 1;
+        }
+
+        found
+    }
+}
+
+impl<K, V> Container for BTreeMultiMap<K, V> {
+    /// Return the number of key-value bindings.
+    #[inline]
+    fn len(&self) -> uint { self.length }
+}
+
+impl<K, V> Mutable for BTreeMultiMap<K, V> {
+    fn clear(&mut self) {
+        self.tree.clear();
+        self.length = 0;
+    }
+}
+
+impl<'a, K: Eq, V> Iterator<(&'a K, &'a V)> for BTreeMultiIterator<'a, K, V> {
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        loop {
+            match self.values.next() {
+                Some(value) => return Some((self.key.unwrap(), value)),
+                None => {}
+            }
+
+            match self.iter.next() {
+                Some((key, values)) => {
+                    self.key = Some(key);
+                    self.values = values.iter();
+                }
+                None => return None,
+            }
+        }
+    }
+}
+
+impl<T: Ord> BTreeSet<T> {
+    /// Return an empty set.
+    pub fn new() -> BTreeSet<T> {
+        BTreeSet { map: BTree::new() }
+    }
+
+    /// Return an iterator over the values in ascending order.
+    pub fn iter<'a>(&'a self) -> BTreeKeyIterator<'a, T, ()> {
+        self.map.keys()
+    }
+
+    /// Return an iterator over the values in descending order.
+    pub fn rev_iter<'a>(&'a self) -> Invert<BTreeKeyIterator<'a, T, ()>> {
+        self.map.keys().invert()
+    }
+
+    /// Return the smallest value, or None if the set is empty.
+    pub fn min<'a>(&'a self) -> Option<&'a T> { self.map.min_key() }
+
+    /// Return the largest value, or None if the set is empty.
+    pub fn max<'a>(&'a self) -> Option<&'a T> { self.map.max_key() }
+}
+
+impl<T> Container for BTreeSet<T> {
+    #[inline]
+    fn len(&self) -> uint { self.map.len() }
+}
+
+impl<T> Mutable for BTreeSet<T> {
+    #[inline]
+    fn clear(&mut self) { self.map.clear() }
+}
+
+impl<T: Ord> Set<T> for BTreeSet<T> {
+    #[inline]
+    fn contains(&self, value: &T) -> bool { self.map.contains_key(value) }
+
+    fn is_disjoint(&self, other: &BTreeSet<T>) -> bool {
+        let (small, large) = if self.len() <= other.len() {
+            (self, other)
+        } else {
+            (other, self)
+        };
+
+        !small.iter().any(|v| large.contains(v))
+    }
+
+    fn is_subset(&self, other: &BTreeSet<T>) -> bool {
+        self.len() <= other.len() && self.iter().all(|v| other.contains(v))
+    }
+
+    #[inline]
+    fn is_superset(&self, other: &BTreeSet<T>) -> bool {
+        other.is_subset(self)
+    }
+}
+
+impl<T: Ord> MutableSet<T> for BTreeSet<T> {
+    #[inline]
+    fn insert(&mut self, value: T) -> bool { self.map.insert(value, ()) }
+
+    #[inline]
+    fn remove(&mut self, value: &T) -> bool { self.map.pop(value).is_some() }
+}
+
+impl<T: Ord> BTreeMultiset<T> {
+    /// Return an empty multiset.
+    pub fn new() -> BTreeMultiset<T> {
+        BTreeMultiset { map: BTree::new(), length: 0 }
+    }
+
+    /// Add one occurrence of `value`.
+    #[inline]
+    pub fn insert(&mut self, value: T) { self.insert_many(value, 1) }
+
+    /// Add `n` occurrences of `value` with a single descent of the tree.
+    pub fn insert_many(&mut self, value: T, n: uint) {
+        if n == 0 {
+            return;
+        }
+
+        match self.map.entry(value) {
+            Occupied(e) => *e.into_mut() += n,
+            Vacant(e) => { e.set(n); }
+        }
+
+        self.length += n;
+    }
+
+    /// Remove one occurrence of `value`. Return true if the value occurred.
+    #[inline]
+    pub fn remove_one(&mut self, value: &T) -> bool {
+        self.remove_many(value, 1) == 1
+    }
+
+    /// Remove up to `n` occurrences of `value` and return the number of
+    /// occurrences that were removed.
+    pub fn remove_many(&mut self, value: &T, n: uint) -> uint {
+        let (removed, gone) = match self.map.find_mut(value) {
+            Some(count) => {
+                if *count > n {
+                    *count -= n;
+                    (n, false)
+                } else {
+                    (*count, true)
+                }
+            }
+            None => (0, false),
+        };
+
+        if gone {
+            self.map.pop(value);
+        }
+
+        self.length -= removed;
+        removed
+    }
+
+    /// Remove every occurrence of `value` and return their number.
+    pub fn remove_all(&mut self, value: &T) -> uint {
+        let removed = self.map.pop(value).unwrap_or(0);
+        self.length -= removed;
+        removed
+    }
+
+    /// Return the number of occurrences of `value`.
+    pub fn count(&self, value: &T) -> uint {
+        match self.map.find(value) {
+            Some(&count) => count,
+            None => 0,
+        }
+    }
+
+    /// Return true if `value` occurs at least once.
+    #[inline]
+    pub fn contains(&self, value: &T) -> bool { self.map.contains_key(value) }
+
+    /// Return the number of distinct values.
+    #[inline]
+    pub fn distinct_len(&self) -> uint { self.map.len() }
+
+    /// Return an iterator over the values in ascending order, which yields a
+    /// value as often as it occurs.
+    pub fn iter<'a>(&'a self) -> BTreeMultisetIterator<'a, T> {
+        BTreeMultisetIterator { iter: self.map.iter(), value: None, left: 0 }
+    }
+
+    /// Return an iterator over the distinct values and their counts in
+    /// ascending order.
+    pub fn counts<'a>(&'a self) -> BTreeIterator<'a, T, uint> {
+        self.map.iter()
+    }
+}
+
+impl<T> Container for BTreeMultiset<T> {
+    /// Return the number of values, counting every occurrence.
+    #[inline]
+    fn len(&self) -> uint { self.length }
+}
+
+impl<T> Mutable for BTreeMultiset<T> {
+    fn clear(&mut self) {
+        self.map.clear();
+        self.length = 0;
+    }
+}
+
+impl<'a, T: Eq> Iterator<&'a T> for BTreeMultisetIterator<'a, T> {
+    fn next(&mut self) -> Option<&'a T> {
+        while self.left == 0 {
+            match self.iter.next() {
+                Some((value, &count)) => {
+                    self.value = Some(value);
+                    self.left = count;
+                }
+                None => return None,
+            }
+        }
+
+        self.left -= 1;
+        self.value
+    }
+}
+
+impl<T: Ord + Clone, V> Monoid<(T, T), V, T> for MaxEnd {
+    fn summarize(&self, key: &(T, T), _: &V) -> T {
+        let (_, ref end) = *key;
+        end.clone()
+    }
+
+    fn combine(&self, a: &T, b: &T) -> T {
+        if *a < *b { b.clone() } else { a.clone() }
+    }
+}
+
+impl<T: Ord + Clone + Send + Freeze + 'static, V> BTreeIntervals<T, V> {
+    /// Return an empty collection of intervals.
+    pub fn new() -> BTreeIntervals<T, V> {
+        BTreeIntervals { tree: BTree::with_aggregate::<T, MaxEnd>(MaxEnd) }
+    }
+
+    /// Insert the interval `start..end` with a value. If the interval is
+    /// already present, replace its value and return the old value. The
+    /// interval must not end before it starts.
+    pub fn insert(&mut self, start: T, end: T, value: V) -> Option<V> {
+        assert!(start <= end);
+        self.tree.swap((start, end), value)
+    }
+
+    /// Remove the interval `start..end` and return its value.
+    pub fn remove(&mut self, start: &T, end: &T) -> Option<V> {
+        self.tree.pop(&(start.clone(), end.clone()))
+    }
+
+    /// Return the value of the interval `start..end`.
+    pub fn find<'a>(&'a self, start: &T, end: &T) -> Option<&'a V> {
+        self.tree.find(&(start.clone(), end.clone()))
+    }
+
+    /// Return an iterator over the intervals that contain `x`, ordered by
+    /// start and end.
+    pub fn stabbing<'a>(&'a self, x: &T) -> BTreeIntervalIterator<'a, T, V> {
+        self.query(Some(x.clone()), Some(x.clone()), true)
+    }
+
+    /// Return an iterator over the intervals that overlap `lo..hi`, ordered
+    /// by start and end.
+    pub fn overlapping<'a>(&'a self, lo: &T, hi: &T)
+                           -> BTreeIntervalIterator<'a, T, V> {
+        self.query(Some(lo.clone()), Some(hi.clone()), false)
+    }
+
+    /// Return an iterator over all intervals ordered by start and end.
+    pub fn iter<'a>(&'a self) -> BTreeIntervalIterator<'a, T, V> {
+        self.query(None, None, false)
+    }
+
+    /// Return an iterator over the intervals that end after `lo` and start
+    /// before `hi`, or not after it if `inclusive` is true. Every change
+    /// keeps the summaries of the nodes it changed, so the iterator can skip
+    /// the subtrees by their largest end.
+    fn query<'a>(&'a self, lo: Option<T>, hi: Option<T>, inclusive: bool)
+                 -> BTreeIntervalIterator<'a, T, V> {
+        let mut iter = BTreeIntervalIterator { stack: ~[], lo: lo, hi: hi,
+                                               inclusive: inclusive };
+
+        if !self.tree.is_empty() {
+            iter.stack.push((&*self.tree, 0));
+        }
+
+        iter
+    }
+}
+
+impl<T, V> Container for BTreeIntervals<T, V> {
+    #[inline]
+    fn len(&self) -> uint { self.tree.len() }
+}
+
+impl<T, V> Mutable for BTreeIntervals<T, V> {
+    fn clear(&mut self) {
+        self.tree.clear();
+    }
+}
+
+impl<'a, T: Ord + 'static, V> BTreeIntervalIterator<'a, T, V> {
+    /// Return true if an interval of the subtree of the child ends after
+    /// `lo`. A child whose summary is not kept may hold any interval.
+    fn ends_after(&self, child: &NodeRef<(T, T), V>) -> bool {
+        match (child.summary(), &self.lo) {
+            (Some(end), &Some(ref lo)) => *unbox_summary::<T>(end) > *lo,
+            _ => true,
+        }
+    }
+
+    /// Return true if an interval with this start lies after `hi`.
+    fn starts_after(&self, start: &T) -> bool {
+        match self.hi {
+            Some(ref hi) if self.inclusive => *start > *hi,
+            Some(ref hi) => *start >= *hi,
+            None => false,
+        }
+    }
+}
+
+impl<'a, T: Ord + 'static, V> Iterator<(&'a (T, T), &'a V)>
+    for BTreeIntervalIterator<'a, T, V> {
+    fn next(&mut self) -> Option<(&'a (T, T), &'a V)> {
+        loop {
+            if self.stack.is_empty() {
+                return None;
+            }
+
+            let (node, slot) = self.stack.pop();
+            let pos = slot / 2;
+
+            // The pair of the last leaf slot of a bottom node is visited by
+            // the ancestor that stores its key, after the child it ends.
+            if slot % 2 == 0 {
+                if pos < node.used {
+                    self.stack.push((node, slot + 1));
+                }
+
+                match node.nodes[pos] {
+                    Some(TreeNode { value: ref child })
+                        if self.ends_after(child) => {
+                        self.stack.push((child.get(), 0));
+                    }
+                    _ => {}
+                }
+
+                continue;
+            }
+
+            self.stack.push((node, slot + 1));
+
+            let key = node.keys[pos].get_ref();
+            let (ref start, ref end) = *key;
+
+            // The intervals that follow start no earlier than this one.
+            if self.starts_after(start) {
+                self.stack.clear();
+                return None;
+            }
+
+            let after_lo = match self.lo {
+                Some(ref lo) => *end > *lo,
+                None => true,
+            };
+
+            if after_lo {
+                return Some((key, value_at(node, pos)));
+            }
+        }
+    }
+}
+
+impl<K: Ord + Clone, V> BTreeCache<K, V> {
+    /// Return an empty cache that holds at most `capacity` pairs.
+    pub fn new(capacity: uint) -> BTreeCache<K, V> {
+        BTreeCache { tree: BTree::new(), recency: BTree::new(),
+                     capacity: capacity, tick: 0 }
+    }
+
+    /// Return the maximum number of pairs.
+    #[inline]
+    pub fn capacity(&self) -> uint { self.capacity }
+
+    /// Change the maximum number of pairs, and return the pairs that were
+    /// evicted to fit, least recently used first.
+    pub fn set_capacity(&mut self, capacity: uint) -> ~[(K, V)] {
+        self.capacity = capacity;
+
+        let mut evicted = ~[];
+
+        while self.tree.len() > self.capacity {
+            evicted.push(self.evict().unwrap());
+        }
+
+        evicted
+    }
+
+    /// Insert a pair as the most recently used one. If the key is already
+    /// present, its value is replaced. Otherwise, if the cache is full, the
+    /// least recently used pair is evicted and returned.
+    pub fn insert(&mut self, key: K, value: V) -> Option<(K, V)> {
+        let tick = self.next_tick();
+
+        match self.tree.find_mut(&key) {
+            Some(entry) => {
+                let (_, old) = util::replace(entry, (value, tick));
+                self.recency.pop(&old);
+                self.recency.insert(tick, key);
+                return None;
+            }
+            None => {}
+        }
+
+        let evicted = if self.tree.len() >= self.capacity {
+            self.evict()
+        } else {
+            None
+        };
+
+        if self.capacity > 0 {
+            self.recency.insert(tick, key.clone());
+            self.tree.insert(key, (value, tick));
+            evicted
+        } else {
+            Some((key, value))
+        }
+    }
+
+    /// Return the value of the key and mark the pair as the most recently
+    /// used one.
+    pub fn find<'a>(&'a mut self, key: &K) -> Option<&'a V> {
+        let tick = self.next_tick();
+
+        match self.tree.find_mut(key) {
+            Some(entry) => {
+                let (ref value, ref mut last) = *entry;
+                let key = self.recency.pop(last).unwrap();
+                self.recency.insert(tick, key);
+                *last = tick;
+                Some(value)
+            }
+            None => None,
+        }
+    }
+
+    /// Return the value of the key without marking the pair as used.
+    pub fn peek<'a>(&'a self, key: &K) -> Option<&'a V> {
+        self.tree.find(key).map(|&(ref value, _)| value)
+    }
+
+    /// Remove the key and return its value.
+    pub fn pop(&mut self, key: &K) -> Option<V> {
+        self.tree.pop(key).map(|(value, tick)| {
+            self.recency.pop(&tick);
+            value
+        })
+    }
+
+    /// Remove and return the least recently used pair.
+    pub fn evict(&mut self) -> Option<(K, V)> {
+        self.recency.pop_min().map(|(_, key)| {
+            let (value, _) = self.tree.pop(&key).unwrap();
+            (key, value)
+        })
+    }
+
+    /// Return an iterator over the pairs in ascending key order.
+    pub fn iter<'a>(&'a self) -> BTreeCacheIterator<'a, K, V> {
+        BTreeCacheIterator { iter: self.tree.iter() }
+    }
+
+    fn next_tick(&mut self) -> uint {
+        self.tick += 1;
+        self.tick
+    }
+}
+
+impl<K, V> Container for BTreeCache<K, V> {
+    #[inline]
+    fn len(&self) -> uint { self.tree.len() }
+}
+
+impl<K, V> Mutable for BTreeCache<K, V> {
+    fn clear(&mut self) {
+        self.tree.clear();
+        self.recency.clear();
+    }
+}
+
+impl<'a, K: Eq, V> Iterator<(&'a K, &'a V)> for BTreeCacheIterator<'a, K, V> {
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        self.iter.next().map(|(key, &(ref value, _))| (key, value))
+    }
+}
+
+impl<K: Ord + Clone, T: Ord + Clone, V> BTreeExpiring<K, T, V> {
+    /// Return an empty tree.
+    pub fn new() -> BTreeExpiring<K, T, V> {
+        BTreeExpiring { tree: BTree::new(), expiry: BTree::new() }
+    }
+
+    /// Insert a pair that expires at `expires`. If the key is already
+    /// present, replace its value and expiry time and return the old value.
+    pub fn insert(&mut self, key: K, value: V, expires: T) -> Option<V> {
+        self.expiry.find_or_insert(expires.clone(), ~[]).push(key.clone());
+
+        match self.tree.swap(key.clone(), (value, expires)) {
+            Some((old, at)) => {
+                self.unschedule(&key, &at);
+                Some(old)
+            }
+            None => None,
+        }
+    }
+
+    /// Return the value of the key if it is live at `now`. A stale pair is
+    /// removed.
+    pub fn find<'a>(&'a mut self, key: &K, now: &T) -> Option<&'a V> {
+        let stale = match self.tree.find(key) {
+            Some(&(_, ref expires)) => *expires < *now,
+            None => return None,
+        };
+
+        if stale {
+            self.pop(key);
+            return None;
+        }
+
+        self.tree.find(key).map(|&(ref value, _)| value)
+    }
+
+    /// Return the expiry time of the key.
+    pub fn expires<'a>(&'a self, key: &K) -> Option<&'a T> {
+        self.tree.find(key).map(|&(_, ref expires)| expires)
+    }
+
+    /// Remove the key and return its value, whether it is stale or not.
+    pub fn pop(&mut self, key: &K) -> Option<V> {
+        match self.tree.pop(key) {
+            Some((value, at)) => {
+                self.unschedule(key, &at);
+                Some(value)
+            }
+            None => None,
+        }
+    }
+
+    /// Remove the pairs that expire before `t`, and return their number. The
+    /// expiry times before `t` are split off as a whole.
+    pub fn expire_before(&mut self, t: &T) -> uint {
+        let later = self.expiry.split_off(t);
+        let stale = util::replace(&mut self.expiry, later);
+        let mut removed = 0;
+
+        for (_, keys) in stale.move_iter() {
+            for key in keys.iter() {
+                self.tree.pop(key);
+                removed += 1;
+            }
+        }
+
+        removed
+    }
+
+    /// Return an iterator over the pairs in ascending key order, with their
+    /// values and expiry times. Stale pairs that were not removed yet are
+    /// included.
+    pub fn iter<'a>(&'a self) -> BTreeIterator<'a, K, (V, T)> {
+        self.tree.iter()
+    }
+
+    /// Remove the key from the keys that expire at `at`.
+    fn unschedule(&mut self, key: &K, at: &T) {
+        let empty = match self.expiry.find_mut(at) {
+            Some(keys) => {
+                match keys.iter().position(|k| *k == *key) {
+                    Some(i) => { keys.remove(i); }
+                    None => {}
+                }
+
+                keys.is_empty()
+            }
+            None => false,
+        };
+
+        if empty {
+            self.expiry.pop(at);
+        }
+    }
+}
+
+impl<K, T, V> Container for BTreeExpiring<K, T, V> {
+    /// Return the number of pairs, including stale pairs that were not
+    /// removed yet.
+    #[inline]
+    fn len(&self) -> uint { self.tree.len() }
+}
+
+impl<K, T, V> Mutable for BTreeExpiring<K, T, V> {
+    fn clear(&mut self) {
+        self.tree.clear();
+        self.expiry.clear();
+    }
+}
+
+impl<K: Ord + Clone, V> BTreeVersioned<K, V> {
+    /// Return an empty tree at version 0.
+    pub fn new() -> BTreeVersioned<K, V> {
+        BTreeVersioned { tree: BTree::new(), version: 0 }
+    }
+
+    /// Return the version of the last change.
+    #[inline]
+    pub fn version(&self) -> uint { self.version }
+
+    /// Set the value of the key, and return the version of the change.
+    pub fn insert(&mut self, key: K, value: V) -> uint {
+        self.version += 1;
+        self.tree.insert((key, self.version), Some(value));
+        self.version
+    }
+
+    /// Remove the key, and return the version of the change, or None if the
+    /// key has no value.
+    pub fn remove(&mut self, key: &K) -> Option<uint> {
+        if self.find(key).is_none() {
+            return None;
+        }
+
+        self.version += 1;
+        self.tree.insert((key.clone(), self.version), None);
+        Some(self.version)
+    }
+
+    /// Return the current value of the key.
+    #[inline]
+    pub fn find<'a>(&'a self, key: &K) -> Option<&'a V> {
+        self.find_at(key, self.version)
+    }
+
+    /// Return the value that the key had at `version`.
+    pub fn find_at<'a>(&'a self, key: &K, version: uint) -> Option<&'a V> {
+        match self.tree.floor(&(key.clone(), version)) {
+            Some((&(ref k, _), value)) if *k == *key => value.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// Return an iterator over the changes of the key in ascending version
+    /// order. A removal of the key has None as its value.
+    pub fn history<'a>(&'a self, key: &K)
+                       -> BTreeRangeIterator<'a, (K, uint), Option<V>> {
+        self.tree.range(Included(&(key.clone(), 0)),
+                        Included(&(key.clone(), self.version)))
+    }
+
+    /// Drop the changes that are not needed to look up versions from
+    /// `version` on, and return their number. Of the changes of a key before
+    /// `version`, only the last one is kept, unless it is a removal.
+    pub fn truncate_before(&mut self, version: uint) -> uint {
+        let mut stale = ~[];
+
+        {
+            let mut iter = self.tree.iter().peekable();
+
+            loop {
+                let (key, value) = match iter.next() {
+                    Some(pair) => pair,
+                    None => break,
+                };
+
+                let &(ref k, v) = key;
+
+                if v > version {
+                    continue;
+                }
+
+                let replaced = match iter.peek() {
+                    Some(&(&(ref next, w), _)) => *next == *k && w <= version,
+                    None => false,
+                };
+
+                if replaced || value.is_none() {
+                    stale.push(key.clone());
+                }
+            }
+        }
+
+        for key in stale.iter() {
+            self.tree.pop(key);
+        }
+
+        stale.len()
+    }
+}
+
+impl<K, V> Container for BTreeVersioned<K, V> {
+    /// Return the number of stored changes.
+    #[inline]
+    fn len(&self) -> uint { self.tree.len() }
+}
+
+impl<K: Ord + Clone, V, A: Ord + Clone, E: KeyOf<V, A>>
+    BTreeIndexed<K, V, A, E> {
+    /// Return an empty tree with an index on the attribute that `extract`
+    /// returns.
+    pub fn new(extract: E) -> BTreeIndexed<K, V, A, E> {
+        BTreeIndexed { tree: BTree::new(), index: BTree::new(),
+                       extract: extract }
+    }
+
+    /// Insert a pair. If the key is already present, replace its value and
+    /// return the old value.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let attr = self.extract.key_of(&value).clone();
+        let old = self.tree.swap(key.clone(), value);
+
+        match old {
+            Some(ref v) => {
+                self.index.pop(&(self.extract.key_of(v).clone(), key.clone()));
+            }
+            None => {}
+        }
+
+        self.index.insert((attr, key), ());
+        old
+    }
+
+    /// Remove the key and return its value.
+    pub fn pop(&mut self, key: &K) -> Option<V> {
+        let value = self.tree.pop(key);
+
+        match value {
+            Some(ref v) => {
+                self.index.pop(&(self.extract.key_of(v).clone(), key.clone()));
+            }
+            None => {}
+        }
+
+        value
+    }
+
+    /// Return the value of the key.
+    #[inline]
+    pub fn find<'a>(&'a self, key: &K) -> Option<&'a V> { self.tree.find(key) }
+
+    /// Return an iterator over the pairs whose value has the attribute
+    /// `attr`. The lookup takes a single descent of the index.
+    pub fn find_by<'a>(&'a self, attr: &A) -> BTreeIndexIterator<'a, K, V, A> {
+        let iter = range_by(&*self.index, |k| {
+            let (ref a, _) = *k;
+            *a < *attr
+        }, |k| {
+            let (ref a, _) = *k;
+            *a > *attr
+        });
+
+        BTreeIndexIterator { tree: &*self.tree, iter: iter }
+    }
+
+    /// Return an iterator over the pairs in ascending order of the primary
+    /// key.
+    pub fn iter<'a>(&'a self) -> BTreeIterator<'a, K, V> { self.tree.iter() }
+}
+
+impl<K, V, A, E> Container for BTreeIndexed<K, V, A, E> {
+    #[inline]
+    fn len(&self) -> uint { self.tree.len() }
+}
+
+impl<K, V, A, E> Mutable for BTreeIndexed<K, V, A, E> {
+    fn clear(&mut self) {
+        self.tree.clear();
+        self.index.clear();
+    }
+}
+
+impl<'a, K: Ord, V, A: Eq> Iterator<(&'a K, &'a V)>
+    for BTreeIndexIterator<'a, K, V, A> {
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        let tree = self.tree;
+
+        self.iter.next().map(|(&(_, ref key), _)| {
+            (key, tree.find(key).unwrap())
+        })
+    }
+}
+
+impl<K: Ord + Clone, V: Ord + Clone> BTreeBiMap<K, V> {
+    /// Return an empty map.
+    pub fn new() -> BTreeBiMap<K, V> {
+        BTreeBiMap { forward: BTree::new(), backward: BTree::new() }
+    }
+
+    /// Insert a pair, and return the pairs that are removed because they
+    /// have the same key or the same value.
+    pub fn insert(&mut self, key: K, value: V) -> ~[(K, V)] {
+        let mut removed = ~[];
+
+        match self.pop_by_key(&key) {
+            Some(v) => removed.push((key.clone(), v)),
+            None => {}
+        }
+
+        match self.pop_by_value(&value) {
+            Some(k) => removed.push((k, value.clone())),
+            None => {}
+        }
+
+        self.forward.insert(key.clone(), value.clone());
+        self.backward.insert(value, key);
+
+        removed
+    }
+
+    /// Insert a pair if neither the key nor the value is in the map.
+    /// Otherwise leave the map unchanged and hand the pair back.
+    pub fn try_insert(&mut self, key: K, value: V) -> Result<(), (K, V)> {
+        if self.forward.contains_key(&key) ||
+           self.backward.contains_key(&value) {
+            return Err((key, value));
+        }
+
+        self.forward.insert(key.clone(), value.clone());
+        self.backward.insert(value, key);
+
+        Ok(())
+    }
+
+    /// Return the value of the key.
+    #[inline]
+    pub fn find_by_key<'a>(&'a self, key: &K) -> Option<&'a V> {
+        self.forward.find(key)
+    }
+
+    /// Return the key of the value.
+    #[inline]
+    pub fn find_by_value<'a>(&'a self, value: &V) -> Option<&'a K> {
+        self.backward.find(value)
+    }
+
+    /// Remove the key and return its value.
+    pub fn pop_by_key(&mut self, key: &K) -> Option<V> {
+        let value = self.forward.pop(key);
+
+        match value {
+            Some(ref v) => { self.backward.pop(v); }
+            None => {}
+        }
+
+        value
+    }
+
+    /// Remove the value and return its key.
+    pub fn pop_by_value(&mut self, value: &V) -> Option<K> {
+        let key = self.backward.pop(value);
+
+        match key {
+            Some(ref k) => { self.forward.pop(k); }
+            None => {}
+        }
+
+        key
+    }
+
+    /// Return an iterator over the pairs in ascending key order.
+    pub fn iter<'a>(&'a self) -> BTreeIterator<'a, K, V> {
+        self.forward.iter()
+    }
+
+    /// Return an iterator over the values and their keys in ascending value
+    /// order.
+    pub fn iter_by_value<'a>(&'a self) -> BTreeIterator<'a, V, K> {
+        self.backward.iter()
+    }
+}
+
+impl<K, V> Container for BTreeBiMap<K, V> {
+    #[inline]
+    fn len(&self) -> uint { self.forward.len() }
+}
+
+impl<K, V> Mutable for BTreeBiMap<K, V> {
+    fn clear(&mut self) {
+        self.forward.clear();
+        self.backward.clear();
+    }
+}
+
+impl<K: Ord + Clone + 'static, V: Clone + 'static> PersistentBTree<K, V> {
+    /// Return an empty tree.
+    pub fn new() -> PersistentBTree<K, V> {
+        PersistentBTree { root: None, length: 0 }
+    }
+
+    /// Return a tree with the pair inserted, which shares the nodes off the
+    /// path of the key with this tree. If the key is present, its value is
+    /// replaced.
+    pub fn insert(&self, key: K, value: V) -> PersistentBTree<K, V> {
+        let root = match self.root {
+            Some(root) => root,
+            None => {
+                let node = PersistentNode { keys: ~[key], values: ~[value],
+                                            children: ~[] };
+                return PersistentBTree { root: Some(@node), length: 1 };
+            }
+        };
+
+        let mut added = true;
+
+        let root = match persistent_insert(root, key, value, &mut added) {
+            Fit(node) => node,
+            Split(left, key, value, right) => {
+                @PersistentNode { keys: ~[key], values: ~[value],
+                                  children: ~[left, right] }
+            }
+        };
+
+        let length = if added { self.length + 1 } else { self.length };
+        PersistentBTree { root: Some(root), length: length }
+    }
+
+    /// Return a tree without the key, which shares the nodes off the path of
+    /// the key with this tree. If the key is not present, the tree is
+    /// returned as is.
+    pub fn remove(&self, key: &K) -> PersistentBTree<K, V> {
+        let root = match self.root {
+            Some(root) if persistent_find(&*root, key).is_some() => root,
+            _ => return self.clone(),
+        };
+
+        let node = persistent_remove(root, key);
+
+        let root = if !node.keys.is_empty() {
+            Some(@node)
+        } else if !node.children.is_empty() {
+            Some(node.children[0])
+        } else {
+            None
+        };
+
+        PersistentBTree { root: root, length: self.length - 1 }
+    }
+
+    /// Return the value of the key.
+    pub fn find<'a>(&'a self, key: &K) -> Option<&'a V> {
+        match self.root {
+            Some(ref root) => persistent_find(&**root, key),
+            None => None,
+        }
+    }
+
+    /// Return true if the key is in the tree.
+    #[inline]
+    pub fn contains_key(&self, key: &K) -> bool { self.find(key).is_some() }
+
+    /// Call `f` on every key-value pair in ascending key order, until `f`
+    /// returns false. Return false if the traversal was stopped early.
+    pub fn each<'a>(&'a self, f: |&'a K, &'a V| -> bool) -> bool {
+        match self.root {
+            Some(ref root) => persistent_each(&**root, f),
+            None => true,
+        }
+    }
+
+    /// Return true if both trees share their root, which means that neither
+    /// was changed since one was copied from the other.
+    pub fn same_root(&self, other: &PersistentBTree<K, V>) -> bool {
+        match (self.root, other.root) {
+            (Some(a), Some(b)) => managed::ptr_eq(a, b),
+            (None, None) => true,
+            _ => false,
+        }
+    }
+}
+
+impl<K, V> Container for PersistentBTree<K, V> {
+    #[inline]
+    fn len(&self) -> uint { self.length }
+}
+
+/// Return the value of the key in a subtree of a persistent tree.
+fn persistent_find<'a, K: Ord, V>(node: &'a PersistentNode<K, V>, key: &K)
+                                  -> Option<&'a V> {
+    let mut node = node;
+
+    loop {
+        let pos = persistent_pos(node, key);
+
+        if pos < node.keys.len() && node.keys[pos] == *key {
+            return Some(&node.values[pos]);
+        }
+
+        if node.children.is_empty() {
+            return None;
+        }
+
+        node = &*node.children[pos];
+    }
+}
+
+/// Return the position of the first key in the node that is not less than
+/// `key`.
+fn persistent_pos<K: Ord, V>(node: &PersistentNode<K, V>, key: &K) -> uint {
+    let mut pos = 0;
+
+    while pos < node.keys.len() && node.keys[pos] < *key {
+        pos += 1;
+    }
+
+    pos
+}
+
+/// Call `f` on every key-value pair of a subtree of a persistent tree in
+/// ascending key order, until `f` returns false, see `each`.
+fn persistent_each<'a, K, V>(node: &'a PersistentNode<K, V>,
+                             f: |&'a K, &'a V| -> bool) -> bool {
+    for i in range(0, node.keys.len() + 1) {
+        if !node.children.is_empty() &&
+           !persistent_each(&*node.children[i], |k, v| f(k, v)) {
+            return false;
+        }
+
+        if i < node.keys.len() && !f(&node.keys[i], &node.values[i]) {
+            return false;
+        }
+    }
+
+    true
+}
+
+/// Insert a pair into a copy of the node, and split the copy if it has more
+/// than `2t - 1` keys. `added` is set to false if the key was present.
+fn persistent_insert<K: Ord + Clone + 'static, V: Clone + 'static>(
+        node: @PersistentNode<K, V>, key: K, value: V, added: &mut bool)
+        -> Inserted<K, V> {
+    let pos = persistent_pos(&*node, &key);
+    let mut copy = (*node).clone();
+
+    if pos < copy.keys.len() && copy.keys[pos] == key {
+        copy.values[pos] = value;
+        *added = false;
+        return Fit(@copy);
+    }
+
+    if copy.children.is_empty() {
+        copy.keys.insert(pos, key);
+        copy.values.insert(pos, value);
+    } else {
+        match persistent_insert(copy.children[pos], key, value, added) {
+            Fit(child) => copy.children[pos] = child,
+            Split(left, key, value, right) => {
+                copy.keys.insert(pos, key);
+                copy.values.insert(pos, value);
+                copy.children[pos] = left;
+                copy.children.insert(pos + 1, right);
+            }
+        }
+    }
+
+    if copy.keys.len() <= BTREE_KEYS_UBOUND {
+        return Fit(@copy);
+    }
+
+    // The copy has 2t keys: t keys stay, the median moves up and the last
+    // t - 1 keys move to the right node, with the children after them.
+    let t = BTREE_MIN_DEGREE;
+    let keys = copy.keys.slice_from(t + 1).to_owned();
+    let values = copy.values.slice_from(t + 1).to_owned();
+    let children = if copy.children.is_empty() {
+        ~[]
+    } else {
+        copy.children.slice_from(t + 1).to_owned()
+    };
+
+    copy.keys.truncate(t + 1);
+    copy.values.truncate(t + 1);
+    copy.children.truncate(t + 1);
+
+    let key = copy.keys.pop();
+    let value = copy.values.pop();
+    let right = PersistentNode { keys: keys, values: values,
+                                 children: children };
+
+    Split(@copy, key, value, @right)
+}
+
+/// Remove a key that is present from a copy of the node. The copy may be
+/// left with fewer than `t - 1` keys, which its parent repairs.
+fn persistent_remove<K: Ord + Clone + 'static, V: Clone + 'static>(
+        node: @PersistentNode<K, V>, key: &K) -> PersistentNode<K, V> {
+    let pos = persistent_pos(&*node, key);
+    let mut copy = (*node).clone();
+    let found = pos < copy.keys.len() && copy.keys[pos] == *key;
+
+    if copy.children.is_empty() {
+        copy.keys.remove(pos);
+        copy.values.remove(pos);
+        return copy;
+    }
+
+    let child = if found {
+        let (child, key, value) = persistent_remove_max(copy.children[pos]);
+        copy.keys[pos] = key;
+        copy.values[pos] = value;
+        child
+    } else {
+        persistent_remove(copy.children[pos], key)
+    };
+
+    persistent_fix(&mut copy, pos, child);
+    copy
+}
+
+/// Remove the largest pair from a copy of the node, and return the copy with
+/// the pair.
+fn persistent_remove_max<K: Ord + Clone + 'static, V: Clone + 'static>(
+        node: @PersistentNode<K, V>) -> (PersistentNode<K, V>, K, V) {
+    let mut copy = (*node).clone();
+
+    if copy.children.is_empty() {
+        let key = copy.keys.pop();
+        let value = copy.values.pop();
+        return (copy, key, value);
+    }
+
+    let pos = copy.keys.len();
+    let (child, key, value) = persistent_remove_max(copy.children[pos]);
+    persistent_fix(&mut copy, pos, child);
+
+    (copy, key, value)
+}
+
+/// Store a changed child at `pos` in the node. A child with fewer than
+/// `t - 1` keys borrows a pair through the node from a sibling, or is merged
+/// with a sibling if neither can spare one.
+fn persistent_fix<K: Clone + 'static, V: Clone + 'static>(
+        node: &mut PersistentNode<K, V>, pos: uint,
+        child: PersistentNode<K, V>) {
+    let mut child = child;
+
+    if child.keys.len() >= BTREE_KEYS_LBOUND {
+        node.children[pos] = @child;
+        return;
+    }
+
+    if pos > 0 && node.children[pos - 1].keys.len() > BTREE_KEYS_LBOUND {
+        let mut left = (*node.children[pos - 1]).clone();
+        let key = util::replace(&mut node.keys[pos - 1], left.keys.pop());
+        let value = util::replace(&mut node.values[pos - 1],
+                                  left.values.pop());
+        child.keys.unshift(key);
+        child.values.unshift(value);
+
+        if !left.children.is_empty() {
+            child.children.unshift(left.children.pop());
+        }
+
+        node.children[pos - 1] = @left;
+        node.children[pos] = @child;
+        return;
+    }
+
+    if pos < node.keys.len() &&
+       node.children[pos + 1].keys.len() > BTREE_KEYS_LBOUND {
+        let mut right = (*node.children[pos + 1]).clone();
+        let key = util::replace(&mut node.keys[pos], right.keys.shift());
+        let value = util::replace(&mut node.values[pos], right.values.shift());
+        child.keys.push(key);
+        child.values.push(value);
+
+        if !right.children.is_empty() {
+            child.children.push(right.children.shift());
+        }
+
+        node.children[pos] = @child;
+        node.children[pos + 1] = @right;
+        return;
+    }
+
+    let (mut left, right, at) = if pos > 0 {
+        ((*node.children[pos - 1]).clone(), child, pos - 1)
+    } else {
+        (child, (*node.children[1]).clone(), 0)
+    };
+
+    left.keys.push(node.keys.remove(at));
+    left.values.push(node.values.remove(at));
+    left.keys.push_all_move(right.keys);
+    left.values.push_all_move(right.values);
+    left.children.push_all_move(right.children);
+
+    node.children.remove(at + 1);
+    node.children[at] = @left;
+}
+
+impl<K: Ord + Clone, V: Clone> BTreeHistory<K, V> {
+    /// Return an empty tree with an empty history.
+    pub fn new() -> BTreeHistory<K, V> {
+        BTreeHistory { tree: BTree::new(), undo: ~[], redo: ~[] }
+    }
+
+    /// Insert a pair and record the edit. If the key is present, replace its
+    /// value and return the old value. The edits that were undone can no
+    /// longer be redone.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let old = self.tree.swap(key.clone(), value);
+        self.record(key, old.clone());
+        old
+    }
+
+    /// Remove the key and return its value. The removal is recorded if the
+    /// key was present.
+    pub fn pop(&mut self, key: &K) -> Option<V> {
+        let old = self.tree.pop(key);
+
+        if old.is_some() {
+            self.record(key.clone(), old.clone());
+        }
+
+        old
+    }
+
+    /// Return the value of the key.
+    #[inline]
+    pub fn find<'a>(&'a self, key: &K) -> Option<&'a V> { self.tree.find(key) }
+
+    /// Undo the last `n` edits, and return the number of edits undone, which
+    /// is less than `n` if the history has fewer edits.
+    pub fn undo(&mut self, n: uint) -> uint {
+        replay(&mut *self.tree, &mut self.undo, &mut self.redo, n)
+    }
+
+    /// Redo the last `n` edits that were undone, and return the number of
+    /// edits redone.
+    pub fn redo(&mut self, n: uint) -> uint {
+        replay(&mut *self.tree, &mut self.redo, &mut self.undo, n)
+    }
+
+    /// Return the number of edits that can be undone.
+    #[inline]
+    pub fn undo_len(&self) -> uint { self.undo.len() }
+
+    /// Return the number of edits that can be redone.
+    #[inline]
+    pub fn redo_len(&self) -> uint { self.redo.len() }
+
+    /// Forget all recorded edits, keeping the pairs.
+    pub fn clear_history(&mut self) {
+        self.undo.truncate(0);
+        self.redo.truncate(0);
+    }
+
+    /// Return an iterator over the pairs in ascending key order.
+    pub fn iter<'a>(&'a self) -> BTreeIterator<'a, K, V> { self.tree.iter() }
+
+    fn record(&mut self, key: K, old: Option<V>) {
+        self.undo.push((key, old));
+        self.redo.truncate(0);
+    }
+}
+
+impl<K, V> Container for BTreeHistory<K, V> {
+    #[inline]
+    fn len(&self) -> uint { self.tree.len() }
+}
+
+/// Restore up to `n` edits from `from`, and record the values they replace
+/// in `to`. Return the number of edits that were restored.
+fn replay<K: Ord + Clone, V>(tree: &mut BTree<K, V>,
+                             from: &mut ~[(K, Option<V>)],
+                             to: &mut ~[(K, Option<V>)], n: uint) -> uint {
+    let mut done = 0;
+
+    while done < n && !from.is_empty() {
+        let (key, value) = from.pop();
+
+        let current = match value {
+            Some(value) => tree.swap(key.clone(), value),
+            None => tree.pop(&key),
+        };
+
+        to.push((key, current));
+        done += 1;
+    }
+
+    done
+}
+
+impl<K: Ord + Clone, V> BTreeTracked<K, V> {
+    /// Return an empty tree at version 0.
+    pub fn new() -> BTreeTracked<K, V> {
+        BTreeTracked { tree: BTree::new(), changes: BTree::new(), version: 0,
+                       length: 0 }
+    }
+
+    /// Return the version of the last change.
+    #[inline]
+    pub fn version(&self) -> uint { self.version }
+
+    /// Insert a pair. If the key is present, replace its value and return
+    /// the old value.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let old = self.change(key, Some(value));
+
+        if old.is_none() {
+            self.length += 1;
+        }
+
+        old
+    }
+
+    /// Remove the key and return its value. The removal is a change of the
+    /// key, which leaves a tombstone.
+    pub fn pop(&mut self, key: &K) -> Option<V> {
+        if self.find(key).is_none() {
+            return None;
+        }
+
+        self.length -= 1;
+        self.change(key.clone(), None)
+    }
+
+    /// Return the value of the key.
+    pub fn find<'a>(&'a self, key: &K) -> Option<&'a V> {
+        match self.tree.find(key) {
+            Some(&(ref value, _)) => value.as_ref(),
+            None => None,
+        }
+    }
+
+    /// Return an iterator over the keys that changed after `version`, with
+    /// the version of their last change and their value, which is None for
+    /// a removed key. A key that changed several times is listed once.
+    pub fn changes_since<'a>(&'a self, version: uint)
+                             -> BTreeChangeIterator<'a, K, V> {
+        BTreeChangeIterator { tree: &*self.tree,
+                              iter: self.changes.range(Excluded(&version),
+                                                       Unbounded) }
+    }
+
+    /// Drop the tombstones of the keys that were removed up to `version`,
+    /// and return their number. Their removal is no longer listed by
+    /// `changes_since`.
+    pub fn forget_removed(&mut self, version: uint) -> uint {
+        let mut removed = ~[];
+
+        for (&v, key) in self.changes.range(Unbounded, Included(&version)) {
+            match self.tree.find(key) {
+                Some(&(None, _)) => removed.push(v),
+                _ => {}
+            }
+        }
+
+        for v in removed.iter() {
+            let key = self.changes.pop(v).unwrap();
+            self.tree.pop(&key);
+        }
+
+        removed.len()
+    }
+
+    /// Store the value of the key as a new change, and return the old value.
+    fn change(&mut self, key: K, value: Option<V>) -> Option<V> {
+        self.version += 1;
+
+        let old = self.tree.swap(key.clone(), (value, self.version));
+
+        match old {
+            Some((old, v)) => {
+                self.changes.pop(&v);
+                self.changes.insert(self.version, key);
+                old
+            }
+            None => {
+                self.changes.insert(self.version, key);
+                None
+            }
+        }
+    }
+}
+
+impl<K, V> Container for BTreeTracked<K, V> {
+    /// Return the number of keys that are not removed.
+    #[inline]
+    fn len(&self) -> uint { self.length }
+}
+
+impl<'a, K: Ord, V> Iterator<(uint, &'a K, Option<&'a V>)>
+    for BTreeChangeIterator<'a, K, V> {
+    fn next(&mut self) -> Option<(uint, &'a K, Option<&'a V>)> {
+        let tree = self.tree;
+
+        self.iter.next().map(|(&version, key)| {
+            let &(ref value, _) = tree.find(key).unwrap();
+            (version, key, value.as_ref())
+        })
+    }
+}
+
+// Return the error of a result of a paged b-tree from the enclosing function,
+// or evaluate to its value.
+macro_rules! page_try (
+    ($e:expr) => (match $e { Ok(value) => value, Err(err) => return Err(err) })
+)
+
+impl<K: Ord + Binary, V: Binary> PagedBTree<K, V> {
+    /// Open the paged b-tree in the file at `path`, or create an empty tree
+    /// if there is no such file. Return None if the file cannot be opened,
+    /// its header is corrupt, or it is not compatible with this build: it has
+    /// a newer format version, another page size, or other key or value
+    /// encodings.
+    ///
+    /// The first page of the file is a header with the bytes `BTRP`, the
+    /// format version, `BTREE_PAGE_VERSION`, the page size, the names of the
+    /// key and value encodings, the first page of the root, the number of
+    /// pages, the first free page and the number of pairs. Every other page
+    /// starts with the next page of its chain and the number of bytes of a
+    /// node it holds, followed by those bytes. The bytes of a node are its
+    /// number of keys, its keys and values, and the first pages of its
+    /// children. Numbers take 8 bytes, see `Binary`. Since version 2, the
+    /// last 4 bytes of every page hold the Adler-32 checksum of the other
+    /// bytes, which is checked when the page is read from the file. Since
+    /// version 3, the header ends with a byte that is 1 if nodes are
+    /// compressed, see `set_compression`, and the bytes of a node start with
+    /// a byte that is 1 if the rest is compressed with deflate.
+    ///
+    /// The log of the tree is kept next to the file, with `-wal` appended to
+    /// its name. If the program stopped during a sync, the sync is completed
+    /// from the log, or discarded if it had not reached the log entirely.
+    /// The pages that are written are also recorded for the next backup in a
+    /// file with `-changes` appended to the name, see `backup_incremental`.
+    pub fn open(path: &Path) -> Option<PagedBTree<K, V>> {
+        let exists = path.exists();
+
+        let mut name = path.filename().unwrap().to_owned();
+        name.push_all(bytes!("-wal"));
+        let log_path = path.with_filename(name);
+
+        let mut name = path.filename().unwrap().to_owned();
+        name.push_all(bytes!("-changes"));
+        let changes_path = path.with_filename(name);
+
+        let file = match File::open_mode(path, Open, ReadWrite) {
+            Some(file) => file,
+            None => return None,
+        };
+
+        let log = match File::open_mode(&log_path, Open, ReadWrite) {
+            Some(log) => log,
+            None => return None,
+        };
+
+        let mut changes = match File::open_mode(&changes_path, Open,
+                                                ReadWrite) {
+            Some(changes) => changes,
+            None => return None,
+        };
+
+        if fs::stat(&changes_path).size < 8 {
+            changes.write_le_u64(0);
+            changes.fsync();
+        }
+
+        let size = fs::stat(&changes_path).size as uint;
+        changes.seek(0, SeekSet);
+        let backups = changes.read_le_u64() as uint;
+        let changed = changes.read_bytes(size - 8);
+
+        let mut tree = PagedBTree {
+            pager: Pager { file: file, log: log, changes: changes,
+                           backups: backups, changed: changed,
+                           pool: BufferPool::new(BTREE_PAGE_CACHE),
+                           version: BTREE_PAGE_VERSION, compress: false,
+                           pages: 1, free: 0 },
+            root: 0,
+            length: 0,
+        };
+
+        if !exists {
+            tree.sync();
+            return Some(tree);
+        }
+
+        tree.pager.recover(fs::stat(&log_path).size as uint);
+
+        if fs::stat(path).size < BTREE_PAGE_SIZE as u64 {
+            return None;
+        }
+
+        if tree.read_header() { Some(tree) } else { None }
+    }
+
+    /// Return the value of the key, which is read from the nodes on its path.
+    /// Return an error if one of the pages that are read from the file is
+    /// corrupt. The tree is not changed by an error, and can still be used
+    /// for the keys whose paths are intact.
+    pub fn get(&mut self, key: &K) -> Result<Option<V>, PageError> {
+        let mut id = self.root;
+
+        while id != 0 {
+            let mut node: PagedNode<K, V> = page_try!(self.pager.read_node(id));
+            let pos = paged_pos(&node, key);
+
+            if pos < node.keys.len() && node.keys[pos] == *key {
+                return Ok(Some(node.values.remove(pos)));
+            }
+
+            if node.children.is_empty() {
+                return Ok(None);
+            }
+
+            id = node.children[pos];
+        }
+
+        Ok(None)
+    }
+
+    /// Return true if the key is in the tree, or an error if a page on its
+    /// path is corrupt.
+    #[inline]
+    pub fn contains_key(&mut self, key: &K) -> Result<bool, PageError> {
+        self.get(key).map(|value| value.is_some())
+    }
+
+    /// Insert a pair, or replace the value if the key is present. Return true
+    /// if the key was not present. The changed pages are kept in the cache
+    /// until `sync` writes them to the file. Return an error if a page that
+    /// is read from the file is corrupt, in which case the tree may be left
+    /// partly changed, and must not be synced.
+    pub fn put(&mut self, key: K, value: V) -> Result<bool, PageError> {
+        if self.root == 0 {
+            let node = PagedNode { keys: ~[key], values: ~[value],
+                                   children: ~[] };
+            self.root = page_try!(self.pager.allocate());
+            page_try!(self.pager.write_node(self.root, &node));
+            self.length = 1;
+            return Ok(true);
+        }
+
+        let mut added = true;
+
+        match page_try!(paged_insert(&mut self.pager, self.root, key, value,
+                                     &mut added)) {
+            Some((key, value, right)) => {
+                let node = PagedNode { keys: ~[key], values: ~[value],
+                                       children: ~[self.root, right] };
+                self.root = page_try!(self.pager.allocate());
+                page_try!(self.pager.write_node(self.root, &node));
+            }
+            None => {}
+        }
+
+        if added {
+            self.length += 1;
+        }
+
+        Ok(added)
+    }
+
+    /// Remove the key and return its value. The changed pages are kept in the
+    /// cache until `sync` writes them to the file. Return an error if a page
+    /// that is read from the file is corrupt, see `put`.
+    pub fn delete(&mut self, key: &K) -> Result<Option<V>, PageError> {
+        let value = match page_try!(self.get(key)) {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+
+        let node: PagedNode<K, V> = page_try!(paged_remove(&mut self.pager,
+                                                           self.root, key));
+
+        if !node.keys.is_empty() {
+            page_try!(self.pager.write_node(self.root, &node));
+        } else {
+            page_try!(self.pager.release(self.root));
+            self.root = if node.children.is_empty() {
+                0
+            } else {
+                node.children[0]
+            };
+        }
+
+        self.length -= 1;
+        Ok(Some(value))
+    }
+
+    /// Return the maximum number of pages in the cache that are not pinned.
+    #[inline]
+    pub fn cache_capacity(&self) -> uint { self.pager.pool.capacity }
+
+    /// Change the maximum number of pages in the cache, and evict the least
+    /// recently used pages to fit. The pages of the nodes on the path of an
+    /// insertion or removal are pinned until it is done, and the pages that
+    /// changed are kept until `sync`, so the cache can hold more pages.
+    pub fn set_cache_capacity(&mut self, capacity: uint) {
+        self.pager.pool.capacity = capacity;
+        self.pager.pool.shrink();
+    }
+
+    /// Return the number of hits, misses and evictions of the cache since
+    /// the tree was opened, or since `reset_cache_stats`.
+    #[inline]
+    pub fn cache_stats(&self) -> PageCacheStats { self.pager.pool.stats }
+
+    /// Reset the statistics of the cache to zero.
+    pub fn reset_cache_stats(&mut self) {
+        self.pager.pool.stats = PageCacheStats { hits: 0, misses: 0,
+                                                 evictions: 0 };
+    }
+
+    /// Return true if nodes are compressed when they are written.
+    #[inline]
+    pub fn compression(&self) -> bool { self.pager.compress }
+
+    /// Compress nodes with deflate when they are written, if that makes them
+    /// smaller, or stop compressing them. A compressed node takes fewer
+    /// pages, but is inflated whenever it is read. The nodes that were
+    /// written before keep their encoding until they change. The setting is
+    /// stored in the header by `sync`. Return false if the file has a format
+    /// version before 3, which cannot hold compressed nodes.
+    pub fn set_compression(&mut self, compress: bool) -> bool {
+        if self.pager.version < 3 {
+            return false;
+        }
+
+        self.pager.compress = compress;
+        true
+    }
+
+    /// Write the pages that changed since the last sync to the file, with
+    /// the header, and flush the file to disk. The pages are first written
+    /// to the log and flushed, with a commit record after them, so a sync
+    /// that is interrupted is completed from the log when the tree is opened
+    /// again. The changes since the last sync are lost if the program stops
+    /// before it.
+    pub fn sync(&mut self) {
+        self.write_header();
+        self.pager.flush();
+    }
+
+    /// Sync the tree, and write a backup of the pages that were written
+    /// since the last backup to `w`, or of all pages for the first backup.
+    /// Return the number of pages in the backup. The size of a backup follows
+    /// the changes to the tree rather than its size. A backup holds the bytes
+    /// `BTRB`, the number of the backup, counting from 0 for the first one,
+    /// the number of pages of the file and the number of pages in the backup,
+    /// followed by every page after its number. Numbers take 8 bytes. See
+    /// `restore_paged_backup`.
+    pub fn backup_incremental(&mut self, w: &mut Writer) -> uint {
+        self.sync();
+
+        let (backups, ids) = self.pager.changed_pages();
+
+        w.write(BACKUP_MAGIC);
+        w.write_le_u64(backups as u64);
+        w.write_le_u64(self.pager.pages as u64);
+        w.write_le_u64(ids.len() as u64);
+
+        for &id in ids.iter() {
+            w.write_le_u64(id as u64);
+            w.write(self.pager.load(id).as_slice());
+        }
+
+        self.pager.finish_backup(backups + 1);
+        ids.len()
+    }
+
+    /// Write the header page to the cache, see `open`.
+    fn write_header(&mut self) {
+        let mut header = MemWriter::new();
+
+        {
+            let w = &mut header as &mut Writer;
+            w.write(PAGE_MAGIC);
+            self.pager.version.write_binary(w);
+            BTREE_PAGE_SIZE.write_binary(w);
+            Binary::binary_encoding(None::<K>).write_binary(w);
+            Binary::binary_encoding(None::<V>).write_binary(w);
+            self.root.write_binary(w);
+            self.pager.pages.write_binary(w);
+            self.pager.free.write_binary(w);
+            self.length.write_binary(w);
+
+            if self.pager.version >= 3 {
+                w.write_u8(self.pager.compress as u8);
+            }
+        }
+
+        self.pager.write_page(0, header.inner().as_slice());
+    }
+
+    /// Read the header from the file, see `open`. Return false if the file is
+    /// not compatible with this build, or its header is corrupt.
+    fn read_header(&mut self) -> bool {
+        let page = self.pager.load(0);
+
+        let header = {
+            let mut r = BufReader::new(page.as_slice());
+            read_paged_header::<K, V>(&mut r as &mut Reader)
+        };
+
+        match header {
+            Some(header) => {
+                if header.version >= 2 && !verify_page(page.as_slice()) {
+                    return false;
+                }
+
+                self.root = header.root;
+                self.pager.version = header.version;
+                self.pager.pages = header.pages;
+                self.pager.free = header.free;
+                self.pager.compress = header.compress;
+                self.length = header.length;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl<K, V> Container for PagedBTree<K, V> {
+    #[inline]
+    fn len(&self) -> uint { self.length }
+}
+
+impl Pager {
+    /// Return the bytes of a page without its checksum, from the cache if it
+    /// holds the page.
+    fn read_page(&mut self, id: uint) -> Result<~[u8], PageError> {
+        let mut page = match self.pool.get(id) {
+            Some(page) => page,
+            None => {
+                let page = page_try!(self.fault(id));
+                self.pool.put(id, page.clone(), false);
+                self.pool.shrink();
+                page
+            }
+        };
+
+        page.truncate(self.page_data());
+        Ok(page)
+    }
+
+    /// Write a page to the cache, which is padded with zeros and followed by
+    /// its checksum. The page is written to the file by `flush`.
+    fn write_page(&mut self, id: uint, page: &[u8]) {
+        assert!(page.len() <= self.page_data());
+
+        let mut page = page.to_owned();
+        page.grow(self.page_data() - page.len(), &0u8);
+
+        if self.version >= 2 {
+            let mut checksum = MemWriter::new();
+            checksum.write_le_u32(adler32(page.as_slice()));
+            page.push_all(checksum.inner().as_slice());
+        }
+
+        self.pool.put(id, page, true);
+        self.pool.shrink();
+    }
+
+    /// Return the number of bytes of a page that are not its checksum.
+    #[inline]
+    fn page_data(&self) -> uint {
+        if self.version >= 2 {
+            BTREE_PAGE_SIZE - PAGE_CHECKSUM
+        } else {
+            BTREE_PAGE_SIZE
+        }
+    }
+
+    /// Write the dirty pages to the log and then to the file, and empty the
+    /// log once the file is flushed to disk.
+    fn flush(&mut self) {
+        let dirty = self.log_dirty();
+        self.record_changes(dirty.as_slice());
+
+        for &id in dirty.iter() {
+            {
+                let frame = self.pool.frames.find(&id).unwrap();
+                self.file.seek((id * BTREE_PAGE_SIZE) as i64, SeekSet);
+                self.file.write(frame.bytes.as_slice());
+            }
+
+            self.pool.clean(id);
+        }
+
+        self.file.fsync();
+        self.log.truncate(0);
+        self.log.fsync();
+        self.pool.shrink();
+    }
+
+    /// Write the dirty pages to the log, followed by a commit record once
+    /// they are on disk, and return the pages. The log holds the number of
+    /// pages, every page after its number, and the bytes `BTRC`.
+    fn log_dirty(&mut self) -> ~[uint] {
+        let dirty: ~[uint] = self.pool.frames.iter()
+            .filter(|&(_, frame)| frame.dirty)
+            .map(|(&id, _)| id)
+            .collect();
+
+        self.log.truncate(0);
+        self.log.seek(0, SeekSet);
+        self.log.write_le_u64(dirty.len() as u64);
+
+        for &id in dirty.iter() {
+            self.log.write_le_u64(id as u64);
+            let frame = self.pool.frames.find(&id).unwrap();
+            self.log.write(frame.bytes.as_slice());
+        }
+
+        self.log.fsync();
+        self.log.write(LOG_COMMIT);
+        self.log.fsync();
+        dirty
+    }
+
+    /// Write the pages of a log of `size` bytes to the file if the log ends
+    /// with a commit record, and empty the log. A log without a commit
+    /// record is discarded, since none of its pages were written yet.
+    fn recover(&mut self, size: uint) {
+        let record = 8 + BTREE_PAGE_SIZE;
+
+        if size >= 8 + LOG_COMMIT.len() &&
+           (size - 8 - LOG_COMMIT.len()) % record == 0 {
+            self.log.seek((size - LOG_COMMIT.len()) as i64, SeekSet);
+            let commit = self.log.read_bytes(LOG_COMMIT.len());
+
+            self.log.seek(0, SeekSet);
+            let n = self.log.read_le_u64() as uint;
+
+            if commit.as_slice() == LOG_COMMIT &&
+               n == (size - 8 - LOG_COMMIT.len()) / record {
+                let mut ids = ~[];
+
+                for _ in range(0, n) {
+                    let id = self.log.read_le_u64() as uint;
+                    let page = self.log.read_bytes(BTREE_PAGE_SIZE);
+                    self.file.seek((id * BTREE_PAGE_SIZE) as i64, SeekSet);
+                    self.file.write(page.as_slice());
+                    ids.push(id);
+                }
+
+                self.record_changes(ids.as_slice());
+                self.file.fsync();
+            }
+        }
+
+        self.log.truncate(0);
+        self.log.fsync();
+    }
+
+    /// Record pages that are about to be written to the file for the next
+    /// backup. They are recorded before the file is written, so a backup
+    /// never misses a page that was written. Only the bytes of the bitmap
+    /// that gain a page are written, and the file is only flushed if there
+    /// is one, so pages that are written again are not recorded again. The
+    /// first backup holds all pages, so nothing is recorded before it.
+    fn record_changes(&mut self, ids: &[uint]) {
+        if self.backups == 0 {
+            return;
+        }
+
+        let mut recorded = false;
+
+        for &id in ids.iter() {
+            let (byte, bit) = (id / 8, 1u8 << (id % 8));
+
+            if byte >= self.changed.len() {
+                self.changed.grow(byte + 1 - self.changed.len(), &0u8);
+            }
+
+            if self.changed[byte] & bit == 0 {
+                self.changed[byte] |= bit;
+                self.changes.seek((8 + byte) as i64, SeekSet);
+                self.changes.write_u8(self.changed[byte]);
+                recorded = true;
+            }
+        }
+
+        if recorded {
+            self.changes.fsync();
+        }
+    }
+
+    /// Return the number of backups that were made, and the pages that were
+    /// written since the last one in ascending order, which always include
+    /// the header page. Without a backup, all pages are returned.
+    fn changed_pages(&self) -> (uint, ~[uint]) {
+        if self.backups == 0 {
+            return (0, range(0, self.pages).collect());
+        }
+
+        let mut ids = ~[0u];
+
+        for (byte, &bits) in self.changed.iter().enumerate() {
+            for bit in range(0u, 8) {
+                let id = byte * 8 + bit;
+
+                if bits & (1 << bit) != 0 && id > 0 && id < self.pages {
+                    ids.push(id);
+                }
+            }
+        }
+
+        (self.backups, ids)
+    }
+
+    /// Forget the recorded pages, and store the number of backups.
+    fn finish_backup(&mut self, backups: uint) {
+        self.backups = backups;
+        self.changed = ~[];
+        self.changes.truncate(0);
+        self.changes.seek(0, SeekSet);
+        self.changes.write_le_u64(backups as u64);
+        self.changes.fsync();
+    }
+
+    /// Read a page from the file.
+    fn load(&mut self, id: uint) -> ~[u8] {
+        self.file.seek((id * BTREE_PAGE_SIZE) as i64, SeekSet);
+        self.file.read_bytes(BTREE_PAGE_SIZE)
+    }
+
+    /// Read a page from the file and check its checksum.
+    fn fault(&mut self, id: uint) -> Result<~[u8], PageError> {
+        if id >= self.pages {
+            return Err(MalformedPage(id));
+        }
+
+        let page = self.load(id);
+
+        if self.version >= 2 && !verify_page(page.as_slice()) {
+            return Err(ChecksumMismatch(id));
+        }
+
+        Ok(page)
+    }
+
+    /// Pin a page in the cache, so it is not evicted until it is unpinned as
+    /// often.
+    fn pin(&mut self, id: uint) -> Result<(), PageError> {
+        if !self.pool.touch(id) {
+            let page = page_try!(self.fault(id));
+            self.pool.put(id, page, false);
+        }
+
+        self.pool.pin(id);
+        self.pool.shrink();
+        Ok(())
+    }
+
+    /// Undo a pin of a page, see `pin`.
+    fn unpin(&mut self, id: uint) {
+        self.pool.unpin(id);
+        self.pool.shrink();
+    }
+
+    /// Return the next page of the chain of a node, or of the list of free
+    /// pages, or 0 if the page is the last one.
+    fn next_page(&mut self, id: uint) -> Result<uint, PageError> {
+        let mut page = MemReader::new(page_try!(self.read_page(id)));
+        Ok(page.read_le_u64() as uint)
+    }
+
+    /// Return an empty page, which is taken from the free pages or added to
+    /// the end of the file.
+    fn allocate(&mut self) -> Result<uint, PageError> {
+        let id = if self.free != 0 {
+            let id = self.free;
+            self.free = page_try!(self.next_page(id));
+            id
+        } else {
+            self.pages += 1;
+            self.pages - 1
+        };
+
+        self.write_page(id, []);
+        Ok(id)
+    }
+
+    /// Add the chain of pages that starts at `id` to the free pages.
+    fn release(&mut self, id: uint) -> Result<(), PageError> {
+        let mut id = id;
+
+        while id != 0 {
+            let next = page_try!(self.next_page(id));
+            let mut page = MemWriter::new();
+            page.write_le_u64(self.free as u64);
+            self.write_page(id, page.inner().as_slice());
+            self.free = id;
+            id = next;
+        }
+
+        Ok(())
+    }
+
+    /// Read the node that is stored in the chain of pages that starts at
+    /// `first`.
+    fn read_node<K: Binary, V: Binary>(&mut self, first: uint)
+                                       -> Result<PagedNode<K, V>, PageError> {
+        let mut bytes = ~[];
+        let mut id = first;
+
+        while id != 0 {
+            let mut page = MemReader::new(page_try!(self.read_page(id)));
+            let next = page.read_le_u64() as uint;
+            let size = page.read_le_u64() as uint;
+
+            if size > self.page_data() - PAGE_HEADER {
+                return Err(MalformedPage(id));
+            }
+
+            bytes.push_all_move(page.read_bytes(size));
+            id = next;
+        }
+
+        match decode_paged_node(self.version, bytes) {
+            Some(node) => Ok(node),
+            None => Err(MalformedPage(first)),
+        }
+    }
+
+    /// Write a node to the chain of pages that starts at `id`. Pages are added
+    /// to the chain, or released from it, as the node grows or shrinks.
+    fn write_node<K: Binary, V: Binary>(&mut self, id: uint,
+                                        node: &PagedNode<K, V>)
+                                        -> Result<(), PageError> {
+        let mut image = MemWriter::new();
+
+        {
+            let w = &mut image as &mut Writer;
+            node.keys.len().write_binary(w);
+
+            for key in node.keys.iter() {
+                key.write_binary(w);
+            }
+
+            for value in node.values.iter() {
+                value.write_binary(w);
+            }
+
+            node.children.write_binary(w);
+        }
+
+        let mut bytes = image.inner();
+
+        if self.version >= 3 {
+            let deflated = if self.compress {
+                Some(flate::deflate_bytes(bytes.as_slice()))
+            } else {
+                None
+            };
+
+            let mut node = ~[];
+
+            match deflated {
+                Some(deflated) if deflated.len() < bytes.len() => {
+                    node.push(NODE_DEFLATED);
+                    node.push_all_move(deflated);
+                }
+                _ => {
+                    node.push(NODE_RAW);
+                    node.push_all_move(bytes);
+                }
+            }
+
+            bytes = node;
+        }
+
+        let payload = self.page_data() - PAGE_HEADER;
+        let mut id = id;
+        let mut start = 0;
+
+        loop {
+            let end = if bytes.len() - start > payload {
+                start + payload
+            } else {
+                bytes.len()
+            };
+
+            let mut next = page_try!(self.next_page(id));
+
+            if end == bytes.len() {
+                page_try!(self.release(next));
+                next = 0;
+            } else if next == 0 {
+                next = page_try!(self.allocate());
+            }
+
+            let mut page = MemWriter::new();
+            page.write_le_u64(next as u64);
+            page.write_le_u64((end - start) as u64);
+            page.write(bytes.slice(start, end));
+            self.write_page(id, page.inner().as_slice());
+
+            if next == 0 {
+                return Ok(());
+            }
+
+            id = next;
+            start = end;
+        }
+    }
+}
+
+impl<K: Ord + Binary, V: Binary> MappedBTree<K, V> {
+    /// Map the file of a paged b-tree at `path` into memory, see
+    /// `PagedBTree::open`. Return None if the file cannot be mapped, or is
+    /// not compatible with this build. Changes that are made to the file
+    /// after it is mapped must not be synced while the map is in use.
+    pub fn open(path: &Path) -> Option<MappedBTree<K, V>> {
+        if !path.exists() {
+            return None;
+        }
+
+        let size = fs::stat(path).size as uint;
+
+        if size < BTREE_PAGE_SIZE {
+            return None;
+        }
+
+        let fd = path.with_c_str(|p| unsafe {
+            libc::open(p, libc::O_RDONLY, 0)
+        });
+
+        if fd < 0 {
+            return None;
+        }
+
+        let map = MemoryMap::new(size, [MapReadable, MapFd(fd)]);
+        unsafe { libc::close(fd); }
+
+        let mut tree = match map {
+            Ok(map) => MappedBTree { map: map, root: 0, pages: 1, length: 0,
+                                     version: 0 },
+            Err(_) => return None,
+        };
+
+        let header = tree.with_page(0, |page| {
+            let mut header = BufReader::new(page);
+
+            match read_paged_header::<K, V>(&mut header as &mut Reader) {
+                Some(header) if header.version < 2 || verify_page(page) => {
+                    Some(header)
+                }
+                _ => None,
+            }
+        });
+
+        match header {
+            Some(header) => {
+                if header.pages * BTREE_PAGE_SIZE > size {
+                    return None;
+                }
+
+                tree.root = header.root;
+                tree.pages = header.pages;
+                tree.length = header.length;
+                tree.version = header.version;
+                Some(tree)
+            }
+            None => None,
+        }
+    }
+
+    /// Return the value of the key, or an error if a page on its path is
+    /// corrupt, see `PagedBTree::get`.
+    pub fn get(&self, key: &K) -> Result<Option<V>, PageError> {
+        let mut id = self.root;
+
+        while id != 0 {
+            let mut node: PagedNode<K, V> = page_try!(self.read_node(id));
+            let pos = paged_pos(&node, key);
+
+            if pos < node.keys.len() && node.keys[pos] == *key {
+                return Ok(Some(node.values.remove(pos)));
+            }
+
+            if node.children.is_empty() {
+                return Ok(None);
+            }
+
+            id = node.children[pos];
+        }
+
+        Ok(None)
+    }
+
+    /// Return true if the key is in the tree, or an error if a page on its
+    /// path is corrupt.
+    #[inline]
+    pub fn contains_key(&self, key: &K) -> Result<bool, PageError> {
+        self.get(key).map(|value| value.is_some())
+    }
+
+    /// Call `f` with the bytes of a page, which must be in the map.
+    fn with_page<T>(&self, id: uint, f: |&[u8]| -> T) -> T {
+        assert!(id < self.pages);
+
+        unsafe {
+            let page = (self.map.data as *u8)
+                .offset((id * BTREE_PAGE_SIZE) as int);
+            vec::raw::buf_as_slice(page, BTREE_PAGE_SIZE, f)
+        }
+    }
+
+    /// Decode the node that is stored in the chain of pages that starts at
+    /// `first`, and check the checksums of the pages. A node that fits in a
+    /// page is decoded from the mapped bytes, unless it is compressed, and
+    /// one that spans pages is first copied out of them. Either way all the
+    /// keys and values of the node are decoded into owned values.
+    fn read_node(&self, first: uint) -> Result<PagedNode<K, V>, PageError> {
+        let data_size = if self.version >= 2 {
+            BTREE_PAGE_SIZE - PAGE_CHECKSUM
+        } else {
+            BTREE_PAGE_SIZE
+        };
+
+        let mut bytes = ~[];
+        let mut id = first;
+
+        while id != 0 {
+            if id >= self.pages {
+                return Err(MalformedPage(id));
+            }
+
+            let node = self.with_page(id, |page| {
+                if self.version >= 2 && !verify_page(page) {
+                    return Err(ChecksumMismatch(id));
+                }
+
+                let mut header = BufReader::new(page.slice_to(PAGE_HEADER));
+                let next = header.read_le_u64() as uint;
+                let size = header.read_le_u64() as uint;
+
+                if size > data_size - PAGE_HEADER {
+                    return Err(MalformedPage(id));
+                }
+
+                let data = page.slice(PAGE_HEADER, PAGE_HEADER + size);
+
+                if next == 0 && bytes.is_empty() {
+                    return match decode_paged_node(self.version, data) {
+                        Some(node) => Ok((0, Some(node))),
+                        None => Err(MalformedPage(first)),
+                    };
+                }
+
+                bytes.push_all(data);
+                Ok((next, None))
+            });
+
+            match page_try!(node) {
+                (_, Some(node)) => return Ok(node),
+                (next, None) => id = next,
+            }
+        }
+
+        match decode_paged_node(self.version, bytes) {
+            Some(node) => Ok(node),
+            None => Err(MalformedPage(first)),
+        }
+    }
+}
+
+impl<K, V> Container for MappedBTree<K, V> {
+    #[inline]
+    fn len(&self) -> uint { self.length }
+}
+
+impl BufferPool {
+    /// Return an empty pool that holds at most `capacity` pages that are
+    /// neither pinned nor dirty.
+    fn new(capacity: uint) -> BufferPool {
+        BufferPool { frames: BTree::new(), recency: BTree::new(),
+                     capacity: capacity, tick: 0,
+                     stats: PageCacheStats { hits: 0, misses: 0,
+                                             evictions: 0 } }
+    }
+
+    /// Mark a page as the most recently used one, and count a hit. Return
+    /// false, and count a miss, if the page is not in the pool.
+    fn touch(&mut self, id: uint) -> bool {
+        let tick = self.next_tick();
+
+        match self.frames.find_mut(&id) {
+            Some(frame) => {
+                if frame.evictable() {
+                    self.recency.pop(&frame.tick);
+                    self.recency.insert(tick, id);
+                }
+
+                frame.tick = tick;
+                self.stats.hits += 1;
+                true
+            }
+            None => {
+                self.stats.misses += 1;
+                false
+            }
+        }
+    }
+
+    /// Return a copy of a page and mark it as the most recently used one.
+    fn get(&mut self, id: uint) -> Option<~[u8]> {
+        if self.touch(id) {
+            Some(self.frames.find(&id).unwrap().bytes.clone())
+        } else {
+            None
+        }
+    }
+
+    /// Add a page, or replace the bytes of a page, as the most recently used
+    /// one. A page that is written is dirty until it is cleaned. The pool
+    /// may hold more pages than its capacity until `shrink`.
+    fn put(&mut self, id: uint, bytes: ~[u8], dirty: bool) {
+        let tick = self.next_tick();
+
+        match self.frames.find_mut(&id) {
+            Some(frame) => {
+                if frame.evictable() {
+                    self.recency.pop(&frame.tick);
+                }
+
+                frame.bytes = bytes;
+                frame.dirty = frame.dirty || dirty;
+                frame.tick = tick;
+
+                if frame.evictable() {
+                    self.recency.insert(tick, id);
+                }
+
+                return;
+            }
+            None => {}
+        }
+
+        if !dirty {
+            self.recency.insert(tick, id);
+        }
+
+        self.frames.insert(id, PageFrame { bytes: bytes, pins: 0,
+                                           dirty: dirty, tick: tick });
+    }
+
+    /// Mark a dirty page as written to the file, so it can be evicted.
+    fn clean(&mut self, id: uint) {
+        let tick = self.next_tick();
+        let frame = self.frames.find_mut(&id).unwrap();
+
+        frame.dirty = false;
+
+        if frame.evictable() {
+            frame.tick = tick;
+            self.recency.insert(tick, id);
+        }
+    }
+
+    /// Pin a page that is in the pool.
+    fn pin(&mut self, id: uint) {
+        let frame = self.frames.find_mut(&id).unwrap();
+
+        if frame.evictable() {
+            self.recency.pop(&frame.tick);
+        }
+
+        frame.pins += 1;
+    }
+
+    /// Undo a pin of a page. A page that is no longer pinned becomes the most
+    /// recently used one.
+    fn unpin(&mut self, id: uint) {
+        let tick = self.next_tick();
+        let frame = self.frames.find_mut(&id).unwrap();
+
+        assert!(frame.pins > 0);
+        frame.pins -= 1;
+
+        if frame.evictable() {
+            frame.tick = tick;
+            self.recency.insert(tick, id);
+        }
+    }
+
+    /// Evict the least recently used pages that are neither pinned nor
+    /// dirty, while the pool holds more pages than its capacity.
+    fn shrink(&mut self) {
+        while self.frames.len() > self.capacity {
+            match self.recency.pop_min() {
+                Some((_, id)) => {
+                    self.frames.pop(&id);
+                    self.stats.evictions += 1;
+                }
+                None => break,
+            }
+        }
+    }
+
+    fn next_tick(&mut self) -> uint {
+        self.tick += 1;
+        self.tick
+    }
+}
+
+impl PageFrame {
+    /// Return true if the page can be evicted from its pool.
+    #[inline]
+    fn evictable(&self) -> bool { self.pins == 0 && !self.dirty }
+}
+
+/// The first bytes of the file of a paged b-tree, see `PagedBTree::open`.
+static PAGE_MAGIC: &'static [u8] = bytes!("BTRP");
+
+/// The last bytes of the log of a paged b-tree, which commit the pages in it,
+/// see `Pager::log_dirty`.
+static LOG_COMMIT: &'static [u8] = bytes!("BTRC");
+
+/// The bytes at the start of a backup of a paged b-tree, see
+/// `PagedBTree::backup_incremental`.
+static BACKUP_MAGIC: &'static [u8] = bytes!("BTRB");
+
+/// The number of bytes at the start of a page of a node, which hold the next
+/// page of the chain and the number of bytes of the node in the page.
+static PAGE_HEADER: uint = 16;
+
+/// The number of bytes at the end of a page that hold its checksum.
+static PAGE_CHECKSUM: uint = 4;
+
+/// The first byte of the bytes of a node, which tells whether the rest is
+/// stored as is or compressed with deflate.
+static NODE_RAW: u8 = 0;
+static NODE_DEFLATED: u8 = 1;
+
+/// The fields of the header of the file of a paged b-tree that describe the
+/// tree, see `PagedBTree::open`.
+struct PageHeader {
+    version: uint,
+    root: uint,
+    pages: uint,
+    free: uint,
+    length: uint,
+    compress: bool,
+}
+
+/// Return the Adler-32 checksum of the bytes.
+fn adler32(bytes: &[u8]) -> u32 {
+    let mut a = 1u32;
+    let mut b = 0u32;
+
+    for &byte in bytes.iter() {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+
+    (b << 16) | a
+}
+
+/// Return true if the checksum at the end of a page matches its other bytes.
+fn verify_page(page: &[u8]) -> bool {
+    let data = page.len() - PAGE_CHECKSUM;
+    let mut checksum = BufReader::new(page.slice_from(data));
+    checksum.read_le_u32() == adler32(page.slice_to(data))
+}
+
+/// Restore the file of a paged b-tree at `path` from a backup written by
+/// `PagedBTree::backup_incremental`. The first backup creates the file, and
+/// every later one must be restored after the backup before it. The whole
+/// backup is read and checked before the file is opened, so the file is
+/// left as it is if the backup is malformed or truncated. Return false in
+/// that case, or if the file cannot be opened or written.
+pub fn restore_paged_backup(path: &Path, r: &mut Reader) -> bool {
+    let mut truncated = false;
+
+    let backup = io_error::cond.trap(|_| truncated = true).inside(|| {
+        read_paged_backup(r)
+    });
+
+    let (backups, pages, records) = match backup {
+        Some(backup) if !truncated => backup,
+        _ => return false,
+    };
+
+    let mut failed = false;
+
+    let written = io_error::cond.trap(|_| failed = true).inside(|| {
+        let file = if backups == 0 {
+            File::create(path)
+        } else {
+            File::open_mode(path, Open, ReadWrite)
+        };
+
+        let mut file = match file {
+            Some(file) => file,
+            None => return false,
+        };
+
+        for &(id, ref page) in records.iter() {
+            file.seek((id * BTREE_PAGE_SIZE) as i64, SeekSet);
+            file.write(page.as_slice());
+        }
+
+        file.truncate((pages * BTREE_PAGE_SIZE) as i64);
+        file.fsync();
+        true
+    });
+
+    written && !failed
+}
+
+/// Read a backup of a paged b-tree, see `restore_paged_backup`. Return the
+/// number of backups before it, the number of pages of the tree, and the
+/// pages in the backup with their ids, or None if the backup is malformed or
+/// ends early. The header and records are read with `read_bytes`, which
+/// returns the bytes up to the end of the reader, so a short read tells of
+/// a truncated backup.
+fn read_paged_backup(r: &mut Reader)
+                     -> Option<(u64, uint, ~[(uint, ~[u8])])> {
+    let magic = BACKUP_MAGIC.len();
+    let header = r.read_bytes(magic + 24);
+
+    if header.len() < magic + 24 || header.slice_to(magic) != BACKUP_MAGIC {
+        return None;
+    }
+
+    let mut h = BufReader::new(header.slice_from(magic));
+    let backups = h.read_le_u64();
+    let pages = h.read_le_u64() as uint;
+    let n = h.read_le_u64() as uint;
+    let mut records = ~[];
+
+    for _ in range(0, n) {
+        let record = r.read_bytes(8 + BTREE_PAGE_SIZE);
+
+        if record.len() < 8 + BTREE_PAGE_SIZE {
+            return None;
+        }
+
+        let id = BufReader::new(record.slice_to(8)).read_le_u64() as uint;
+
+        if id >= pages {
+            return None;
+        }
+
+        records.push((id, record.slice_from(8).to_owned()));
+    }
+
+    Some((backups, pages, records))
+}
+
+/// Read the header of the file of a paged b-tree, see `PagedBTree::open`.
+/// Return None if the file is not compatible with this build.
+fn read_paged_header<K: Binary, V: Binary>(r: &mut Reader)
+                                           -> Option<PageHeader> {
+    if r.read_bytes(PAGE_MAGIC.len()).as_slice() != PAGE_MAGIC {
+        return None;
+    }
+
+    let version: uint = Binary::read_binary(r);
+
+    if version == 0 || version > BTREE_PAGE_VERSION {
+        return None;
+    }
+
+    let size: uint = Binary::read_binary(r);
+    let key: ~str = Binary::read_binary(r);
+    let value: ~str = Binary::read_binary(r);
+
+    if size != BTREE_PAGE_SIZE ||
+       key != Binary::binary_encoding(None::<K>) ||
+       value != Binary::binary_encoding(None::<V>) {
+        return None;
+    }
+
+    let root = Binary::read_binary(r);
+    let pages = Binary::read_binary(r);
+    let free = Binary::read_binary(r);
+    let length = Binary::read_binary(r);
+    let compress = version >= 3 && r.read_u8() != 0;
+
+    Some(PageHeader { version: version, root: root, pages: pages, free: free,
+                      length: length, compress: compress })
+}
+
+/// Decode the bytes of a node of a paged b-tree with a format version. Since
+/// version 3, the bytes start with a byte that tells whether the node is
+/// compressed, see `PagedBTree::open`. Return None if that byte is not valid.
+fn decode_paged_node<K: Binary, V: Binary>(version: uint, bytes: &[u8])
+                                           -> Option<PagedNode<K, V>> {
+    if version < 3 {
+        let mut node = BufReader::new(bytes);
+        return Some(read_paged_node(&mut node as &mut Reader));
+    }
+
+    if bytes.is_empty() {
+        return None;
+    }
+
+    if bytes[0] == NODE_RAW {
+        let mut node = BufReader::new(bytes.slice_from(1));
+        Some(read_paged_node(&mut node as &mut Reader))
+    } else if bytes[0] == NODE_DEFLATED {
+        let inflated = flate::inflate_bytes(bytes.slice_from(1));
+        let mut node = MemReader::new(inflated);
+        Some(read_paged_node(&mut node as &mut Reader))
+    } else {
+        None
+    }
+}
+
+/// Read the bytes of a node of a paged b-tree, see `PagedBTree::open`.
+fn read_paged_node<K: Binary, V: Binary>(r: &mut Reader) -> PagedNode<K, V> {
+    let n: uint = Binary::read_binary(r);
+    let keys = vec::from_fn(n, |_| Binary::read_binary(r));
+    let values = vec::from_fn(n, |_| Binary::read_binary(r));
+    let children = Binary::read_binary(r);
+
+    PagedNode { keys: keys, values: values, children: children }
+}
+
+/// Return the position of the first key in the node that is not less than
+/// `key`.
+fn paged_pos<K: Ord, V>(node: &PagedNode<K, V>, key: &K) -> uint {
+    let mut pos = 0;
+
+    while pos < node.keys.len() && node.keys[pos] < *key {
+        pos += 1;
+    }
+
+    pos
+}
+
+/// Insert a pair into the node that starts at page `id`, and split the node
+/// if it has more than `2t - 1` keys. Return the median pair and the first
+/// page of the right node of a split. `added` is set to false if the key was
+/// present.
+fn paged_insert<K: Ord + Binary, V: Binary>(pager: &mut Pager, id: uint,
+                                            key: K, value: V,
+                                            added: &mut bool)
+                                            -> Result<Option<(K, V, uint)>,
+                                                      PageError> {
+    let mut node: PagedNode<K, V> = page_try!(pager.read_node(id));
+    let pos = paged_pos(&node, &key);
+
+    if pos < node.keys.len() && node.keys[pos] == key {
+        node.values[pos] = value;
+        *added = false;
+        page_try!(pager.write_node(id, &node));
+        return Ok(None);
+    }
+
+    if node.children.is_empty() {
+        node.keys.insert(pos, key);
+        node.values.insert(pos, value);
+    } else {
+        page_try!(pager.pin(id));
+        let split = paged_insert(pager, node.children[pos], key, value, added);
+        pager.unpin(id);
+
+        match page_try!(split) {
+            Some((key, value, right)) => {
+                node.keys.insert(pos, key);
+                node.values.insert(pos, value);
+                node.children.insert(pos + 1, right);
+            }
+            None => return Ok(None),
+        }
+    }
+
+    if node.keys.len() <= BTREE_KEYS_UBOUND {
+        page_try!(pager.write_node(id, &node));
+        return Ok(None);
+    }
+
+    // The node has 2t keys: t keys stay, the median moves up and the last
+    // t - 1 keys move to the right node, with the children after them.
+    let t = BTREE_MIN_DEGREE;
+    let mut right = PagedNode { keys: ~[], values: ~[], children: ~[] };
+
+    while node.keys.len() > t + 1 {
+        right.keys.unshift(node.keys.pop());
+        right.values.unshift(node.values.pop());
+    }
+
+    while node.children.len() > t + 1 {
+        right.children.unshift(node.children.pop());
+    }
+
+    let key = node.keys.pop();
+    let value = node.values.pop();
+    let right_id = page_try!(pager.allocate());
+
+    page_try!(pager.write_node(right_id, &right));
+    page_try!(pager.write_node(id, &node));
+    Ok(Some((key, value, right_id)))
+}
+
+/// Remove a key that is present from the node that starts at page `id`, and
+/// return the node without writing it. The node may be left with fewer than
+/// `t - 1` keys, which its parent repairs.
+fn paged_remove<K: Ord + Binary, V: Binary>(pager: &mut Pager, id: uint,
+                                            key: &K)
+                                            -> Result<PagedNode<K, V>,
+                                                      PageError> {
+    let mut node: PagedNode<K, V> = page_try!(pager.read_node(id));
+    let pos = paged_pos(&node, key);
+    let found = pos < node.keys.len() && node.keys[pos] == *key;
+
+    if node.children.is_empty() {
+        node.keys.remove(pos);
+        node.values.remove(pos);
+        return Ok(node);
+    }
+
+    page_try!(pager.pin(id));
+
+    let child = if found {
+        paged_remove_max(pager, node.children[pos]).map(|(child, key, value)| {
+            node.keys[pos] = key;
+            node.values[pos] = value;
+            child
+        })
+    } else {
+        paged_remove(pager, node.children[pos], key)
+    };
+
+    let fixed = child.and_then(|child| paged_fix(pager, &mut node, pos, child));
+    pager.unpin(id);
+
+    page_try!(fixed);
+    Ok(node)
+}
+
+/// Remove the largest pair from the node that starts at page `id`, and
+/// return the node, without writing it, with the pair.
+fn paged_remove_max<K: Binary, V: Binary>(pager: &mut Pager, id: uint)
+                                          -> Result<(PagedNode<K, V>, K, V),
+                                                    PageError> {
+    let mut node: PagedNode<K, V> = page_try!(pager.read_node(id));
+
+    if node.children.is_empty() {
+        let key = node.keys.pop();
+        let value = node.values.pop();
+        return Ok((node, key, value));
+    }
+
+    let pos = node.keys.len();
+
+    page_try!(pager.pin(id));
+
+    let removed = paged_remove_max(pager, node.children[pos]);
+    let fixed = removed.and_then(|(child, key, value)| {
+        paged_fix(pager, &mut node, pos, child).map(|_| (key, value))
+    });
+
+    pager.unpin(id);
+
+    let (key, value) = page_try!(fixed);
+    Ok((node, key, value))
+}
+
+/// Write a changed child at `pos` of the node. A child with fewer than
+/// `t - 1` keys borrows a pair through the node from its left sibling, or
+/// from its right sibling if it is the first child, or is merged with that
+/// sibling if it cannot spare one.
+fn paged_fix<K: Binary, V: Binary>(pager: &mut Pager,
+                                   node: &mut PagedNode<K, V>, pos: uint,
+                                   child: PagedNode<K, V>)
+                                   -> Result<(), PageError> {
+    let mut child = child;
+
+    if child.keys.len() >= BTREE_KEYS_LBOUND {
+        return pager.write_node(node.children[pos], &child);
+    }
+
+    if pos > 0 {
+        let mut left: PagedNode<K, V> =
+            page_try!(pager.read_node(node.children[pos - 1]));
+
+        if left.keys.len() <= BTREE_KEYS_LBOUND {
+            return paged_merge(pager, node, pos - 1, left, child);
+        }
+
+        let key = util::replace(&mut node.keys[pos - 1], left.keys.pop());
+        let value = util::replace(&mut node.values[pos - 1],
+                                  left.values.pop());
+        child.keys.unshift(key);
+        child.values.unshift(value);
+
+        if !left.children.is_empty() {
+            child.children.unshift(left.children.pop());
+        }
+
+        page_try!(pager.write_node(node.children[pos - 1], &left));
+        return pager.write_node(node.children[pos], &child);
+    }
+
+    let mut right: PagedNode<K, V> =
+        page_try!(pager.read_node(node.children[1]));
+
+    if right.keys.len() <= BTREE_KEYS_LBOUND {
+        return paged_merge(pager, node, 0, child, right);
+    }
+
+    let key = util::replace(&mut node.keys[0], right.keys.shift());
+    let value = util::replace(&mut node.values[0], right.values.shift());
+    child.keys.push(key);
+    child.values.push(value);
+
+    if !right.children.is_empty() {
+        child.children.push(right.children.shift());
+    }
+
+    page_try!(pager.write_node(node.children[0], &child));
+    pager.write_node(node.children[1], &right)
+}
+
+/// Merge the children at `at` and `at + 1` of the node around the key
+/// between them, into the pages of the left child, and release the pages of
+/// the right child.
+fn paged_merge<K: Binary, V: Binary>(pager: &mut Pager,
+                                     node: &mut PagedNode<K, V>, at: uint,
+                                     left: PagedNode<K, V>,
+                                     right: PagedNode<K, V>)
+                                     -> Result<(), PageError> {
+    let mut left = left;
+
+    left.keys.push(node.keys.remove(at));
+    left.values.push(node.values.remove(at));
+    left.keys.push_all_move(right.keys);
+    left.values.push_all_move(right.values);
+    left.children.push_all_move(right.children);
+
+    page_try!(pager.release(node.children[at + 1]));
+    node.children.remove(at + 1);
+    pager.write_node(node.children[at], &left)
+}
+
+impl<K: Ord, V> BTreeBuilder<K, V> {
+    /// Create a builder without pairs.
+    pub fn new() -> BTreeBuilder<K, V> {
+        BTreeBuilder { pairs: ~[], dropped: 0, order: NaturalOrder }
+    }
+
+    /// Add a pair after the pairs that were added before. Of pairs with equal
+    /// keys the last one wins. Return false, and drop the pair, if its key is
+    /// smaller than the last key.
+    pub fn push(&mut self, key: K, value: V) -> bool {
+        let n = self.pairs.len();
+
+        let (after, same) = if n == 0 {
+            (true, false)
+        } else {
+            let (ref last, _) = self.pairs[n - 1];
+            (self.order.less(last, &key), self.order.equal(last, &key))
+        };
+
+        if after {
+            self.pairs.push((key, value));
+        } else if same {
+            self.pairs[n - 1] = (key, value);
+        } else {
+            self.dropped += 1;
+            return false;
+        }
+
+        true
+    }
+
+    /// Return the number of pairs that `push` dropped for being out of order.
+    pub fn dropped(&self) -> uint {
+        self.dropped
+    }
+
+    /// Build the tree of the pairs that were added.
+    pub fn build(self) -> ~BTree<K, V> {
+        let mut tree = build_sorted(self.pairs);
+        tree.order = self.order;
+        tree
+    }
+}
+
+impl<K, V> Container for BTreeBuilder<K, V> {
+    #[inline]
+    fn len(&self) -> uint { self.pairs.len() }
+}
+
+impl<'a, K, V> OccupiedEntry<'a, K, V> {
+    /// Return a reference to the key of the entry.
+    pub fn key<'b>(&'b self) -> &'b K {
+        node_at(&*self.tree, self.path).keys[self.pos].get_ref()
+    }
+
+    /// Return a reference to the value of the entry.
+    pub fn get<'b>(&'b self) -> &'b V {
+        value_at(node_at(&*self.tree, self.path), self.pos)
+    }
+
+    /// Return a mutable reference to the value of the entry.
+    pub fn get_mut<'b>(&'b mut self) -> &'b mut V {
+        value_at_mut(node_at_mut(&mut *self.tree, self.path), self.pos)
+    }
+
+    /// Convert the entry into a mutable reference to its value, which lives
+    /// as long as the borrow of the tree.
+    pub fn into_mut(self) -> &'a mut V {
+        let OccupiedEntry { tree, path, pos } = self;
+        value_at_mut(node_at_mut(tree, path), pos)
+    }
+
+    /// Replace the value of the entry and return the old value.
+    pub fn set(&mut self, value: V) -> V {
+        util::replace(self.get_mut(), value)
+    }
+
+    /// Remove the entry from the tree and return its value.
+    pub fn take(self) -> V {
+        let OccupiedEntry { tree, path, pos } = self;
+        let (_, value) = remove_at(&mut *tree, path, pos);
+        collapse_root(tree);
+        tree.generation += 1;
+        value
+    }
+
+    fn into_key_value(self) -> (&'a K, &'a mut V) {
+        let OccupiedEntry { tree, path, pos } = self;
+        key_value_at_mut(node_at_mut(tree, path), pos)
+    }
+}
+
+impl<'a, K, V> Frame<'a, K, V> {
+    fn new(node: &'a BTree<K, V>, sep: Option<&'a K>) -> Frame<'a, K, V> {
+        Frame { node: node, sep: sep, lo: 0, hi: node.used + 1 }
+    }
+}
+
+impl<'a, K: Eq, V> Iterator<(&'a K, &'a V)> for BTreeIterator<'a, K, V> {
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        check_generation(self.tree, self.generation);
+
+        match next_pair(&mut self.front, false) {
+            Some((k, v)) if !is_same_key(self.back_key, k) => {
+                self.front_key = Some(k);
+                self.remaining -= 1;
+                Some((k, v))
+            }
+            _ => {
+                self.front = ~[];
+                self.back = ~[];
+                self.remaining = 0;
+                None
+            }
+        }
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (uint, Option<uint>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a, K: Eq, V> DoubleEndedIterator<(&'a K, &'a V)>
+        for BTreeIterator<'a, K, V> {
+    fn next_back(&mut self) -> Option<(&'a K, &'a V)> {
+        check_generation(self.tree, self.generation);
+
+        match next_pair(&mut self.back, true) {
+            Some((k, v)) if !is_same_key(self.front_key, k) => {
+                self.back_key = Some(k);
+                self.remaining -= 1;
+                Some((k, v))
+            }
+            _ => {
+                self.front = ~[];
+                self.back = ~[];
+                self.remaining = 0;
+                None
+            }
+        }
+    }
+}
+
+impl<'a, K: Eq, V> ExactSize<(&'a K, &'a V)> for BTreeIterator<'a, K, V> {}
+
+impl<'a, K: Eq, V> Iterator<(&'a K, &'a V)> for BTreeRevIterator<'a, K, V> {
+    #[inline]
+    fn next(&mut self) -> Option<(&'a K, &'a V)> { self.iter.next_back() }
+
+    #[inline]
+    fn size_hint(&self) -> (uint, Option<uint>) { self.iter.size_hint() }
+}
+
+impl<'a, K: Eq, V> DoubleEndedIterator<(&'a K, &'a V)>
+        for BTreeRevIterator<'a, K, V> {
+    #[inline]
+    fn next_back(&mut self) -> Option<(&'a K, &'a V)> { self.iter.next() }
+}
+
+impl<'a, K: Eq, V> ExactSize<(&'a K, &'a V)> for BTreeRevIterator<'a, K, V> {}
+
+impl<'a, K: Ord, V> BTreeRangeIterator<'a, K, V> {
+    /// Skip forward to the first key in the range that is not less than
+    /// `key`. The iterator keeps its stack and only descends again below the
+    /// lowest node that still holds `key`, so a seek to a nearby key is cheap.
+    /// Seeking to a key before the next key has no effect, and seeking past
+    /// the back of the iterator exhausts it. The skipped keys are counted
+    /// from the subtree counts, see `rank`, to keep `size_hint` exact.
+    pub fn seek(&mut self, key: &K) {
+        let order = self.order;
+        let past = match self.iter.back_key {
+            Some(back) => order.less(back, key),
+            None => false,
+        };
+
+        if past {
+            self.iter.front = ~[];
+            self.iter.back = ~[];
+            self.iter.remaining = 0;
+            return;
+        }
+
+        let below = count_prefix(self.root, self.sep, |k| order.less(k, key));
+        let passed = match self.iter.front_key {
+            Some(front) => {
+                count_prefix(self.root, self.sep, |k| !order.less(front, k))
+            }
+            None => 0,
+        };
+
+        if below > passed {
+            let skipped = below - passed;
+
+            self.iter.remaining = if skipped < self.iter.remaining {
+                self.iter.remaining - skipped
+            } else {
+                0
+            };
+        }
+
+        seek_forward(&mut self.iter.front, order, key);
+    }
+}
+
+impl<'a, K: Eq, V> Iterator<(&'a K, &'a V)> for BTreeRangeIterator<'a, K, V> {
+    #[inline]
+    fn next(&mut self) -> Option<(&'a K, &'a V)> { self.iter.next() }
+
+    #[inline]
+    fn size_hint(&self) -> (uint, Option<uint>) { self.iter.size_hint() }
+}
+
+impl<'a, K: Eq, V> DoubleEndedIterator<(&'a K, &'a V)>
+        for BTreeRangeIterator<'a, K, V> {
+    #[inline]
+    fn next_back(&mut self) -> Option<(&'a K, &'a V)> { self.iter.next_back() }
+}
+
+impl<'a, K: Eq, V> ExactSize<(&'a K, &'a V)>
+        for BTreeRangeIterator<'a, K, V> {}
+
+impl<'a, K: Ord, V: Eq> Iterator<Difference<'a, K, V>>
+    for BTreeDiffIterator<'a, K, V> {
+    fn next(&mut self) -> Option<Difference<'a, K, V>> {
+        loop {
+            let (l, r) = match (self.left_next, self.right_next) {
+                (None, None) => return None,
+                (Some((k, v)), None) => {
+                    self.left_next = next_pair(&mut self.left, false);
+                    return Some(LeftOnly(k, v));
+                }
+                (None, Some((k, v))) => {
+                    self.right_next = next_pair(&mut self.right, false);
+                    return Some(RightOnly(k, v));
+                }
+                (Some(l), Some(r)) => (l, r),
+            };
+
+            let ((lk, lv), (rk, rv)) = (l, r);
+
+            if self.order.less(lk, rk) {
+                self.left_next = next_pair(&mut self.left, false);
+                return Some(LeftOnly(lk, lv));
+            }
+
+            if self.order.less(rk, lk) {
+                self.right_next = next_pair(&mut self.right, false);
+                return Some(RightOnly(rk, rv));
+            }
+
+            // Both walks passed the same key, so they may enter a node that
+            // the trees share next.
+            skip_shared(&mut self.left, &mut self.right, self.order);
+            self.left_next = next_pair(&mut self.left, false);
+            self.right_next = next_pair(&mut self.right, false);
+
+            if *lv != *rv {
+                return Some(Changed(lk, lv, rv));
+            }
+        }
+    }
+}
+
+impl<'a, K, V> MutFrame<'a, K, V> {
+    fn new(node: &'a mut BTree<K, V>,
+           sep: Option<&'a K>) -> MutFrame<'a, K, V> {
+        let keys = &'a node.keys;
+        let nodes = &'a mut node.nodes;
+
+        MutFrame { keys: keys.iter(), nodes: nodes.mut_iter(), sep: sep }
+    }
+}
+
+impl<'a, K, V> Iterator<(&'a K, &'a mut V)> for BTreeMutIterator<'a, K, V> {
+    fn next(&mut self) -> Option<(&'a K, &'a mut V)> {
+        unsafe { check_generation(&*self.tree, self.generation); }
+
+        loop {
+            if self.stack.is_empty() {
+                return None;
+            }
+
+            let top = self.stack.len() - 1;
+
+            // The slots past the last key pair with the unused keys, so they
+            // take the key of the last leaf slot.
+            let sep = self.stack[top].sep;
+            let key = match self.stack[top].keys.next() {
+                Some(&Some(ref k)) => Some(k),
+                Some(&None) | None => sep,
+            };
+
+            let slot = match self.stack[top].nodes.next() {
+                Some(slot) => slot,
+                None => {
+                    self.stack.pop();
+                    continue;
+                }
+            };
+
+            match *slot {
+                Some(TreeNode { value: ref mut child }) => {
+                    self.stack.push(MutFrame::new(child.get_mut(), key));
+                }
+                Some(TreeLeaf { value: ref mut value }) => {
+                    self.remaining -= 1;
+                    return Some((key.unwrap(), value));
+                }
+                None => {}
+            }
+        }
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (uint, Option<uint>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<K, V> Iterator<(K, V)> for BTreeMoveIterator<K, V> {
+    fn next(&mut self) -> Option<(K, V)> {
+        let pair = next_owned(&mut self.stack, None);
+
+        if pair.is_some() {
+            self.remaining -= 1;
+        }
+
+        pair
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (uint, Option<uint>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a, K, V> Iterator<(K, V)> for BTreeDrainIterator<'a, K, V> {
+    fn next(&mut self) -> Option<(K, V)> {
+        let pair = next_owned(&mut self.stack, Some(&mut *self.spare));
+
+        if pair.is_some() {
+            self.remaining -= 1;
+        }
+
+        pair
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (uint, Option<uint>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a, K: Ord, V> Iterator<(K, V)> for BTreeDrainFilter<'a, K, V> {
+    fn next(&mut self) -> Option<(K, V)> {
+        loop {
+            let taken = match self.cursor.get() {
+                Some((k, v)) => (self.pred)(k, &*v),
+                None => return None,
+            };
+
+            if taken {
+                return self.cursor.remove_current();
+            }
+
+            self.cursor.next();
+        }
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (uint, Option<uint>) {
+        (0, Some(self.cursor.tree.len() - self.cursor.index))
+    }
+}
+
+impl<'a, K: Eq, V> Iterator<&'a K> for BTreeKeyIterator<'a, K, V> {
+    #[inline]
+    fn next(&mut self) -> Option<&'a K> {
+        self.iter.next().map(|(k, _)| k)
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (uint, Option<uint>) { self.iter.size_hint() }
+}
+
+impl<'a, K: Eq, V> DoubleEndedIterator<&'a K> for BTreeKeyIterator<'a, K, V> {
+    #[inline]
+    fn next_back(&mut self) -> Option<&'a K> {
+        self.iter.next_back().map(|(k, _)| k)
+    }
+}
+
+impl<'a, K: Eq, V> ExactSize<&'a K> for BTreeKeyIterator<'a, K, V> {}
+
+impl<'a, K: Eq, V> Iterator<&'a V> for BTreeValueIterator<'a, K, V> {
+    #[inline]
+    fn next(&mut self) -> Option<&'a V> {
+        self.iter.next().map(|(_, v)| v)
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (uint, Option<uint>) { self.iter.size_hint() }
+}
+
+impl<'a, K: Eq, V> DoubleEndedIterator<&'a V>
+        for BTreeValueIterator<'a, K, V> {
+    #[inline]
+    fn next_back(&mut self) -> Option<&'a V> {
+        self.iter.next_back().map(|(_, v)| v)
+    }
+}
+
+impl<'a, K: Eq, V> ExactSize<&'a V> for BTreeValueIterator<'a, K, V> {}
+
+impl<'a, K, V> Chunk<'a, K, V> {
+    /// Return an iterator over the keys of the chunk in ascending order. The
+    /// iterator also indexes the keys, see `RandomAccessIterator`.
+    #[inline]
+    pub fn keys(&self) -> ChunkKeyIterator<'a, K> {
+        ChunkKeyIterator { iter: self.keys.iter() }
+    }
+
+    /// Return an iterator over the values of the chunk in the order of their
+    /// keys. The iterator also indexes the values.
+    #[inline]
+    pub fn values(&self) -> ChunkValueIterator<'a, K, V> {
+        ChunkValueIterator { iter: self.nodes.iter() }
+    }
+
+    /// Return the key at position `i` of the chunk, or None if the chunk has
+    /// `i` or fewer pairs.
+    #[inline]
+    pub fn key(&self, i: uint) -> Option<&'a K> {
+        if i < self.keys.len() { self.keys[i].as_ref() } else { None }
+    }
+
+    /// Return the value at position `i` of the chunk, or None if the chunk
+    /// has `i` or fewer pairs.
+    #[inline]
+    pub fn value(&self, i: uint) -> Option<&'a V> {
+        if i < self.nodes.len() { chunk_value(&self.nodes[i]) } else { None }
+    }
+}
+
+/// Return the value of a leaf slot of a chunk. Chunks only hold leaf slots.
+#[inline]
+fn chunk_value<'a, K, V>(item: &'a Option<TreeItem<K, V>>) -> Option<&'a V> {
+    match *item {
+        Some(TreeLeaf { value: ref value }) => Some(value),
+        _ => None,
+    }
+}
+
+impl<'a, K> Iterator<&'a K> for ChunkKeyIterator<'a, K> {
+    #[inline]
+    fn next(&mut self) -> Option<&'a K> {
+        self.iter.next().and_then(|k| k.as_ref())
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (uint, Option<uint>) { self.iter.size_hint() }
+}
+
+impl<'a, K> DoubleEndedIterator<&'a K> for ChunkKeyIterator<'a, K> {
+    #[inline]
+    fn next_back(&mut self) -> Option<&'a K> {
+        self.iter.next_back().and_then(|k| k.as_ref())
+    }
+}
+
+impl<'a, K> RandomAccessIterator<&'a K> for ChunkKeyIterator<'a, K> {
+    #[inline]
+    fn indexable(&self) -> uint { self.iter.indexable() }
+
+    #[inline]
+    fn idx(&self, index: uint) -> Option<&'a K> {
+        self.iter.idx(index).and_then(|k| k.as_ref())
+    }
+}
+
+impl<'a, K> ExactSize<&'a K> for ChunkKeyIterator<'a, K> {}
+
+impl<'a, K, V> Iterator<&'a V> for ChunkValueIterator<'a, K, V> {
+    #[inline]
+    fn next(&mut self) -> Option<&'a V> {
+        self.iter.next().and_then(|item| chunk_value(item))
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (uint, Option<uint>) { self.iter.size_hint() }
+}
+
+impl<'a, K, V> DoubleEndedIterator<&'a V> for ChunkValueIterator<'a, K, V> {
+    #[inline]
+    fn next_back(&mut self) -> Option<&'a V> {
+        self.iter.next_back().and_then(|item| chunk_value(item))
+    }
+}
+
+impl<'a, K, V> RandomAccessIterator<&'a V> for ChunkValueIterator<'a, K, V> {
+    #[inline]
+    fn indexable(&self) -> uint { self.iter.indexable() }
+
+    #[inline]
+    fn idx(&self, index: uint) -> Option<&'a V> {
+        self.iter.idx(index).and_then(|item| chunk_value(item))
+    }
+}
+
+impl<'a, K, V> ExactSize<&'a V> for ChunkValueIterator<'a, K, V> {}
+
+impl<'a, K, V> Container for Chunk<'a, K, V> {
+    #[inline]
+    fn len(&self) -> uint { self.keys.len() }
+}
+
+impl<'a, K, V> Iterator<Chunk<'a, K, V>> for BTreeChunkIterator<'a, K, V> {
+    fn next(&mut self) -> Option<Chunk<'a, K, V>> {
+        loop {
+            if self.stack.is_empty() {
+                return None;
+            }
+
+            let top = self.stack.len() - 1;
+            let node = self.stack[top].node;
+            let pos = self.stack[top].pos;
+
+            if pos > node.used {
+                self.stack.pop();
+                continue;
+            }
+
+            match node.nodes[pos] {
+                Some(TreeNode { value: ref child }) => {
+                    self.stack[top].pos = pos + 1;
+
+                    let sep = if pos < node.used {
+                        node.keys.slice(pos, pos + 1)
+                    } else {
+                        self.stack[top].sep
+                    };
+
+                    self.stack.push(ChunkFrame { node: child.get(), sep: sep,
+                                                 pos: 0 });
+                }
+                Some(TreeLeaf { value: _ }) if pos < node.used => {
+                    self.stack[top].pos = node.used;
+
+                    return Some(Chunk { keys: node.keys.slice(0, node.used),
+                                        nodes: node.nodes.slice(0, node.used) });
+                }
+                Some(TreeLeaf { value: _ }) => {
+                    self.stack[top].pos = pos + 1;
+
+                    return Some(Chunk { keys: self.stack[top].sep,
+                                        nodes: node.nodes.slice(pos, pos + 1) });
+                }
+                None => {
+                    self.stack[top].pos = pos + 1;
+                }
+            }
+        }
+    }
+}
+
+impl<'a, K, V> Step<'a, K, V> {
+    /// Return the step into the child at the slot of this step, starting at
+    /// its first slot or, if `forward` is false, at its last slot.
+    fn child(&self, child: &'a BTree<K, V>, forward: bool) -> Step<'a, K, V> {
+        let (node, pos) = (self.node, self.pos);
+
+        let lo = if pos > 0 { node.keys[pos - 1].as_ref() } else { self.lo };
+        let sep = if pos < node.used { node.keys[pos].as_ref() }
+                  else { self.sep };
+
+        Step {
+            node: child,
+            lo: lo,
+            sep: sep,
+            pos: if forward { 0 } else { child.used },
+        }
+    }
+}
+
+impl<'a, K: Ord, V> BTreeCursor<'a, K, V> {
+    /// Move the cursor to the smallest key. Return false if the tree is empty.
+    pub fn seek_first(&mut self) -> bool {
+        self.generation = self.tree.generation;
+        self.path = ~[Step { node: self.tree, lo: None, sep: None, pos: 0 }];
+        move_cursor(&mut self.path, true, true)
+    }
+
+    /// Move the cursor to the largest key. Return false if the tree is empty.
+    pub fn seek_last(&mut self) -> bool {
+        self.generation = self.tree.generation;
+        let used = self.tree.used;
+        self.path = ~[Step { node: self.tree, lo: None, sep: None, pos: used }];
+        move_cursor(&mut self.path, false, true)
+    }
+
+    /// Move the cursor to the smallest key that is greater than or equal to
+    /// `key`. The descent starts from the lowest node on the path of the
+    /// cursor whose subtree can contain `key`. Return false if all keys are
+    /// smaller than `key`.
+    pub fn seek(&mut self, key: &K) -> bool {
+        check_generation(self.tree, self.generation);
+
+        let tree = self.tree;
+        let order = &tree.order;
+
+        while !self.path.is_empty() {
+            let step = self.path[self.path.len() - 1];
+
+            let above = match step.lo {
+                Some(lo) => order.less(lo, key),
+                None => true
+            };
+
+            let below = match step.sep {
+                Some(sep) => !order.less(sep, key),
+                None => true
+            };
+
+            if above && below {
+                break;
+            }
+
+            self.path.pop();
+        }
+
+        if self.path.is_empty() {
+            let root = Step { node: self.tree, lo: None, sep: None, pos: 0 };
+            self.path.push(root);
+        }
+
+        loop {
+            let top = self.path.len() - 1;
+            let pos = find_node_pos(self.path[top].node, order, key);
+            self.path[top].pos = pos;
+
+            let step = self.path[top];
+
+            match step.node.nodes[pos] {
+                Some(TreeNode { value: ref child }) => {
+                    self.path.push(step.child(child.get(), true));
+                }
+                Some(TreeLeaf { value: _ }) => return true,
+                None => {
+                    self.path = ~[];
+                    return false;
+                }
+            }
+        }
+    }
+
+    /// Move the cursor to the next key and return it with its value.
+    pub fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        check_generation(self.tree, self.generation);
+        move_cursor(&mut self.path, true, false);
+        self.get()
+    }
+
+    /// Move the cursor to the previous key and return it with its value.
+    pub fn prev(&mut self) -> Option<(&'a K, &'a V)> {
+        check_generation(self.tree, self.generation);
+        move_cursor(&mut self.path, false, false);
+        self.get()
+    }
+
+    /// Return the key and value at the cursor.
+    pub fn get(&self) -> Option<(&'a K, &'a V)> {
+        check_generation(self.tree, self.generation);
+
+        if self.path.is_empty() {
+            return None;
+        }
+
+        let step = self.path[self.path.len() - 1];
+        let key = if step.pos < step.node.used {
+            step.node.keys[step.pos].as_ref()
+        } else {
+            step.sep
+        };
+
+        match step.node.nodes[step.pos] {
+            Some(TreeLeaf { value: ref value }) => Some((key.unwrap(), value)),
+            Some(TreeNode { value: _ }) | None => None,
+        }
+    }
+
+    /// Return the key at the cursor.
+    pub fn key(&self) -> Option<&'a K> {
+        self.get().map(|(k, _)| k)
+    }
+
+    /// Return the value at the cursor.
+    pub fn value(&self) -> Option<&'a V> {
+        self.get().map(|(_, v)| v)
+    }
+}
+
+impl<'a, K, V> VacantEntry<'a, K, V> {
+    /// Return a reference to the key of the entry.
+    pub fn key<'b>(&'b self) -> &'b K { &self.key }
+
+    /// Insert the value for the key of the entry and return a mutable
+    /// reference to it.
+    pub fn set(self, value: V) -> &'a mut V {
+        let (_, v) = self.insert_entry(value);
+        v
+    }
+
+    fn insert_entry(self, value: V) -> (&'a K, &'a mut V) {
+        let VacantEntry { tree, path, pos, key } = self;
+
+        let node = grow_path(tree, path);
+        insert_slot(node, pos, key, pos, TreeLeaf { value: value });
+        key_value_at_mut(node, pos)
+    }
+}
+
+/// Descend to the position of `key`, splitting full children on the way down
+/// like `insert_non_full`. The child positions of the descent are pushed onto
+/// `path`. Return the position of the key in the last node, and whether the
+/// key is stored in that node.
+fn entry_path<K: Ord, V>(tree: &mut BTree<K, V>, order: &Order<K>, key: &K,
+                         path: &mut ~[uint],
+                         spare: &mut ~[~BTree<K, V>])
+    -> (uint, bool) {
+    let mut pos = find_node_pos(tree, order, key);
+
+    if pos < tree.used && order.equal(tree.keys[pos].get_ref(), key) {
+        return (pos, true);
+    }
+
+    if tree.used == 0 || is_leaf(tree) {
+        return (pos, false);
+    }
+
+    if child_used(tree, pos) == BTREE_KEYS_UBOUND {
+        split_child(tree, pos, spare);
+
+        if order.equal(tree.keys[pos].get_ref(), key) {
+            return (pos, true);
+        }
+
+        if order.less(tree.keys[pos].get_ref(), key) {
+            pos += 1;
+        }
+    }
+
+    path.push(pos);
+
+    match tree.nodes[pos] {
+        Some(TreeNode { value: ref mut child }) => {
+            entry_path(child.get_mut(), order, key, path, spare)
+        }
+        Some(TreeLeaf { value: _ }) |
+        None => fail!("unreachable path: leaf has same depth as a node"),
+    }
+}
+
+/// Return the node at the end of a path of child positions.
+fn node_at<'a, K, V>(tree: &'a BTree<K, V>, path: &[uint]) -> &'a BTree<K, V> {
+    if path.is_empty() {
+        return tree;
+    }
+
+    match tree.nodes[path[0]] {
+        Some(TreeNode { value: ref child }) => {
+            node_at(child.get(), path.tail())
+        }
+        Some(TreeLeaf { value: _ }) |
+        None => fail!("unreachable path: tree.nodes[pos] should be a TreeNode"),
+    }
+}
+
+fn node_at_mut<'a, K, V>(tree: &'a mut BTree<K, V>, path: &[uint])
+    -> &'a mut BTree<K, V> {
+    if path.is_empty() {
+        return tree;
+    }
+
+    match tree.nodes[path[0]] {
+        Some(TreeNode { value: ref mut child }) => {
+            node_at_mut(child.get_mut(), path.tail())
+        }
+        Some(TreeLeaf { value: _ }) |
+        None => fail!("unreachable path: tree.nodes[pos] should be a TreeNode"),
+    }
+}
+
+/// Make the range `lo..hi` of `tree` equal to that of `source`, where `lo` is
+/// included and `hi` excluded, and return the number of pairs copied from
+/// `source`, see `BTree::sync_from`.
+fn sync_range<'b, K: Ord + Clone + IterBytes, V: Clone + IterBytes>(
+        tree: &mut BTree<K, V>, source: &BTree<K, V>, lo: Bound<'b, K>,
+        hi: Bound<'b, K>) -> uint {
+    let digest = digest_range(tree, &tree.order, None, lo, hi);
+
+    if digest == digest_range(source, &source.order, None, lo, hi) {
+        return 0;
+    }
+
+    let (start, end) = rank_range(tree, lo, hi);
+    let (source_start, source_end) = rank_range(source, lo, hi);
+
+    // Halve the range of the tree that has more pairs in it. The middle key
+    // lies after the first pair of the range, so both halves are smaller,
+    // unless the range starts with a run of keys equal to the middle key, as
+    // a tree that keeps duplicate keys can have. Such a range is copied.
+    let mid = if end - start <= BTREE_KEYS_UBOUND &&
+                 source_end - source_start <= BTREE_KEYS_UBOUND {
+        None
+    } else {
+        let (node, first, middle) = if end - start > source_end - source_start {
+            (&*tree, start, start + (end - start) / 2)
+        } else {
+            (source, source_start,
+             source_start + (source_end - source_start) / 2)
+        };
+
+        let (first, _) = node.select(first).unwrap();
+        let (middle, _) = node.select(middle).unwrap();
+
+        if node.order.equal(first, middle) {
+            None
+        } else {
+            Some(middle.clone())
+        }
+    };
+
+    match mid {
+        Some(mid) => {
+            return sync_range(tree, source, lo, Excluded(&mid)) +
+                sync_range(tree, source, Included(&mid), hi);
+        }
+        None => {}
+    }
+
+    let keys: ~[K] = tree.range(lo, hi).map(|(k, _)| k.clone()).collect();
+
+    for key in keys.iter() {
+        tree.pop(key);
+    }
+
+    // Kept equal keys end up in the reverse order of their insertion, so the
+    // pairs are inserted backwards to keep the order of the source.
+    for (k, v) in source.range(lo, hi).invert() {
+        tree.insert(k.clone(), v.clone());
+    }
+
+    source_end - source_start
+}
+
+/// Return the ranks of both ends of the range `lo..hi`, where `lo` is not
+/// excluded and `hi` is not included.
+fn rank_range<'b, K: Ord, V>(tree: &BTree<K, V>, lo: Bound<'b, K>,
+                             hi: Bound<'b, K>) -> (uint, uint) {
+    let start = match lo {
+        Included(k) | Excluded(k) => tree.rank(k),
+        Unbounded => 0,
+    };
+
+    let end = match hi {
+        Included(k) | Excluded(k) => tree.rank(k),
+        Unbounded => tree.len(),
+    };
+
+    (start, end)
+}
+
+/// The first bytes of a binary image of a tree, see `BTree::write_to`.
+static IMAGE_MAGIC: &'static [u8] = bytes!("BTRE");
+
+/// The kinds of items of a node in a binary image: no items in an empty root,
+/// leaves in a bottom node, and child nodes in an inner node.
+static IMAGE_EMPTY: u8 = 0;
+static IMAGE_BOTTOM: u8 = 1;
+static IMAGE_INNER: u8 = 2;
+
+/// Write a node and its subtree to a binary image. The values of a bottom
+/// node follow a flag that tells whether its last leaf slot has a value,
+/// which it has not on the rightmost path of the tree.
+fn write_node<K: Binary, V: Binary>(tree: &BTree<K, V>, w: &mut Writer) {
+    tree.used.write_binary(w);
+
+    for key in tree.keys.slice_to(tree.used).iter() {
+        key.get_ref().write_binary(w);
+    }
+
+    match tree.nodes[0] {
+        None => w.write_u8(IMAGE_EMPTY),
+        Some(TreeLeaf { value: _ }) => {
+            w.write_u8(IMAGE_BOTTOM);
+
+            w.write_u8(tree.nodes[tree.used].is_some() as u8);
+
+            for item in tree.nodes.slice_to(tree.used + 1).iter() {
+                match *item {
+                    Some(TreeLeaf { value: ref value }) => {
+                        value.write_binary(w);
+                    }
+                    Some(TreeNode { value: _ }) => {
+                        fail!("unreachable path: node in a bottom node")
+                    }
+                    None => {}
+                }
+            }
+        }
+        Some(TreeNode { value: _ }) => {
+            w.write_u8(IMAGE_INNER);
+
+            for item in tree.nodes.slice_to(tree.used + 1).iter() {
+                match *item {
+                    Some(TreeNode { value: ref child }) => {
+                        write_node(child.get(), w);
+                    }
+                    Some(TreeLeaf { value: _ }) | None => {
+                        fail!("unreachable path: leaf has same depth as a node")
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Read a node and its subtree from a binary image, see `write_node`, and
+/// count the values of the subtree. Return the node with the height of its
+/// subtree, or None if the image is malformed: a node but the root holds
+/// fewer than `BTREE_KEYS_LBOUND` keys, the children of a node differ in
+/// height, or the last leaf slot of a bottom node has a value on the
+/// rightmost path of the tree, or has none elsewhere.
+fn read_node<K: Binary, V: Binary>(r: &mut Reader, root: bool, rightmost: bool)
+                                   -> Option<(~BTree<K, V>, uint)> {
+    let mut tree = BTree::new();
+    let used: uint = Binary::read_binary(r);
+
+    if used > BTREE_KEYS_UBOUND || (!root && used < BTREE_KEYS_LBOUND) {
+        return None;
+    }
+
+    for i in range(0, used) {
+        tree.keys[i] = Some(Binary::read_binary(r));
+    }
+
+    tree.used = used;
+
+    let kind = r.read_u8();
+    let mut height = 1;
+
+    if kind == IMAGE_BOTTOM && used > 0 {
+        let last = r.read_u8() != 0;
+
+        if last == rightmost {
+            return None;
+        }
+
+        let n = if last { used + 1 } else { used };
+
+        for i in range(0, n) {
+            tree.nodes[i] = Some(TreeLeaf { value: Binary::read_binary(r) });
+        }
+    } else if kind == IMAGE_INNER && used > 0 {
+        for i in range(0, used + 1) {
+            match read_node(r, false, rightmost && i == used) {
+                Some((child, h)) if i == 0 || h + 1 == height => {
+                    height = h + 1;
+                    let child = NodeRef::new(child);
+                    tree.nodes[i] = Some(TreeNode { value: child });
+                }
+                _ => return None,
+            }
+        }
+    } else if kind != IMAGE_EMPTY || used > 0 || !root {
+        return None;
+    }
+
+    recount(&mut *tree);
+    Some((tree, height))
+}
+
+/// Return the hash of a key-value pair.
+fn digest_pair<K: IterBytes, V: IterBytes>(key: &K, value: &V) -> u64 {
+    (key.hash(), value.hash()).hash()
+}
+
+/// Return the digest of the pairs of a subtree, without the pair of the key
+/// of its last leaf slot, which is the digest of the subtree that its parent
+/// keeps. The digests of the child nodes are taken from their pointers where
+/// they are kept, so only the nodes that changed since are visited.
+fn digest_node<K: IterBytes, V: IterBytes>(tree: &BTree<K, V>) -> u64 {
+    let mut digest = 0u64;
+
+    for i in range(0, tree.used + 1) {
+        match tree.nodes[i] {
+            Some(TreeNode { value: ref child }) => {
+                digest += child.digest(|node| digest_node(node));
+
+                if i < tree.used {
+                    digest += digest_pair(tree.keys[i].get_ref(),
+                                          last_value(child.get()));
+                }
+            }
+            Some(TreeLeaf { value: ref value }) if i < tree.used => {
+                digest += digest_pair(tree.keys[i].get_ref(), value);
+            }
+            _ => {}
+        }
+    }
+
+    digest
+}
+
+/// Return the digest of the pairs of a subtree with keys within `lo` and
+/// `hi`, where `sep` is the key of the last leaf slot of the node. A child
+/// whose keys all lie within the bounds contributes its kept digest, so only
+/// the children on the paths of the bounds are descended into.
+fn digest_range<'b, K: IterBytes, V: IterBytes>(tree: &BTree<K, V>,
+                                                order: &Order<K>,
+                                                sep: Option<&K>,
+                                                lo: Bound<'b, K>,
+                                                hi: Bound<'b, K>) -> u64 {
+    let mut digest = 0u64;
+    let mut prev = None;
+
+    for i in range(0, tree.used + 1) {
+        let key = if i < tree.used { tree.keys[i].as_ref() } else { sep };
+
+        // The keys of the child lie between the previous key and its own.
+        match prev {
+            Some(p) if hi.is_above(order, p) => break,
+            _ => {}
+        }
+
+        let after_lo = match (lo, prev) {
+            (Unbounded, _) => true,
+            (_, Some(p)) => !lo.is_below(order, p),
+            (_, None) => false,
+        };
+
+        let before_hi = match (hi, key) {
+            (Unbounded, _) => true,
+            (_, Some(k)) => !hi.is_above(order, k),
+            (_, None) => false,
+        };
+
+        let in_range = match key {
+            Some(k) => !lo.is_below(order, k) && !hi.is_above(order, k),
+            None => false,
+        };
+
+        match tree.nodes[i] {
+            Some(TreeNode { value: ref child }) if after_lo && before_hi => {
+                digest += child.digest(|node| digest_node(node));
+
+                match key {
+                    Some(k) => {
+                        digest += digest_pair(k, last_value(child.get()));
+                    }
+                    None => {}
+                }
+            }
+            Some(TreeNode { value: ref child }) => {
+                let below = match key {
+                    Some(k) => lo.is_below(order, k),
+                    None => false,
+                };
+
+                if !below {
+                    digest += digest_range(child.get(), order, key, lo, hi);
+                }
+            }
+            Some(TreeLeaf { value: ref value }) if in_range => {
+                digest += digest_pair(key.unwrap(), value);
+            }
+            _ => {}
+        }
+
+        prev = key;
+    }
+
+    digest
+}
+
+/// Return the summary of type `S` in a boxed summary, see `Summarizer`.
+fn unbox_summary<'a, S: 'static>(summary: &'a Summary) -> &'a S {
+    match summary.as_ref::<S>() {
+        Some(summary) => summary,
+        None => fail!("the summaries of the tree are of another type"),
+    }
+}
+
+/// Return the summary of the pairs of `acc` followed by the pairs of `next`,
+/// where None stands for no pairs.
+fn combine_summary<K, V>(aggregate: &Aggregate<K, V>, acc: Option<Summary>,
+                         next: Option<&Summary>) -> Option<Summary> {
+    match (acc, next) {
+        (Some(a), Some(b)) => Some(aggregate.get().combine(&a, b)),
+        (None, Some(b)) => Some(aggregate.get().copy(b)),
+        (a, None) => a,
+    }
+}
+
+/// Return the summary of the pairs of the subtree of a child, which is taken
+/// from the child if it is kept, or computed otherwise.
+fn child_summary<K, V>(aggregate: &Aggregate<K, V>, acc: Option<Summary>,
+                       child: &NodeRef<K, V>) -> Option<Summary> {
+    match child.summary() {
+        Some(kept) => combine_summary(aggregate, acc, Some(kept)),
+        None => {
+            let summary = node_summary(child.get(), aggregate);
+            combine_summary(aggregate, acc, summary.as_ref())
+        }
+    }
+}
+
+/// Return the summary of the pair of a key and its value.
+fn pair_summary<K, V>(aggregate: &Aggregate<K, V>, acc: Option<Summary>,
+                      key: &K, value: &V) -> Option<Summary> {
+    let pair = aggregate.get().summarize(key, value);
+    combine_summary(aggregate, acc, Some(&pair))
+}
+
+/// Return the summary of the pairs of a subtree, without the pair of the key
+/// of its last leaf slot, which is the summary of the subtree that its
+/// parent keeps, as for `digest_node`.
+fn node_summary<K, V>(tree: &BTree<K, V>, aggregate: &Aggregate<K, V>)
+                      -> Option<Summary> {
+    let mut summary = None;
+
+    for i in range(0, tree.used + 1) {
+        match tree.nodes[i] {
+            Some(TreeNode { value: ref child }) => {
+                summary = child_summary(aggregate, summary, child);
+
+                if i < tree.used {
+                    summary = pair_summary(aggregate, summary,
+                                           tree.keys[i].get_ref(),
+                                           last_value(child.get()));
+                }
+            }
+            Some(TreeLeaf { value: ref value }) if i < tree.used => {
+                summary = pair_summary(aggregate, summary,
+                                       tree.keys[i].get_ref(), value);
+            }
+            _ => {}
+        }
+    }
+
+    summary
+}
+
+/// Keep the summaries of the nodes of a tree that changed since the last
+/// query, see `keep_summaries`, and return the monoid of the tree, or None
+/// if the tree keeps no summaries.
+fn refresh_summaries<K, V>(tree: &BTree<K, V>) -> Option<Aggregate<K, V>> {
+    let aggregate = match tree.aggregate {
+        Some(ref aggregate) => aggregate.clone(),
+        None => return None,
+    };
+
+    keep_summaries(tree, &aggregate);
+    Some(aggregate)
+}
+
+/// Compute and keep the summaries of the child nodes of a subtree that
+/// changed since their summaries were kept. A node whose summary is kept
+/// did not change below, so only the nodes on the paths of the changes
+/// since the last query are visited. The nodes that are shared with other
+/// trees keep their summaries too, see `NodeRef::keep_summary`.
+fn keep_summaries<K, V>(tree: &BTree<K, V>, aggregate: &Aggregate<K, V>) {
+    for item in tree.nodes.iter() {
+        match *item {
+            Some(TreeNode { value: ref child }) => {
+                child.keep_summary(|node| {
+                    keep_summaries(node, aggregate);
+                    node_summary(node, aggregate)
+                });
+            }
+            Some(TreeLeaf { value: _ }) | None => {}
+        }
+    }
+}
+
+/// Return the summary of the pairs of a subtree with keys within `lo` and
+/// `hi`, where `sep` is the key of the last leaf slot of the node, as for
+/// `digest_range`.
+fn summary_range<'b, K: Ord, V>(tree: &BTree<K, V>,
+                                aggregate: &Aggregate<K, V>,
+                                order: &Order<K>, sep: Option<&K>,
+                                lo: Bound<'b, K>, hi: Bound<'b, K>)
+                                -> Option<Summary> {
+    let mut summary = None;
+    let mut prev = None;
+
+    for i in range(0, tree.used + 1) {
+        let key = if i < tree.used { tree.keys[i].as_ref() } else { sep };
+
+        // The keys of the child lie between the previous key and its own.
+        match prev {
+            Some(p) if hi.is_above(order, p) => break,
+            _ => {}
+        }
+
+        let after_lo = match (lo, prev) {
+            (Unbounded, _) => true,
+            (_, Some(p)) => !lo.is_below(order, p),
+            (_, None) => false,
+        };
+
+        let before_hi = match (hi, key) {
+            (Unbounded, _) => true,
+            (_, Some(k)) => !hi.is_above(order, k),
+            (_, None) => false,
+        };
+
+        let in_range = match key {
+            Some(k) => !lo.is_below(order, k) && !hi.is_above(order, k),
+            None => false,
+        };
+
+        match tree.nodes[i] {
+            Some(TreeNode { value: ref child }) if after_lo && before_hi => {
+                summary = child_summary(aggregate, summary, child);
+
+                match key {
+                    Some(k) => {
+                        summary = pair_summary(aggregate, summary, k,
+                                               last_value(child.get()));
+                    }
+                    None => {}
+                }
+            }
+            Some(TreeNode { value: ref child }) => {
+                let below = match key {
+                    Some(k) => lo.is_below(order, k),
+                    None => false,
+                };
+
+                if !below {
+                    let part = summary_range(child.get(), aggregate, order,
+                                             key, lo, hi);
+                    summary = combine_summary(aggregate, summary,
+                                              part.as_ref());
+                }
+            }
+            Some(TreeLeaf { value: ref value }) if in_range => {
+                summary = pair_summary(aggregate, summary, key.unwrap(),
+                                       value);
+            }
+            _ => {}
+        }
+
+        prev = key;
+    }
+
+    summary
+}
+
+/// Return the node at the end of a path of child positions, and count a new
+/// pair in the subtrees of the nodes before it, which the pair is inserted
+/// below.
+fn grow_path<'a, K, V>(tree: &'a mut BTree<K, V>, path: &[uint])
+    -> &'a mut BTree<K, V> {
+    if path.is_empty() {
+        return tree;
+    }
+
+    tree.length += 1;
+
+    match tree.nodes[path[0]] {
+        Some(TreeNode { value: ref mut child }) => {
+            grow_path(child.get_mut(), path.tail())
+        }
+        Some(TreeLeaf { value: _ }) |
+        None => fail!("unreachable path: tree.nodes[pos] should be a TreeNode"),
+    }
+}
+
+/// Return the value of the key at `pos` in the node that stores the key.
+fn value_at<'a, K, V>(tree: &'a BTree<K, V>, pos: uint) -> &'a V {
+    match tree.nodes[pos] {
+        Some(TreeNode { value: ref child }) => last_value(child.get()),
+        Some(TreeLeaf { value: ref value }) => value,
+        None => fail!("unreachable path: key without a value"),
+    }
+}
+
+fn key_value_at_mut<'a, K, V>(tree: &'a mut BTree<K, V>, pos: uint)
+    -> (&'a K, &'a mut V) {
+    match tree.nodes[pos] {
+        Some(TreeNode { value: ref mut child }) => {
+            (tree.keys[pos].get_ref(), last_value_mut(child.get_mut()))
+        }
+        Some(TreeLeaf { value: ref mut value }) => {
+            (tree.keys[pos].get_ref(), value)
+        }
+        None => fail!("unreachable path: key without a value"),
+    }
+}
+
+fn value_at_mut<'a, K, V>(tree: &'a mut BTree<K, V>, pos: uint) -> &'a mut V {
+    match tree.nodes[pos] {
+        Some(TreeNode { value: ref mut child }) => {
+            last_value_mut(child.get_mut())
+        }
+        Some(TreeLeaf { value: ref mut value }) => value,
+        None => fail!("unreachable path: key without a value"),
+    }
+}
+
+/// Remove the key at `pos` of the node at the end of `path`, rebalancing the
+/// nodes along the path, and return it with its value.
+fn remove_at<K, V>(tree: &mut BTree<K, V>, path: &[uint], pos: uint)
+                   -> (K, V) {
+    if path.is_empty() {
+        return remove_key_at(tree, pos);
+    }
+
+    let entry = match tree.nodes[path[0]] {
+        Some(TreeNode { value: ref mut child }) => {
+            remove_at(child.get_mut(), path.tail(), pos)
+        }
+        Some(TreeLeaf { value: _ }) |
+        None => fail!("unreachable path: tree.nodes[pos] should be a TreeNode"),
+    };
+
+    tree.length -= 1;
+    fix_child(tree, path[0]);
+
+    entry
+}
+
+/// Return the path of child positions to the node that stores the key at
+/// in-order position `index`, with the position of the key in that node. The
+/// key of the last leaf slot of a bottom node is stored in the ancestor it
+/// separates, so the path ends at the deepest node whose key at the slot that
+/// is descended through has not been passed.
+fn index_path<K, V>(tree: &BTree<K, V>, index: uint) -> (~[uint], uint) {
+    let mut current = tree;
+    let mut index = index;
+    let mut path = ~[];
+    let mut stored = (0, 0);
+    let mut pos = 0;
+
+    loop {
+        match current.nodes[pos] {
+            Some(TreeNode { value: ref child }) => {
+                if index < child.get().length {
+                    if pos < current.used {
+                        stored = (path.len(), pos);
+                    }
+
+                    path.push(pos);
+                    current = child.get();
+                    pos = 0;
+                    continue;
+                }
+
+                index -= child.get().length;
+            }
+            Some(TreeLeaf { value: _ }) => {
+                if index == 0 {
+                    if pos < current.used {
+                        return (path, pos);
+                    }
+
+                    let (depth, at) = stored;
+                    path.truncate(depth);
+                    return (path, at);
+                }
+
+                index -= 1;
+            }
+            None => fail!("unreachable path: subtree counts do not match"),
+        }
+
+        pos += 1;
+    }
+}
+
+/// Remove the pair at in-order position `index`, which must be smaller than
+/// the number of pairs, and return it.
+fn remove_index<K, V>(tree: &mut BTree<K, V>, index: uint) -> (K, V) {
+    let (path, pos) = index_path(tree, index);
+    let entry = remove_at(tree, path, pos);
+    collapse_root(tree);
+    tree.generation += 1;
+    entry
+}
+
+/// The head of one input run during a k-way merge. `PriorityQueue` is a
+/// max-heap, so heads are ordered such that the smallest key is popped first
+/// and, among equal keys, the head of the earliest run.
+struct MergeHead<'a, K> {
+    key: K,
+    run: uint,
+    order: &'a Order<K>,
+}
+
+impl<'a, K: Ord> Ord for MergeHead<'a, K> {
+    fn lt(&self, other: &MergeHead<'a, K>) -> bool {
+        self.order.less(&other.key, &self.key)
+            || (!self.order.less(&self.key, &other.key) && other.run < self.run)
+    }
+}
+
+/// Merge the contents of several trees into a new tree. The in-order
+/// iterators of the trees are merged into a single sorted stream using a
+/// min-heap over the heads of the runs, and the stream is loaded bottom-up by
+/// a `BTreeBuilder`. If a key occurs in more than one tree, the value of the
+/// last tree in `trees` wins. The trees must order their keys alike, and the
+/// merged tree orders them like the first tree.
+pub fn merge_k<K: Ord + Clone, V: Clone>(trees: &[&BTree<K, V>])
+    -> ~BTree<K, V> {
+    let order = match trees.head_opt() {
+        Some(tree) => tree.order.clone(),
+        None => NaturalOrder,
+    };
+    let mut iters: ~[BTreeIterator<K, V>] = trees.iter().map(|t| t.iter())
+                                                  .collect();
+    let mut values = ~[];
+    let mut heap = PriorityQueue::new();
+
+    for (i, iter) in iters.mut_iter().enumerate() {
+        match iter.next() {
+            Some((key, value)) => {
+                let key = key.clone();
+                heap.push(MergeHead { key: key, run: i, order: &order });
+                values.push(Some(value));
+            }
+            None => values.push(None),
+        }
+    }
+
+    let mut builder = BTreeBuilder::new();
+    builder.order = order.clone();
+
+    // Equal keys are popped in run order, so the builder replaces the pair
+    // of an earlier run with that of a later one.
+    while !heap.is_empty() {
+        let head = heap.pop();
+        let value = util::replace(&mut values[head.run], None).unwrap();
+
+        match iters[head.run].next() {
+            Some((key, next)) => {
+                heap.push(MergeHead { key: key.clone(), run: head.run,
+                                      order: &order });
+                values[head.run] = Some(next);
+            }
+            None => {}
+        }
+
+        builder.push(head.key, value.clone());
+    }
+
+    builder.build()
+}
+
+/// Call `f` on every key-value pair of the tree in ascending key order, until
+/// `f` returns false. The value of a key stored in an inner node lives in the
+/// last leaf slot of the bottom node that is the rightmost descendant of the
+/// key's left child; `sep` is that key for the subtree being visited. Return
+/// false if the traversal was stopped early.
+fn each<'a, K, V>(tree: &'a BTree<K, V>, sep: Option<&'a K>,
+                  f: |&'a K, &'a V| -> bool) -> bool {
+    let mut i = 0;
+
+    while i <= tree.used {
+        let key = if i < tree.used { tree.keys[i].as_ref() } else { sep };
+
+        match tree.nodes[i] {
+            Some(TreeNode { value: ref child }) => {
+                if !each(child.get(), key, |k, v| f(k, v)) {
+                    return false;
+                }
+            }
+            Some(TreeLeaf { value: ref value }) => {
+                if !f(key.unwrap(), value) {
+                    return false;
+                }
+            }
+            None => {}
+        }
+
+        i += 1;
+    }
+
+    true
+}
+
+/// Call `f` on every key-value pair of the tree in descending key order, until
+/// `f` returns false, see `each`.
+fn each_reverse<'a, K, V>(tree: &'a BTree<K, V>, sep: Option<&'a K>,
+                          f: |&'a K, &'a V| -> bool) -> bool {
+    let mut i = tree.used + 1;
+
+    while i > 0 {
+        i -= 1;
+
+        let key = if i < tree.used { tree.keys[i].as_ref() } else { sep };
+
+        match tree.nodes[i] {
+            Some(TreeNode { value: ref child }) => {
+                if !each_reverse(child.get(), key, |k, v| f(k, v)) {
+                    return false;
+                }
+            }
+            Some(TreeLeaf { value: ref value }) => {
+                if !f(key.unwrap(), value) {
+                    return false;
+                }
+            }
+            None => {}
+        }
+    }
+
+    true
+}
+
+/// Return the next key-value pair of a consuming iterator, moving it out of
+/// the node at the top of `stack`. A node is dropped once all of its slots are
+/// visited, or emptied and kept in `spare` if it is given.
+fn next_owned<K, V>(stack: &mut ~[MoveFrame<K, V>],
+                    spare: Option<&mut ~[~BTree<K, V>]>) -> Option<(K, V)> {
+    let mut spare = spare;
+
+    loop {
+        if stack.is_empty() {
+            return None;
+        }
+
+        let top = stack.len() - 1;
+        let i = stack[top].pos;
+
+        if i > stack[top].node.used {
+            let frame = stack.pop();
+            let mut node = frame.node;
+
+            match spare {
+                Some(ref mut spare) => {
+                    node.used = 0;
+                    node.length = 0;
+                    spare.push(node);
+                }
+                None => {}
+            }
+
+            continue;
+        }
+
+        stack[top].pos += 1;
+
+        let key = if i < stack[top].node.used {
+            stack[top].node.keys[i].take()
+        } else {
+            stack[top].sep.take()
+        };
+
+        match stack[top].node.nodes[i].take() {
+            Some(TreeNode { value: child }) => {
+                let child = child.unwrap();
+                stack.push(MoveFrame { node: child, sep: key, pos: 0 });
+            }
+            Some(TreeLeaf { value: value }) => {
+                return Some((key.unwrap(), value));
+            }
+            None => {}
+        }
+    }
+}
+
+/// Return true if `other` refers to the same stored key as `key`. The ends of
+/// an iterator compare keys by address, as a tree that keeps duplicate keys
+/// can hold keys that are equal, see `Keep`.
+#[inline]
+fn is_same_key<K>(other: Option<&K>, key: &K) -> bool {
+    match other {
+        Some(other) => other as *K == key as *K,
+        None => false,
+    }
+}
+
+/// Return the next key-value pair of an iterator. The top frame of `stack` is
+/// the node that is visited; its first unvisited slot is taken, or its last
+/// one if `rev` is true. Child nodes are pushed and visited nodes are popped,
+/// so the same stack walks the tree in either direction.
+fn next_pair<'a, K, V>(stack: &mut ~[Frame<'a, K, V>],
+                       rev: bool) -> Option<(&'a K, &'a V)> {
+    loop {
+        if stack.is_empty() {
+            return None;
+        }
+
+        let top = stack.len() - 1;
+
+        if stack[top].lo == stack[top].hi {
+            stack.pop();
+            continue;
+        }
+
+        let i = if rev {
+            stack[top].hi -= 1;
+            stack[top].hi
+        } else {
+            stack[top].lo += 1;
+            stack[top].lo - 1
+        };
+
+        let node = stack[top].node;
+        let sep = stack[top].sep;
+        let key = if i < node.used { node.keys[i].as_ref() } else { sep };
+
+        match node.nodes[i] {
+            Some(TreeNode { value: ref child }) => {
+                stack.push(Frame::new(child.get(), key));
+            }
+            Some(TreeLeaf { value: ref value }) => {
+                return Some((key.unwrap(), value));
+            }
+            None => {}
+        }
+    }
+}
+
+/// Skip the subtrees that two walks are about to enter when they are the same
+/// node, see `BTree::diff`, so their pairs are not visited. While the walks
+/// are about to enter different nodes, both descend into them, so a shared
+/// node is found at any depth of either tree. The walks must be at pairs
+/// with equal keys.
+fn skip_shared<'a, K, V>(left: &mut ~[Frame<'a, K, V>],
+                         right: &mut ~[Frame<'a, K, V>], order: &Order<K>) {
+    loop {
+        pop_visited(left);
+        pop_visited(right);
+
+        if left.is_empty() || right.is_empty() {
+            return;
+        }
+
+        let (l, r) = (left.len() - 1, right.len() - 1);
+        let (i, j) = (left[l].lo, right[r].lo);
+        let (a, b) = (left[l].node, right[r].node);
+
+        // A child is followed by the pair of the key of its slot, which is
+        // the separator of the node for the last slot.
+        let a_key = if i < a.used { a.keys[i].as_ref() } else { left[l].sep };
+        let b_key = if j < b.used { b.keys[j].as_ref() } else { right[r].sep };
+
+        let same_key = match (a_key, b_key) {
+            (Some(x), Some(y)) => order.equal(x, y),
+            (None, None) => true,
+            _ => false,
+        };
+
+        match (&a.nodes[i], &b.nodes[j]) {
+            (&Some(TreeNode { value: ref x }),
+             &Some(TreeNode { value: ref y })) => {
+                left[l].lo += 1;
+                right[r].lo += 1;
+
+                if x.ptr != y.ptr || !same_key {
+                    left.push(Frame::new(x.get(), a_key));
+                    right.push(Frame::new(y.get(), b_key));
+                }
+            }
+            _ => return,
+        }
+    }
+}
+
+/// Pop the frames of a walk whose slots have all been visited.
+fn pop_visited<'a, K, V>(stack: &mut ~[Frame<'a, K, V>]) {
+    while !stack.is_empty() {
+        let top = stack.len() - 1;
+
+        if stack[top].lo < stack[top].hi {
+            return;
+        }
+
+        stack.pop();
+    }
+}
+
+/// Return the number of keys of a node for which `below` is true. These are
+/// the first keys of the node, as `below` holds for a prefix of the keys.
+fn count_below<K, V>(tree: &BTree<K, V>, below: |&K| -> bool) -> uint {
+    let mut pos = 0;
+
+    while pos < tree.used && below(tree.keys[pos].get_ref()) {
+        pos += 1;
+    }
+
+    pos
+}
+
+/// Return the number of keys of the subtree for which `below` is true, where
+/// `sep` is the key of the last leaf slot of the subtree. `below` must hold
+/// for a prefix of the keys in ascending order. The subtree counts of the
+/// items left of the descent are added up, like in `rank`.
+fn count_prefix<K, V>(tree: &BTree<K, V>, sep: Option<&K>,
+                      below: |&K| -> bool) -> uint {
+    let mut current = tree;
+    let mut sep = sep;
+    let mut count = 0;
+
+    loop {
+        let pos = count_below(current, |k| below(k));
+
+        match current.nodes[pos] {
+            Some(TreeNode { value: ref child }) => {
+                for item in current.nodes.slice_to(pos).iter() {
+                    count += item_length(item.get_ref());
+                }
+
+                if pos < current.used {
+                    sep = current.keys[pos].as_ref();
+                }
+
+                current = child.get();
+            }
+            Some(TreeLeaf { value: _ }) if pos == current.used => {
+                let last = match sep { Some(k) => below(k), None => false };
+                return count + pos + if last { 1 } else { 0 };
+            }
+            Some(TreeLeaf { value: _ }) | None => return count + pos,
+        }
+    }
+}
+
+/// Split the keys of the tree where `below` stops holding, and return the last
+/// key for which `below` is true and the first key for which it is false.
+/// `below` must hold for a prefix of the keys in ascending order.
+fn partition_point<'a, K, V>(tree: &'a BTree<K, V>, below: |&K| -> bool)
+                             -> (Option<&'a K>, Option<&'a K>) {
+    let mut current = tree;
+    let mut last = None;
+    let mut first = None;
+
+    // Keys further down the descent lie between the candidates, so they
+    // replace them.
+    loop {
+        let pos = count_below(current, |k| below(k));
+
+        if pos > 0 {
+            last = current.keys[pos - 1].as_ref();
+        }
+
+        if pos < current.used {
+            first = current.keys[pos].as_ref();
+        }
+
+        match current.nodes[pos] {
+            Some(TreeNode { value: ref tree }) => {
+                current = tree.get();
+            }
+            Some(TreeLeaf { value: _ }) |
+            None => return (last, first)
+        }
+    }
+}
+
+/// Return the stack of an iterator that starts at the first key of the tree
+/// for which `below` is false. Every node on the path to that key is pushed
+/// with the slots before the path already visited.
+fn seek<'a, K, V>(tree: &'a BTree<K, V>,
+                  below: |&K| -> bool) -> ~[Frame<'a, K, V>] {
+    let mut stack = ~[];
+    let mut current = tree;
+    let mut sep = None;
+
+    loop {
+        let pos = count_below(current, |k| below(k));
+        let mut frame = Frame::new(current, sep);
+
+        match current.nodes[pos] {
+            Some(TreeNode { value: ref child }) => {
+                frame.lo = pos + 1;
+                stack.push(frame);
+
+                if pos < current.used {
+                    sep = current.keys[pos].as_ref();
+                }
+
+                current = child.get();
+            }
+            Some(TreeLeaf { value: _ }) | None => {
+                frame.lo = pos;
+                stack.push(frame);
+
+                return stack;
+            }
+        }
+    }
+}
+
+/// Return the stack of an iterator that starts from the back at the last key
+/// of the tree for which `above` is false, see `seek`. The slots of a node
+/// from `pos` on have keys for which `above` is true. This includes the last
+/// leaf slot, as `sep` is a key for which `above` is true, or None on the last
+/// path of the tree, where that slot is empty.
+fn seek_back<'a, K, V>(tree: &'a BTree<K, V>,
+                       above: |&K| -> bool) -> ~[Frame<'a, K, V>] {
+    let mut stack = ~[];
+    let mut current = tree;
+    let mut sep = None;
+
+    loop {
+        let pos = count_below(current, |k| !above(k));
+        let mut frame = Frame::new(current, sep);
+
+        match current.nodes[pos] {
+            Some(TreeNode { value: ref child }) => {
+                frame.hi = pos;
+                stack.push(frame);
+
+                if pos < current.used {
+                    sep = current.keys[pos].as_ref();
+                }
+
+                current = child.get();
+            }
+            Some(TreeLeaf { value: _ }) | None => {
+                frame.hi = pos;
+                stack.push(frame);
+
+                return stack;
+            }
+        }
+    }
+}
+
+/// Move the front stack of an iterator forward to the first key that is not
+/// less than `key`. Frames of subtrees that lie entirely below `key` are
+/// popped, as their `sep` is the last key they hold, and the descent continues
+/// from the lowest remaining frame, see `seek`. A key at or before the current
+/// position leaves the stack unchanged.
+fn seek_forward<'a, K: Ord, V>(stack: &mut ~[Frame<'a, K, V>],
+                               order: &Order<K>, key: &K) {
+    loop {
+        if stack.is_empty() {
+            return;
+        }
+
+        let sep = stack[stack.len() - 1].sep;
+
+        match sep {
+            Some(sep) if order.less(sep, key) => { stack.pop(); }
+            _ => break,
+        }
+    }
+
+    let mut top = stack.len() - 1;
+    let mut pos = count_below(stack[top].node, |k| order.less(k, key));
+
+    if pos < stack[top].lo {
+        return;
+    }
+
+    loop {
+        let node = stack[top].node;
+
+        match node.nodes[pos] {
+            Some(TreeNode { value: ref child }) => {
+                stack[top].lo = pos + 1;
+
+                let sep = if pos < node.used {
+                    node.keys[pos].as_ref()
+                } else {
+                    stack[top].sep
+                };
+
+                stack.push(Frame::new(child.get(), sep));
+                top += 1;
+                pos = count_below(stack[top].node, |k| order.less(k, key));
+            }
+            Some(TreeLeaf { value: _ }) | None => {
+                stack[top].lo = pos;
+                return;
+            }
+        }
+    }
+}
+
+/// Return an iterator over the subtree of `node` and the pair of `sep`, whose
+/// value is in the last leaf slot of the subtree. `tree` is the root of the
+/// tree, and `remaining` is the subtree count of `node`, which includes that
+/// pair.
+fn subtree_iter<'a, K, V>(tree: &'a BTree<K, V>, node: &'a BTree<K, V>,
+                          sep: Option<&'a K>, remaining: uint)
+                          -> BTreeRangeIterator<'a, K, V> {
+    BTreeRangeIterator {
+        iter: BTreeIterator {
+            tree: tree,
+            generation: tree.generation,
+            front: ~[Frame::new(node, sep)],
+            back: ~[Frame::new(node, sep)],
+            front_key: None,
+            back_key: None,
+            remaining: remaining,
+        },
+        root: node,
+        sep: sep,
+        order: &tree.order,
+    }
+}
+
+/// Return an iterator over the keys of the tree for which both `below` and
+/// `above` are false. `below` must hold for a prefix of the keys in ascending
+/// order, and `above` for a suffix. The keys just outside the range are the
+/// keys last passed at either end of the iterator, and the keys in between
+/// are counted from the subtree counts, see `count_prefix`.
+fn range_by<'a, K: Ord, V>(tree: &'a BTree<K, V>, below: |&K| -> bool,
+                           above: |&K| -> bool)
+                           -> BTreeRangeIterator<'a, K, V> {
+    let (before, first) = partition_point(tree, |k| below(k));
+    let (last, after) = partition_point(tree, |k| !above(k));
+
+    let empty = match (first, last) {
+        (Some(first), Some(last)) => tree.order.less(last, first),
+        _ => true,
+    };
+
+    if empty {
+        return empty_range(tree);
+    }
+
+    let remaining = count_prefix(tree, None, |k| !above(k)) -
+                    count_prefix(tree, None, |k| below(k));
+
+    let iter = BTreeIterator {
+        tree: tree,
+        generation: tree.generation,
+        front: seek(tree, |k| below(k)),
+        back: seek_back(tree, |k| above(k)),
+        front_key: before,
+        back_key: after,
+        remaining: remaining,
+    };
+
+    BTreeRangeIterator { iter: iter, root: tree, sep: None,
+                         order: &tree.order }
+}
+
+/// Return an iterator over none of the keys of the tree.
+fn empty_range<'a, K: Ord, V>(tree: &'a BTree<K, V>)
+                              -> BTreeRangeIterator<'a, K, V> {
+    let iter = BTreeIterator { tree: tree, generation: tree.generation,
+                               front: ~[], back: ~[], front_key: None,
+                               back_key: None, remaining: 0 };
+
+    BTreeRangeIterator { iter: iter, root: tree, sep: None,
+                         order: &tree.order }
+}
+
+/// Move a cursor path to the next leaf slot in key order, or the previous one
+/// if `forward` is false. If `descend` is true, the path is first completed
+/// from the slot of its last node. Return false and leave the path empty if
+/// the path moves past the end of the tree.
+fn move_cursor<'a, K, V>(path: &mut ~[Step<'a, K, V>], forward: bool,
+                         descend: bool) -> bool {
+    let mut descend = descend;
+
+    loop {
+        if path.is_empty() {
+            return false;
+        }
+
+        let top = path.len() - 1;
+
+        if !descend {
+            let step = path[top];
+
+            if forward && step.pos < step.node.used {
+                path[top].pos += 1;
+            } else if !forward && step.pos > 0 {
+                path[top].pos -= 1;
+            } else {
+                path.pop();
+                continue;
+            }
+        }
+
+        let step = path[top];
+
+        match step.node.nodes[step.pos] {
+            Some(TreeLeaf { value: _ }) => return true,
+            Some(TreeNode { value: ref child }) => {
+                path.push(step.child(child.get(), forward));
+                descend = true;
+            }
+            None => descend = false,
+        }
+    }
+}
+
+/// Move a path of child positions and the position of a key in the node at
+/// its end, as `index_path` returns them, to the next key in key order, or
+/// the previous one if `forward` is false. Return false and leave them as
+/// they are if there is no such key.
+fn move_index_path<'a, K, V>(tree: &'a BTree<K, V>, path: &mut ~[uint],
+                             pos: &mut uint, forward: bool) -> bool {
+    let mut nodes = ~[tree];
+
+    for &p in path.iter() {
+        let child = match nodes[nodes.len() - 1].nodes[p] {
+            Some(TreeNode { value: ref child }) => child.get(),
+            Some(TreeLeaf { value: _ }) | None => {
+                fail!("unreachable path: tree.nodes[pos] should be a TreeNode")
+            }
+        };
+
+        nodes.push(child);
+    }
+
+    let mut node = nodes[nodes.len() - 1];
+
+    match node.nodes[0] {
+        Some(TreeNode { value: _ }) => {
+            // The keys next to a key of an inner node are the first key of
+            // the subtree after it and the last key of the subtree before it,
+            // whose last leaf slot holds the value of the key itself.
+            let mut child = if forward { *pos + 1 } else { *pos };
+
+            loop {
+                path.push(child);
+
+                node = match node.nodes[child] {
+                    Some(TreeNode { value: ref next }) => next.get(),
+                    Some(TreeLeaf { value: _ }) | None => {
+                        fail!("unreachable path: leaf has same depth as a node")
+                    }
+                };
+
+                match node.nodes[0] {
+                    Some(TreeNode { value: _ }) => {
+                        child = if forward { 0 } else { node.used };
+                    }
+                    Some(TreeLeaf { value: _ }) | None => {
+                        *pos = if forward { 0 } else { node.used - 1 };
+                        return true;
+                    }
+                }
+            }
+        }
+        Some(TreeLeaf { value: _ }) | None => {}
+    }
+
+    if forward && *pos + 1 < node.used {
+        *pos += 1;
+        return true;
+    }
+
+    if !forward && *pos > 0 {
+        *pos -= 1;
+        return true;
+    }
+
+    // The key at either end of a bottom node is next to the key of the
+    // nearest ancestor that the path passes on that side of it.
+    let mut depth = path.len();
+
+    while depth > 0 {
+        depth -= 1;
+        let p = path[depth];
+
+        if forward && p < nodes[depth].used {
+            path.truncate(depth);
+            *pos = p;
+            return true;
+        }
+
+        if !forward && p > 0 {
+            path.truncate(depth);
+            *pos = p - 1;
+            return true;
+        }
+    }
+
+    false
+}
+
+fn find_node_pos<K: Ord, V>(tree: &BTree<K, V>, order: &Order<K>,
+                            key: &K) -> uint {
+    // NB Find the position using binary search on the keys in this node. The
+    // following code performs the binary search, but it results in slower
+    // run-time. Binary search on the keys should be faster than linear search,
+    // but perhaps cache misses explain why the binary search performs poor.
+    /*
+    if tree.used == 0 {
+        return 0;
+    }
+
+    let mut l = 0;
+    let mut u = tree.used - 1;
+
+    loop {
+        if l == u {
+            if key <= tree.keys[l].get_ref() {
+                return l;
+            }
+            else {
+                return l + 1;
+            }
+        }
+
+        let i = (l + u) / 2;
+
+        let cmp_key = tree.keys[i].get_ref();
+
+        if key < cmp_key {
+            if i == 0 {
+                return 0;
+            }
+
+            u = if i - 1 < l { l } else { i - 1 };
+        } else if key > cmp_key {
+            l = i + 1;
+        } else {
+            return i;
+        }
+    }
+    */
+
+    // Find the position using linear search on the keys in this node.
+    for (i, k) in tree.keys.iter().enumerate() {
+        let k : &Option<K> = k;
+        match *k {
+            Some(ref k) => {
+                if !order.less(k, key) {
+                    return i;
+                }
+            }
+            None => return tree.used,
+        };
+    }
+
+    tree.used
+}
+
+fn find<'a, K: Ord, V>(tree: &'a BTree<K, V>, order: &Order<K>,
+                       key: &K) -> Option<&'a V> {
+    let mut current = tree;
+
+    // Once the key is found in an inner node, the descent continues along the
+    // rightmost path of its left child, because the value is stored in the
+    // last leaf slot of the bottom node of that path.
+    let mut found = false;
+
+    loop {
+        let pos = match current.nodes[0] {
+            Some(TreeNode { value: _ }) |
+            Some(TreeLeaf { value: _ }) => find_node_pos(current, order, key),
+            None => return None
+        };
+
+        if pos < current.used
+           && order.equal(current.keys[pos].get_ref(), key) {
+            found = true;
+        }
+
+        match current.nodes[pos] {
+            Some(TreeNode { value: ref tree }) => {
+                current = tree.get();
+            }
+            Some(TreeLeaf { value: ref value }) => {
+                return if found { Some(value) } else { None };
+            }
+            None => return None
+        }
+    }
+}
+
+/// Return the value of the key equivalent to `key`. The descent is the same
+/// as in `find`, using `KeyEquiv::cmp_key` to compare with the keys.
+fn find_equiv<'a, K, V, Q: KeyEquiv<K>>(tree: &'a BTree<K, V>,
+                                        key: &Q) -> Option<&'a V> {
+    let mut current = tree;
+    let mut found = false;
+
+    loop {
+        if current.nodes[0].is_none() {
+            return None;
+        }
+
+        let mut pos = current.used;
+
+        for i in range(0, current.used) {
+            if key.cmp_key(current.keys[i].get_ref()) != Greater {
+                pos = i;
+                break;
+            }
+        }
+
+        if pos < current.used && key.equiv(current.keys[pos].get_ref()) {
+            found = true;
+        }
+
+        match current.nodes[pos] {
+            Some(TreeNode { value: ref tree }) => {
+                current = tree.get();
+            }
+            Some(TreeLeaf { value: ref value }) => {
+                return if found { Some(value) } else { None };
+            }
+            None => return None
+        }
+    }
+}
+
+/// Return a mutable reference to the value of `key` in the subtree. `found`
+/// is true if the key was already seen in an inner node during the descent,
+/// see `BTree::find`.
+fn find_mut<'a, K: Ord, V>(tree: &'a mut BTree<K, V>, order: &Order<K>,
+                           key: &K, found: bool) -> Option<&'a mut V> {
+    let pos = find_node_pos(tree, order, key);
+    let found = found || (pos < tree.used
+                          && order.equal(tree.keys[pos].get_ref(), key));
+
+    match tree.nodes[pos] {
+        Some(TreeNode { value: ref mut child }) => {
+            find_mut(child.get_mut(), order, key, found)
+        }
+        Some(TreeLeaf { value: ref mut value }) => {
+            if found { Some(value) } else { None }
+        }
+        None => None
+    }
+}
+
+/// Return the largest key below `key`, or equal to it if `inclusive`, with
+/// its value.
+fn find_below<'a, K: Ord, V>(tree: &'a BTree<K, V>, order: &Order<K>,
+                             key: &K, inclusive: bool)
+                             -> Option<(&'a K, &'a V)> {
+    let mut current = tree;
+    let mut candidate = None;
+
+    loop {
+        let pos = find_node_pos(current, order, key);
+
+        if inclusive && pos < current.used
+           && order.equal(current.keys[pos].get_ref(), key) {
+            return Some((current.keys[pos].get_ref(), value_at(current, pos)));
+        }
+
+        // Keys further down the descent lie between the candidate and the key,
+        // so they replace the candidate.
+        if pos > 0 {
+            candidate = Some((current, pos - 1));
+        }
+
+        match current.nodes[pos] {
+            Some(TreeNode { value: ref tree }) => {
+                current = tree.get();
+            }
+            Some(TreeLeaf { value: _ }) |
+            None => break
+        }
+    }
+
+    candidate.map(|(node, pos)| (node.keys[pos].get_ref(), value_at(node, pos)))
+}
+
+/// Return the smallest key above `key`, or equal to it if `inclusive`, with
+/// its value.
+fn find_above<'a, K: Ord, V>(tree: &'a BTree<K, V>, order: &Order<K>,
+                             key: &K, inclusive: bool)
+                             -> Option<(&'a K, &'a V)> {
+    let mut current = tree;
+    let mut candidate = None;
+
+    loop {
+        let mut pos = find_node_pos(current, order, key);
+
+        if pos < current.used
+           && order.equal(current.keys[pos].get_ref(), key) {
+            if inclusive {
+                return Some((current.keys[pos].get_ref(),
+                             value_at(current, pos)));
+            }
+
+            pos += 1;
+        }
+
+        if pos < current.used {
+            candidate = Some((current, pos));
+        }
+
+        match current.nodes[pos] {
+            Some(TreeNode { value: ref tree }) => {
+                current = tree.get();
+            }
+            Some(TreeLeaf { value: _ }) |
+            None => break
+        }
+    }
+
+    candidate.map(|(node, pos)| (node.keys[pos].get_ref(), value_at(node, pos)))
+}
+
+/// Push the values of sorted `keys` in the subtree onto `out`. Each run of
+/// keys that falls between the same two keys of a node is looked up in the
+/// corresponding child with a single descent.
+fn find_sorted<'a, K: Ord, V>(tree: &'a BTree<K, V>, order: &Order<K>,
+                              keys: &[K], out: &mut ~[Option<&'a V>]) {
+    let mut pos = 0;
+    let mut i = 0;
+
+    while i < keys.len() {
+        while pos < tree.used
+              && order.less(tree.keys[pos].get_ref(), &keys[i]) {
+            pos += 1;
+        }
+
+        if pos < tree.used
+           && order.equal(tree.keys[pos].get_ref(), &keys[i]) {
+            out.push(Some(value_at(tree, pos)));
+            i += 1;
+            continue;
+        }
+
+        let mut j = i + 1;
+
+        while j < keys.len()
+              && (pos == tree.used
+                  || order.less(&keys[j], tree.keys[pos].get_ref())) {
+            j += 1;
+        }
+
+        match tree.nodes[pos] {
+            Some(TreeNode { value: ref child }) => {
+                find_sorted(child.get(), order, keys.slice(i, j), out);
+            }
+            Some(TreeLeaf { value: _ }) |
+            None => {
+                for _ in range(i, j) {
+                    out.push(None);
+                }
+            }
+        }
+
+        i = j;
+    }
+}
+
+fn find_node<'r, K: Ord, V>(tree: &'r BTree<K, V>, order: &Order<K>,
+                            key: &K) -> &'r BTree<K, V> {
+    // TODO make iterative if the borrow checker allows it
+    //match tree.nodes[0] {
+    //    Some(TreeNode { value: _ }) => {
+    //        let pos = find_node_pos(tree, key);
+
+    //        match tree.nodes[pos] {
+    //            Some(TreeNode { value: ref mut tree }) => {
+    //                return find_node(&mut **tree, key);
+    //            }
+    //            Some(TreeLeaf { value: _ }) |
+    //            None => fail!("tree.nodes[pos] != TreeNode"),
+    //        }
+    //    }
+    //    Some(TreeLeaf { value: _ }) |
+    //    None => tree,
+    //}
+    let mut current = tree;
+
+    loop {
+        match current.nodes[0] {
+            Some(TreeNode { value: _ }) => {
+                let pos = find_node_pos(tree, order, key);
+
+                match tree.nodes[pos] {
+                    Some(TreeNode { value: ref tree }) => {
+                        //return find_node(&mut **tree, key);
+                        current = tree.get();
+                    }
+                    Some(TreeLeaf { value: _ }) |
+                    None => fail!("tree.nodes[pos] != TreeNode"),
+                }
+            }
+            Some(TreeLeaf { value: _ }) |
+            None => return tree,
+        }
+    }
+}
+
+/// Move the contents of a full root node into a new child node and split that
+/// child, so the root has room for another key.
+fn split_root<K: Ord, V>(tree: &mut BTree<K, V>,
+                         spare: &mut ~[~BTree<K, V>]) {
+    let mut child = new_node(spare);
+
+    let mut i = 0;
+
+    while i < BTREE_KEYS_UBOUND + 1 {
+        util::swap(&mut tree.nodes[i], &mut child.nodes[i]);
+        i += 1;
+    }
+
+    i = 0;
+
+    while i < BTREE_KEYS_UBOUND {
+        util::swap(&mut tree.keys[i], &mut child.keys[i]);
+        i += 1;
+    }
+
+    let child = NodeRef::new(child);
+    util::replace(&mut tree.nodes[0], Some(TreeNode { value: child }));
+
+    tree.used = 0;
+
+    split_child(tree, 0, spare);
+}
+
+fn split_child<K: Ord, V>(tree: &mut BTree<K, V>, pos: uint,
+                          spare: &mut ~[~BTree<K, V>]) {
+    let t = BTREE_MIN_DEGREE;
+
+    // Make a free slot in the parent node for the to-be-inserted key.
+    // Move the median key from the left node to the parent node. The median
+    // key separates the left and right node.
+    let mut i = tree.used;
+
+    while i > pos {
+        tree.nodes.swap(i, i + 1);
+        tree.keys.swap(i - 1, i);
+        i -= 1;
+    }
+
+    let right = match tree.nodes[pos] {
+        Some(TreeNode { value: ref mut left }) => {
+            let left = left.get_mut();
+            let mut right = new_node(spare);
+
+            let mut i = 0;
+
+            // Move the larger `t - 1' keys from the left to the right node.
+            while i < t - 1 {
+                util::swap(&mut right.keys[i], &mut left.keys[i + t]);
+                i += 1;
+            }
+
+            i = 0;
+
+            // Move the larger `t' nodes from the left to the right node.
+            while i < t {
+                util::swap(&mut right.nodes[i], &mut left.nodes[i + t]);
+                i += 1;
+            }
+
+            util::swap(&mut tree.keys[pos], &mut left.keys[t - 1]);
+
+            left.used = t - 1;
+            right.used = t - 1;
+
+            recount(left);
+            recount(&mut *right);
+
+            right
+        }
+        _ => fail!("unreachable path: tree.nodes[pos] should be a TreeNode"),
+    };
+
+    // Insert the new right node into the parent
+    tree.nodes[pos + 1] = Some(TreeNode { value: NodeRef::new(right) });
+    tree.used += 1;
+}
+
+/// Take an empty node from the spare nodes, or allocate one if there are no
+/// spare nodes left.
+fn new_node<K: Ord, V>(spare: &mut ~[~BTree<K, V>]) -> ~BTree<K, V> {
+    if spare.is_empty() {
+        BTree::new()
+    } else {
+        spare.pop()
+    }
+}
+
+fn is_leaf<K, V>(tree: &mut BTree<K, V>) -> bool {
+    match tree.nodes[0] {
+        Some(TreeLeaf { value: _ }) => true,
+        Some(TreeNode { value: _ }) | None => false,
+    }
+}
+
+/// Insert a pair at in-order position `index` of a non-full node, splitting
+/// full children on the way down like `entry_path`. The child to descend
+/// into is found from the subtree counts, so no keys are compared, and the
+/// key must be ordered between the pairs before and after the position. The
+/// child positions of the descent are pushed onto `path`. Return the position
+/// of the key in the bottom node that stores it.
+fn insert_index<K: Ord, V>(tree: &mut BTree<K, V>, index: uint, key: K,
+                           value: V, path: &mut ~[uint],
+                           spare: &mut ~[~BTree<K, V>]) -> uint {
+    if tree.used == 0 || is_leaf(tree) {
+        insert_slot(tree, index, key, index, TreeLeaf { value: value });
+        return index;
+    }
+
+    // The subtree of a child also counts the separator key after it, whose
+    // value is kept in its last leaf slot, so a pair inserted at the end of
+    // the subtree goes into the next child.
+    let mut index = index;
+    let mut pos = 0;
+
+    while pos < tree.used && index >= child_length(tree, pos) {
+        index -= child_length(tree, pos);
+        pos += 1;
+    }
+
+    if child_used(tree, pos) == BTREE_KEYS_UBOUND {
+        split_child(tree, pos, spare);
+
+        if index >= child_length(tree, pos) {
+            index -= child_length(tree, pos);
+            pos += 1;
+        }
+    }
+
+    tree.length += 1;
+    path.push(pos);
+
+    match tree.nodes[pos] {
+        Some(TreeNode { value: ref mut child }) => {
+            insert_index(child.get_mut(), index, key, value, path, spare)
+        }
+        Some(TreeLeaf { value: _ }) |
+        None => fail!("unreachable path: leaf has same depth as a node"),
+    }
+}
+
+/// Insert a key-value pair into a non-full node, splitting full children on
+/// the way down. Return the previous value if the key was already present.
+fn insert_non_full<K: Ord, V>(tree: &mut BTree<K, V>, order: &Order<K>,
+                              key: K, value: V, duplicates: Duplicates,
+                              spare: &mut ~[~BTree<K, V>]) -> Option<V> {
+    if tree.used == 0 || is_leaf(tree) {
+        let pos = find_node_pos(tree, order, &key);
+
+        if duplicates != Keep && pos < tree.used
+                && order.equal(tree.keys[pos].get_ref(), &key) {
+            if duplicates == Reject {
+                return Some(value);
+            }
+
+            return match tree.nodes[pos] {
+                Some(TreeLeaf { value: ref mut v }) => {
+                    Some(util::replace(v, value))
+                }
+                Some(TreeNode { value: _ }) |
+                None => fail!("unreachable path: tree.nodes[pos] != TreeLeaf"),
+            };
+        }
+
+        // A duplicate key that is kept is inserted before the equal keys.
+        insert_slot(tree, pos, key, pos, TreeLeaf { value: value });
+
+        None
+    } else {
+        let mut pos = find_node_pos(tree, order, &key);
+        let mut split = false;
+
+        match tree.nodes[pos] {
+            Some(TreeNode { value: ref t }) => {
+                if t.get().used == t.get().capacity() {
+                    split = true;
+                }
+            }
+            Some(TreeLeaf { value: _ }) => {
+                fail!("unreachable path: leaf has same depth as a node");
+            }
+            None => fail!("todo")
+        }
+
+        if split {
+            split_child(tree, pos, spare);
+
+            match tree.keys[pos] {
+                Some(ref k) => {
+                    if order.less(k, &key) {
+                        pos += 1;
+                    }
+                }
+                None => {}
+            }
+        }
+
+        // The key is stored in this node, so replace the value in the last
+        // leaf slot of its left child. A duplicate key that is kept goes to
+        // the end of the left child instead.
+        if duplicates != Keep && pos < tree.used
+                && order.equal(tree.keys[pos].get_ref(), &key) {
+            if duplicates == Reject {
+                return Some(value);
+            }
+
+            return match tree.nodes[pos] {
+                Some(TreeNode { value: ref mut t }) => {
+                    Some(util::replace(last_value_mut(t.get_mut()), value))
+                }
+                Some(TreeLeaf { value: _ }) |
+                None => fail!("unreachable path: leaf has same depth as a node")
+            };
+        }
+
+        let old = match tree.nodes[pos] {
+            Some(TreeNode { value: ref mut t }) => {
+                insert_non_full(t.get_mut(), order, key, value, duplicates,
+                                spare)
+            }
+            Some(TreeLeaf { value: _ }) |
+            None => fail!("unreachable path: leaf has same depth as a node")
+        };
+
+        if old.is_none() {
+            tree.length += 1;
+        }
+
+        old
+    }
+}
+
+/// Move all key-value pairs of the subtree into `out` in ascending key order,
+/// leaving the subtree empty. `sep` is the key of the value in the last leaf
+/// slot of the subtree, see `each`.
+fn take_entries<K, V>(tree: &mut BTree<K, V>, sep: Option<K>,
+                      out: &mut ~[(K, V)]) {
+    let mut sep = sep;
+    let mut i = 0;
+
+    while i <= tree.used {
+        let key = if i < tree.used { tree.keys[i].take() } else { sep.take() };
+
+        match tree.nodes[i].take() {
+            Some(TreeNode { value: mut child }) => {
+                take_entries(child.get_mut(), key, out);
+            }
+            Some(TreeLeaf { value: value }) => out.push((key.unwrap(), value)),
+            None => {}
+        }
+
+        i += 1;
+    }
+
+    tree.used = 0;
+    tree.length = 0;
+}
+
+/// Write a pair of a pair stream, see `BTree::write_pairs`.
+fn write_pair<K: Binary, V: Binary>(w: &mut Writer, key: &K, value: &V) {
+    w.write_u8(1);
+    key.write_binary(w);
+    value.write_binary(w);
+}
+
+/// Read the next pair of a pair stream, which is Some(None) at the end of
+/// the stream, or None if the stream is malformed.
+fn read_pair<K: Binary, V: Binary>(r: &mut Reader) -> Option<Option<(K, V)>> {
+    match r.read_u8() {
+        0 => Some(None),
+        1 => {
+            let key = Binary::read_binary(r);
+            let value = Binary::read_binary(r);
+            Some(Some((key, value)))
+        }
+        _ => None,
+    }
+}
+
+/// Build a tree from an unsorted pair stream in the file at `path` by an
+/// external sort with runs of `run_pairs` pairs, see
+/// `BTree::build_from_unsorted_file`.
+fn build_external<K: Ord + Binary, V: Binary>(path: &Path, run_pairs: uint)
+                                             -> Option<~BTree<K, V>> {
+    let mut input = match File::open(path) {
+        Some(file) => BufferedReader::new(file),
+        None => return None,
+    };
+
+    let mut runs = ~[];
+    let mut done = false;
+
+    while !done {
+        // A tree sorts the run, and keeps the last of pairs with equal keys.
+        let mut run = BTree::new();
+
+        while run.len() < run_pairs {
+            match read_pair(&mut input as &mut Reader) {
+                Some(Some((key, value))) => { run.insert(key, value); }
+                Some(None) => { done = true; break; }
+                None => { remove_runs(runs); return None; }
+            }
+        }
+
+        if run.is_empty() {
+            break;
+        }
+
+        let mut name = path.filename().unwrap().to_owned();
+        name.push_all(format!("-run{}", runs.len()).as_bytes());
+        let run_path = path.with_filename(name);
+
+        let mut w = match File::create(&run_path) {
+            Some(file) => BufferedWriter::new(file),
+            None => { remove_runs(runs); return None; }
+        };
+
+        run.write_pairs(&mut w as &mut Writer);
+        w.flush();
+        runs.push(run_path);
+    }
+
+    let tree = merge_runs(runs.as_slice());
+    remove_runs(runs);
+    tree
+}
+
+/// Merge sorted pair streams in the files at `paths` into a tree. A min-heap
+/// holds the next key of every run, and its value is kept aside in `values`.
+/// Equal keys are popped in run order, so the pair of the last run wins.
+fn merge_runs<K: Ord + Binary, V: Binary>(paths: &[Path])
+                                         -> Option<~BTree<K, V>> {
+    let mut readers = ~[];
+
+    for path in paths.iter() {
+        match File::open(path) {
+            Some(file) => readers.push(BufferedReader::new(file)),
+            None => return None,
+        }
+    }
+
+    let order = NaturalOrder;
+    let mut heap = PriorityQueue::new();
+    let mut values = ~[];
+
+    for (i, r) in readers.mut_iter().enumerate() {
+        match read_pair(r as &mut Reader) {
+            Some(Some((key, value))) => {
+                heap.push(MergeHead { key: key, run: i, order: &order });
+                values.push(Some(value));
+            }
+            Some(None) => values.push(None),
+            None => return None,
+        }
+    }
+
+    let mut builder = BTreeBuilder::new();
+
+    while !heap.is_empty() {
+        let head = heap.pop();
+        let value = util::replace(&mut values[head.run], None).unwrap();
+
+        match read_pair(&mut readers[head.run] as &mut Reader) {
+            Some(Some((key, next))) => {
+                heap.push(MergeHead { key: key, run: head.run,
+                                      order: &order });
+                values[head.run] = Some(next);
+            }
+            Some(None) => {}
+            None => return None,
+        }
+
+        builder.push(head.key, value);
+    }
+
+    Some(builder.build())
+}
+
+/// Remove the run files of an external sort.
+fn remove_runs(runs: ~[Path]) {
+    for path in runs.iter() {
+        fs::unlink(path);
+    }
+}
+
+/// Build a tree from key-value pairs in strictly ascending key order. The
+/// pairs are distributed evenly over the bottom nodes from left to right, and
+/// the inner levels are built on top of them in the same way. Every node is
+/// filled to between `t` and `2t - 1` items, so no splits are needed.
+fn build_sorted<K: Ord, V>(pairs: ~[(K, V)]) -> ~BTree<K, V> {
+    // The items of the level being built, where `keys[i]` is the key that
+    // follows `items[i]`. On the bottom level every leaf is followed by its
+    // own key. On the inner levels the last node has no key following it.
+    let mut items = ~[];
+    let mut keys = ~[];
+
+    for (key, value) in pairs.move_iter() {
+        keys.push(key);
+        items.push(TreeLeaf { value: value });
+    }
+
+    loop {
+        let m = items.len();
+        let groups = (m + BTREE_KEYS_UBOUND - 1) / BTREE_KEYS_UBOUND;
+        let groups = if groups == 0 { 1 } else { groups };
+
+        let mut nodes = ~[];
+        let mut seps = ~[];
+        let mut item_iter = items.move_iter();
+        let mut key_iter = keys.move_iter();
+
+        for g in range(0, groups) {
+            let size = m / groups + if g < m % groups { 1 } else { 0 };
+            let mut node = BTree::new();
+
+            for j in range(0, size) {
+                node.nodes[j] = item_iter.next();
+
+                // The key following the last item of a node moves up to
+                // separate the node from the next one.
+                match key_iter.next() {
+                    Some(key) => {
+                        if j + 1 < size || g + 1 == groups {
+                            node.keys[j] = Some(key);
+                            node.used += 1;
+                        } else {
+                            seps.push(key);
+                        }
+                    }
+                    None => {}
+                }
+            }
+
+            recount(&mut *node);
+            nodes.push(node);
+        }
+
+        if groups == 1 {
+            return nodes.pop();
+        }
+
+        items = nodes.move_iter().map(|n| {
+            TreeNode { value: NodeRef::new(n) }
+        }).collect();
+        keys = seps;
+    }
+}
+
+/// Join two non-empty trees into `left`, where all keys of `left` are smaller
+/// than `key` and all keys of `right` are greater. The lower tree is hung as
+/// a whole below the spine of the taller tree, with `key` as the separator,
+/// and only the nodes on that spine are split or rebalanced.
+fn join<K: Ord, V>(left: &mut BTree<K, V>, key: K, value: V,
+                   right: ~BTree<K, V>) {
+    let mut right = right;
+    let duplicates = left.duplicates;
+    let order = left.order.clone();
+    let aggregate = left.aggregate.clone();
+    let generation = left.generation + 1;
+    let mut spare = util::replace(&mut left.spare, ~[]);
+
+    if left.used == left.capacity() {
+        split_root(left, &mut spare);
+    }
+
+    if right.used == right.capacity() {
+        split_root(&mut *right, &mut spare);
+    }
+
+    let left_height = left.height();
+    let right_height = right.height();
+
+    if left_height > right_height {
+        join_right(left, key, value, right, left_height - right_height,
+                   &mut spare);
+    } else if left_height < right_height {
+        let lower = ~util::replace(left, *right);
+        join_left(left, key, value, lower, right_height - left_height,
+                  &mut spare);
+    } else {
+        let mut lower = ~util::replace(left, *BTree::new());
+        set_last_value(&mut *lower, value);
+
+        left.keys[0] = Some(key);
+        left.nodes[0] = Some(TreeNode { value: NodeRef::new(lower) });
+        left.nodes[1] = Some(TreeNode { value: NodeRef::new(right) });
+        left.used = 1;
+        recount(left);
+
+        fill_child(left, 0, BTREE_KEYS_LBOUND);
+
+        if left.used > 0 {
+            fill_child(left, 1, BTREE_KEYS_LBOUND);
+        }
+
+        collapse_root(left);
+    }
+
+    left.spare.push_all_move(spare);
+    left.duplicates = duplicates;
+    left.order = order;
+    left.aggregate = aggregate;
+    left.generation = generation;
+}
+
+/// Hang `right` as the last child of the node at `depth` levels down the last
+/// path of a non-full subtree, splitting full nodes on the way down.
+fn join_right<K: Ord, V>(tree: &mut BTree<K, V>, key: K, value: V,
+                         right: ~BTree<K, V>, depth: uint,
+                         spare: &mut ~[~BTree<K, V>]) {
+    let mut pos = tree.used;
+
+    tree.length += right.length + 1;
+
+    if depth == 1 {
+        match tree.nodes[pos] {
+            Some(TreeNode { value: ref mut child }) => {
+                set_last_value(child.get_mut(), value);
+            }
+            Some(TreeLeaf { value: _ }) |
+            None => fail!("unreachable path: leaf has same depth as a node"),
+        }
+
+        tree.keys[pos] = Some(key);
+        tree.nodes[pos + 1] = Some(TreeNode { value: NodeRef::new(right) });
+        tree.used += 1;
+
+        fill_child(tree, pos + 1, BTREE_KEYS_LBOUND);
+        return;
+    }
+
+    if child_used(tree, pos) == BTREE_KEYS_UBOUND {
+        split_child(tree, pos, spare);
+        pos = tree.used;
+    }
+
+    match tree.nodes[pos] {
+        Some(TreeNode { value: ref mut child }) => {
+            join_right(child.get_mut(), key, value, right, depth - 1, spare);
+        }
+        Some(TreeLeaf { value: _ }) |
+        None => fail!("unreachable path: leaf has same depth as a node"),
+    }
+}
+
+/// Hang `left` as the first child of the node at `depth` levels down the first
+/// path of a non-full subtree, splitting full nodes on the way down.
+fn join_left<K: Ord, V>(tree: &mut BTree<K, V>, key: K, value: V,
+                        left: ~BTree<K, V>, depth: uint,
+                        spare: &mut ~[~BTree<K, V>]) {
+    if depth == 1 {
+        let mut left = left;
+        set_last_value(&mut *left, value);
+
+        insert_slot(tree, 0, key, 0, TreeNode { value: NodeRef::new(left) });
+        fill_child(tree, 0, BTREE_KEYS_LBOUND);
+        return;
+    }
+
+    tree.length += left.length + 1;
+
+    if child_used(tree, 0) == BTREE_KEYS_UBOUND {
+        split_child(tree, 0, spare);
+    }
+
+    match tree.nodes[0] {
+        Some(TreeNode { value: ref mut child }) => {
+            join_left(child.get_mut(), key, value, left, depth - 1, spare);
+        }
+        Some(TreeLeaf { value: _ }) |
+        None => fail!("unreachable path: leaf has same depth as a node"),
+    }
+}
+
+/// Store `value` in the empty last leaf slot of a subtree on the last path of
+/// its tree, which turns the subtree into the left child of a new separator.
+fn set_last_value<K, V>(tree: &mut BTree<K, V>, value: V) {
+    let used = tree.used;
+    tree.length += 1;
+
+    match tree.nodes[used] {
+        Some(TreeNode { value: ref mut child }) => {
+            return set_last_value(child.get_mut(), value);
+        }
+        Some(TreeLeaf { value: _ }) => {
+            fail!("unreachable path: last leaf slot is in use");
+        }
+        None => {}
+    }
+
+    tree.nodes[used] = Some(TreeLeaf { value: value });
+}
+
+/// Split the tree after its first `index` pairs, see `BTree::split_off`. The
+/// tree keeps the first pairs, and the other ones are returned as a new tree
+/// with the same duplicate policy, order and monoid.
+fn split_at<K: Ord, V>(tree: &mut BTree<K, V>, index: uint) -> ~BTree<K, V> {
+    let duplicates = tree.duplicates;
+    let order = tree.order.clone();
+    let aggregate = tree.aggregate.clone();
+    let generation = tree.generation + 1;
+    let spare = util::replace(&mut tree.spare, ~[]);
+    let root = ~util::replace(tree, *BTree::new());
+
+    let mut orphan = None;
+    let (left, right) = split_node(root, index, &mut orphan);
+
+    let mut left = left.unwrap_or(BTree::new());
+    let mut right = right.unwrap_or(BTree::new());
+
+    fix_edge(&mut *left, true, true);
+    fix_edge(&mut *right, false, true);
+
+    right.duplicates = duplicates;
+    right.order = order.clone();
+    right.aggregate = aggregate.clone();
+
+    *tree = *left;
+    tree.spare = spare;
+    tree.duplicates = duplicates;
+    tree.order = order;
+    tree.aggregate = aggregate;
+    tree.generation = generation;
+
+    match orphan {
+        Some((k, v)) => {
+            tree.insert(k, v);
+        }
+        None => {}
+    }
+
+    right
+}
+
+/// Split a subtree into a subtree with its first `index` pairs and one with
+/// the other pairs; either may be empty. The position of the cut in every
+/// node is found from the subtree counts, so the cut of a tree at the rank of
+/// a key separates the keys smaller than it from the others. The nodes on the
+/// path of the cut are cut in two and may be left with too few keys. If the
+/// last key of a left part loses its right child, the key is removed together
+/// with its value and stored in `orphan`.
+fn split_node<K: Ord, V>(tree: ~BTree<K, V>, index: uint,
+                         orphan: &mut Option<(K, V)>)
+    -> (Option<~BTree<K, V>>, Option<~BTree<K, V>>) {
+    let mut tree = tree;
+    let mut right = BTree::new();
+    let mut index = index;
+    let mut pos = 0;
+
+    while pos < tree.used {
+        let length = item_length(tree.nodes[pos].get_ref());
+
+        if index < length {
+            break;
+        }
+
+        index -= length;
+        pos += 1;
+    }
+
+    if tree.used == 0 || is_leaf(&mut *tree) {
+        let mut i = pos;
+
+        while i <= tree.used {
+            if i < tree.used {
+                right.keys[i - pos] = tree.keys[i].take();
+            }
+
+            right.nodes[i - pos] = tree.nodes[i].take();
+            i += 1;
+        }
+
+        right.used = tree.used - pos;
+        tree.used = pos;
+
+        recount(&mut *tree);
+        recount(&mut *right);
+
+        return (non_empty(tree), non_empty(right));
+    }
+
+    let child = take_child(&mut *tree, pos);
+    let (child_left, child_right) = split_node(child, index, orphan);
+
+    let mut i = pos;
+
+    while i <= tree.used {
+        if i < tree.used {
+            right.keys[i - pos] = tree.keys[i].take();
+        }
+
+        if i > pos {
+            right.nodes[i - pos] = tree.nodes[i].take();
+        }
+
+        i += 1;
+    }
+
+    right.used = tree.used - pos;
+    tree.used = pos;
+
+    // The right part of the child is only empty if the child was the last
+    // child, in which case the right part of this node is empty as well.
+    match child_right {
+        Some(c) => right.nodes[0] = Some(TreeNode { value: NodeRef::new(c) }),
+        None => {}
+    }
+
+    match child_left {
+        Some(c) => tree.nodes[pos] = Some(TreeNode { value: NodeRef::new(c) }),
+        None if pos > 0 => {
+            let k = tree.keys[pos - 1].take().unwrap();
+
+            let v = match tree.nodes[pos - 1] {
+                Some(TreeNode { value: ref mut c }) => {
+                    take_last_value(c.get_mut())
+                }
+                Some(TreeLeaf { value: _ }) |
+                None => fail!("unreachable path: leaf has same depth as a node"),
+            };
+
+            tree.used -= 1;
+            *orphan = Some((k, v));
+        }
+        None => {}
+    }
+
+    recount(&mut *tree);
+    recount(&mut *right);
+
+    (non_empty(tree), non_empty(right))
+}
+
+fn non_empty<K, V>(tree: ~BTree<K, V>) -> Option<~BTree<K, V>> {
+    if tree.nodes[0].is_none() { None } else { Some(tree) }
+}
+
+/// Remove and return the value in the last leaf slot of the subtree.
+fn take_last_value<K, V>(tree: &mut BTree<K, V>) -> V {
+    let used = tree.used;
+    tree.length -= 1;
+
+    match tree.nodes[used] {
+        Some(TreeNode { value: ref mut child }) => {
+            return take_last_value(child.get_mut());
+        }
+        Some(TreeLeaf { value: _ }) | None => {}
+    }
+
+    match tree.nodes[used].take() {
+        Some(TreeLeaf { value: value }) => value,
+        Some(TreeNode { value: _ }) |
+        None => fail!("unreachable path: separator key without a value"),
+    }
+}
+
+/// Restore the minimum number of keys along the last path of a tree if
+/// `last`, or along the first path otherwise, after the tree was cut along
+/// that path. Roots without keys are collapsed, and every node on the path is
+/// filled to at least `t` keys before descending, so merges further down
+/// leave it with at least `t - 1` keys.
+fn fix_edge<K, V>(tree: &mut BTree<K, V>, last: bool, root: bool) {
+    loop {
+        if root {
+            while tree.used == 0 && tree.nodes[0].is_some() && !is_leaf(tree) {
+                collapse_root(tree);
+            }
+        }
+
+        if tree.used == 0 || is_leaf(tree) {
+            return;
+        }
+
+        let pos = if last { tree.used } else { 0 };
+        fill_child(tree, pos, BTREE_MIN_DEGREE);
+
+        if tree.used > 0 {
+            break;
+        }
+    }
+
+    let pos = if last { tree.used } else { 0 };
+
+    match tree.nodes[pos] {
+        Some(TreeNode { value: ref mut child }) => {
+            fix_edge(child.get_mut(), last, false)
+        }
+        Some(TreeLeaf { value: _ }) |
+        None => fail!("unreachable path: leaf has same depth as a node"),
+    }
+}
+
+/// Return the number of nodes and the number of leaves of the subtree.
+fn count_items<K, V>(tree: &BTree<K, V>) -> (uint, uint) {
+    let mut nodes = 1;
+    let mut leaves = 0;
+
+    for item in tree.nodes.iter() {
+        match *item {
+            Some(TreeNode { value: ref child }) => {
+                let (n, l) = count_items(child.get());
+                nodes += n;
+                leaves += l;
+            }
+            Some(TreeLeaf { value: _ }) => leaves += 1,
+            None => {}
+        }
+    }
+
+    (nodes, leaves)
+}
+
+/// Return the value in the last leaf slot of the rightmost bottom node of the
+/// subtree. This is the value of the key that separates the subtree from its
+/// right sibling.
+fn last_value<'a, K, V>(tree: &'a BTree<K, V>) -> &'a V {
+    match tree.nodes[tree.used] {
+        Some(TreeNode { value: ref child }) => last_value(child.get()),
+        Some(TreeLeaf { value: ref value }) => value,
+        None => fail!("unreachable path: separator key without a value"),
+    }
+}
+
+fn last_value_mut<'a, K, V>(tree: &'a mut BTree<K, V>) -> &'a mut V {
+    let used = tree.used;
+
+    match tree.nodes[used] {
+        Some(TreeNode { value: ref mut child }) => {
+            last_value_mut(child.get_mut())
+        }
+        Some(TreeLeaf { value: ref mut value }) => value,
+        None => fail!("unreachable path: separator key without a value"),
+    }
+}
+
+/// Insert a key at `key_pos` and an item at `node_pos` into a non-full node,
+/// shifting the keys and items after them one slot to the right.
+fn insert_slot<K, V>(tree: &mut BTree<K, V>, key_pos: uint, key: K,
+                     node_pos: uint, item: TreeItem<K, V>) {
+    let mut i = tree.used;
+
+    while i > key_pos {
+        tree.keys.swap(i - 1, i);
+        i -= 1;
+    }
+
+    i = tree.used + 1;
+
+    while i > node_pos {
+        tree.nodes.swap(i - 1, i);
+        i -= 1;
+    }
+
+    tree.length += item_length(&item);
+    tree.keys[key_pos] = Some(key);
+    tree.nodes[node_pos] = Some(item);
+    tree.used += 1;
+}
+
+/// Remove the key at `key_pos` and the item at `node_pos` from a node,
+/// shifting the keys and items after them one slot to the left.
+fn remove_slot<K, V>(tree: &mut BTree<K, V>, key_pos: uint, node_pos: uint)
+    -> (K, TreeItem<K, V>) {
+    let key = tree.keys[key_pos].take().unwrap();
+    let item = tree.nodes[node_pos].take().unwrap();
+    tree.length -= item_length(&item);
+
+    let mut i = key_pos;
+
+    while i + 1 < tree.used {
+        tree.keys.swap(i, i + 1);
+        i += 1;
+    }
+
+    i = node_pos;
+
+    while i < tree.used {
+        tree.nodes.swap(i, i + 1);
+        i += 1;
+    }
+
+    tree.used -= 1;
+
+    (key, item)
+}
+
+impl<K, V> NodeRef<K, V> {
+    fn new(node: ~BTree<K, V>) -> NodeRef<K, V> {
+        let shared = ~SharedNode { count: AtomicUint::new(1),
+                                   copy: AtomicUint::new(0),
+                                   hashed: AtomicBool::new(false),
+                                   hash_low: AtomicUint::new(0),
+                                   hash_high: AtomicUint::new(0),
+                                   summary: AtomicUint::new(0), node: node };
+
+        NodeRef { ptr: unsafe { cast::transmute(shared) } }
+    }
+
+    fn get<'a>(&'a self) -> &'a BTree<K, V> {
+        unsafe { &*(*self.ptr).node }
+    }
+
+    /// Return the node for a change, after replacing it by a copy of its own
+    /// if it is shared.
+    fn get_mut<'a>(&'a mut self) -> &'a mut BTree<K, V> {
+        if self.is_shared() {
+            *self = NodeRef::new(self.copy());
+        }
+
+        self.drop_summary();
+
+        unsafe {
+            (*self.ptr).hashed.store(false, SeqCst);
+            &mut *(*self.ptr).node
+        }
+    }
+
+    /// Return the summary of the subtree of the node if it is kept, see
+    /// `keep_summaries`.
+    fn summary<'a>(&'a self) -> Option<&'a Summary> {
+        unsafe {
+            match (*self.ptr).summary.load(SeqCst) {
+                0 => None,
+                kept => Some(&*(kept as *Summary)),
+            }
+        }
+    }
+
+    /// Keep the summary that `compute` returns for the node, unless one is
+    /// kept already. Trees that share the node may compute its summary at
+    /// the same time, and the first one to finish keeps it.
+    fn keep_summary(&self, compute: |&BTree<K, V>| -> Option<Summary>) {
+        if self.summary().is_some() {
+            return;
+        }
+
+        match compute(self.get()) {
+            Some(summary) => unsafe {
+                let summary: uint = cast::transmute(~summary);
+
+                if (*self.ptr).summary.compare_and_swap(0, summary,
+                                                        SeqCst) != 0 {
+                    let _: ~Summary = cast::transmute(summary);
+                }
+            },
+            None => {}
+        }
+    }
+
+    /// Drop the summary that is kept for the node. Only called while this is
+    /// the only pointer to the node, so no other tree reads the summary.
+    fn drop_summary(&mut self) {
+        unsafe {
+            let kept = (*self.ptr).summary.swap(0, SeqCst);
+
+            if kept != 0 {
+                let _: ~Summary = cast::transmute(kept);
+            }
+        }
+    }
+
+    /// Return the digest of the subtree of the node, which `compute` returns
+    /// unless it was kept since the node last changed.
+    fn digest(&self, compute: |&BTree<K, V>| -> u64) -> u64 {
+        unsafe {
+            if (*self.ptr).hashed.load(SeqCst) {
+                let low = (*self.ptr).hash_low.load(SeqCst) as u64;
+                let high = (*self.ptr).hash_high.load(SeqCst) as u64;
+                return (high << 32) | low;
+            }
+
+            let digest = compute(self.get());
+            let low = (digest & 0xffffffff) as uint;
+            (*self.ptr).hash_low.store(low, SeqCst);
+            (*self.ptr).hash_high.store((digest >> 32) as uint, SeqCst);
+            (*self.ptr).hashed.store(true, SeqCst);
+            digest
+        }
+    }
+
+    /// Take the node out of the pointer, or a copy of it if it is shared.
+    fn unwrap(mut self) -> ~BTree<K, V> {
+        if self.is_shared() {
+            return self.copy();
+        }
+
+        self.drop_summary();
+
+        unsafe {
+            let shared: ~SharedNode<K, V> = cast::transmute(self.ptr);
+            cast::forget(self);
+
+            let shared = *shared;
+            shared.node
+        }
+    }
+
+    /// Return another pointer to the node, which is copied with `copy` once
+    /// either pointer changes it. The first share of the node sets `copy`,
+    /// and later ones, which pass the same function, leave it as it is.
+    fn share(&self, copy: fn(&BTree<K, V>) -> ~BTree<K, V>) -> NodeRef<K, V> {
+        unsafe {
+            let copy: uint = cast::transmute(copy);
+            (*self.ptr).copy.compare_and_swap(0, copy, SeqCst);
+            (*self.ptr).count.fetch_add(1, SeqCst);
+        }
+
+        NodeRef { ptr: self.ptr }
+    }
+
+    fn is_shared(&self) -> bool {
+        unsafe { (*self.ptr).count.load(SeqCst) > 1 }
+    }
+
+    fn copy(&self) -> ~BTree<K, V> {
+        let copy = unsafe { (*self.ptr).copy.load(SeqCst) };
+        assert!(copy != 0);
+
+        let copy: fn(&BTree<K, V>) -> ~BTree<K, V> = unsafe {
+            cast::transmute(copy)
+        };
+        copy(self.get())
+    }
+}
+
+#[unsafe_destructor]
+impl<K, V> Drop for NodeRef<K, V> {
+    fn drop(&mut self) {
+        unsafe {
+            if (*self.ptr).count.fetch_sub(1, SeqCst) == 1 {
+                self.drop_summary();
+                let _: ~SharedNode<K, V> = cast::transmute(self.ptr);
+            }
+        }
+    }
+}
+
+/// Copy a node for a snapshot, cloning its keys and the values in its leaf
+/// slots and sharing its children.
+fn copy_node<K: Ord + Clone, V: Clone>(tree: &BTree<K, V>) -> ~BTree<K, V> {
+    let mut copy = BTree::new();
+
+    for i in range(0, tree.used + 1) {
+        if i < tree.used {
+            copy.keys[i] = tree.keys[i].clone();
+        }
+
+        copy.nodes[i] = match tree.nodes[i] {
+            Some(TreeNode { value: ref child }) => {
+                Some(TreeNode { value: child.share(copy_node) })
+            }
+            Some(TreeLeaf { value: ref value }) => {
+                Some(TreeLeaf { value: value.clone() })
+            }
+            None => None,
+        };
+    }
+
+    copy.used = tree.used;
+    copy.length = tree.length;
+    copy
+}
+
+fn take_child<K, V>(tree: &mut BTree<K, V>, pos: uint) -> ~BTree<K, V> {
+    match tree.nodes[pos].take() {
+        Some(TreeNode { value: child }) => child.unwrap(),
+        Some(TreeLeaf { value: _ }) |
+        None => fail!("unreachable path: tree.nodes[pos] should be a TreeNode"),
+    }
+}
+
+/// Return the number of values in an item, which is one for a leaf.
+fn item_length<K, V>(item: &TreeItem<K, V>) -> uint {
+    match *item {
+        TreeNode { value: ref child } => child.get().length,
+        TreeLeaf { value: _ } => 1,
+    }
+}
+
+/// Recompute the number of values in the subtree of a node from its items.
+fn recount<K, V>(tree: &mut BTree<K, V>) {
+    let mut length = 0;
+
+    for item in tree.nodes.iter() {
+        match *item {
+            Some(ref item) => length += item_length(item),
+            None => {}
+        }
+    }
+
+    tree.length = length;
+}
+
+fn child_used<K, V>(tree: &BTree<K, V>, pos: uint) -> uint {
+    match tree.nodes[pos] {
+        Some(TreeNode { value: ref child }) => child.get().used,
+        Some(TreeLeaf { value: _ }) |
+        None => fail!("unreachable path: tree.nodes[pos] should be a TreeNode"),
+    }
+}
+
+fn child_length<K, V>(tree: &BTree<K, V>, pos: uint) -> uint {
+    match tree.nodes[pos] {
+        Some(TreeNode { value: ref child }) => child.get().length,
+        Some(TreeLeaf { value: _ }) |
+        None => fail!("unreachable path: tree.nodes[pos] should be a TreeNode"),
+    }
+}
+
+fn pop<K: Ord, V>(tree: &mut BTree<K, V>, key: &K) -> Option<V> {
+    let order = tree.order.clone();
+    let value = remove_from(tree, &order, key);
+
+    if value.is_some() {
+        collapse_root(tree);
+        tree.generation += 1;
+        refresh_summaries(&*tree);
+    }
+
+    value
+}
+
+/// Remove `key` from the subtree and return its value. Children that drop
+/// below the minimum number of keys are rebalanced on the way back up; the
+/// node itself is rebalanced by its parent.
+fn remove_from<K: Ord, V>(tree: &mut BTree<K, V>, order: &Order<K>,
+                          key: &K) -> Option<V> {
+    let pos = find_node_pos(tree, order, key);
+
+    if pos < tree.used && order.equal(tree.keys[pos].get_ref(), key) {
+        let (_, value) = remove_key_at(tree, pos);
+        return Some(value);
+    }
+
+    if tree.used == 0 || is_leaf(tree) {
+        return None;
+    }
+
+    let value = match tree.nodes[pos] {
+        Some(TreeNode { value: ref mut child }) => {
+            remove_from(child.get_mut(), order, key)
+        }
+        Some(TreeLeaf { value: _ }) |
+        None => fail!("unreachable path: leaf has same depth as a node"),
+    };
+
+    if value.is_some() {
+        tree.length -= 1;
+        fix_child(tree, pos);
+    }
+
+    value
+}
+
+/// Remove the key at `pos` from the node that stores it and return it with
+/// its value. Children that drop below the minimum number of keys are
+/// rebalanced; the node itself is rebalanced by its parent.
+fn remove_key_at<K, V>(tree: &mut BTree<K, V>, pos: uint) -> (K, V) {
+    if is_leaf(tree) {
+        return match remove_slot(tree, pos, pos) {
+            (key, TreeLeaf { value: value }) => (key, value),
+            (_, TreeNode { value: _ }) => {
+                fail!("unreachable path: node has same depth as a leaf")
+            }
+        };
+    }
+
+    let entry = match tree.nodes[pos] {
+        Some(TreeNode { value: ref mut child }) => {
+            // Replace the key by its predecessor, whose value takes over the
+            // leaf slot of the removed value.
+            let (prev, value) = pop_last(child.get_mut());
+            let key = util::replace(&mut tree.keys[pos], Some(prev));
+            (key.unwrap(), value)
+        }
+        Some(TreeLeaf { value: _ }) |
+        None => fail!("unreachable path: leaf has same depth as a node"),
+    };
+
+    tree.length -= 1;
+    fix_child(tree, pos);
+
+    entry
+}
+
+/// Remove the smallest key of the subtree and return it with its value.
+fn remove_first<K, V>(tree: &mut BTree<K, V>) -> (K, V) {
+    if is_leaf(tree) {
+        return match remove_slot(tree, 0, 0) {
+            (key, TreeLeaf { value: value }) => (key, value),
+            (_, TreeNode { value: _ }) => {
+                fail!("unreachable path: node has same depth as a leaf")
+            }
+        };
+    }
+
+    let entry = match tree.nodes[0] {
+        Some(TreeNode { value: ref mut child }) => {
+            remove_first(child.get_mut())
+        }
+        Some(TreeLeaf { value: _ }) |
+        None => fail!("unreachable path: leaf has same depth as a node"),
+    };
+
+    tree.length -= 1;
+    fix_child(tree, 0);
+
+    entry
+}
+
+/// Remove the largest key of a subtree on the rightmost path of the tree and
+/// return it with its value. Such a subtree has no separator key to its
+/// right, so its largest key is stored in its rightmost bottom node.
+fn remove_last<K, V>(tree: &mut BTree<K, V>) -> (K, V) {
+    let used = tree.used;
+
+    if is_leaf(tree) {
+        return match remove_slot(tree, used - 1, used - 1) {
+            (key, TreeLeaf { value: value }) => (key, value),
+            (_, TreeNode { value: _ }) => {
+                fail!("unreachable path: node has same depth as a leaf")
+            }
+        };
+    }
+
+    let entry = match tree.nodes[used] {
+        Some(TreeNode { value: ref mut child }) => remove_last(child.get_mut()),
+        Some(TreeLeaf { value: _ }) |
+        None => fail!("unreachable path: leaf has same depth as a node"),
+    };
+
+    tree.length -= 1;
+    fix_child(tree, used);
+
+    entry
+}
+
+/// Remove the last key of the subtree together with the value in the last
+/// leaf slot, which belongs to the key separating the subtree from its right
+/// sibling. The value of the removed key moves into that slot, so the removed
+/// key can take the place of the separator.
+fn pop_last<K, V>(tree: &mut BTree<K, V>) -> (K, V) {
+    let used = tree.used;
+
+    if is_leaf(tree) {
+        let key = tree.keys[used - 1].take().unwrap();
+
+        let value = match tree.nodes[used].take() {
+            Some(TreeLeaf { value: value }) => value,
+            Some(TreeNode { value: _ }) |
+            None => fail!("unreachable path: separator key without a value"),
+        };
+
+        tree.used -= 1;
+        tree.length -= 1;
+
+        return (key, value);
+    }
+
+    let result = match tree.nodes[used] {
+        Some(TreeNode { value: ref mut child }) => pop_last(child.get_mut()),
+        Some(TreeLeaf { value: _ }) |
+        None => fail!("unreachable path: leaf has same depth as a node"),
+    };
+
+    tree.length -= 1;
+    fix_child(tree, used);
+
+    result
+}
+
+/// Restore the minimum number of keys of child `pos` after a removal. A key
+/// is borrowed through the parent from a sibling that has keys to spare, and
+/// otherwise the child is merged with a sibling.
+fn fix_child<K, V>(tree: &mut BTree<K, V>, pos: uint) {
+    fill_child(tree, pos, BTREE_KEYS_LBOUND);
+}
+
+/// Bring child `pos` up to at least `min` keys, where `min` is at most `t`.
+/// Keys are borrowed one at a time from a sibling that has keys to spare.
+/// Otherwise the child is merged with a sibling of exactly `t - 1` keys,
+/// which always fits because the child has less than `t` keys.
+fn fill_child<K, V>(tree: &mut BTree<K, V>, pos: uint, min: uint) {
+    while child_used(tree, pos) < min {
+        if pos > 0 && child_used(tree, pos - 1) > BTREE_KEYS_LBOUND {
+            rotate_right(tree, pos - 1);
+        } else if pos < tree.used
+                  && child_used(tree, pos + 1) > BTREE_KEYS_LBOUND {
+            rotate_left(tree, pos);
+        } else if pos > 0 {
+            merge_children(tree, pos - 1);
+            return;
+        } else {
+            merge_children(tree, pos);
+            return;
+        }
+    }
+}
+
+/// Move the last key of child `pos` up into the parent, and the separator
+/// down to the front of child `pos + 1`, together with the last item of
+/// child `pos`.
+fn rotate_right<K, V>(tree: &mut BTree<K, V>, pos: uint) {
+    let mut left = take_child(tree, pos);
+    let mut right = take_child(tree, pos + 1);
+
+    let used = left.used;
+    let item = left.nodes[used].take().unwrap();
+    let sep = util::replace(&mut tree.keys[pos], left.keys[used - 1].take());
+    left.used -= 1;
+    left.length -= item_length(&item);
+
+    insert_slot(&mut *right, 0, sep.unwrap(), 0, item);
+
+    tree.nodes[pos] = Some(TreeNode { value: NodeRef::new(left) });
+    tree.nodes[pos + 1] = Some(TreeNode { value: NodeRef::new(right) });
+}
+
+/// Move the first key of child `pos + 1` up into the parent, and the
+/// separator down to the end of child `pos`, together with the first item of
+/// child `pos + 1`.
+fn rotate_left<K, V>(tree: &mut BTree<K, V>, pos: uint) {
+    let mut left = take_child(tree, pos);
+    let mut right = take_child(tree, pos + 1);
+
+    let (key, item) = remove_slot(&mut *right, 0, 0);
+    let sep = util::replace(&mut tree.keys[pos], Some(key));
+
+    let used = left.used;
+    left.length += item_length(&item);
+    left.keys[used] = sep;
+    left.nodes[used + 1] = Some(item);
+    left.used += 1;
+
+    tree.nodes[pos] = Some(TreeNode { value: NodeRef::new(left) });
+    tree.nodes[pos + 1] = Some(TreeNode { value: NodeRef::new(right) });
+}
+
+/// Merge child `pos + 1` and the separator between them into child `pos`.
+fn merge_children<K, V>(tree: &mut BTree<K, V>, pos: uint) {
+    let (sep, item) = remove_slot(tree, pos, pos + 1);
+
+    let mut right = match item {
+        TreeNode { value: right } => right.unwrap(),
+        TreeLeaf { value: _ } => fail!("unreachable path: leaf next to a node"),
+    };
+
+    let mut left = take_child(tree, pos);
+    let used = left.used;
+
+    left.keys[used] = Some(sep);
+
+    let mut i = 0;
+
+    while i < right.used {
+        left.keys[used + 1 + i] = right.keys[i].take();
+        i += 1;
+    }
+
+    i = 0;
+
+    while i <= right.used {
+        left.nodes[used + 1 + i] = right.nodes[i].take();
+        i += 1;
+    }
+
+    left.used += right.used + 1;
+    left.length += right.length;
+    tree.length += right.length;
+
+    tree.nodes[pos] = Some(TreeNode { value: NodeRef::new(left) });
+}
+
+/// Replace a root without keys by its only child, which decreases the height
+/// of the tree by one.
+fn collapse_root<K, V>(tree: &mut BTree<K, V>) {
+    if tree.used > 0 {
+        return;
+    }
+
+    match tree.nodes[0] {
+        Some(TreeNode { value: _ }) => {}
+        Some(TreeLeaf { value: _ }) | None => return,
+    }
+
+    let length = tree.length;
+    let duplicates = tree.duplicates;
+    let order = util::replace(&mut tree.order, NaturalOrder);
+    let aggregate = tree.aggregate.take();
+    let generation = tree.generation;
+    let spare = util::replace(&mut tree.spare, ~[]);
+    let child = take_child(tree, 0);
+
+    *tree = *child;
+    tree.length = length;
+    tree.spare = spare;
+    tree.duplicates = duplicates;
+    tree.order = order;
+    tree.aggregate = aggregate;
+    tree.generation = generation;
+}
+
+/// Fail if the tree changed since an iterator or cursor saw it at
+/// `generation`, see `BTree::generation`. Safe code cannot change a tree that
+/// is borrowed, so the check only runs in a build with `--cfg debug`.
+#[cfg(debug)]
+fn check_generation<K, V>(tree: &BTree<K, V>, generation: uint) {
+    if tree.generation != generation {
+        fail!("the tree changed under an iterator or cursor");
+    }
+}
+
+#[cfg(not(debug))]
+#[inline(always)]
+fn check_generation<K, V>(_: &BTree<K, V>, _: uint) {}
+
+impl<K, V> Container for BTree<K, V> {
+    /// Return the number of key-value pairs in the b-tree.
+    #[inline]
+    fn len(&self) -> uint { self.length }
+
+    /// Return true if the b-tree contains no key-value pairs.
+    #[inline]
+    fn is_empty(&self) -> bool { self.length == 0 }
+}
+
+impl<K, V> Mutable for BTree<K, V> {
+    /// Clear the b-tree, removing all nodes.
+    fn clear(&mut self) {
+        for key in self.keys.mut_iter() {
+            *key = None;
+        }
+
+        for node in self.nodes.mut_iter() {
+            *node = None;
+        }
+
+        self.used = 0;
+        self.length = 0;
+        self.generation += 1;
+    }
+}
+
+impl<K: Ord, V> Map<K, V> for BTree<K, V> {
+    #[inline]
+    fn find<'a>(&'a self, key: &K) -> Option<&'a V> {
+        find(self, &self.order, key)
+    }
+
+    #[inline]
+    fn contains_key(&self, key: &K) -> bool { self.contains_key(key) }
+}
+
+impl<K: Ord, V> MutableMap<K, V> for BTree<K, V> {
+    #[inline]
+    fn insert(&mut self, key: K, value: V) -> bool { self.insert(key, value) }
+
+    #[inline]
+    fn remove(&mut self, key: &K) -> bool { pop(self, key).is_some() }
+
+    #[inline]
+    fn swap(&mut self, key: K, value: V) -> Option<V> { self.swap(key, value) }
+
+    #[inline]
+    fn pop(&mut self, key: &K) -> Option<V> { pop(self, key) }
+
+    #[inline]
+    fn find_mut<'a>(&'a mut self, key: &K) -> Option<&'a mut V> {
+        let order = self.order.clone();
+        find_mut(self, &order, key, false)
+    }
+}
+
+impl<K: Ord, V: Clone> Index<K, V> for BTree<K, V> {
+    /// Return a copy of the value corresponding to the key. Fail if the key is
+    /// not in the tree.
+    fn index(&self, key: &K) -> V {
+        match find(self, &self.order, key) {
+            Some(value) => value.clone(),
+            None => fail!("key not found in b-tree"),
+        }
+    }
+}
+
+impl<K: ToStr, V> ToStr for BTree<K, V> {
+    fn to_str(&self) -> ~str { to_str(self, 0) }
+}
+
+fn to_str<K: ToStr, V>(tree: &BTree<K, V>, indent: uint) -> ~str {
+    let buf : ~[~str] = tree.nodes.iter().enumerate().map(|(i, x)| {
+        if i < tree.used {
+            let key = match tree.keys[i] {
+                Some(ref key) => key,
+                None => fail!("unreachable path"),
+            };
+
+            "\t".repeat(indent) + match *x {
+                Some(TreeNode { value: ref tree }) => {
+                    ~"Node(key=" + key.to_str() + ")\n"
+                    + to_str::<K, V>(tree.get(), indent + 1)
+                }
+                Some(TreeLeaf { value: _ }) => {
+                    ~"Leaf(key=" + key.to_str() + ")"
+                }
+                None => ~"None",
+            }
+        } else {
+            "\t".repeat(indent) + match *x {
+                Some(TreeNode { value: ref tree }) => {
+                    ~"Node(key=None)\n" + to_str::<K, V>(tree.get(), indent + 1)
+                }
+                Some(TreeLeaf { value: _ }) => ~"Leaf(key=None)",
+                None => ~"None",
+            }
+        }
+    }).collect();
+
+    buf.connect("\n")
+}
+
+impl<E: Encoder, K: Encodable<E>, V: Encodable<E>> Encodable<E>
+    for BTree<K, V> {
+    /// Encode the tree as a map with the pairs in ascending key order.
+    fn encode(&self, e: &mut E) {
+        e.emit_map(self.len(), |e| {
+            let mut i = 0;
+
+            each(self, None, |k, v| {
+                e.emit_map_elt_key(i, |e| k.encode(e));
+                e.emit_map_elt_val(i, |e| v.encode(e));
+                i += 1;
+                true
+            });
+        })
+    }
+}
+
+impl<D: Decoder, K: Ord + Decodable<D>, V: Decodable<D>> Decodable<D>
+    for BTree<K, V> {
+    /// Decode a map into a tree. A key that occurs more than once keeps its
+    /// last value.
+    fn decode(d: &mut D) -> BTree<K, V> {
+        d.read_map(|d, len| {
+            let mut tree = BTree::new();
+
+            for i in range(0, len) {
+                let key = d.read_map_elt_key(i, |d| Decodable::decode(d));
+                let value = d.read_map_elt_val(i, |d| Decodable::decode(d));
+                tree.insert(key, value);
+            }
+
+            *tree
+        })
+    }
+}
+
+impl<K: ToJson, V: ToJson> ToJson for BTree<K, V> {
+    /// Convert the tree to a JSON list of `[key, value]` lists in ascending
+    /// key order. Unlike a JSON object, the list keeps the order of the keys
+    /// and allows keys that are not strings.
+    fn to_json(&self) -> json::Json {
+        let mut pairs = vec::with_capacity(self.len());
+
+        each(self, None, |k, v| {
+            pairs.push(json::List(~[k.to_json(), v.to_json()]));
+            true
+        });
+
+        json::List(pairs)
+    }
+}
+
+impl<K: Ord + Decodable<json::Decoder>, V: Decodable<json::Decoder>>
+    BTree<K, V> {
+    /// Build a tree from a JSON list of `[key, value]` lists, as written by
+    /// `to_json`. Pairs in ascending key order are built into the tree
+    /// bottom-up, see `insert_sorted`.
+    pub fn from_json(json: json::Json) -> ~BTree<K, V> {
+        let mut decoder = json::Decoder::new(json);
+        let pairs: ~[(K, V)] = Decodable::decode(&mut decoder);
+
+        let mut tree = BTree::new();
+        tree.insert_sorted(pairs);
+        tree
+    }
+}
+
+impl<K: Eq, V: Eq> Eq for BTree<K, V> {
+    #[inline]
+    fn eq(&self, other: &BTree<K, V>) -> bool {
+        self.used == other.used
+            && self.keys == other.keys
+            && self.nodes == other.nodes
+    }
+
+    #[inline]
+    fn ne(&self, other: &BTree<K, V>) -> bool { !(*self).eq(other) }
+}
+
+impl<K: Eq, V: Eq> Eq for TreeItem<K, V> {
+    #[inline]
+    fn eq(&self, other: &TreeItem<K, V>) -> bool {
+        match *self {
+            TreeNode { value: ref v1 } => {
+                match *other {
+                    TreeNode { value: ref v2 } => v1.get() == v2.get(),
+                    TreeLeaf { value: _ } => false,
+                }
+            }
+            TreeLeaf { value: ref v1 } => {
+                match *other {
+                    TreeLeaf { value: ref v2 } => v1 == v2,
+                    TreeNode { value: _ } => false,
+                }
+            }
+        }
+    }
+
+    #[inline]
+    fn ne(&self, other: &TreeItem<K, V>) -> bool { !(*self).eq(other) }
+}
+
+#[bench]
+fn bench_insert_random(bh: &mut BenchHarness) {
+    let iterations = 1000;
+
+    let mut rng = IsaacRng::new();
+    rng.reseed([42u32]);
+
+    let mut random_keys = ~[];
+    for k in range(0, iterations) { random_keys.push(k); }
+    rng.shuffle_mut(random_keys);
+
+    do bh.iter {
+        let mut t = BTree::new();
+
+        for &key in random_keys.iter() {
+            t.insert(key, key);
+        }
+    }
+}
+
+#[bench]
+fn bench_insert_random_comparator(bh: &mut BenchHarness) {
+    let iterations = 1000;
+
+    let mut rng = IsaacRng::new();
+    rng.reseed([42u32]);
+
+    let mut random_keys = ~[];
+    for k in range(0, iterations) { random_keys.push(k); }
+    rng.shuffle_mut(random_keys);
+
+    do bh.iter {
+        let mut t = BTree::with_comparator(Natural);
+
+        for &key in random_keys.iter() {
+            t.insert(key, key);
+        }
+    }
+}
+
+#[bench]
+fn bench_find_comparator(bh: &mut BenchHarness) {
+    let mut t = BTree::with_comparator(Natural);
+
+    for k in range(0, 1000) {
+        t.insert(k, k);
+    }
+
+    do bh.iter {
+        for k in range(0, 1000) {
+            t.find(&k);
+        }
+    }
+}
+
+#[bench]
+fn bench_find_fn_order(bh: &mut BenchHarness) {
+    fn natural(a: &int, b: &int) -> Ordering { a.cmp(b) }
+
+    let mut t = BTree::with_order(natural);
+
+    for k in range(0, 1000) {
+        t.insert(k, k);
+    }
+
+    do bh.iter {
+        for k in range(0, 1000) {
+            t.find(&k);
+        }
+    }
+}
+
+#[bench]
+fn bench_find(bh: &mut BenchHarness) {
+    let mut t = BTree::new();
+
+    for k in range(0, 1000) {
+        t.insert(k, k);
+    }
+
+    do bh.iter {
+        for k in range(0, 1000) {
+            t.find(&k);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_btree {
+
+    use super::*;
+    use extra::arc::Arc;
+    use std::rand::{Rng, IsaacRng, SeedableRng};
+    use std::iter::range;
+    use std::vec;
+    use extra::treemap::TreeMap;
+
+    fn tree<K, V>(keys: [Option<K>, ..BTREE_KEYS_UBOUND],
+                  nodes: [Option<TreeItem<K, V>>, ..BTREE_KEYS_UBOUND + 1])
+        -> ~BTree<K, V> {
+        let mut t = ~BTree { used: keys.iter().filter(|x| x.is_some()).len(),
+            length: 0, spare: ~[], duplicates: Replace,
+            order: super::NaturalOrder, aggregate: None, generation: 0,
+            keys : keys, nodes: nodes };
+
+        super::recount(&mut *t);
+
+        t
+    }
+
+    fn node<K, V>(value: ~BTree<K, V>) -> Option<TreeItem<K, V>> {
+        Some(TreeNode { value: NodeRef::new(value) })
+    }
+
+    fn leaf<K, V>(value: V) -> Option<TreeItem<K, V>> {
+        Some(TreeLeaf { value: value })
+    }
+
+    //macro_rules! check_values (
+    //    ($list:expr, $values:expr) => {{
+    fn check_values<T: Eq>(list: &[Option<T>], values: &[Option<T>]) {
+            assert!(list.len() >= values.len());
+
+            let mut i = 0;
+            let len = values.len();
+
+            while i < len {
+                assert_eq!(&list[i], &values[i]);
+                i += 1;
+            }
+
+            let len = list.len();
+
+            while i < len {
+                assert_eq!(&list[i], &None);
+                i += 1;
+            }
+    }
+    //    }}
+    //)
+
+    //macro_rules! check_used (
+    //    ($list:expr, $used:expr) => {{
+    fn check_used<T>(list: &[Option<T>], used: &[bool]) {
+            assert!(list.len() >= used.len());
+
+            let mut i = 0;
+            let len = used.len();
+
+            while i < len {
+                if list[i].is_some() != used[i] {
+                    fail!(format!("list[{}] = {:?} is not in use. used[{}] = {}",
+                                  i, list[i], i, used[i]));
+                }
+
+                i += 1;
+            }
+
+            let len = list.len();
+
+            while i < len {
+                if list[i].is_some() {
+                    fail!(format!("list[{}] = {:?} is used but should be unused",
+                                  i, list[i]));
+                }
+
+                i += 1;
+            }
+    }
+    //    }}
+    //)
+
+    fn get_node<'r, K, V>(tree: &'r BTree<K, V>, pos: uint)
+        -> &'r BTree<K, V> {
+        match tree.nodes[pos] {
+            Some(TreeNode { value: ref v }) => v.get(),
+            Some(TreeLeaf { value: _ }) |
+            None  => fail!("unreachable path"),
+        }
+    }
+
+    /// An operation of a differential test run.
+    enum Op {
+        Insert(int, int),
+        Remove(int),
+        Find(int),
+    }
+
+    /// Generate `n` random operations on keys in `0..keys`.
+    fn random_ops(n: uint, keys: int) -> ~[Op] {
+        let mut rng = IsaacRng::new();
+        rng.reseed([42u32]);
+
+        range(0, n).map(|_| {
+            let key = rng.gen_range(0, keys);
+
+            match rng.gen_range(0, 3) {
+                0 => Insert(key, rng.gen::<int>()),
+                1 => Remove(key),
+                _ => Find(key),
+            }
+        }).collect()
+    }
+
+    /// Apply the operations to both a b-tree and a `TreeMap`, and fail with
+    /// the first operation whose observable result differs.
+    fn check_against_reference(ops: &[Op]) {
+        let mut t = BTree::new();
+        let mut reference = TreeMap::new();
+
+        for (i, op) in ops.iter().enumerate() {
+            match *op {
+                Insert(key, value) => {
+                    let expected = reference.insert(key, value);
+                    let actual = t.insert(key, value);
+
+                    if actual != expected {
+                        fail!(format!("op {}: insert({}, {}) returned {}, \
+                                       expected {}",
+                                      i, key, value, actual, expected));
+                    }
+                }
+                Remove(key) => {
+                    let expected = reference.remove(&key);
+                    let actual = t.remove(&key);
+
+                    if actual != expected {
+                        fail!(format!("op {}: remove({}) returned {}, \
+                                       expected {}",
+                                      i, key, actual, expected));
+                    }
+                }
+                Find(key) => {
+                    let expected = reference.find(&key);
+                    let actual = t.find(&key);
+
+                    if actual != expected {
+                        fail!(format!("op {}: find({}) returned {:?}, \
+                                       expected {:?}",
+                                      i, key, actual, expected));
+                    }
+                }
+            }
+        }
+
+        assert_eq!(t.len(), reference.len());
+        check_invariants(&*t, true);
+    }
+
+    /// Check that the number of keys of each node is within bounds, that the
+    /// keys are sorted and that all bottom nodes have the same depth. Return
+    /// the height of the subtree.
+    fn check_invariants<K: Ord, V>(t: &BTree<K, V>, root: bool) -> uint {
+        check_node(t, &t.order, t.duplicates != Keep, root)
+    }
+
+    /// Check the invariants of a subtree whose keys are ordered by `order`,
+    /// see `check_invariants`. Equal keys are allowed unless `strict`.
+    fn check_node<K: Ord, V>(t: &BTree<K, V>, order: &super::Order<K>,
+                             strict: bool, root: bool) -> uint {
+        assert!(root || t.used >= BTREE_KEYS_LBOUND);
+        assert!(t.used <= BTREE_KEYS_UBOUND);
+
+        let mut i = 1;
+
+        while i < t.used {
+            let (a, b) = (t.keys[i - 1].get_ref(), t.keys[i].get_ref());
+            assert!(if strict { order.less(a, b) } else { !order.less(b, a) });
+            i += 1;
+        }
+
+        check_used(t.keys, vec::from_elem(t.used, true));
+
+        let (_, leaves) = super::count_items(t);
+        assert_eq!(t.length, leaves);
+
+        match t.nodes[0] {
+            Some(TreeNode { value: _ }) => {
+                let height = check_node(get_node(t, 0), order, strict, false);
+
+                i = 1;
+
+                while i <= t.used {
+                    assert_eq!(check_node(get_node(t, i), order, strict, false),
+                               height);
+                    i += 1;
+                }
+
+                height + 1
+            }
+            Some(TreeLeaf { value: _ }) | None => 1,
+        }
+    }
+
+    /// Return a tree that maps every key in `lo..hi` to itself.
+    fn range_tree(lo: int, hi: int) -> ~BTree<int, int> {
+        let mut t = BTree::new();
+
+        for k in range(lo, hi) {
+            t.insert(k, k);
+        }
+
+        t
+    }
+
+    #[test]
+    fn test_basic_insert() {
+        let foo = "foo";
+        let bar = "bar";
+        let baz = "baz";
+
+        let mut t = BTree::new();
+        assert!(t.is_empty());
+        assert_eq!(t.used, 0);
+
+        assert!(t.insert(42, bar));
+        assert!(!t.is_empty());
+        assert_eq!(t.used, 1);
+
+        assert!(t.insert(3, baz));
+        assert!(!t.is_empty());
+        assert_eq!(t.used, 2);
+
+        assert!(t.insert(1, foo));
+        assert!(!t.is_empty());
+        assert_eq!(t.used, 3);
+
+        assert_eq!(t.find(&1).unwrap(), &foo);
+        assert_eq!(t.find(&3).unwrap(), &baz);
+        assert_eq!(t.find(&42).unwrap(), &bar);
+    }
+
+    #[test]
+    fn test_basic_len() {
+        let foo = "foo";
+        let bar = "bar";
+
+        let mut t = BTree::new();
+        assert_eq!(t.len(), 0);
+
+        assert!(t.insert(1, foo));
+        assert_eq!(t.len(), 1);
+
+        assert!(t.insert(42, bar));
+        assert_eq!(t.len(), 2);
+
+        t.clear();
+        assert!(t.is_empty());
+        assert_eq!(t.len(), 0);
+    }
+
+    #[test]
+    fn test_clear() {
+        let foo = "foo";
+        let bar = "bar";
+
+        let mut t = BTree::new();
+        assert!(t.is_empty());
+
+        assert!(t.insert(1, foo));
+        assert!(!t.is_empty());
+
+        assert!(t.insert(42, bar));
+        assert!(!t.is_empty());
+
+        t.clear();
+        assert!(t.is_empty());
+
+        assert_eq!(t.find(&1), None);
+        assert_eq!(t.find(&42), None);
+    }
+
+    #[test]
+    fn test_insert_new_key() {
+        let foo = "foo";
+        let bar = "bar";
+
+        let mut t = BTree::new();
+        assert!(t.is_empty());
+        assert_eq!(t.used, 0);
+
+        assert!(t.insert(42, foo));
+        assert!(!t.is_empty());
+        assert_eq!(t.used, 1);
+        assert_eq!(t.find(&42).unwrap(), &foo);
+
+        assert!(!t.insert(42, bar));
+        assert!(!t.is_empty());
+        assert_eq!(t.used, 1);
+
+        assert_eq!(t.find(&42).unwrap(), &bar);
+
+        t.clear();
+        assert!(t.is_empty());
+
+        assert!(t.insert(42, foo));
+        assert!(!t.is_empty());
+        assert_eq!(t.used, 1);
+        assert_eq!(t.find(&42).unwrap(), &foo);
+    }
+
+    /*
+    // NB The following test will only work when BTREE_MIN_DEGREE = 2;
+    #[test]
+    fn test_insert_split_root() {
+        assert_eq!(BTREE_MIN_DEGREE, 2);
+        let mut t = tree([Some(4), Some(5), Some(6)],
+                         [leaf(4), leaf(5), leaf(6), None]);
+
+        assert!(t.insert(10, 10));
+
+        assert_eq!(t.used, 1);
+
+        check_values(t.keys, [Some(5)]);
+        check_used(t.nodes, [true, true]);
+
+        let l = get_node(&*t, 0);
+        check_values(l.keys, [Some(4)]);
+        check_values(l.nodes, [leaf(4), leaf(5)]);
+
+        let r = get_node(&*t, 1);
+        check_values(r.keys, [Some(6), Some(10)]);
+        check_values(r.nodes, [leaf(6), leaf(10)]);
+    }
+
+    // NB The following test will only work when BTREE_MIN_DEGREE = 2;
+    #[test]
+    fn test_insert_split_right_leaf() {
+        assert_eq!(BTREE_MIN_DEGREE, 2);
+
+        let l = tree([Some(4), None, None],
+                     [leaf(4), leaf(5), None, None]);
+        let r = tree([Some(6), Some(10), Some(17)],
+                     [leaf(6), leaf(10), leaf(17), None]);
+        let mut t = tree([Some(5), None, None],
+                         [node(l), node(r), None, None]);
+
+        assert!(t.insert(21, 21));
+
+        assert_eq!(t.used, 2);
+
+        check_values(t.keys, [Some(5), Some(10)]);
+        check_used(t.nodes, [true, true, true]);
+
+        let l = get_node(&*t, 0);
+        check_values(l.keys, [Some(4)]);
+        check_values(l.nodes, [leaf(4), leaf(5)]);
+
+        let m = get_node(&*t, 1);
+        check_values(m.keys, [Some(6)]);
+        check_values(m.nodes, [leaf(6), leaf(10)]);
+
+        let r = get_node(&*t, 2);
+        check_values(r.keys, [Some(17), Some(21)]);
+        check_values(r.nodes, [leaf(17), leaf(21)]);
+    }
+
+    // NB The following test will only work when BTREE_MIN_DEGREE = 2;
+    #[test]
+    fn test_insert_split_middle_leaf() {
+        assert_eq!(BTREE_MIN_DEGREE, 2);
+
+        let l = tree([Some(4), None, None],
+                     [leaf(4), leaf(5), None, None]);
+        let m = tree([Some(6), Some(7), Some(8)],
+                     [leaf(6), leaf(7), leaf(8), leaf(10)]);
+        let r = tree([Some(17), Some(21), None],
+                     [leaf(17), leaf(21), None, None]);
+        let mut t = tree([Some(5), Some(10), None],
+                         [node(l), node(m), node(r), None]);
+
+        assert!(t.insert(9, 9));
+
+        assert_eq!(t.used, 3);
+
+        check_values(t.keys, [Some(5), Some(7), Some(10)]);
+        check_used(t.nodes, [true, true, true, true]);
+
+        let t0 = get_node(&*t, 0);
+        check_values(t0.keys, [Some(4)]);
+        check_values(t0.nodes, [leaf(4), leaf(5)]);
+
+        let t1 = get_node(&*t, 1);
+        check_values(t1.keys, [Some(6)]);
+        check_values(t1.nodes, [leaf(6), leaf(7)]);
+
+        let t2 = get_node(&*t, 2);
+        check_values(t2.keys, [Some(8), Some(9)]);
+        check_values(t2.nodes, [leaf(8), leaf(9), leaf(10)]);
+
+        let t3 = get_node(&*t, 3);
+        check_values(t3.keys, [Some(17), Some(21)]);
+        check_values(t3.nodes, [leaf(17), leaf(21)]);
+    }
+    */
+
+    #[test]
+    fn test_insert_split_random() {
+        let iterations = 100000;
+
+        let mut t = BTree::new();
+        let mut rng = IsaacRng::new();
+        rng.reseed([42u32]);
+
+        let mut random_keys = ~[];
+        for k in range(0, iterations) { random_keys.push(k); }
+        rng.shuffle_mut(random_keys);
+
+        for &key in random_keys.iter() {
+            t.insert(key, key);
+        }
+
+        for &k in random_keys.iter() {
+            assert_eq!(t.find(&k).unwrap(), &k);
+        }
+    }
+
+    #[test]
+    fn test_insertion_index() {
+        let mut t = BTree::new();
+
+        for k in range(0, 100) {
+            t.insert(k * 2, k);
+        }
+
+        assert_eq!(t.insertion_index(&-1), (0, false));
+        assert_eq!(t.insertion_index(&0), (0, true));
+        assert_eq!(t.insertion_index(&100), (50, true));
+        assert_eq!(t.insertion_index(&101), (51, false));
+        assert_eq!(t.insertion_index(&198), (99, true));
+        assert_eq!(t.insertion_index(&199), (100, false));
+
+        // Keys that are stored in inner nodes are found as well.
+        for k in range(100, 5000) {
+            t.insert(k * 2, k);
+        }
+
+        for k in range(-1, 10001) {
+            let expected = if k < 0 { 0 } else { ((k + 1) / 2) as uint };
+            assert_eq!(t.insertion_index(&k), (expected, k >= 0 && k % 2 == 0
+                                                         && k < 10000));
+        }
+    }
+
+    #[test]
+    fn test_diff() {
+        let mut left = range_tree(0, 100);
+        let mut right = range_tree(50, 150);
+
+        left.insert(60, -60);
+        right.insert(70, -70);
+        right.insert(80, -80);
+        left.insert(80, -80);
+
+        let mut only_left = 0;
+        let mut only_right = 0;
+        let mut changed = ~[];
+
+        for d in left.diff(&*right) {
+            match d {
+                LeftOnly(&k, _) => { assert!(k < 50); only_left += 1; }
+                RightOnly(&k, _) => { assert!(k >= 100); only_right += 1; }
+                Changed(&k, &l, &r) => changed.push((k, l, r)),
+            }
+        }
+
+        assert_eq!(only_left, 50);
+        assert_eq!(only_right, 50);
+        assert_eq!(changed, ~[(60, -60, 60), (70, 70, -70)]);
+
+        assert_eq!(left.diff(&*left).len(), 0);
+        assert_eq!(left.diff(&*BTree::new()).len(), 100);
+
+        // A NaN value is unequal to itself, so it is only passed over
+        // without a change when its node is shared by both trees.
+        let mut t = BTree::new();
+
+        for k in range(0, 10000) {
+            t.insert(k, 0.0f64 / 0.0);
+        }
+
+        let snapshot = t.snapshot();
+        assert_eq!(t.diff(&*snapshot).len(), 0);
+
+        t.insert(5000, 1.0f64);
+        t.pop(&7000);
+
+        let diff: ~[Difference<int, f64>] = t.diff(&*snapshot).collect();
+        assert!(diff.len() < 4 * BTREE_KEYS_UBOUND);
+        assert!(diff.iter().any(|d| match *d {
+            Changed(&5000, &v, _) => v == 1.0,
+            _ => false,
+        }));
+        assert!(diff.iter().any(|d| match *d {
+            RightOnly(&7000, _) => true,
+            _ => false,
+        }));
+    }
+
+    #[test]
+    fn test_binary_image() {
+        use std::io::mem::{MemWriter, MemReader};
+
+        let mut t = BTree::with_duplicates(Keep);
+
+        for k in range(0, 5000) {
+            t.insert(k % 2000, format!("v{}", k));
+        }
+
+        let mut w = MemWriter::new();
+        assert!(t.write_to(&mut w as &mut Writer));
+
+        let mut r = MemReader::new(w.inner());
+        let u: ~BTree<int, ~str> =
+            BTree::read_from(&mut r as &mut Reader).unwrap();
+
+        check_invariants(&*u, true);
+        assert_eq!(u.len(), 5000);
+        assert_eq!(u.duplicates(), Keep);
+        assert!(*u == *t);
+
+        let mut w = MemWriter::new();
+        let empty: ~BTree<int, ~str> = BTree::new();
+        assert!(empty.write_to(&mut w as &mut Writer));
+
+        let mut r = MemReader::new(w.inner());
+        let u: ~BTree<int, ~str> =
+            BTree::read_from(&mut r as &mut Reader).unwrap();
+        assert!(u.is_empty());
+
+        let mut r = MemReader::new(~[0u8, 1, 2, 3]);
+        let u: Option<~BTree<int, ~str>> =
+            BTree::read_from(&mut r as &mut Reader);
+        assert!(u.is_none());
+
+        let mut w = MemWriter::new();
+        assert!(t.write_to(&mut w as &mut Writer));
+
+        let mut r = MemReader::new(w.inner());
+        let u: Option<~BTree<uint, ~str>> =
+            BTree::read_from(&mut r as &mut Reader);
+        assert!(u.is_none());
+
+        let mut w = MemWriter::new();
+        assert!(t.write_to(&mut w as &mut Writer));
+
+        let mut image = w.inner();
+        image[4] = (BTREE_IMAGE_VERSION + 1) as u8;
+
+        let mut r = MemReader::new(image);
+        let u: Option<~BTree<int, ~str>> =
+            BTree::read_from(&mut r as &mut Reader);
+        assert!(u.is_none());
+
+        // A root with the keys in the given order, then a root whose two
+        // children hold a key each, which is fewer than a node must hold.
+        let read = |keys: &[int], children: bool| {
+            let mut w = MemWriter::new();
+            {
+                let w = &mut w as &mut Writer;
+                w.write(super::IMAGE_MAGIC);
+                BTREE_IMAGE_VERSION.write_binary(w);
+                BTREE_MIN_DEGREE.write_binary(w);
+                Binary::binary_encoding(None::<int>).write_binary(w);
+                Binary::binary_encoding(None::<~str>).write_binary(w);
+                w.write_u8(0);
+
+                keys.len().write_binary(w);
+
+                for k in keys.iter() {
+                    k.write_binary(w);
+                }
+
+                if children {
+                    w.write_u8(super::IMAGE_INNER);
+
+                    let pairs = [keys[0] - 1, keys[0] + 1];
+
+                    for (i, k) in pairs.iter().enumerate() {
+                        1u.write_binary(w);
+                        k.write_binary(w);
+                        w.write_u8(super::IMAGE_BOTTOM);
+                        w.write_u8((i == 0) as u8);
+                        (~"v").write_binary(w);
+
+                        if i == 0 {
+                            (~"v").write_binary(w);
+                        }
+                    }
+                } else {
+                    w.write_u8(super::IMAGE_BOTTOM);
+                    w.write_u8(0);
+
+                    for _ in keys.iter() {
+                        (~"v").write_binary(w);
+                    }
+                }
+            }
+
+            let mut r = MemReader::new(w.inner());
+            let u: Option<~BTree<int, ~str>> =
+                BTree::read_from(&mut r as &mut Reader);
+            u
+        };
+
+        assert_eq!(read([3, 5], false).unwrap().len(), 2);
+        assert!(read([5, 3], false).is_none());
+        assert!(read([3, 3], false).is_none());
+        assert!(read([3], true).is_none());
+    }
+
+    #[test]
+    fn test_binary_image_custom_order() {
+        use std::io::mem::MemWriter;
+
+        let mut t: ~BTree<int, ~str> =
+            BTree::with_comparator(Reverse(Natural));
+        t.insert(1, ~"a");
+
+        let mut w = MemWriter::new();
+        assert!(!t.write_to(&mut w as &mut Writer));
+        assert!(w.inner().is_empty());
+    }
+
+    #[test]
+    fn test_paged() {
+        use extra::tempfile::TempDir;
+
+        let dir = TempDir::new("btree").unwrap();
+        let path = dir.path().join("tree");
+
+        {
+            let mut t: PagedBTree<int, ~str> =
+                PagedBTree::open(&path).unwrap();
+
+            for k in range(0, 2000) {
+                assert!(t.put(k * 7 % 2000, format!("v{}", k)).unwrap());
+            }
+
+            assert!(!t.put(5, ~"five").unwrap());
+            assert!(t.put(5000, "x".repeat(10000)).unwrap());
+
+            for k in range(0, 1000) {
+                assert!(t.delete(&(k * 2)).unwrap().is_some());
+            }
+
+            assert!(t.delete(&0).unwrap().is_none());
+            assert_eq!(t.len(), 1001);
+            t.sync();
+        }
+
+        let mut t: PagedBTree<int, ~str> = PagedBTree::open(&path).unwrap();
+        assert_eq!(t.len(), 1001);
+        assert_eq!(t.get(&5), Ok(Some(~"five")));
+        assert_eq!(t.get(&5000).unwrap().unwrap().len(), 10000);
+
+        for k in range(0, 1000) {
+            assert!(!t.contains_key(&(k * 2)).unwrap());
+            assert!(t.contains_key(&(k * 2 + 1)).unwrap());
+        }
+
+        for k in range(0, 1000) {
+            t.delete(&(k * 2 + 1)).unwrap();
+        }
+
+        assert!(t.put(1, ~"one").unwrap());
+        assert_eq!(t.get(&1), Ok(Some(~"one")));
+
+        let u: Option<PagedBTree<uint, ~str>> = PagedBTree::open(&path);
+        assert!(u.is_none());
+    }
+
+    #[test]
+    fn test_paged_recovery() {
+        use extra::tempfile::TempDir;
+
+        let dir = TempDir::new("btree").unwrap();
+        let path = dir.path().join("tree");
+
+        {
+            let mut t: PagedBTree<int, int> = PagedBTree::open(&path).unwrap();
+
+            for k in range(0, 1000) {
+                t.put(k, k).unwrap();
+            }
+
+            t.sync();
+
+            for k in range(1000, 2000) {
+                t.put(k, k).unwrap();
+            }
+        }
+
+        {
+            let mut t: PagedBTree<int, int> = PagedBTree::open(&path).unwrap();
+            assert_eq!(t.len(), 1000);
+            assert_eq!(t.get(&1500), Ok(None));
+
+            for k in range(1000, 2000) {
+                t.put(k, k).unwrap();
+            }
+
+            // Stop after the log is committed, before the file is written.
+            t.write_header();
+            t.pager.log_dirty();
+        }
+
+        let mut t: PagedBTree<int, int> = PagedBTree::open(&path).unwrap();
+        assert_eq!(t.len(), 2000);
+
+        for k in range(0, 2000) {
+            assert_eq!(t.get(&k), Ok(Some(k)));
+        }
+    }
+
+    #[test]
+    fn test_page_cache() {
+        use extra::tempfile::TempDir;
+
+        let dir = TempDir::new("btree").unwrap();
+        let mut t: PagedBTree<int, int> =
+            PagedBTree::open(&dir.path().join("tree")).unwrap();
+
+        assert_eq!(t.cache_capacity(), BTREE_PAGE_CACHE);
+        t.set_cache_capacity(4);
+
+        for k in range(0, 5000) {
+            t.put(k * 7 % 5000, k).unwrap();
+        }
+
+        t.sync();
+        t.reset_cache_stats();
+
+        for k in range(0, 5000) {
+            assert_eq!(t.get(&(k * 7 % 5000)), Ok(Some(k)));
+        }
+
+        let stats = t.cache_stats();
+        assert!(stats.hits > 0);
+        assert!(stats.misses > 0);
+        assert!(stats.evictions > 0);
+
+        t.set_cache_capacity(1000);
+        t.reset_cache_stats();
+
+        for _ in range(0, 2) {
+            for k in range(0, 5000) {
+                t.get(&k).unwrap();
+            }
+        }
+
+        let stats = t.cache_stats();
+        assert_eq!(stats.evictions, 0);
+        assert!(stats.hits > stats.misses * 2);
+
+        for k in range(0, 5000) {
+            assert!(t.delete(&k).unwrap().is_some());
+        }
+
+        assert!(t.is_empty());
+    }
+
+    #[test]
+    fn test_page_checksums() {
+        use extra::tempfile::TempDir;
+        use std::io::{File, Open, ReadWrite, Seek, SeekSet, Writer};
+
+        let dir = TempDir::new("btree").unwrap();
+        let path = dir.path().join("tree");
+
+        {
+            let mut t: PagedBTree<int, int> = PagedBTree::open(&path).unwrap();
+
+            for k in range(0, 2000) {
+                t.put(k, k).unwrap();
+            }
+
+            t.sync();
+        }
+
+        {
+            let mut file = File::open_mode(&path, Open, ReadWrite).unwrap();
+            file.seek((2 * BTREE_PAGE_SIZE + 100) as i64, SeekSet);
+            file.write([0xffu8, 0xff, 0xff, 0xff]);
+        }
+
+        let mut t: PagedBTree<int, int> = PagedBTree::open(&path).unwrap();
+        let mut errors = 0;
+
+        for k in range(0, 2000) {
+            match t.get(&k) {
+                Ok(value) => assert_eq!(value, Some(k)),
+                Err(err) => {
+                    assert_eq!(err, ChecksumMismatch(2));
+                    errors += 1;
+                }
+            }
+        }
+
+        assert!(errors > 0);
+
+        let t: MappedBTree<int, int> = MappedBTree::open(&path).unwrap();
+        let mut mapped_errors = 0;
+
+        for k in range(0, 2000) {
+            if t.get(&k).is_err() {
+                mapped_errors += 1;
+            }
+        }
+
+        assert_eq!(mapped_errors, errors);
+    }
+
+    #[test]
+    fn test_page_compression() {
+        use extra::tempfile::TempDir;
+        use std::io::fs;
+
+        let dir = TempDir::new("btree").unwrap();
+        let plain = dir.path().join("plain");
+        let packed = dir.path().join("packed");
+
+        for path in [plain.clone(), packed.clone()].iter() {
+            let mut t: PagedBTree<int, ~str> = PagedBTree::open(path).unwrap();
+            assert!(!t.compression());
+            assert!(t.set_compression(*path == packed));
+
+            for k in range(0, 2000) {
+                t.put(k, "value ".repeat(50)).unwrap();
+            }
+
+            t.sync();
+        }
+
+        assert!(fs::stat(&packed).size * 2 < fs::stat(&plain).size);
+
+        let mut t: PagedBTree<int, ~str> = PagedBTree::open(&packed).unwrap();
+        assert!(t.compression());
+
+        for k in range(0, 2000) {
+            assert_eq!(t.get(&k), Ok(Some("value ".repeat(50))));
+        }
+
+        assert!(t.set_compression(false));
+        t.put(5000, ~"plain").unwrap();
+        assert_eq!(t.get(&5000), Ok(Some(~"plain")));
+        t.sync();
+
+        let t: MappedBTree<int, ~str> = MappedBTree::open(&packed).unwrap();
+        assert_eq!(t.get(&1000), Ok(Some("value ".repeat(50))));
+        assert_eq!(t.get(&5000), Ok(Some(~"plain")));
+    }
+
+    #[test]
+    fn test_paged_backup() {
+        use extra::tempfile::TempDir;
+        use std::io::File;
+        use std::io::fs;
+        use std::io::mem::{MemWriter, MemReader};
+
+        let dir = TempDir::new("btree").unwrap();
+        let path = dir.path().join("tree");
+        let copy = dir.path().join("copy");
+        let mut t: PagedBTree<int, int> = PagedBTree::open(&path).unwrap();
+
+        let changes = dir.path().join("tree-changes");
+
+        for k in range(0, 2000) {
+            t.put(k, k).unwrap();
+        }
+
+        // The first backup holds all pages, so none are recorded before it.
+        t.sync();
+        assert_eq!(fs::stat(&changes).size, 8);
+
+        let mut w = MemWriter::new();
+        let full = t.backup_incremental(&mut w as &mut Writer);
+        let mut r = MemReader::new(w.inner());
+        assert!(restore_paged_backup(&copy, &mut r as &mut Reader));
+
+        // A page that is written by several syncs is recorded once.
+        for _ in range(0, 20) {
+            for k in range(0, 10) {
+                t.put(k, -k).unwrap();
+            }
+
+            t.sync();
+        }
+
+        assert!(fs::stat(&changes).size as uint <= 8 + t.pager.pages / 8 + 1);
+
+        t.delete(&1999).unwrap();
+
+        let mut w = MemWriter::new();
+        let changed = t.backup_incremental(&mut w as &mut Writer);
+        assert!(changed * 4 < full);
+        let mut r = MemReader::new(w.inner());
+        assert!(restore_paged_backup(&copy, &mut r as &mut Reader));
+
+        // Only the header is written by a sync without changes.
+        let mut w = MemWriter::new();
+        assert_eq!(t.backup_incremental(&mut w as &mut Writer), 1);
+
+        let mut u: PagedBTree<int, int> = PagedBTree::open(&copy).unwrap();
+        assert_eq!(u.len(), 1999);
+        assert_eq!(u.get(&1999), Ok(None));
+
+        for k in range(0, 1999) {
+            let expected = if k < 10 { -k } else { k };
+            assert_eq!(u.get(&k), Ok(Some(expected)));
+        }
+
+        let mut r = MemReader::new(~[1u8, 2, 3, 4]);
+        assert!(!restore_paged_backup(&copy, &mut r as &mut Reader));
+
+        // A truncated backup is rejected before the file is changed, and so
+        // is one that records a page past the end of the tree.
+        let mut w = MemWriter::new();
+        t.backup_incremental(&mut w as &mut Writer);
+        let bytes = w.inner();
+        let before = File::open(&copy).unwrap().read_to_end();
+
+        for &end in [2, 20, 40, bytes.len() - 1].iter() {
+            let mut r = MemReader::new(bytes.slice_to(end).to_owned());
+            assert!(!restore_paged_backup(&copy, &mut r as &mut Reader));
+            assert!(File::open(&copy).unwrap().read_to_end() == before);
+        }
+
+        // The number of pages follows the magic bytes and the number of
+        // backups before it.
+        let mut bad = bytes.clone();
+        bad[12] = 0;
+        bad[13] = 0;
+        let mut r = MemReader::new(bad);
+        assert!(!restore_paged_backup(&copy, &mut r as &mut Reader));
+        assert!(File::open(&copy).unwrap().read_to_end() == before);
+
+        let mut r = MemReader::new(bytes);
+        assert!(restore_paged_backup(&copy, &mut r as &mut Reader));
+    }
+
+    #[test]
+    fn test_mapped() {
+        use extra::tempfile::TempDir;
+
+        let dir = TempDir::new("btree").unwrap();
+        let path = dir.path().join("tree");
+
+        let u: Option<MappedBTree<int, ~str>> = MappedBTree::open(&path);
+        assert!(u.is_none());
+
+        {
+            let mut t: PagedBTree<int, ~str> =
+                PagedBTree::open(&path).unwrap();
+
+            for k in range(0, 2000) {
+                t.put(k, format!("v{}", k)).unwrap();
+            }
+
+            t.put(5000, "x".repeat(10000)).unwrap();
+            t.sync();
+        }
+
+        let t: MappedBTree<int, ~str> = MappedBTree::open(&path).unwrap();
+        assert_eq!(t.len(), 2001);
+        assert_eq!(t.get(&5000).unwrap().unwrap().len(), 10000);
+        assert_eq!(t.get(&2000), Ok(None));
+
+        for k in range(0, 2000) {
+            assert_eq!(t.get(&k), Ok(Some(format!("v{}", k))));
+        }
+
+        let u: Option<MappedBTree<uint, ~str>> = MappedBTree::open(&path);
+        assert!(u.is_none());
+    }
+
+    #[test]
+    fn test_encodable() {
+        use extra::json;
+        use extra::serialize::{Decodable, Encodable};
+        use std::io::Writer;
+        use std::io::mem::MemWriter;
+        use std::str;
+
+        let mut t = BTree::new();
+
+        for k in range(0, 1000) {
+            t.insert(format!("key{:04d}", k), k);
+        }
+
+        let mut w = MemWriter::new();
+
+        {
+            let mut encoder = json::Encoder::new(&mut w as &mut Writer);
+            t.encode(&mut encoder);
+        }
+
+        let text = str::from_utf8_owned(w.inner());
+        assert!(text.starts_with("{\"key0000\":0,\"key0001\":1,"));
+
+        let mut decoder = json::Decoder::new(json::from_str(text).unwrap());
+        let u: BTree<~str, int> = Decodable::decode(&mut decoder);
+
+        check_invariants(&u, true);
+        assert_eq!(u.len(), 1000);
+        assert_eq!(u.find_equiv(&"key0042"), Some(&42));
+        assert_eq!(u.patch(&*t), ~[]);
+    }
+
+    #[test]
+    fn test_json() {
+        use extra::json;
+        use extra::json::ToJson;
+
+        let mut t = BTree::new();
+
+        for k in range(0, 100) {
+            t.insert(k * 10, format!("v{}", k));
+        }
+
+        let text = t.to_json().to_str();
+        assert!(text.starts_with("[[0,\"v0\"],"));
+
+        let u: ~BTree<int, ~str> =
+            BTree::from_json(json::from_str(text).unwrap());
+        check_invariants(&*u, true);
+        assert_eq!(u.len(), 100);
+        assert_eq!(u.patch(&*t), ~[]);
+
+        let empty: ~BTree<int, int> = BTree::from_json(json::List(~[]));
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn test_digest() {
+        let mut a = BTree::new();
+        let mut b = BTree::new();
+
+        for k in range(0, 1000) {
+            a.insert(k, k);
+            b.insert(999 - k, 999 - k);
+        }
+
+        let empty: ~BTree<int, int> = BTree::new();
+        assert_eq!(a.digest(), b.digest());
+        assert!(a.digest() != empty.digest());
+
+        b.insert(500, -1);
+        assert!(a.digest() != b.digest());
+        assert_eq!(a.range_digest(&0, &500), b.range_digest(&0, &500));
+        assert_eq!(a.range_digest(&501, &1000), b.range_digest(&501, &1000));
+        assert!(a.range_digest(&500, &501) != b.range_digest(&500, &501));
+
+        // The kept digests of the nodes follow every change of the tree and
+        // of its snapshot, whose nodes are shared until they are changed.
+        let mut rng = IsaacRng::new();
+        let snapshot = a.snapshot();
+
+        for i in range(0, 5000) {
+            let k = rng.gen_range(0, 3000);
+
+            if i % 3 == 0 {
+                a.pop(&k);
+            } else {
+                a.insert(k, i);
+            }
+
+            if i % 500 == 0 {
+                assert_eq!(a.digest(), sum_digests(a.iter()));
+                assert_eq!(snapshot.digest(), sum_digests(snapshot.iter()));
+            }
+        }
+
+        check_invariants(&*a, true);
+        assert_eq!(a.digest(), sum_digests(a.iter()));
+        assert_eq!(snapshot.digest(), sum_digests(snapshot.iter()));
+
+        for _ in range(0, 200) {
+            let lo = rng.gen_range(-10, 3010);
+            let hi = lo + rng.gen_range(0, 1000);
+            let pairs = a.range(Included(&lo), Excluded(&hi));
+            assert_eq!(a.range_digest(&lo, &hi), sum_digests(pairs));
+        }
+
+        assert_eq!(a.range_digest(&-10, &5000), a.digest());
+        assert_eq!(a.range_digest(&10, &10), 0);
+    }
+
+    fn sum_digests<'a, I: Iterator<(&'a int, &'a int)>>(pairs: I) -> u64 {
+        use std::hash::Hash;
+
+        let mut digest = 0u64;
+
+        for (k, v) in pairs {
+            digest += (k.hash(), v.hash()).hash();
+        }
+
+        digest
+    }
+
+    #[test]
+    fn test_sync() {
+        let mut source = range_tree(0, 10000);
+        let mut replica = range_tree(0, 10000);
+
+        replica.insert(20000, 0);
+        replica.pop(&1234);
+        replica.insert(5678, 0);
+
+        assert_eq!(replica.sync_from(&*source), 78);
+        assert_eq!(replica.digest(), source.digest());
+        assert_eq!(replica.patch(&*source), ~[]);
+        assert_eq!(replica.sync_from(&*source), 0);
+        check_invariants(&*replica, true);
+
+        let mut empty = BTree::new();
+        assert_eq!(empty.sync_from(&*source), 10000);
+        assert_eq!(source.sync_from(&*BTree::new()), 0);
+        assert!(source.is_empty());
+
+        // A run of equal keys that is longer than a node is copied whole.
+        let mut source = BTree::with_duplicates(Keep);
+        let mut replica = BTree::with_duplicates(Keep);
+
+        for k in range(0, 200) {
+            source.insert(k, k);
+            replica.insert(k, k);
+        }
+
+        for i in range(0, 60) {
+            source.insert(100, i);
+            replica.insert(100, i);
+        }
+
+        replica.insert(100, -1);
+
+        assert_eq!(replica.sync_from(&*source), 79);
+        let pairs: ~[(int, int)] = replica.iter().map(|(&k, &v)| (k, v))
+                                          .collect();
+        let expected: ~[(int, int)] = source.iter().map(|(&k, &v)| (k, v))
+                                            .collect();
+        assert_eq!(pairs, expected);
+        check_invariants(&*replica, true);
+    }
+
+    #[test]
+    fn test_freeze() {
+        let mut t = range_tree(0, 1000);
+
+        for k in range(0, 500) {
+            t.pop(&k);
+        }
+
+        let frozen = Arc::new(t.freeze());
+        let shared = frozen.clone();
+
+        assert_eq!(shared.get().len(), 500);
+        assert_eq!(shared.get().find(&700), Some(&700));
+        assert_eq!(frozen.get().get().rank(&700), 200);
+        assert_eq!(frozen.get().iter().next(), Some((&500, &500)));
+
+        // Snapshots of the frozen tree share its nodes again, and a change to
+        // one of them copies the nodes on its path.
+        let mut a = frozen.get().get().snapshot();
+        let b = shared.get().get().snapshot();
+        a.insert(0, 0);
+        assert_eq!(b.digest(), frozen.get().get().digest());
+        assert!(a.digest() != b.digest());
+        assert_eq!(shared.get().find(&0), None);
+
+        let mut t = range_tree(0, 10).freeze().thaw();
+        t.insert(10, 10);
+        assert_eq!(t.len(), 11);
+        check_invariants(&*t, true);
+    }
+
+    #[test]
+    fn test_patch() {
+        let old = range_tree(0, 100);
+        let mut new = range_tree(50, 150);
+        new.insert(70, -70);
+
+        let patch = old.patch(&*new);
+        assert_eq!(patch.len(), 101);
+        assert_eq!(patch[0], Removed(0, 0));
+        assert!(patch.contains(&Updated(70, 70, -70)));
+
+        let mut replica = old.snapshot();
+        assert_eq!(replica.apply_patch(patch.clone()), Ok(()));
+        assert_eq!(replica.patch(&*new), ~[]);
+        check_invariants(&*replica, true);
+
+        let mut stale = old.snapshot();
+        stale.insert(120, 0);
+        assert_eq!(stale.apply_patch(patch), Err(Added(120, 120)));
+        assert_eq!(stale.len(), 101);
+        assert!(old.patch(&*stale) == ~[Added(120, 0)]);
+
+        let patch = ~[Updated(3, 3, -3), Added(200, 200), Removed(4, 4)];
+
+        for &duplicates in [Reject, Keep].iter() {
+            let mut t = BTree::with_duplicates(duplicates);
+
+            for k in range(0, 10) {
+                t.insert(k, k);
+            }
+
+            assert_eq!(t.apply_patch(patch.clone()), Ok(()));
+            assert_eq!(t.len(), 10);
+            assert_eq!(t.find(&3), Some(&-3));
+            assert_eq!(t.find(&200), Some(&200));
+            assert_eq!(t.find(&4), None);
+            check_invariants(&*t, true);
+
+            let bad = ~[Updated(5, 5, -5), Updated(5, 5, -5)];
+            assert_eq!(t.apply_patch(bad), Err(Updated(5, 5, -5)));
+            assert_eq!(t.len(), 10);
+            assert_eq!(t.find(&5), Some(&5));
+        }
+    }
+
+    #[test]
+    fn test_transaction() {
+        let mut t = range_tree(0, 100);
+
+        {
+            let mut tx = t.begin();
+            assert!(tx.insert(200, 200));
+            assert!(!tx.insert(5, -5));
+            assert!(tx.remove(&6));
+            assert!(!tx.remove(&6));
+            assert!(tx.insert(6, -6));
+            assert_eq!(tx.find(&5), Some(&-5));
+            assert_eq!(tx.len(), 4);
+            tx.rollback();
+        }
+
+        check_invariants(&*t, true);
+        assert_eq!(t.len(), 100);
+        assert!(t.iter().all(|(&k, &v)| k == v));
+
+        {
+            let mut tx = t.begin();
+
+            for k in range(0, 50) {
+                assert!(tx.remove(&k));
+            }
+
+            tx.commit();
+        }
+
+        assert_eq!(t.len(), 50);
+        assert_eq!(t.min_key(), Some(&50));
+
+        {
+            let mut tx = t.begin();
+            assert!(tx.remove(&50));
+            assert!(tx.insert(0, 0));
+        }
+
+        check_invariants(&*t, true);
+        assert_eq!(t.len(), 50);
+        assert_eq!(t.min_key(), Some(&50));
+
+        let mut t = BTree::with_duplicates(Keep);
+
+        for k in range(0, 100) {
+            t.insert(k % 10, k);
+        }
+
+        let before: ~[(int, int)] = t.iter().map(|(&k, &v)| (k, v)).collect();
+
+        {
+            let mut tx = t.begin();
+            assert!(tx.insert(5, -1));
+            assert!(tx.insert(5, -2));
+            assert!(tx.remove(&5));
+            assert!(tx.remove(&7));
+            assert!(tx.insert(7, -3));
+            assert_eq!(tx.len(), 5);
+            tx.rollback();
+        }
+
+        check_invariants(&*t, true);
+        let after: ~[(int, int)] = t.iter().map(|(&k, &v)| (k, v)).collect();
+        assert_eq!(after, before);
+
+        {
+            let mut tx = t.begin();
+
+            for k in range(0, 10) {
+                assert!(tx.insert(k, -k));
+            }
+        }
+
+        let after: ~[(int, int)] = t.iter().map(|(&k, &v)| (k, v)).collect();
+        assert_eq!(after, before);
+    }
+
+    #[test]
+    fn test_history() {
+        let mut t = BTreeHistory::new();
+
+        for k in range(0, 10) {
+            assert!(t.insert(k, k).is_none());
+        }
+
+        assert_eq!(t.insert(3, 30), Some(3));
+        assert_eq!(t.pop(&4), Some(4));
+        assert_eq!(t.pop(&4), None);
+        assert_eq!(t.undo_len(), 12);
+
+        assert_eq!(t.undo(2), 2);
+        assert_eq!(t.find(&3), Some(&3));
+        assert_eq!(t.find(&4), Some(&4));
+
+        assert_eq!(t.redo(1), 1);
+        assert_eq!(t.find(&3), Some(&30));
+        assert_eq!(t.redo_len(), 1);
+
+        assert_eq!(t.undo(100), 11);
+        assert!(t.is_empty());
+        assert_eq!(t.redo(5), 5);
+        assert_eq!(t.len(), 5);
+
+        t.insert(20, 20);
+        assert_eq!(t.redo_len(), 0);
+        assert_eq!(t.redo(1), 0);
+
+        t.clear_history();
+        assert_eq!(t.undo(1), 0);
+        assert_eq!(t.len(), 6);
+    }
+
+    #[test]
+    fn test_persistent() {
+        let empty = PersistentBTree::new();
+        let mut versions = ~[empty.clone()];
+        let mut rng = IsaacRng::new();
+        let mut keys = ~[];
+
+        for _ in range(0, 2000) {
+            let k = rng.gen_range(0, 5000);
+            let next = versions[versions.len() - 1].insert(k, k * 2);
+            versions.push(next);
+            keys.push(k);
+        }
+
+        assert!(empty.is_empty());
+        assert_eq!(versions[1].len(), 1);
+        assert_eq!(versions[1].find(&keys[0]), Some(&(keys[0] * 2)));
+
+        let last = versions[versions.len() - 1].clone();
+        assert!(last.same_root(&versions[versions.len() - 1]));
+
+        for (i, &k) in keys.iter().enumerate() {
+            assert!(versions[i + 1].contains_key(&k));
+            assert_eq!(last.find(&k), Some(&(k * 2)));
+        }
+
+        let mut t = last.clone();
+
+        for &k in keys.iter() {
+            t = t.remove(&k);
+            assert!(!t.contains_key(&k));
+            assert!(last.contains_key(&k));
+        }
+
+        assert!(t.is_empty());
+        assert!(t.same_root(&empty));
+
+        let mut prev = -1;
+        let mut n = 0;
+
+        assert!(last.each(|&k, &v| {
+            assert!(prev < k);
+            assert_eq!(v, k * 2);
+            prev = k;
+            n += 1;
+            true
+        }));
+
+        assert_eq!(n, last.len());
+        assert!(last.remove(&-1).same_root(&last));
+    }
+
+    #[test]
+    fn test_bimap() {
+        let mut m = BTreeBiMap::new();
+
+        for k in range(0, 100) {
+            assert!(m.insert(k, format!("v{}", k)).is_empty());
+        }
+
+        assert_eq!(m.find_by_key(&42), Some(&~"v42"));
+        assert_eq!(m.find_by_value(&~"v42"), Some(&42));
+        assert_eq!(m.try_insert(42, ~"new"), Err((42, ~"new")));
+        assert_eq!(m.try_insert(200, ~"v1"), Err((200, ~"v1")));
+        assert_eq!(m.try_insert(200, ~"new"), Ok(()));
+
+        assert_eq!(m.insert(1, ~"v2"), ~[(1, ~"v1"), (2, ~"v2")]);
+        assert_eq!(m.find_by_value(&~"v2"), Some(&1));
+        assert_eq!(m.find_by_key(&2), None);
+        assert_eq!(m.len(), 100);
+
+        assert_eq!(m.pop_by_value(&~"new"), Some(200));
+        assert_eq!(m.pop_by_key(&200), None);
+        assert_eq!(m.pop_by_key(&1), Some(~"v2"));
+        assert_eq!(m.find_by_value(&~"v2"), None);
+        assert_eq!(m.len(), 98);
+
+        let pairs: ~[(~str, int)] = m.iter_by_value().take(2)
+                                     .map(|(v, &k)| (v.clone(), k)).collect();
+        assert_eq!(pairs, ~[(~"v0", 0), (~"v10", 10)]);
+    }
+
+    #[test]
+    fn test_tracked() {
+        let mut t = BTreeTracked::new();
+
+        for k in range(0, 10) {
+            assert!(t.insert(k, k).is_none());
+        }
+
+        let seen = t.version();
+        assert_eq!(seen, 10);
+
+        assert_eq!(t.insert(3, 30), Some(3));
+        assert_eq!(t.pop(&4), Some(4));
+        assert_eq!(t.pop(&4), None);
+        assert_eq!(t.insert(3, 31), Some(30));
+        assert_eq!(t.len(), 9);
+
+        let changes: ~[(uint, int, Option<int>)] = t.changes_since(seen)
+            .map(|(v, &k, value)| (v, k, value.map(|&x| x))).collect();
+        assert_eq!(changes, ~[(12, 4, None), (13, 3, Some(31))]);
+
+        assert_eq!(t.changes_since(t.version()).len(), 0);
+        assert_eq!(t.changes_since(0).len(), 10);
+
+        assert_eq!(t.forget_removed(11), 0);
+        assert_eq!(t.forget_removed(12), 1);
+        assert_eq!(t.changes_since(seen).len(), 1);
+        assert!(t.insert(4, 4).is_none());
+        assert_eq!(t.len(), 10);
+    }
+
+    #[test]
+    fn test_versioned() {
+        let mut t = BTreeVersioned::new();
+
+        assert_eq!(t.insert(~"row", 1), 1);
+        assert_eq!(t.insert(~"other", 7), 2);
+        assert_eq!(t.insert(~"row", 2), 3);
+        assert_eq!(t.remove(&~"row"), Some(4));
+        assert_eq!(t.remove(&~"row"), None);
+        assert_eq!(t.insert(~"row", 3), 5);
+
+        assert_eq!(t.find_at(&~"row", 0), None);
+        assert_eq!(t.find_at(&~"row", 1), Some(&1));
+        assert_eq!(t.find_at(&~"row", 2), Some(&1));
+        assert_eq!(t.find_at(&~"row", 3), Some(&2));
+        assert_eq!(t.find_at(&~"row", 4), None);
+        assert_eq!(t.find(&~"row"), Some(&3));
+        assert_eq!(t.find_at(&~"other", 1), None);
+        assert_eq!(t.find(&~"other"), Some(&7));
+
+        let versions: ~[uint] = t.history(&~"row").map(|(&(_, v), _)| v)
+                                 .collect();
+        assert_eq!(versions, ~[1, 3, 4, 5]);
+
+        assert_eq!(t.truncate_before(3), 1);
+        assert_eq!(t.find_at(&~"row", 3), Some(&2));
+        assert_eq!(t.find_at(&~"other", 3), Some(&7));
+        assert_eq!(t.truncate_before(5), 2);
+        assert_eq!(t.find_at(&~"row", 5), Some(&3));
+        assert_eq!(t.len(), 2);
+    }
+
+    #[test]
+    fn test_expiring() {
+        let mut t = BTreeExpiring::new();
+
+        for k in range(0, 100) {
+            assert!(t.insert(k, k * 10, k % 10).is_none());
+        }
+
+        assert_eq!(t.find(&15, &5), Some(&150));
+        assert_eq!(t.find(&15, &6), None);
+        assert_eq!(t.len(), 99);
+
+        assert_eq!(t.insert(25, 0, 20), Some(250));
+        assert_eq!(t.expires(&25), Some(&20));
+
+        assert_eq!(t.expire_before(&3), 30);
+        assert_eq!(t.len(), 69);
+        assert!(t.iter().all(|(_, &(_, at))| at >= 3));
+
+        assert_eq!(t.pop(&3), Some(30));
+        assert_eq!(t.expire_before(&10), 67);
+        assert_eq!(t.len(), 1);
+        assert_eq!(t.find(&25, &20), Some(&0));
+        assert_eq!(t.expire_before(&21), 1);
+        assert!(t.is_empty());
+    }
+
+    #[test]
+    fn test_cache() {
+        let mut c = BTreeCache::new(3);
+
+        for k in range(0, 3) {
+            assert!(c.insert(k, k * 10).is_none());
+        }
+
+        assert_eq!(c.find(&0), Some(&0));
+        assert_eq!(c.insert(3, 30), Some((1, 10)));
+        assert_eq!(c.peek(&2), Some(&20));
+        assert_eq!(c.insert(4, 40), Some((2, 20)));
+        assert!(c.insert(0, 1).is_none());
+        assert_eq!(c.len(), 3);
+
+        let pairs: ~[(int, int)] = c.iter().map(|(&k, &v)| (k, v)).collect();
+        assert_eq!(pairs, ~[(0, 1), (3, 30), (4, 40)]);
+
+        assert_eq!(c.set_capacity(1), ~[(3, 30), (4, 40)]);
+        assert_eq!(c.pop(&0), Some(1));
+        assert!(c.is_empty());
+        assert_eq!(c.evict(), None);
+
+        c.set_capacity(0);
+        assert_eq!(c.insert(5, 50), Some((5, 50)));
+        assert!(c.is_empty());
+    }
+
+    fn check_intervals(t: &BTreeIntervals<int, int>, rng: &mut IsaacRng) {
+        check_summaries::<(int, int), int, int>(&*t.tree);
+
+        let all: ~[(int, int)] = t.iter().map(|(&k, _)| k).collect();
+        assert_eq!(t.len(), all.len());
+
+        for _ in range(0, 200) {
+            let x = rng.gen_range(-10, 10050);
+            let found: ~[(int, int)] = t.stabbing(&x).map(|(&k, _)| k)
+                                         .collect();
+            let expected: ~[(int, int)] = all.iter()
+                .filter(|&&(s, e)| s <= x && x < e).map(|&k| k).collect();
+            assert_eq!(found, expected);
+
+            let hi = x + rng.gen_range(0, 100);
+            let found: ~[(int, int)] = t.overlapping(&x, &hi)
+                .map(|(&k, _)| k).collect();
+            let expected: ~[(int, int)] = all.iter()
+                .filter(|&&(s, e)| s < hi && x < e).map(|&k| k).collect();
+            assert_eq!(found, expected);
+        }
+    }
+
+    #[test]
+    fn test_intervals() {
+        let mut t = BTreeIntervals::new();
+        let mut rng = IsaacRng::new();
+
+        for i in range(0, 1000) {
+            let start = rng.gen_range(0, 10000);
+            t.insert(start, start + rng.gen_range(0, 50), i);
+        }
+
+        check_intervals(&t, &mut rng);
+
+        let all: ~[(int, int)] = t.iter().map(|(&k, _)| k).collect();
+        let (s, e) = all[0];
+        assert!(t.insert(s, e, -1).is_some());
+        assert_eq!(t.len(), all.len());
+
+        assert!(t.insert(-1000, 20000, 0).is_none());
+        assert_eq!(t.stabbing(&15000).len(), 1);
+        assert_eq!(t.stabbing(&-1000).len(), 1);
+        assert_eq!(t.stabbing(&20000).len(), 0);
+        check_intervals(&t, &mut rng);
+        assert_eq!(t.remove(&-1000, &20000), Some(0));
+        assert_eq!(t.stabbing(&15000).len(), 0);
+        assert_eq!(t.remove(&-1000, &20000), None);
+
+        for (i, &(s, e)) in all.iter().enumerate() {
+            if i % 3 != 0 {
+                assert!(t.remove(&s, &e).is_some());
+            }
+        }
+
+        check_intervals(&t, &mut rng);
+
+        t.clear();
+        assert_eq!(t.stabbing(&10).len(), 0);
+        assert_eq!(t.iter().len(), 0);
+    }
+
+    #[test]
+    fn test_rank_select() {
+        let mut t = BTree::new();
+        let mut rng = IsaacRng::new();
+
+        for _ in range(0, 2000) {
+            let k = rng.gen_range(0, 1000) * 2;
+            t.insert(k, k);
+        }
+
+        check_invariants(&*t, true);
+
+        let keys: ~[int] = t.iter().map(|(&k, _)| k).collect();
+
+        for (i, &k) in keys.iter().enumerate() {
+            assert_eq!(t.rank(&k), i);
+            assert_eq!(t.rank(&(k + 1)), i + 1);
+            assert_eq!(t.select(i), Some((&k, &k)));
+        }
+
+        assert_eq!(t.select(keys.len()), None);
+
+        for k in range(0, 1000) {
+            t.pop(&(k * 4));
+        }
+
+        check_invariants(&*t, true);
+
+        for i in range(0, t.len()) {
+            let (&k, _) = t.select(i).unwrap();
+            assert_eq!(t.rank(&k), i);
+        }
+
+        let right = t.split_off(&1000);
+        check_invariants(&*t, true);
+        check_invariants(&*right, true);
+        assert_eq!(right.rank(&1000), 0);
+        assert_eq!(t.rank(&1000), t.len());
+    }
+
+    #[test]
+    fn test_merge_k() {
+        let mut shards = ~[];
+
+        for i in range(0, 4) {
+            let mut t = BTree::new();
+
+            for k in range(0, 100) {
+                t.insert(k * 4 + i, k * 4 + i);
+            }
+
+            shards.push(t);
+        }
+
+        let refs: ~[&BTree<int, int>] = shards.iter().map(|t| &**t).collect();
+        let merged = merge_k(refs);
+
+        for k in range(0, 400) {
+            assert_eq!(merged.find(&k).unwrap(), &k);
+        }
+
+        assert_eq!(merged.insertion_index(&400), (400, false));
+
+        let mut a = BTree::new();
+        let mut b = BTree::new();
+
+        for k in range(0, 10) { a.insert(k, 1); }
+        for k in range(5, 15) { b.insert(k, 2); }
+
+        let merged = merge_k([&*a, &*b]);
+
+        for k in range(0, 5) { assert_eq!(merged.find(&k).unwrap(), &1); }
+        for k in range(5, 15) { assert_eq!(merged.find(&k).unwrap(), &2); }
+
+        assert_eq!(merged.insertion_index(&15), (15, false));
+    }
+
+    #[test]
+    fn test_entry_ref() {
+        let mut t = BTree::new();
+
+        for k in range(0, 900) {
+            let (key, count) = t.entry_ref(k % 300);
+            assert_eq!(*key, k % 300);
+
+            *count += 1;
+            assert_eq!(*count, k / 300 + 1);
+        }
+
+        for k in range(0, 300) {
+            assert_eq!(t.find(&k).unwrap(), &3);
+        }
+    }
+
+    #[test]
+    fn test_against_reference() {
+        check_against_reference(random_ops(100000, 10000));
+    }
+
+    #[test]
+    fn test_remove() {
+        let iterations = 100000;
+
+        let mut t = BTree::new();
+        let mut rng = IsaacRng::new();
+        rng.reseed([42u32]);
+
+        let mut random_keys = ~[];
+        for k in range(0, iterations) { random_keys.push(k); }
+        rng.shuffle_mut(random_keys);
+
+        for &key in random_keys.iter() {
+            t.insert(key, key);
+        }
+
+        for &key in random_keys.iter() {
+            if key % 2 == 0 {
+                assert!(t.remove(&key));
+                assert!(!t.remove(&key));
+            }
+        }
+
+        check_invariants(&*t, true);
+
+        for &key in random_keys.iter() {
+            if key % 2 == 0 {
+                assert_eq!(t.find(&key), None);
+            } else {
+                assert_eq!(t.find(&key).unwrap(), &key);
+            }
+        }
+
+        for &key in random_keys.iter() {
+            assert_eq!(t.remove(&key), key % 2 == 1);
+        }
+
+        assert!(t.is_empty());
+        check_invariants(&*t, true);
+    }
+
+    #[test]
+    fn test_pop() {
+        let mut t = BTree::new();
+
+        for k in range(0, 1000) {
+            t.insert(k, ~[k]);
+        }
+
+        for k in range(0, 1000) {
+            assert_eq!(t.pop(&k), Some(~[k]));
+            assert_eq!(t.pop(&k), None);
+        }
+
+        assert!(t.is_empty());
+        check_invariants(&*t, true);
+    }
+
+    #[test]
+    fn test_find_mut() {
+        let mut t = BTree::new();
+        assert_eq!(t.find_mut(&1), None);
+
+        for k in range(0, 1000) {
+            t.insert(k, 0);
+        }
+
+        for k in range(0, 3000) {
+            *t.find_mut(&(k % 1000)).unwrap() += 1;
+        }
+
+        assert_eq!(t.find_mut(&1000), None);
+
+        for k in range(0, 1000) {
+            assert_eq!(t.find(&k).unwrap(), &3);
+        }
+    }
+
+    #[test]
+    fn test_contains_key() {
+        let mut t = BTree::new();
+        assert!(!t.contains_key(&0));
+
+        for k in range(0, 1000) {
+            t.insert(k * 2, k);
+        }
+
+        for k in range(0, 2000) {
+            assert_eq!(t.contains_key(&k), k % 2 == 0);
+        }
+
+        assert!(t.remove(&10));
+        assert!(!t.contains_key(&10));
+    }
+
+    /// A borrowed probe that looks up `int` keys by a wider integer.
+    struct Wide(i64);
+
+    impl Equiv<int> for Wide {
+        fn equiv(&self, other: &int) -> bool {
+            let Wide(w) = *self;
+            w == *other as i64
+        }
+    }
+
+    impl KeyEquiv<int> for Wide {
+        fn cmp_key(&self, key: &int) -> Ordering {
+            let Wide(w) = *self;
+            w.cmp(&(*key as i64))
+        }
+    }
+
+    #[test]
+    fn test_find_equiv() {
+        let mut t = BTree::new();
+        assert_eq!(t.find_equiv(&Wide(0)), None);
+
+        for k in range(0, 1000) {
+            t.insert(k * 2, k);
+        }
+
+        for k in range(-10, 2010) {
+            assert_eq!(t.find_equiv(&Wide(k as i64)), t.find(&k));
+        }
+
+        let mut natural = BTree::with_comparator(Natural);
+
+        for k in range(0, 1000) {
+            natural.insert(k * 2, k);
+        }
+
+        assert_eq!(natural.find_equiv(&Wide(998)), Some(&499));
+        assert_eq!(natural.find_equiv(&Wide(999)), None);
+    }
+
+    #[test]
+    fn test_find_equiv_reverse() {
+        let mut t = BTree::with_comparator(Reverse(Natural));
+
+        for k in range(0, 1000) {
+            t.insert(k * 2, k);
+        }
+
+        assert_eq!(t.find(&998), Some(&499));
+        assert_eq!(t.find_equiv(&Wide(998)), None);
+    }
+
+    #[test]
+    fn test_prefix_range_fn_order() {
+        fn descending(a: &(int, int), b: &(int, int)) -> Ordering { b.cmp(a) }
+
+        let mut t = BTree::with_order(descending);
+        t.insert((1, 2), ());
+        assert!(t.prefix_range(&Prefix(&1)).next().is_none());
+        assert!(t.suffix_range(&Prefix(&1), Unbounded, Unbounded)
+                 .next().is_none());
+    }
+
+    #[test]
+    fn test_string_keys() {
+        let mut t = BTree::new();
+
+        for k in range(0, 1000) {
+            assert!(t.insert(format!("key{:04d}", k), k));
+        }
+
+        assert!(!t.insert(~"key0042", 0));
+        assert_eq!(t.len(), 1000);
+        check_invariants(&*t, true);
+
+        assert_eq!(t.find(&~"key0042"), Some(&0));
+        assert_eq!(t.find_equiv(&"key0999"), Some(&999));
+        assert_eq!(t.find_equiv(&"key1000"), None);
+        assert_eq!(t.find_equiv(&"key"), None);
+
+        for k in range(0, 500) {
+            assert_eq!(t.pop(&format!("key{:04d}", k * 2)), Some(k * 2));
+        }
+
+        check_invariants(&*t, true);
+
+        let keys: ~[~str] = t.keys().map(|k| k.clone()).collect();
+        let expected: ~[~str] = range(0, 500).map(|k| format!("key{:04d}",
+                                                             k * 2 + 1))
+                                             .collect();
+        assert_eq!(keys, expected);
+        assert_eq!(t.min_key(), Some(&~"key0001"));
+    }
+
+    /// A value that cannot be compared.
+    struct Handle(int);
+
+    #[test]
+    fn test_values_without_eq() {
+        let mut t = BTree::new();
+
+        for k in range(0, 1000) {
+            assert!(t.insert(k, Handle(k)));
+        }
+
+        assert!(!t.insert(42, Handle(-1)));
+        check_invariants(&*t, true);
+
+        match t.find(&42) {
+            Some(&Handle(v)) => assert_eq!(v, -1),
+            None => fail!(),
+        }
+
+        for k in range(0, 1000) {
+            match t.pop(&k) {
+                Some(Handle(v)) => assert!(v == k || k == 42),
+                None => fail!(),
+            }
+        }
+
+        assert!(t.is_empty());
+    }
+
+    /// Orders the even keys before the odd keys.
+    struct EvensFirst;
+
+    impl Comparator<int> for EvensFirst {
+        fn compare(&self, a: &int, b: &int) -> Ordering {
+            match (*a % 2).cmp(&(*b % 2)) {
+                Equal => a.cmp(b),
+                order => order,
+            }
+        }
+    }
+
+    /// Orders the keys by their remainder modulo `m`, and then by value.
+    struct Modulo {
+        m: int,
+    }
+
+    impl Comparator<int> for Modulo {
+        fn compare(&self, a: &int, b: &int) -> Ordering {
+            match (*a % self.m).cmp(&(*b % self.m)) {
+                Equal => a.cmp(b),
+                order => order,
+            }
+        }
+    }
+
+    fn descending(a: &int, b: &int) -> Ordering { b.cmp(a) }
+
+    #[test]
+    fn test_comparator() {
+        let mut t = BTree::with_comparator(Reverse(Natural));
+
+        for k in range(0, 1000) {
+            assert!(t.insert(k, k));
+        }
+
+        check_invariants(&*t, true);
+        assert_eq!(t.find(&42), Some(&42));
+
+        let keys: ~[int] = t.keys().map(|k| *k).collect();
+        assert_eq!(keys, range(0, 1000).invert().collect());
+
+        let mut t = BTree::with_comparator(EvensFirst);
+
+        for k in range(0, 1000) {
+            assert!(t.insert(999 - k, k));
+        }
+
+        check_invariants(&*t, true);
+
+        let keys: ~[int] = t.keys().map(|k| *k).collect();
+        let evens = range(0, 500).map(|k| k * 2);
+        let odds = range(0, 500).map(|k| k * 2 + 1);
+        let expected: ~[int] = evens.chain(odds).collect();
+        assert_eq!(keys, expected);
+        assert_eq!(t.pop(&998), Some(1));
+        assert_eq!(t.min_key(), Some(&0));
+        assert_eq!(t.max_key(), Some(&999));
+
+        // The odd keys follow the even keys, so the range wraps around.
+        let keys: ~[int] = t.range(Excluded(&996), Included(&5))
+                            .map(|(k, _)| *k).collect();
+        assert_eq!(keys, ~[1, 3, 5]);
+        assert!(t.range(Included(&1), Included(&0)).next().is_none());
+    }
+
+    #[test]
+    fn test_comparator_with_state() {
+        let mut t = BTree::with_comparator(Modulo { m: 10 });
+
+        for k in range(0, 1000) {
+            assert!(t.insert(k, ()));
+        }
+
+        check_invariants(&*t, true);
+
+        let keys: ~[int] = t.keys().take(3).map(|k| *k).collect();
+        assert_eq!(keys, ~[0, 10, 20]);
+
+        let right = t.split_off(&1);
+        check_invariants(&*t, true);
+        check_invariants(&*right, true);
+        assert_eq!(t.len(), 100);
+        assert!(t.iter().all(|(k, _)| *k % 10 == 0));
+
+        // The split off tree keeps the comparator, so inserting a key puts it
+        // by its remainder.
+        let mut right = right;
+        assert!(right.insert(1001, ()));
+        assert_eq!(right.min_key(), Some(&1));
+        assert_eq!(right.keys().nth(100), Some(&1001));
+
+        t.append(right);
+        check_invariants(&*t, true);
+        assert_eq!(t.len(), 1001);
+        assert_eq!(t.rank(&1001), 200);
+
+        let copy = t.snapshot();
+        assert!(!t.insert(5, ()));
+        assert_eq!(copy.find(&2001), None);
+        assert_eq!(copy.floor(&2001), Some((&1001, &())));
+    }
+
+    #[test]
+    fn test_with_order() {
+        let mut t = BTree::with_order(descending);
+
+        for k in range(0, 1000) {
+            assert!(t.insert(k, k));
+        }
+
+        check_invariants(&*t, true);
+        assert_eq!(t.max_key(), Some(&0));
+        assert_eq!(t.ceiling(&500), Some((&500, &500)));
+        assert_eq!(t.next_above(&500), Some((&499, &499)));
+
+        while t.len() > 10 {
+            let k = *t.min_key().unwrap();
+            assert!(t.remove(&k));
+        }
+
+        let keys: ~[int] = t.keys().map(|k| *k).collect();
+        assert_eq!(keys, range(0, 10).invert().collect());
+    }
+
+    #[test]
+    fn test_runtime_order() {
+        for &descending in [false, true].iter() {
+            let mut t = if descending {
+                BTree::with_comparator(Reverse(Natural))
+            } else {
+                BTree::with_comparator(Natural)
+            };
+
+            for k in range(0, 1000) {
+                assert!(t.insert((k * 7) % 1000, k));
+            }
+
+            check_invariants(&*t, true);
+            assert_eq!(t.find(&7), Some(&1));
+
+            let keys: ~[int] = t.keys().map(|k| *k).collect();
+            let expected: ~[int] = if descending {
+                range(0, 1000).invert().collect()
+            } else {
+                range(0, 1000).collect()
+            };
+            assert_eq!(keys, expected);
+        }
+    }
+
+    #[deriving(Eq, Ord)]
+    struct Record {
+        id: int,
+        name: ~str,
+    }
+
+    /// Extracts the id of a record.
+    struct ById;
+
+    impl KeyOf<Record, int> for ById {
+        fn key_of<'a>(&self, record: &'a Record) -> &'a int { &record.id }
+    }
+
+    #[test]
+    fn test_key_of() {
+        let by_id: ByKeyOf<ById, int> = ByKeyOf::new(ById);
+        let mut t = BTree::with_comparator(by_id);
+
+        for k in range(0, 1000) {
+            let id = (k * 7) % 1000;
+            let record = Record { id: id, name: format!("r{}", id) };
+            assert!(t.insert(record, ()));
+        }
+
+        check_invariants(&*t, true);
+
+        let ids: ~[int] = t.keys().map(|r| r.id).collect();
+        assert_eq!(ids, range(0, 1000).collect());
+
+        for id in range(0, 1000) {
+            let field = Field(&id, ById);
+            let (record, _) = t.prefix_range(&field).next().unwrap();
+            assert_eq!(record.name, format!("r{}", id));
+            assert_eq!(t.find_equiv(&field), Some(&()));
+        }
+
+        assert!(t.prefix_range(&Field(&1000, ById)).next().is_none());
+
+        // The comparator only looks at the id, so a record with another name
+        // is a duplicate.
+        let record = Record { id: 42, name: ~"other" };
+        assert!(t.contains_key(&record));
+        assert!(!t.insert(record, ()));
+        assert_eq!(t.len(), 1000);
+    }
+
+    /// Extracts the name of a record.
+    struct ByName;
+
+    impl KeyOf<Record, ~str> for ByName {
+        fn key_of<'a>(&self, record: &'a Record) -> &'a ~str { &record.name }
+    }
+
+    #[test]
+    fn test_indexed() {
+        let mut t = BTreeIndexed::new(ByName);
+
+        for id in range(0, 1000) {
+            let record = Record { id: id, name: format!("r{}", id % 10) };
+            assert!(t.insert(id, record).is_none());
+        }
+
+        let ids: ~[int] = t.find_by(&~"r3").map(|(&id, _)| id).collect();
+        assert_eq!(ids, range(0, 100).map(|i| i * 10 + 3).collect());
+
+        let old = t.insert(13, Record { id: 13, name: ~"other" }).unwrap();
+        assert_eq!(old.name, ~"r3");
+        assert_eq!(t.find_by(&~"r3").len(), 99);
+        let ids: ~[int] = t.find_by(&~"other").map(|(&id, _)| id).collect();
+        assert_eq!(ids, ~[13]);
+
+        assert!(t.pop(&3).is_some());
+        assert!(t.pop(&3).is_none());
+        assert_eq!(t.find_by(&~"r3").len(), 98);
+        assert_eq!(t.find_by(&~"missing").len(), 0);
+        assert_eq!(t.len(), 999);
+        assert_eq!(t.find(&23).unwrap().name, ~"r3");
+    }
+
+    #[test]
+    fn test_duplicates() {
+        let mut t = BTree::with_duplicates(Reject);
+        assert_eq!(t.duplicates(), Reject);
+
+        for k in range(0, 1000) {
+            assert!(t.insert(k, k));
+        }
+
+        assert_eq!(t.swap(42, 0), Some(0));
+        assert!(!t.insert(42, 1));
+        assert_eq!(t.find(&42), Some(&42));
+        assert_eq!(t.len(), 1000);
+
+        t.insert_sorted(~[(42, 1), (1000, 1), (1000, 2)]);
+        check_invariants(&*t, true);
+        assert_eq!(t.find(&42), Some(&42));
+        assert_eq!(t.find(&1000), Some(&1));
+        assert_eq!(t.len(), 1001);
+
+        let mut t = BTree::with_duplicates(Keep);
+
+        for i in range(0, 5) {
+            for k in range(0, 1000) {
+                assert!(t.insert(k, i));
+            }
+        }
+
+        check_invariants(&*t, true);
+        assert_eq!(t.len(), 5000);
+
+        let keys: ~[int] = t.keys().map(|&k| k).collect();
+        let expected: ~[int] = range(0, 5000).map(|k| k / 5).collect();
+        assert_eq!(keys, expected);
+
+        let values: ~[int] = t.range(Included(&42), Included(&42))
+                              .map(|(_, &v)| v).collect();
+        assert_eq!(values, ~[4, 3, 2, 1, 0]);
+
+        let values: ~[int] = t.range(Included(&42), Included(&42)).invert()
+                              .map(|(_, &v)| v).collect();
+        assert_eq!(values, ~[0, 1, 2, 3, 4]);
+
+        let mut iter = t.range(Included(&42), Included(&42));
+        assert_eq!(iter.next(), Some((&42, &4)));
+        assert_eq!(iter.next_back(), Some((&42, &0)));
+        assert_eq!(iter.len(), 3);
+
+        for i in range(0, 5) {
+            assert!(t.find(&7).is_some());
+            assert!(t.pop(&7).is_some());
+            assert_eq!(t.len(), 4999 - i as uint);
+        }
+
+        check_invariants(&*t, true);
+        assert_eq!(t.find(&7), None);
+        assert_eq!(t.keys().filter(|&&k| k == 8).len(), 5);
+
+        // The lookups that insert only when the key is missing never add an
+        // equal key.
+        assert_eq!(t.try_insert(8, 5), Err((8, 5)));
+        assert!(*t.find_or_insert(8, 5) < 5);
+        assert!(match t.entry(8) { Occupied(_) => true, Vacant(_) => false });
+        assert_eq!(t.keys().filter(|&&k| k == 8).len(), 5);
+
+        let right = t.split_off(&500);
+        check_invariants(&*t, true);
+        check_invariants(&*right, true);
+        assert_eq!(right.duplicates(), Keep);
+        assert_eq!(t.duplicates(), Keep);
+        assert_eq!(t.len() + right.len(), 4995);
+
+        t.insert_sorted(~[(9, 5), (9, 6), (10, 5), (1000, 5), (1000, 6)]);
+        check_invariants(&*t, true);
+        let values: ~[int] = t.range(Included(&9), Included(&9))
+                              .map(|(_, &v)| v).collect();
+        assert_eq!(values, ~[6, 5, 4, 3, 2, 1, 0]);
+        let values: ~[int] = t.range(Included(&1000), Unbounded)
+                              .map(|(_, &v)| v).collect();
+        assert_eq!(values, ~[6, 5]);
+
+        t.append(right);
+        check_invariants(&*t, true);
+        assert_eq!(t.len(), 5000);
+    }
+
+    #[test]
+    fn test_set() {
+        let mut s = BTreeSet::new();
+        assert!(s.is_empty());
+        assert_eq!(s.min(), None);
+
+        for k in range(0, 1000) {
+            assert!(s.insert(k * 2));
+            assert!(!s.insert(k * 2));
+        }
+
+        assert_eq!(s.len(), 1000);
+        assert!(s.contains(&42));
+        assert!(!s.contains(&43));
+        assert_eq!(s.min(), Some(&0));
+        assert_eq!(s.max(), Some(&1998));
+
+        let values: ~[int] = s.iter().map(|&k| k).collect();
+        assert_eq!(values, range(0, 1000).map(|k| k * 2).collect());
+
+        let values: ~[int] = s.rev_iter().map(|&k| k).collect();
+        assert_eq!(values, range(0, 1000).map(|k| 1998 - k * 2).collect());
+
+        let mut small = BTreeSet::new();
+        let mut odd = BTreeSet::new();
+
+        for k in range(0, 10) {
+            small.insert(k * 4);
+            odd.insert(k * 2 + 1);
+        }
+
+        assert!(small.is_subset(&s));
+        assert!(s.is_superset(&small));
+        assert!(!s.is_subset(&small));
+        assert!(s.is_disjoint(&odd));
+        assert!(odd.is_disjoint(&s));
+        assert!(!small.is_disjoint(&s));
+
+        odd.insert(8);
+        assert!(!s.is_disjoint(&odd));
+
+        assert!(s.remove(&42));
+        assert!(!s.remove(&42));
+        assert!(!small.is_subset(&s));
+
+        s.clear();
+        assert!(s.is_empty());
+        assert!(s.is_subset(&small));
+    }
+
+    #[test]
+    fn test_multiset() {
+        let mut s = BTreeMultiset::new();
+        assert!(s.is_empty());
+        assert!(s.iter().next().is_none());
+
+        for k in range(0, 100) {
+            s.insert_many(k, k as uint % 4);
+        }
+
+        s.insert(2);
+        s.insert_many(5, 0);
+
+        assert_eq!(s.len(), 151);
+        assert_eq!(s.distinct_len(), 75);
+        assert_eq!(s.count(&2), 3);
+        assert_eq!(s.count(&4), 0);
+        assert!(!s.contains(&4));
+
+        let values: ~[int] = s.iter().take(9).map(|&k| k).collect();
+        assert_eq!(values, ~[1, 2, 2, 2, 3, 3, 3, 5, 6]);
+
+        let counts: ~[(int, uint)] = s.counts().take(3)
+                                      .map(|(&k, &n)| (k, n)).collect();
+        assert_eq!(counts, ~[(1, 1), (2, 3), (3, 3)]);
+
+        assert!(s.remove_one(&2));
+        assert_eq!(s.count(&2), 2);
+        assert_eq!(s.remove_many(&2, 5), 2);
+        assert!(!s.contains(&2));
+        assert!(!s.remove_one(&2));
+        assert_eq!(s.remove_all(&3), 3);
+        assert_eq!(s.remove_all(&3), 0);
+        assert_eq!(s.len(), 145);
+        assert_eq!(s.iter().len(), 145);
+
+        s.clear();
+        assert!(s.is_empty());
+        assert_eq!(s.distinct_len(), 0);
+    }
+
+    #[test]
+    fn test_multimap() {
+        let mut m = BTreeMultiMap::new();
+        assert!(m.is_empty());
+        assert!(m.find_all(&1).next().is_none());
+
+        for k in range(0, 1000) {
+            m.insert(k % 100, k);
+        }
+
+        assert_eq!(m.len(), 1000);
+        assert_eq!(m.count(&42), 10);
+        assert_eq!(m.count(&100), 0);
+
+        let values: ~[int] = m.find_all(&42).map(|&v| v).collect();
+        assert_eq!(values, range(0, 10).map(|i| i * 100 + 42).collect());
+
+        let pairs: ~[(int, int)] = m.iter().map(|(&k, &v)| (k, v)).collect();
+        assert_eq!(pairs.len(), 1000);
+        assert_eq!(pairs[0], (0, 0));
+        assert_eq!(pairs[1], (0, 100));
+        assert_eq!(pairs[999], (99, 999));
+
+        assert!(m.remove(&42, &542));
+        assert!(!m.remove(&42, &542));
+        assert!(!m.remove(&42, &43));
+        assert_eq!(m.count(&42), 9);
+        assert_eq!(m.len(), 999);
+
+        for i in range(0, 10) {
+            m.remove(&7, &(i * 100 + 7));
+        }
+
+        assert!(!m.contains_key(&7));
+        assert_eq!(m.keys().len(), 99);
+
+        assert_eq!(m.remove_all(&3).map(|v| v.len()), Some(10));
+        assert_eq!(m.remove_all(&3), None);
+        assert_eq!(m.len(), 979);
+
+        m.clear();
+        assert!(m.is_empty());
+        assert!(m.iter().next().is_none());
+    }
+
+    #[test]
+    fn test_swap() {
+        let mut t = BTree::new();
+
+        for k in range(0, 1000) {
+            assert_eq!(t.swap(k, k), None);
+        }
+
+        for k in range(0, 1000) {
+            assert_eq!(t.swap(k, k + 1), Some(k));
+            assert!(!t.insert(k, k + 2));
+        }
+
+        for k in range(0, 1000) {
+            assert_eq!(t.find(&k).unwrap(), &(k + 2));
+        }
+
+        check_invariants(&*t, true);
+    }
+
+    #[test]
+    fn test_replace_value() {
+        let mut t = BTree::new();
+        assert_eq!(t.replace_value(&0, 0), None);
+        assert!(t.is_empty());
+
+        for k in range(0, 1000) {
+            t.insert(k * 2, k);
+        }
+
+        for k in range(0, 2000) {
+            let expected = if k % 2 == 0 { Some(k / 2) } else { None };
+            assert_eq!(t.replace_value(&k, -k), expected);
+        }
+
+        assert_eq!(t.len(), 1000);
+
+        for k in range(0, 1000) {
+            assert_eq!(t.find(&(k * 2)).unwrap(), &(-k * 2));
+        }
+    }
+
+    #[test]
+    fn test_insert_or_update_with() {
+        let mut t = BTree::new();
+
+        for k in range(0, 3000) {
+            let count = t.insert_or_update_with(k % 1000, 1, |_, v| *v += 1);
+            assert_eq!(*count, k / 1000 + 1);
+        }
+
+        for k in range(0, 1000) {
+            assert_eq!(t.find(&k).unwrap(), &3);
+        }
+
+        let v = t.insert_or_update_with(5, 0, |k, v| *v += *k);
+        assert_eq!(*v, 8);
+    }
+
+    #[test]
+    fn test_find_or_insert() {
+        let mut t = BTree::new();
+
+        for k in range(0, 3000) {
+            *t.find_or_insert(k % 1000, 0) += 1;
+        }
+
+        for k in range(0, 1000) {
+            assert_eq!(t.find(&k).unwrap(), &3);
+        }
+
+        let mut calls = 0;
+
+        for k in range(0, 2000) {
+            let v = t.find_or_insert_with(k, |k| { calls += 1; *k * 10 });
+            assert_eq!(*v, if k < 1000 { 3 } else { k * 10 });
+        }
+
+        assert_eq!(calls, 1000);
+    }
+
+    #[test]
+    fn test_entry() {
+        let mut t = BTree::new();
+
+        for k in range(0, 1000) {
+            match t.entry(k) {
+                Vacant(e) => {
+                    assert_eq!(e.key(), &k);
+                    assert_eq!(*e.set(k), k);
+                }
+                Occupied(_) => fail!("entry should be vacant"),
+            }
+        }
+
+        for k in range(0, 1000) {
+            match t.entry(k) {
+                Occupied(mut e) => {
+                    assert_eq!(e.key(), &k);
+                    assert_eq!(e.get(), &k);
+                    assert_eq!(e.set(k + 1), k);
+                    *e.get_mut() += 1;
+                    assert_eq!(*e.into_mut(), k + 2);
+                }
+                Vacant(_) => fail!("entry should be occupied"),
+            }
+        }
+
+        for k in range(0, 1000) {
+            match t.entry(k) {
+                Occupied(e) => {
+                    if k % 2 == 0 {
+                        assert_eq!(e.take(), k + 2);
+                    }
+                }
+                Vacant(_) => fail!("entry should be occupied"),
+            }
+        }
+
+        check_invariants(&*t, true);
+
+        for k in range(0, 1000) {
+            assert_eq!(t.contains_key(&k), k % 2 == 1);
+        }
+    }
+
+    #[test]
+    fn test_min_max() {
+        let mut t = BTree::new();
+        assert_eq!(t.min(), None);
+        assert_eq!(t.max_key(), None);
+
+        for k in range(0, 1000) {
+            t.insert(k, k * 2);
+        }
+
+        assert_eq!(t.min(), Some((&0, &0)));
+        assert_eq!(t.max(), Some((&999, &1998)));
+        assert_eq!(t.min_key(), Some(&0));
+        assert_eq!(t.max_key(), Some(&999));
+
+        t.remove(&0);
+        t.remove(&999);
+
+        assert_eq!(t.min_key(), Some(&1));
+        assert_eq!(t.max_key(), Some(&998));
+    }
+
+    #[test]
+    fn test_queue() {
+        let mut t = BTree::new();
+        t.insert(5, ~"map");
+
+        {
+            let mut queue = t.as_queue();
+
+            for &k in [3, 8, 1].iter() {
+                assert!(queue.push(k, ~"job").is_none());
+            }
+
+            assert_eq!(queue.len(), 4);
+            assert_eq!(queue.peek_min(), Some((&1, &~"job")));
+
+            let keys: ~[int] = queue.iter().map(|(&k, _)| k).collect();
+            assert_eq!(keys, ~[1, 3, 5, 8]);
+
+            assert_eq!(queue.pop_min(), Some((1, ~"job")));
+            assert_eq!(queue.pop_min(), Some((3, ~"job")));
+        }
+
+        assert_eq!(t.len(), 2);
+        assert_eq!(t.find(&5), Some(&~"map"));
+        assert_eq!(t.as_queue().pop_min(), Some((5, ~"map")));
+        assert_eq!(t.min(), Some((&8, &~"job")));
+    }
+
+    #[test]
+    fn test_pop_min_max() {
+        let mut t = BTree::new();
+        assert_eq!(t.pop_min(), None);
+        assert_eq!(t.pop_max(), None);
+
+        for k in range(0, 1000) {
+            t.insert(k, k * 2);
+        }
+
+        for k in range(0, 500) {
+            assert_eq!(t.pop_min(), Some((k, k * 2)));
+            assert_eq!(t.pop_max(), Some((999 - k, (999 - k) * 2)));
+            check_invariants(&*t, true);
+        }
+
+        assert!(t.is_empty());
+        assert_eq!(t.pop_min(), None);
+    }
+
+    #[test]
+    fn test_floor_ceiling() {
+        let mut t = BTree::new();
+        assert_eq!(t.floor(&0), None);
+        assert_eq!(t.ceiling(&0), None);
+
+        for k in range(0, 1000) {
+            t.insert(k * 10, k);
+        }
+
+        assert_eq!(t.floor(&-1), None);
+        assert_eq!(t.floor(&0), Some((&0, &0)));
+        assert_eq!(t.floor(&15), Some((&10, &1)));
+        assert_eq!(t.floor(&99999), Some((&9990, &999)));
+
+        assert_eq!(t.ceiling(&-1), Some((&0, &0)));
+        assert_eq!(t.ceiling(&15), Some((&20, &2)));
+        assert_eq!(t.ceiling(&9990), Some((&9990, &999)));
+        assert_eq!(t.ceiling(&9991), None);
+
+        for k in range(0, 9990) {
+            let (f, _) = t.floor(&k).unwrap();
+            let (c, _) = t.ceiling(&k).unwrap();
+            assert_eq!(*f, k - k % 10);
+            assert_eq!(*c, (k + 9) / 10 * 10);
+        }
+    }
+
+    #[test]
+    fn test_next_above_below() {
+        let mut t = BTree::new();
+        assert_eq!(t.next_above(&0), None);
+        assert_eq!(t.next_below(&0), None);
+
+        for k in range(0, 1000) {
+            t.insert(k * 10, k);
+        }
+
+        assert_eq!(t.next_below(&0), None);
+        assert_eq!(t.next_below(&10), Some((&0, &0)));
+        assert_eq!(t.next_above(&9990), None);
+        assert_eq!(t.next_above(&9980), Some((&9990, &999)));
+
+        for k in range(1, 999) {
+            assert_eq!(t.next_below(&(k * 10)), Some((&(k * 10 - 10), &(k - 1))));
+            assert_eq!(t.next_above(&(k * 10)), Some((&(k * 10 + 10), &(k + 1))));
+            assert_eq!(t.next_above(&(k * 10 - 5)), Some((&(k * 10), &k)));
+        }
+    }
+
+    #[test]
+    fn test_iter() {
+        let mut t = BTree::new();
+        assert_eq!(t.iter().next(), None);
+
+        for k in range(0, 10000) {
+            t.insert(k * 2, k);
+        }
+
+        for k in range(0, 1000) {
+            t.remove(&(k * 6));
+        }
+
+        let mut expected = range(0, 10000).filter(|&k| k % 3 != 0 || k >= 3000);
+
+        for (k, v) in t.iter() {
+            let e = expected.next().unwrap();
+            assert_eq!((*k, *v), (e * 2, e));
+        }
+
+        assert_eq!(expected.next(), None);
+        assert_eq!(t.iter().len(), t.len());
+    }
+
+    #[test]
+    fn test_rev_iter() {
+        let mut t = BTree::new();
+        assert_eq!(t.rev_iter().next(), None);
+
+        for k in range(0, 10000) {
+            t.insert(k, -k);
+        }
+
+        let mut expected = range(0, 10000).invert();
+
+        for (k, v) in t.rev_iter() {
+            let e = expected.next().unwrap();
+            assert_eq!((*k, *v), (e, -e));
+        }
+
+        assert_eq!(expected.next(), None);
+
+        let forward: ~[(&int, &int)] = t.iter().collect();
+        let mut backward: ~[(&int, &int)] = t.rev_iter().collect();
+        backward.reverse();
+        assert_eq!(forward, backward);
+    }
+
+    #[test]
+    fn test_mut_iter() {
+        let mut t = BTree::new();
+        assert!(t.mut_iter().next().is_none());
+
+        for k in range(0, 10000) {
+            t.insert(k, k);
+        }
+
+        let mut expected = 0;
+
+        for (k, v) in t.mut_iter() {
+            assert_eq!(*k, expected);
+            *v = -*v;
+            expected += 1;
+        }
+
+        assert_eq!(expected, 10000);
+
+        for k in range(0, 10000) {
+            assert_eq!(t.find(&k).unwrap(), &(-k));
+        }
+    }
+
+    #[test]
+    fn test_move_iter() {
+        let t: ~BTree<int, ~[int]> = BTree::new();
+        assert!(t.move_iter().next().is_none());
+
+        let mut t = BTree::new();
+
+        for k in range(0, 10000) {
+            t.insert(k, ~[k]);
+        }
+
+        let pairs: ~[(int, ~[int])] = t.move_iter().collect();
+
+        assert_eq!(pairs.len(), 10000);
+
+        for (i, (k, v)) in pairs.move_iter().enumerate() {
+            assert_eq!(k, i as int);
+            assert_eq!(v, ~[k]);
+        }
+    }
+
+    #[test]
+    fn test_range() {
+        let mut t = BTree::new();
+        assert!(t.range(Unbounded, Unbounded).next().is_none());
+        assert!(t.range(Included(&0), Excluded(&10)).next().is_none());
+
+        for k in range(0, 5000) {
+            t.insert(k * 2, k);
+        }
+
+        let bounds = [-1, 0, 1, 2, 77, 78, 4000, 9997, 9998, 9999, 20000];
+
+        for &lo in bounds.iter() {
+            for &hi in bounds.iter() {
+                let keys: ~[int] = t.range(Included(&lo), Included(&hi))
+                                    .map(|(&k, _)| k).collect();
+                let expected: ~[int] = range(0, 5000).map(|k| k * 2)
+                    .filter(|&k| lo <= k && k <= hi).collect();
+                assert_eq!(keys, expected);
+
+                let keys: ~[int] = t.range(Excluded(&lo), Excluded(&hi))
+                                    .map(|(&k, _)| k).collect();
+                let expected: ~[int] = range(0, 5000).map(|k| k * 2)
+                    .filter(|&k| lo < k && k < hi).collect();
+                assert_eq!(keys, expected);
+
+                let keys: ~[int] = t.range(Excluded(&lo), Excluded(&hi))
+                                    .invert().map(|(&k, _)| k).collect();
+                let expected: ~[int] = range(0, 5000).invert().map(|k| k * 2)
+                    .filter(|&k| lo < k && k < hi).collect();
+                assert_eq!(keys, expected);
+            }
+
+            let keys: ~[int] = t.range(Included(&lo), Unbounded)
+                                .map(|(&k, _)| k).collect();
+            let expected: ~[int] = range(0, 5000).map(|k| k * 2)
+                .filter(|&k| lo <= k).collect();
+            assert_eq!(keys, expected);
+
+            let keys: ~[int] = t.range(Unbounded, Excluded(&lo))
+                                .map(|(&k, _)| k).collect();
+            let expected: ~[int] = range(0, 5000).map(|k| k * 2)
+                .filter(|&k| k < lo).collect();
+            assert_eq!(keys, expected);
+        }
+
+        assert_eq!(t.range(Unbounded, Unbounded).len(), 5000);
+    }
+
+    #[test]
+    fn test_double_ended() {
+        let mut t = BTree::new();
+        assert_eq!(t.iter().next_back(), None);
+
+        for k in range(0, 10000) {
+            t.insert(k, k);
+        }
+
+        let mut iter = t.iter();
+        let mut lo = 0;
+        let mut hi = 9999;
+
+        while lo <= hi {
+            assert_eq!(iter.next(), Some((&lo, &lo)));
+            lo += 1;
+
+            if lo <= hi {
+                assert_eq!(iter.next_back(), Some((&hi, &hi)));
+                hi -= 1;
+            }
+        }
+
+        assert_eq!(iter.next(), None);
+        assert_eq!(iter.next_back(), None);
+
+        let keys: ~[int] = t.keys().invert().map(|&k| k).collect();
+        assert_eq!(keys, range(0, 10000).invert().collect());
+
+        let values: ~[int] = t.rev_iter().invert().map(|(_, &v)| v).collect();
+        assert_eq!(values, range(0, 10000).collect());
+
+        let mut iter = t.range(Excluded(&100), Included(&200));
+        assert_eq!(iter.next_back(), Some((&200, &200)));
+        assert_eq!(iter.next(), Some((&101, &101)));
+
+        let rest: ~[int] = iter.invert().map(|(&k, _)| k).collect();
+        assert_eq!(rest, range(102, 200).invert().collect());
+
+        let mut iter = t.range(Included(&5), Included(&5));
+        assert_eq!(iter.next_back(), Some((&5, &5)));
+        assert_eq!(iter.next(), None);
+    }
+
+    #[test]
+    fn test_size_hint() {
+        let mut t = BTree::new();
+        assert_eq!(t.iter().size_hint(), (0, Some(0)));
+
+        for k in range(0, 1000) {
+            t.insert(k, k);
+        }
+
+        let mut iter = t.iter();
+        assert_eq!(iter.size_hint(), (1000, Some(1000)));
+
+        iter.next();
+        iter.next_back();
+        assert_eq!(iter.size_hint(), (998, Some(998)));
+        assert_eq!(iter.len(), 998);
+
+        assert_eq!(t.keys().size_hint(), (1000, Some(1000)));
+        assert_eq!(t.values().rposition(|&v| v == 123), Some(123));
+        assert_eq!(t.rev_iter().skip(10).size_hint(), (990, Some(990)));
+        assert_eq!(t.mut_iter().size_hint(), (1000, Some(1000)));
+
+        let pairs: ~[(&int, &int)] = t.iter().collect();
+        assert_eq!(pairs.capacity(), 1000);
+
+        let mut iter = t.range(Included(&10), Excluded(&20));
+        assert_eq!(iter.size_hint(), (10, Some(10)));
+        iter.next_back();
+        assert_eq!(iter.size_hint(), (9, Some(9)));
+        assert_eq!(t.range(Excluded(&10), Unbounded).size_hint(),
+                   (989, Some(989)));
+        assert_eq!(t.range(Excluded(&5000), Unbounded).size_hint(),
+                   (0, Some(0)));
+
+        for mut iter in t.split_iters().move_iter() {
+            let (n, _) = iter.size_hint();
+            assert_eq!(iter.count(), n);
+        }
+
+        let mut iter = t.move_iter();
+        iter.next();
+        assert_eq!(iter.size_hint(), (999, Some(999)));
+    }
+
+    #[test]
+    fn test_cursor() {
+        let mut t = BTree::new();
+
+        {
+            let mut c = t.cursor();
+            assert_eq!(c.get(), None);
+            assert!(!c.seek(&0));
+            assert!(!c.seek_last());
+            assert_eq!(c.next(), None);
+        }
+
+        for k in range(0, 5000) {
+            t.insert(k * 2, k);
+        }
+
+        let mut c = t.cursor();
+        assert_eq!(c.get(), Some((&0, &0)));
+
+        for (k, v) in t.iter().skip(1) {
+            assert_eq!(c.next(), Some((k, v)));
+        }
+
+        assert_eq!(c.next(), None);
+        assert_eq!(c.prev(), None);
+
+        assert!(c.seek_last());
+        assert_eq!(c.key(), Some(&9998));
+
+        for (k, v) in t.rev_iter().skip(1) {
+            assert_eq!(c.prev(), Some((k, v)));
+        }
+
+        assert_eq!(c.prev(), None);
+
+        for k in range(-1, 10000) {
+            assert!(c.seek(&k));
+            let expected = if k % 2 == 0 { k } else { k + 1 };
+            assert_eq!(c.key(), Some(&expected));
+            assert_eq!(c.value(), Some(&(expected / 2)));
+        }
+
+        assert!(!c.seek(&9999));
+        assert_eq!(c.get(), None);
+
+        for &k in [7776, 12, 9000, 12, 14, 0, 9998].iter() {
+            assert!(c.seek(&k));
+            assert_eq!(c.key(), Some(&k));
+            assert_eq!(c.prev().map(|(&k, _)| k), if k > 0 { Some(k - 2) }
+                                                  else { None });
+        }
+    }
+
+    #[test]
+    fn test_mut_cursor() {
+        let mut t = BTree::new();
+
+        for k in range(0, 1000) {
+            t.insert(k * 2, k);
+        }
+
+        {
+            let mut c = t.mut_cursor();
+
+            // Remove every key that is a multiple of 4, and insert an odd key
+            // before every other key.
+            loop {
+                let (k, remove) = match c.get() {
+                    Some((&k, v)) => { *v += 1; (k, k % 4 == 0) }
+                    None => break,
+                };
+
+                if remove {
+                    assert_eq!(c.remove_current().map(|(k, _)| k), Some(k));
+                } else {
+                    assert!(c.insert_before(k - 1, -1).is_ok());
+                    assert!(c.insert_before(k - 1, -1).is_err());
+                    c.next();
+                }
+            }
+
+            assert!(c.insert_before(0, 0).is_err());
+            assert!(c.insert_before(5000, 0).is_ok());
+            assert_eq!(c.prev().map(|(&k, _)| k), Some(5000));
+            assert_eq!(c.prev().map(|(&k, _)| k), Some(1998));
+        }
+
+        assert_eq!(t.len(), 1001);
+        check_invariants(&*t, true);
+
+        for k in range(0, 1000) {
+            if k % 2 == 0 {
+                assert_eq!(t.find(&(k * 2)), None);
+                assert_eq!(t.find(&(k * 2 + 1)).unwrap(), &-1);
+            } else {
+                assert_eq!(t.find(&(k * 2)).unwrap(), &(k + 1));
+                assert_eq!(t.find(&(k * 2 - 1)).unwrap(), &-1);
+            }
+        }
+
+        // Equal keys are visited one at a time, and a removal takes the pair
+        // at the cursor.
+        let mut t = BTree::with_duplicates(Keep);
+        t.insert(1, -1);
+        t.insert(9, -1);
+
+        for v in range(0, 100) {
+            t.insert(5, v);
+        }
+
+        {
+            let mut c = t.mut_cursor();
+            assert!(c.seek(&5));
+
+            for v in range(0, 100).invert() {
+                assert_eq!(c.get().map(|(k, v)| (*k, *v)), Some((5, v)));
+
+                if v % 2 == 0 {
+                    assert_eq!(c.remove_current(), Some((5, v)));
+                } else {
+                    c.next();
+                }
+            }
+
+            assert_eq!(c.get().map(|(k, v)| (*k, *v)), Some((9, -1)));
+            assert_eq!(c.prev().map(|(k, v)| (*k, *v)), Some((5, 1)));
+            assert!(c.next().is_some());
+            assert!(c.next().is_none());
+            assert_eq!(c.prev().map(|(k, v)| (*k, *v)), Some((9, -1)));
+        }
+
+        check_invariants(&*t, true);
+
+        let values: ~[int] = t.iter().map(|(_, &v)| v).collect();
+        let mut expected = ~[-1];
+        expected.push_all_move(range(0, 50).invert().map(|v| v * 2 + 1)
+                                                    .collect());
+        expected.push(-1);
+        assert_eq!(values, expected);
+
+        // Insertions at the same position split the nodes on the path of the
+        // cursor.
+        let mut t = BTree::new();
+        t.insert(0, 0);
+        t.insert(10000, 0);
+
+        {
+            let mut c = t.mut_cursor();
+            assert!(c.seek(&10000));
+
+            for k in range(1, 5000) {
+                assert!(c.insert_before(k, k).is_ok());
+                assert_eq!(c.get().map(|(&k, _)| k), Some(10000));
+            }
+
+            assert_eq!(c.prev().map(|(&k, _)| k), Some(4999));
+        }
+
+        assert_eq!(t.len(), 5001);
+        check_invariants(&*t, true);
+        assert_eq!(t.select(2500), Some((&2500, &2500)));
+    }
+
+    #[test]
+    fn test_generation() {
+        let mut t = range_tree(0, 1000);
+        let g = t.generation();
+
+        // Lookups and changes of values in place keep the generation.
+        t.find(&10);
+        *t.find_mut(&10).unwrap() = 0;
+        t.iter().len();
+        assert_eq!(t.generation(), g);
+
+        t.insert(1000, 1000);
+        assert!(t.generation() > g);
+
+        let g = t.generation();
+        t.pop(&0);
+        assert!(t.generation() > g);
+
+        let g = t.generation();
+        let right = t.split_off(&500);
+        assert!(t.generation() > g);
+
+        let g = t.generation();
+        t.append(right);
+        assert!(t.generation() > g);
+
+        // The changes of a mutable cursor keep it in step with the tree.
+        let g = t.generation();
+
+        {
+            let mut c = t.mut_cursor();
+            assert!(c.remove_current().is_some());
+            assert!(c.insert_before(1, 1).is_ok());
+            assert!(c.next().is_some());
+        }
+
+        assert!(t.generation() > g);
+        check_invariants(&*t, true);
+    }
+
+    #[test]
+    #[cfg(debug)]
+    #[should_fail]
+    fn test_generation_mismatch() {
+        let t = range_tree(0, 1000);
+        let mut iter = t.iter();
+        iter.next();
+
+        // Change the tree under the iterator, as code that gets around the
+        // borrow of the tree could.
+        unsafe {
+            let tree: &mut BTree<int, int> = ::std::cast::transmute_mut(&*t);
+            tree.insert(1000, 1000);
+        }
+
+        iter.next();
+    }
+
+    #[test]
+    fn test_each() {
+        let t = range_tree(0, 1000);
+
+        let mut expected = 0;
+        assert!(t.each(|&k, &v| {
+            assert_eq!((k, v), (expected, expected));
+            expected += 1;
+            true
+        }));
+        assert_eq!(expected, 1000);
+
+        assert!(t.each_reverse(|&k, _| {
+            expected -= 1;
+            assert_eq!(k, expected);
+            true
+        }));
+        assert_eq!(expected, 0);
+
+        let mut seen = 0;
+        assert!(!t.each(|&k, _| { seen += 1; k < 500 }));
+        assert_eq!(seen, 501);
+
+        seen = 0;
+        assert!(!t.each_reverse(|&k, _| { seen += 1; k > 990 }));
+        assert_eq!(seen, 10);
+    }
+
+    #[test]
+    fn test_fold_range() {
+        let t = range_tree(0, 1000);
+
+        assert_eq!(t.fold_range(&10, &20, 0, |acc, _, &v| acc + v), 145);
+        assert_eq!(t.fold_range(&20, &10, 0, |acc, _, &v| acc + v), 0);
+        assert_eq!(t.fold_range(&-5, &5000, 0, |acc, _, _| acc + 1), 1000);
+
+        let keys = t.fold_range(&995, &1000, ~[], |mut acc, &k, _| {
+            acc.push(k);
+            acc
+        });
+        assert_eq!(keys, ~[995, 996, 997, 998, 999]);
+    }
+
+    #[test]
+    fn test_range_len() {
+        let t = range_tree(0, 1000);
+
+        assert_eq!(t.range_len(&10, &20), 10);
+        assert_eq!(t.range_len(&20, &10), 0);
+        assert_eq!(t.range_len(&-5, &5000), 1000);
+        assert_eq!(t.range_len(&995, &995), 0);
+    }
+
+    struct Sum;
+
+    impl Monoid<int, int, int> for Sum {
+        fn summarize(&self, _: &int, value: &int) -> int { *value }
+        fn combine(&self, a: &int, b: &int) -> int { *a + *b }
+    }
+
+    struct Keys;
+
+    impl Monoid<int, int, ~[int]> for Keys {
+        fn summarize(&self, key: &int, _: &int) -> ~[int] { ~[*key] }
+        fn combine(&self, a: &~[int], b: &~[int]) -> ~[int] {
+            vec::append(a.clone(), b.as_slice())
+        }
+    }
+
+    fn check_summaries<K, V, S: Eq + 'static>(t: &BTree<K, V>) {
+        let aggregate = t.aggregate.get_ref();
+
+        for item in t.nodes.iter() {
+            match *item {
+                Some(TreeNode { value: ref child }) => {
+                    check_summaries::<K, V, S>(child.get());
+
+                    match child.summary() {
+                        Some(kept) => {
+                            let summary = super::node_summary(child.get(),
+                                                              aggregate);
+                            let summary = summary.unwrap();
+                            assert!(super::unbox_summary::<S>(kept) ==
+                                    super::unbox_summary::<S>(&summary));
+                        }
+                        None => {}
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn summaries_kept<K, V>(t: &BTree<K, V>) -> bool {
+        t.nodes.iter().all(|item| match *item {
+            Some(TreeNode { value: ref child }) => {
+                child.summary().is_some() && summaries_kept(child.get())
+            }
+            _ => true,
+        })
+    }
+
+    #[test]
+    fn test_aggregate() {
+        let mut t = BTree::with_aggregate::<int, Sum>(Sum);
+        let mut m = TreeMap::new();
+        let mut rng = IsaacRng::new();
+
+        assert_eq!(t.range_aggregate::<int>(&0, &10), None);
+        assert_eq!(t.aggregate::<int>(), None);
+
+        for _ in range(0, 5000) {
+            let k = rng.gen_range(0, 2000);
+            let v = rng.gen_range(-100, 100);
+            assert_eq!(t.swap(k, v), m.swap(k, v));
+        }
+
+        assert_eq!(t.aggregate::<int>(),
+                   Some(m.iter().fold(0, |acc, (_, &v)| acc + v)));
+        check_summaries::<int, int, int>(&*t);
+
+        for _ in range(0, 3000) {
+            let k = rng.gen_range(0, 2000);
+            assert_eq!(t.pop(&k), m.pop(&k));
+        }
+
+        assert_eq!(t.len(), m.len());
+
+        for _ in range(0, 200) {
+            let lo = rng.gen_range(-10, 2010);
+            let hi = lo + rng.gen_range(0, 500);
+            let mut sum = None;
+
+            for (&k, &v) in m.iter() {
+                if lo <= k && k < hi {
+                    sum = Some(sum.unwrap_or(0) + v);
+                }
+            }
+
+            assert_eq!(t.range_aggregate::<int>(&lo, &hi), sum);
+            assert_eq!(t.range_aggregate::<int>(&hi, &lo), None);
+
+            // A change computes the summaries on its path again.
+            let k = rng.gen_range(0, 2000);
+            let v = rng.gen_range(-100, 100);
+            assert_eq!(t.swap(k, v), m.swap(k, v));
+        }
+
+        check_summaries::<int, int, int>(&*t);
+
+        let total = m.iter().fold(0, |acc, (_, &v)| acc + v);
+        assert_eq!(t.aggregate::<int>(), Some(total));
+
+        // The trees that are split off or copied keep summaries of their own
+        // pairs, and a copy leaves the summaries of the shared nodes alone.
+        let copy = t.snapshot();
+        let right = t.split_off(&1000);
+        let left: int = m.iter().filter(|&(&k, _)| k < 1000)
+                         .fold(0, |acc, (_, &v)| acc + v);
+        assert_eq!(t.aggregate::<int>(), Some(left));
+        assert_eq!(right.aggregate::<int>(), Some(total - left));
+        assert_eq!(copy.aggregate::<int>(), Some(total));
+        check_summaries::<int, int, int>(&*t);
+        check_summaries::<int, int, int>(&*right);
+        check_summaries::<int, int, int>(&*copy);
+
+        // A query keeps the summaries of the nodes that are shared with a
+        // snapshot, so later queries of either tree find them kept.
+        let mut big = BTree::with_aggregate::<int, Sum>(Sum);
+
+        for k in range(0, 5000) {
+            big.insert(k, k);
+        }
+
+        assert_eq!(big.aggregate::<int>(), Some(4999 * 5000 / 2));
+        assert!(summaries_kept(&*big));
+
+        // A value changed through a reference drops the summaries on its
+        // path, which are not computed until the next query.
+        *big.find_mut(&2500).unwrap() = 0;
+
+        let snapshot = big.snapshot();
+        assert!(!summaries_kept(&*snapshot));
+        assert_eq!(snapshot.range_aggregate::<int>(&0, &5000),
+                   Some(4999 * 5000 / 2 - 2500));
+        assert!(summaries_kept(&*snapshot));
+        assert!(summaries_kept(&*big));
+
+        // An insertion computes the summaries of the nodes it copied.
+        big.swap(1, 0);
+        assert!(summaries_kept(&*big));
+        assert_eq!(big.aggregate::<int>(), Some(4999 * 5000 / 2 - 2501));
+        assert_eq!(snapshot.aggregate::<int>(), Some(4999 * 5000 / 2 - 2500));
+        check_summaries::<int, int, int>(&*big);
+        check_summaries::<int, int, int>(&*snapshot);
+
+        let mut plain: ~BTree<int, int> = BTree::new();
+        plain.insert(1, 1);
+        assert_eq!(plain.aggregate::<int>(), None);
+        assert_eq!(plain.range_aggregate::<int>(&0, &10), None);
+
+        let mut keys = BTree::with_aggregate::<~[int], Keys>(Keys);
+
+        for k in range(0, 1000).invert() {
+            keys.insert(k * 2, k);
+        }
+
+        let expected: ~[int] = range(5, 50).map(|k| k * 2).collect();
+        assert_eq!(keys.range_aggregate::<~[int]>(&9, &100), Some(expected));
+        assert_eq!(keys.range_aggregate::<~[int]>(&11, &12), None);
+        check_summaries::<int, int, ~[int]>(&*keys);
+
+        for k in range(0, 1000) {
+            assert_eq!(keys.pop(&(k * 2)), Some(k));
+        }
+
+        assert!(keys.is_empty());
+        assert_eq!(keys.aggregate::<~[int]>(), None);
+    }
+
+    #[test]
+    fn test_drain() {
+        let mut t = BTree::new();
+
+        for k in range(0, 10000) {
+            t.insert(k, ~[k]);
+        }
+
+        let nodes = t.node_count();
+
+        {
+            let mut drain = t.drain();
+            assert_eq!(drain.size_hint(), (10000, Some(10000)));
+
+            for k in range(0, 10000) {
+                assert_eq!(drain.next(), Some((k, ~[k])));
+            }
+
+            assert_eq!(drain.next(), None);
+        }
+
+        assert!(t.is_empty());
+        assert_eq!(t.find(&0), None);
+        assert_eq!(t.spare.len(), nodes);
+        check_invariants(&*t, true);
+
+        for k in range(0, 100) {
+            t.insert(k, ~[k]);
+        }
+
+        {
+            let mut drain = t.drain();
+            assert_eq!(drain.next(), Some((0, ~[0])));
+        }
+
+        assert!(t.is_empty());
+        check_invariants(&*t, true);
+    }
+
+    /// A probe that matches the keys from `10 * d` to `10 * d + 9`.
+    struct Decade(int);
+
+    impl Equiv<int> for Decade {
+        fn equiv(&self, other: &int) -> bool {
+            let Decade(d) = *self;
+            d == *other / 10
+        }
+    }
+
+    impl KeyEquiv<int> for Decade {
+        fn cmp_key(&self, key: &int) -> Ordering {
+            let Decade(d) = *self;
+            d.cmp(&(*key / 10))
+        }
+    }
+
+    #[test]
+    fn test_range_seek() {
+        let mut t = BTree::new();
+
+        for k in range(0, 5000) {
+            t.insert(k * 2, k);
+        }
+
+        let mut iter = t.range(Unbounded, Unbounded);
+        assert_eq!(iter.next(), Some((&0, &0)));
+
+        iter.seek(&77);
+        assert_eq!(iter.size_hint(), (4961, Some(4961)));
+        assert_eq!(iter.next(), Some((&78, &39)));
+
+        iter.seek(&10);
+        assert_eq!(iter.size_hint(), (4960, Some(4960)));
+        assert_eq!(iter.next(), Some((&80, &40)));
+
+        iter.seek(&82);
+        assert_eq!(iter.next(), Some((&82, &41)));
+
+        for &key in [85, 1000, 1001, 4443, 9000].iter() {
+            iter.seek(&key);
+            let next = (key + 1) / 2 * 2;
+            assert_eq!(iter.next(), Some((&next, &(next / 2))));
+        }
+
+        let keys: ~[int] = iter.map(|(&k, _)| k).collect();
+        let expected: ~[int] = range(4501, 5000).map(|k| k * 2).collect();
+        assert_eq!(keys, expected);
+
+        let mut iter = t.range(Included(&100), Excluded(&200));
+        assert_eq!(iter.next_back(), Some((&198, &99)));
+
+        iter.seek(&198);
+        assert_eq!(iter.next(), None);
+
+        let mut iter = t.range(Included(&100), Excluded(&200));
+        iter.seek(&196);
+        assert_eq!(iter.size_hint(), (2, Some(2)));
+        assert_eq!(iter.next_back(), Some((&198, &99)));
+        assert_eq!(iter.next(), Some((&196, &98)));
+        assert_eq!(iter.next(), None);
+
+        let mut iter = t.range(Included(&100), Excluded(&200));
+        iter.seek(&300);
+        assert_eq!(iter.next(), None);
+        assert_eq!(iter.next_back(), None);
+    }
+
+    #[test]
+    fn test_split_iters() {
+        let mut t = BTree::new();
+        assert_eq!(t.split_iters().len(), 1);
+        assert!(t.split_iters()[0].next().is_none());
+
+        for k in range(0, 10) {
+            t.insert(k, k);
+        }
+
+        assert_eq!(t.split_iters().len(), 1);
+
+        for k in range(10, 5000) {
+            t.insert(k, k);
+        }
+
+        let iters = t.split_iters();
+        assert!(iters.len() > 1);
+
+        let mut keys = ~[];
+        let mut sums = ~[];
+
+        for iter in iters.move_iter() {
+            let mut sum = 0;
+
+            for (&k, &v) in iter {
+                keys.push(k);
+                sum += v;
+            }
+
+            sums.push(sum);
+        }
+
+        assert_eq!(keys, range(0, 5000).collect());
+        assert_eq!(sums.iter().fold(0, |a, &b| a + b), 4999 * 5000 / 2);
+
+        for mut iter in t.split_iters().move_iter() {
+            let (&first, _) = iter.next().unwrap();
+            let (&last, _) = iter.next_back().unwrap();
+            assert!(first < last);
+            assert_eq!(iter.len() as int, last - first - 1);
+        }
+    }
+
+    #[test]
+    fn test_tuple_keys() {
+        let mut t = BTree::new();
+
+        for a in range(0, 50) {
+            for b in range(0, 40) {
+                if a != 7 {
+                    assert!(t.insert((a, b * 2), a * 100 + b));
+                }
+            }
+        }
+
+        check_invariants(&*t, true);
+        assert_eq!(t.find(&(3, 8)), Some(&304));
+        assert_eq!(t.find(&(3, 9)), None);
+
+        for a in range(-1, 51) {
+            let keys: ~[(int, int)] = t.prefix_range(&Prefix(&a))
+                                       .map(|(&k, _)| k).collect();
+            let expected: ~[(int, int)] = if a < 0 || a >= 50 || a == 7 {
+                ~[]
+            } else {
+                range(0, 40).map(|b| (a, b * 2)).collect()
+            };
+            assert_eq!(keys, expected);
+        }
+
+        let keys: ~[(int, int)] = t.suffix_range(&Prefix(&3), Included(&10),
+                                                 Excluded(&20))
+                                   .map(|(&k, _)| k).collect();
+        assert_eq!(keys, ~[(3, 10), (3, 12), (3, 14), (3, 16), (3, 18)]);
+
+        let keys: ~[(int, int)] = t.suffix_range(&Prefix(&3), Excluded(&74),
+                                                 Unbounded)
+                                   .map(|(&k, _)| k).collect();
+        assert_eq!(keys, ~[(3, 76), (3, 78)]);
+
+        let keys: ~[(int, int)] = t.suffix_range(&Prefix(&49), Unbounded,
+                                                 Included(&2))
+                                   .invert().map(|(&k, _)| k).collect();
+        assert_eq!(keys, ~[(49, 2), (49, 0)]);
+
+        let all: Bound<int> = Unbounded;
+        assert!(t.suffix_range(&Prefix(&7), all, all).next().is_none());
+        assert!(t.suffix_range(&Prefix(&3), Included(&11), Excluded(&12))
+                 .next().is_none());
+
+        let mut t = BTree::new();
+
+        for k in range(0, 1000) {
+            t.insert((k / 100, k / 10 % 10, k % 10), k);
+        }
+
+        let values: ~[int] = t.prefix_range(&Prefix(&4)).map(|(_, &v)| v)
+                              .collect();
+        assert_eq!(values, range(400, 500).collect());
+
+        let values: ~[int] = t.prefix_range(&Prefix2(&4, &2))
+                              .map(|(_, &v)| v).collect();
+        assert_eq!(values, range(420, 430).collect());
+
+        let values: ~[int] = t.suffix_range(&Prefix(&4), Excluded(&2),
+                                            Included(&3))
+                              .map(|(_, &v)| v).collect();
+        assert_eq!(values, range(430, 440).collect());
+
+        let values: ~[int] = t.suffix_range(&Prefix2(&4, &2), Included(&5),
+                                            Unbounded)
+                              .map(|(_, &v)| v).collect();
+        assert_eq!(values, ~[425, 426, 427, 428, 429]);
+
+        let mut t = BTree::new();
+
+        for k in range(0, 1000) {
+            t.insert((k / 100, k / 10 % 10, k % 10 / 5, k % 5), k);
+        }
+
+        check_invariants(&*t, true);
+
+        let values: ~[int] = t.prefix_range(&Prefix3(&9, &9, &1))
+                              .map(|(_, &v)| v).collect();
+        assert_eq!(values, range(995, 1000).collect());
+
+        let values: ~[int] = t.suffix_range(&Prefix3(&0, &1, &0), Unbounded,
+                                            Excluded(&2))
+                              .map(|(_, &v)| v).collect();
+        assert_eq!(values, ~[10, 11]);
+
+        let values: ~[int] = t.suffix_range(&Prefix2(&5, &5), Included(&1),
+                                            Included(&1))
+                              .map(|(_, &v)| v).collect();
+        assert_eq!(values, range(555, 560).collect());
+        let all: Bound<int> = Unbounded;
+        assert!(t.suffix_range(&Prefix(&10), all, all).next().is_none());
+    }
+
+    #[test]
+    fn test_prefix_range() {
+        let mut t = BTree::new();
+
+        for k in range(0, 1000) {
+            if k / 10 != 42 {
+                t.insert(k, k);
+            }
+        }
+
+        for d in range(0, 100) {
+            let keys: ~[int] = t.prefix_range(&Decade(d)).map(|(&k, _)| k)
+                                .collect();
+            let expected: ~[int] = if d == 42 { ~[] }
+                                   else { range(d * 10, d * 10 + 10).collect() };
+            assert_eq!(keys, expected);
+        }
+
+        let mut iter = t.prefix_range(&Decade(7));
+        assert_eq!(iter.next_back(), Some((&79, &79)));
+        assert_eq!(iter.next(), Some((&70, &70)));
+        assert_eq!(iter.len(), 8);
+
+        assert_eq!(t.prefix_range(&Decade(100)).next(), None);
+        assert_eq!(t.prefix_range(&Decade(-1)).next(), None);
+
+        let key = (3, 7);
+        assert!(Prefix(&3).equiv(&key));
+        assert!(!Prefix(&4).equiv(&key));
+        assert_eq!(Prefix(&2).cmp_key(&key), Less);
+        assert_eq!(Prefix(&3).cmp_key(&key), Equal);
+
+        let key = (3, 7, 1);
+        assert!(Prefix2(&3, &7).equiv(&key));
+        assert!(!Prefix2(&3, &8).equiv(&key));
+        assert_eq!(Prefix2(&3, &8).cmp_key(&key), Greater);
+        assert_eq!(Prefix2(&2, &8).cmp_key(&key), Less);
+    }
+
+    #[test]
+    fn test_keys_values() {
+        let mut t = BTree::new();
+        assert_eq!(t.keys().next(), None);
+        assert_eq!(t.values().next(), None);
+
+        for k in range(0, 5000) {
+            t.insert(k * 3, k);
+        }
+
+        let keys: ~[int] = t.keys().map(|&k| k).collect();
+        let values: ~[int] = t.values().map(|&v| v).collect();
+
+        assert_eq!(keys, range(0, 5000).map(|k| k * 3).collect());
+        assert_eq!(values, range(0, 5000).collect());
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mut t = BTree::new();
+        assert!(t.snapshot().is_empty());
+
+        for k in range(0, 5000) {
+            t.insert(k * 2, k);
+        }
+
+        let snapshot = t.snapshot();
+        let mut iter = snapshot.iter();
+
+        for k in range(0, 5000) {
+            assert_eq!(iter.next(), Some((&(k * 2), &k)));
+
+            t.insert(k * 2 + 1, k);
+            t.remove(&(k * 2));
+        }
+
+        assert_eq!(iter.next(), None);
+        assert_eq!(snapshot.len(), 5000);
+        assert_eq!(t.len(), 5000);
+        assert_eq!(t.find(&0), None);
+        assert_eq!(snapshot.find(&0), Some(&0));
+    }
+
+    #[test]
+    fn test_snapshot_copy_on_write() {
+        let mut t = BTree::new();
+
+        for k in range(0, 5000) {
+            t.insert(k, k);
+        }
+
+        let mut a = t.snapshot();
+        let b = a.snapshot();
+
+        *t.find_mut(&10).unwrap() = -1;
+        a.insert(5000, 5000);
+        a.remove(&20);
+        check_invariants(&*a, true);
+        check_invariants(&*b, true);
+        assert_eq!(t.find(&10), Some(&-1));
+        t.clear();
+
+        assert_eq!(a.find(&10), Some(&10));
+        assert_eq!(a.find(&20), None);
+        assert_eq!(a.len(), 5000);
+        assert_eq!(b.find(&20), Some(&20));
+        assert_eq!(b.find(&5000), None);
+        assert_eq!(b.len(), 5000);
+
+        let pairs: ~[(int, int)] = b.move_iter().collect();
+        assert_eq!(pairs, range(0, 5000).map(|k| (k, k)).collect());
+        check_invariants(&*a, true);
+    }
+
+    #[test]
+    fn test_chunks() {
+        let mut t = BTree::new();
+        assert!(t.chunks().next().is_none());
+
+        for k in range(0, 5000) {
+            t.insert(k * 3, k);
+        }
+
+        let mut pairs = ~[];
+        let mut runs = 0;
+
+        for chunk in t.chunks() {
+            assert!(!chunk.is_empty());
+            assert_eq!(chunk.keys().size_hint(),
+                       (chunk.len(), Some(chunk.len())));
+            assert_eq!(chunk.keys().indexable(), chunk.len());
+            assert_eq!(chunk.key(chunk.len()), None);
+            assert_eq!(chunk.value(chunk.len()), None);
+
+            if chunk.len() > 1 {
+                assert!(chunk.len() >= BTREE_KEYS_LBOUND);
+                runs += 1;
+            }
+
+            for i in range(0, chunk.len()) {
+                assert_eq!(chunk.keys().idx(i), chunk.key(i));
+                assert_eq!(chunk.values().idx(i), chunk.value(i));
+                pairs.push((*chunk.key(i).unwrap(), *chunk.value(i).unwrap()));
+            }
+
+            let keys: ~[&int] = chunk.keys().collect();
+            let values: ~[&int] = chunk.values().invert().collect();
+            assert_eq!(keys.len(), values.len());
+        }
+
+        let expected: ~[(int, int)] = range(0, 5000).map(|k| (k * 3, k))
+                                                    .collect();
+        assert_eq!(pairs, expected);
+        assert!(runs >= 5000 / (BTREE_KEYS_UBOUND + 1));
+        assert!(runs <= 5000 / BTREE_KEYS_LBOUND);
+    }
+
+    #[test]
+    fn test_len() {
+        let mut t = BTree::new();
+
+        for k in range(0, 1000) {
+            assert!(t.insert(k, k));
+            assert!(!t.insert(k, k));
+            assert_eq!(t.len(), k as uint + 1);
+        }
+
+        *t.find_or_insert(1000, 0) += 1;
+        *t.find_or_insert(1000, 0) += 1;
+        assert_eq!(t.len(), 1001);
+
+        match t.entry(1000) {
+            Occupied(e) => { e.take(); }
+            Vacant(_) => fail!("entry should be occupied"),
+        }
+
+        assert_eq!(t.len(), 1000);
+
+        t.pop_min();
+        t.pop_max();
+        assert_eq!(t.len(), 998);
+
+        for k in range(0, 1000) {
+            t.remove(&k);
+        }
+
+        assert_eq!(t.len(), 0);
+        assert!(t.is_empty());
+    }
+
+    #[test]
+    fn test_with_capacity() {
+        let mut t = BTree::with_capacity(10000);
+        let spare = t.spare.len();
+
+        assert!(spare > 0);
+        assert!(t.is_empty());
+
+        for k in range(0, 10000) {
+            t.insert(k, k);
+        }
+
+        // All nodes other than the root came from the pool.
+        assert!(!t.spare.is_empty());
+        assert_eq!(t.spare.len() + t.node_count() - 1, spare);
+        check_invariants(&*t, true);
+
+        for k in range(0, 10000) {
+            assert_eq!(t.find(&k).unwrap(), &k);
+        }
+
+        let mut e: ~BTree<int, int> = BTree::with_capacity(0);
+        assert!(e.spare.is_empty());
+        e.insert(1, 1);
+        assert_eq!(e.len(), 1);
+    }
+
+    #[test]
+    fn test_structure_metrics() {
+        let mut t = BTree::new();
+        assert_eq!(t.height(), 1);
+        assert_eq!(t.node_count(), 1);
+        assert_eq!(t.leaf_count(), 0);
+
+        for k in range(0, BTREE_KEYS_UBOUND) {
+            t.insert(k, k);
+        }
+
+        assert_eq!(t.height(), 1);
+        assert_eq!(t.node_count(), 1);
+        assert_eq!(t.leaf_count(), BTREE_KEYS_UBOUND);
+
+        t.insert(BTREE_KEYS_UBOUND, 0);
+
+        assert_eq!(t.height(), 2);
+        assert_eq!(t.node_count(), 3);
+        assert_eq!(t.leaf_count(), BTREE_KEYS_UBOUND + 1);
+
+        for k in range(0, 100000) {
+            t.insert(k, k);
+        }
+
+        assert_eq!(t.leaf_count(), t.len());
+        assert!(t.height() >= 3);
+        assert!(t.node_count() >= t.len() / BTREE_KEYS_UBOUND);
+    }
+
+    #[test]
+    fn test_retain() {
+        let mut t = BTree::new();
+
+        for k in range(0, 10000) {
+            t.insert(k, k * 2);
+        }
+
+        t.retain(|k, v| *k % 3 == 0 && *v % 2 == 0);
+
+        assert_eq!(t.len(), 3334);
+        check_invariants(&*t, true);
+
+        for k in range(0, 10000) {
+            assert_eq!(t.find(&k).is_some(), k % 3 == 0);
+        }
+
+        t.retain(|_, _| false);
+        assert!(t.is_empty());
+        check_invariants(&*t, true);
+    }
+
+    #[test]
+    fn test_truncate() {
+        let mut t = range_tree(0, 10000);
+
+        t.truncate(20000);
+        assert_eq!(t.len(), 10000);
+
+        t.truncate(777);
+        assert_eq!(t.len(), 777);
+        assert_eq!(t.max_key(), Some(&776));
+        check_invariants(&*t, true);
+
+        for k in range(0, 777) {
+            assert_eq!(t.find(&k).unwrap(), &k);
+        }
+
+        t.truncate(0);
+        assert!(t.is_empty());
+        check_invariants(&*t, true);
+
+        let mut t = BTree::with_duplicates(Keep);
+
+        for k in range(0, 1000) {
+            t.insert(k / 100, k);
+        }
 
-    let mut random_keys = ~[];
-    for k in range(0, iterations) { random_keys.push(k); }
-    rng.shuffle_mut(random_keys);
+        t.truncate(250);
+        assert_eq!(t.len(), 250);
+        assert_eq!(t.max_key(), Some(&2));
+        assert_eq!(t.range_len(&2, &3), 50);
+        check_invariants(&*t, true);
+    }
 
-    do bh.iter {
+    #[test]
+    fn test_drain_clear() {
         let mut t = BTree::new();
+        assert_eq!(t.drain_clear(), ~[]);
 
-        for &key in random_keys.iter() {
-            t.insert(key, key);
+        for k in range(0, 1000).invert() {
+            t.insert(k, ~[k]);
         }
-    }
-}
 
-#[cfg(test)]
-mod test_btree {
+        let entries = t.drain_clear();
+        assert!(t.is_empty());
+        assert_eq!(t.find(&0), None);
+        check_invariants(&*t, true);
 
-    use super::*;
-    use std::rand::{Rng, IsaacRng, SeedableRng};
-    use std::iter::range;
+        assert_eq!(entries.len(), 1000);
 
-    fn tree<K, V>(keys: [Option<K>, ..BTREE_KEYS_UBOUND],
-                  nodes: [Option<TreeItem<K, V>>, ..BTREE_KEYS_UBOUND + 1])
-        -> ~BTree<K, V> {
-        ~BTree { used: keys.iter().filter(|x| x.is_some()).len(),
-            keys : keys, nodes: nodes }
-    }
+        for (i, &(k, ref v)) in entries.iter().enumerate() {
+            assert_eq!(k, i as int);
+            assert_eq!(v, &~[k]);
+        }
 
-    fn node<K, V>(value: ~BTree<K, V>) -> Option<TreeItem<K, V>> {
-        Some(TreeNode { value: value })
+        t.insert(5, ~[5]);
+        assert_eq!(t.len(), 1);
     }
 
-    fn leaf<K, V>(value: V) -> Option<TreeItem<K, V>> {
-        Some(TreeLeaf { value: value })
-    }
+    #[test]
+    fn test_drain_filter() {
+        let mut t = range_tree(0, 10000);
 
-    //macro_rules! check_values (
-    //    ($list:expr, $values:expr) => {{
-    fn check_values<T: Eq>(list: &[Option<T>], values: &[Option<T>]) {
-            assert!(list.len() >= values.len());
+        let removed: ~[(int, int)] = t.drain_filter(|k, _| *k % 3 == 0)
+                                      .collect();
 
-            let mut i = 0;
-            let len = values.len();
+        assert_eq!(removed.len(), 3334);
+        assert_eq!(t.len(), 6666);
+        check_invariants(&*t, true);
 
-            while i < len {
-                assert_eq!(&list[i], &values[i]);
-                i += 1;
-            }
+        for (i, &(k, v)) in removed.iter().enumerate() {
+            assert_eq!((k, v), (i as int * 3, i as int * 3));
+        }
 
-            let len = list.len();
+        for k in range(0, 10000) {
+            assert_eq!(t.find(&k).is_some(), k % 3 != 0);
+        }
 
-            while i < len {
-                assert_eq!(&list[i], &None);
-                i += 1;
-            }
-    }
-    //    }}
-    //)
+        assert_eq!(t.drain_filter(|_, _| false).next(), None);
+        assert_eq!(t.len(), 6666);
 
-    //macro_rules! check_used (
-    //    ($list:expr, $used:expr) => {{
-    fn check_used<T>(list: &[Option<T>], used: &[bool]) {
-            assert!(list.len() >= used.len());
+        {
+            let mut iter = t.drain_filter(|k, _| *k > 5000);
+            assert_eq!(iter.next(), Some((5002, 5002)));
+            assert_eq!(iter.next(), Some((5003, 5003)));
+        }
 
-            let mut i = 0;
-            let len = used.len();
+        assert_eq!(t.len(), 6664);
+        assert_eq!(t.find(&5002), None);
+        assert_eq!(t.find(&5005), Some(&5005));
+        check_invariants(&*t, true);
+    }
 
-            while i < len {
-                if list[i].is_some() != used[i] {
-                    fail!(format!("list[{}] = {:?} is not in use. used[{}] = {}",
-                                  i, list[i], i, used[i]));
-                }
+    #[test]
+    fn test_split_off() {
+        for &at in [-1, 0, 1, 2, 501, 3000, 7777, 9998, 9999, 20000].iter() {
+            let mut t = BTree::new();
 
-                i += 1;
+            for k in range(0, 5000) {
+                t.insert(k * 2, k);
             }
 
-            let len = list.len();
+            let r = t.split_off(&at);
 
-            while i < len {
-                if list[i].is_some() {
-                    fail!(format!("list[{}] = {:?} is used but should be unused",
-                                  i, list[i]));
-                }
+            check_invariants(&*t, true);
+            check_invariants(&*r, true);
+            assert_eq!(t.len() + r.len(), 5000);
 
-                i += 1;
+            for k in range(0, 5000) {
+                if k * 2 < at {
+                    assert_eq!(t.find(&(k * 2)).unwrap(), &k);
+                    assert_eq!(r.find(&(k * 2)), None);
+                } else {
+                    assert_eq!(t.find(&(k * 2)), None);
+                    assert_eq!(r.find(&(k * 2)).unwrap(), &k);
+                }
             }
-    }
-    //    }}
-    //)
-
-    fn get_node<'r, K, V>(tree: &'r BTree<K, V>, pos: uint)
-        -> &'r BTree<K, V> {
-        match tree.nodes[pos] {
-            Some(TreeNode { value: ref v }) => &**v,
-            Some(TreeLeaf { value: _ }) |
-            None  => fail!("unreachable path"),
         }
     }
 
     #[test]
-    fn test_basic_insert() {
-        let foo = "foo";
-        let bar = "bar";
-        let baz = "baz";
+    fn test_append() {
+        for &(n, m) in [(1, 1), (10, 1000), (1000, 10), (5000, 5000),
+                        (100, 100000)].iter() {
+            let mut low = range_tree(0, n);
+            low.append(range_tree(n, n + m));
 
-        let mut t = BTree::new();
-        assert!(t.is_empty());
-        assert_eq!(t.used, 0);
+            let mut high = range_tree(n, n + m);
+            high.append(range_tree(0, n));
 
-        assert!(t.insert(42, bar));
-        assert!(!t.is_empty());
-        assert_eq!(t.used, 1);
+            for t in [low, high].iter() {
+                check_invariants(&**t, true);
+                assert_eq!(t.len(), (n + m) as uint);
 
-        assert!(t.insert(3, baz));
-        assert!(!t.is_empty());
-        assert_eq!(t.used, 2);
+                for k in range(0, n + m) {
+                    assert_eq!(t.find(&k).unwrap(), &k);
+                }
+            }
+        }
 
-        assert!(t.insert(1, foo));
-        assert!(!t.is_empty());
-        assert_eq!(t.used, 3);
+        let mut a = BTree::new();
+        let mut b = BTree::new();
+
+        for k in range(0, 1000) { a.insert(k * 2, 0); }
+        for k in range(0, 1000) { b.insert(k * 3, 1); }
 
-        assert_eq!(t.find(1).unwrap(), &foo);
-        assert_eq!(t.find(3).unwrap(), &baz);
-        assert_eq!(t.find(42).unwrap(), &bar);
+        a.append(b);
+
+        assert_eq!(a.len(), 1666);
+        assert_eq!(a.find(&6).unwrap(), &1);
+        assert_eq!(a.find(&4).unwrap(), &0);
+        check_invariants(&*a, true);
     }
 
     #[test]
-    fn test_basic_len() {
-        let foo = "foo";
-        let bar = "bar";
+    fn test_remove_range() {
+        let mut t = range_tree(0, 10000);
 
-        let mut t = BTree::new();
-        assert_eq!(t.len(), 0);
-
-        assert!(t.insert(1, foo));
-        assert_eq!(t.len(), 1);
+        assert_eq!(t.remove_range(&2000, &5000), 3000);
+        assert_eq!(t.remove_range(&2000, &5000), 0);
+        assert_eq!(t.remove_range(&10, &5), 0);
+        assert_eq!(t.remove_range(&-100, &10), 10);
+        assert_eq!(t.remove_range(&9990, &20000), 10);
 
-        assert!(t.insert(42, bar));
-        assert_eq!(t.len(), 2);
+        check_invariants(&*t, true);
+        assert_eq!(t.len(), 6980);
 
-        t.clear();
-        assert!(t.is_empty());
-        assert_eq!(t.len(), 0);
+        for k in range(0, 10000) {
+            let removed = k < 10 || (k >= 2000 && k < 5000) || k >= 9990;
+            assert_eq!(t.contains_key(&k), !removed);
+        }
     }
 
     #[test]
-    fn test_clear() {
-        let foo = "foo";
-        let bar = "bar";
-
+    fn test_get_many() {
         let mut t = BTree::new();
-        assert!(t.is_empty());
+        assert_eq!(t.get_many([1, 2]), ~[None, None]);
 
-        assert!(t.insert(1, foo));
-        assert!(!t.is_empty());
+        for k in range(0, 10000) {
+            t.insert(k * 2, k);
+        }
 
-        assert!(t.insert(42, bar));
-        assert!(!t.is_empty());
+        let keys: ~[int] = range(-10, 20020).collect();
+        let values = t.get_many(keys);
 
-        t.clear();
-        assert!(t.is_empty());
+        assert_eq!(values.len(), keys.len());
+
+        for (k, v) in keys.iter().zip(values.iter()) {
+            assert_eq!(*v, t.find(k));
+        }
 
-        assert_eq!(t.find(1), None);
-        assert_eq!(t.find(42), None);
+        assert_eq!(t.get_many([4, 4, 5]), ~[Some(&2), Some(&2), None]);
     }
 
     #[test]
-    fn test_insert_new_key() {
-        let foo = "foo";
-        let bar = "bar";
+    fn test_bulk_load() {
+        use std::io::mem::{MemWriter, MemReader};
 
-        let mut t = BTree::new();
-        assert!(t.is_empty());
-        assert_eq!(t.used, 0);
+        let mut b = BTreeBuilder::new();
 
-        assert!(t.insert(42, foo));
-        assert!(!t.is_empty());
-        assert_eq!(t.used, 1);
-        assert_eq!(t.find(42).unwrap(), &foo);
+        for k in range(0, 10000) {
+            assert!(b.push(k, k));
+        }
 
-        assert!(!t.insert(42, bar));
-        assert!(!t.is_empty());
-        assert_eq!(t.used, 1);
+        assert!(b.push(9999, -1));
+        assert!(!b.push(5, 5));
+        assert_eq!(b.len(), 10000);
+        assert_eq!(b.dropped(), 1);
+
+        let t = b.build();
+        check_invariants(&*t, true);
+        assert_eq!(t.len(), 10000);
+        assert_eq!(t.find(&9999).unwrap(), &-1);
+        assert_eq!(t.find(&5).unwrap(), &5);
+
+        let u = BTree::from_sorted_iter(range(0, 3000).map(|k| (k, -k)));
+        let u = u.unwrap();
+        check_invariants(&*u, true);
+        assert_eq!(u.len(), 3000);
+
+        let pairs = ~[(1, 1), (3, 3), (2, 2)];
+        assert!(BTree::from_sorted_iter(pairs.move_iter()).is_none());
+
+        let mut w = MemWriter::new();
+        u.write_pairs(&mut w as &mut Writer);
+
+        let mut r = MemReader::new(w.inner());
+        let v: ~BTree<int, int> =
+            BTree::read_sorted(&mut r as &mut Reader).unwrap();
+        check_invariants(&*v, true);
+        assert!(*v == *u);
+
+        let mut r = MemReader::new(~[2u8]);
+        let w: Option<~BTree<int, int>> =
+            BTree::read_sorted(&mut r as &mut Reader);
+        assert!(w.is_none());
+    }
 
-        assert_eq!(t.find(42).unwrap(), &bar);
+    #[test]
+    fn test_build_from_unsorted_file() {
+        use extra::tempfile::TempDir;
+        use std::io::File;
 
-        t.clear();
-        assert!(t.is_empty());
+        let dir = TempDir::new("btree").unwrap();
+        let path = dir.path().join("pairs");
+        let mut rng = IsaacRng::new();
+        let mut expected = TreeMap::new();
 
-        assert!(t.insert(42, foo));
-        assert!(!t.is_empty());
-        assert_eq!(t.used, 1);
-        assert_eq!(t.find(42).unwrap(), &foo);
-    }
+        {
+            let mut w = File::create(&path).unwrap();
 
-    /*
-    // NB The following test will only work when BTREE_MIN_DEGREE = 2;
-    #[test]
-    fn test_insert_split_root() {
-        assert_eq!(BTREE_MIN_DEGREE, 2);
-        let mut t = tree([Some(4), Some(5), Some(6)],
-                         [leaf(4), leaf(5), leaf(6), None]);
+            for i in range(0, 5000) {
+                let key = rng.gen_range(0, 2000);
+                super::write_pair(&mut w as &mut Writer, &key, &i);
+                expected.insert(key, i);
+            }
 
-        assert!(t.insert(10, 10));
+            w.write_u8(0);
+        }
 
-        assert_eq!(t.used, 1);
+        let t: ~BTree<int, int> =
+            super::build_external(&path, 300).unwrap();
+        check_invariants(&*t, true);
+        assert_eq!(t.len(), expected.len());
 
-        check_values(t.keys, [Some(5)]);
-        check_used(t.nodes, [true, true]);
+        for (k, v) in expected.iter() {
+            assert_eq!(t.find(k).unwrap(), v);
+        }
 
-        let l = get_node(&*t, 0);
-        check_values(l.keys, [Some(4)]);
-        check_values(l.nodes, [leaf(4), leaf(5)]);
+        // The run files are removed after the merge.
+        assert!(!dir.path().join("pairs-run0").exists());
 
-        let r = get_node(&*t, 1);
-        check_values(r.keys, [Some(6), Some(10)]);
-        check_values(r.nodes, [leaf(6), leaf(10)]);
+        let u: ~BTree<int, int> =
+            BTree::build_from_unsorted_file(&path).unwrap();
+        assert!(*u == *t);
     }
 
-    // NB The following test will only work when BTREE_MIN_DEGREE = 2;
     #[test]
-    fn test_insert_split_right_leaf() {
-        assert_eq!(BTREE_MIN_DEGREE, 2);
+    fn test_insert_sorted() {
+        let mut t = BTree::new();
+        t.insert_sorted(range(0, 10000).map(|k| (k, k)).collect());
 
-        let l = tree([Some(4), None, None],
-                     [leaf(4), leaf(5), None, None]);
-        let r = tree([Some(6), Some(10), Some(17)],
-                     [leaf(6), leaf(10), leaf(17), None]);
-        let mut t = tree([Some(5), None, None],
-                         [node(l), node(r), None, None]);
+        check_invariants(&*t, true);
+        assert_eq!(t.len(), 10000);
 
-        assert!(t.insert(21, 21));
+        t.insert_sorted(range(10000, 10100).map(|k| (k, k)).collect());
+        t.insert_sorted(~[(5, 0), (5, 1), (20000, 2), (7, 3)]);
 
-        assert_eq!(t.used, 2);
+        check_invariants(&*t, true);
+        assert_eq!(t.len(), 10101);
 
-        check_values(t.keys, [Some(5), Some(10)]);
-        check_used(t.nodes, [true, true, true]);
+        for k in range(0, 10100) {
+            let expected = match k { 5 => 1, 7 => 3, _ => k };
+            assert_eq!(t.find(&k).unwrap(), &expected);
+        }
 
-        let l = get_node(&*t, 0);
-        check_values(l.keys, [Some(4)]);
-        check_values(l.nodes, [leaf(4), leaf(5)]);
+        assert_eq!(t.find(&20000).unwrap(), &2);
+    }
 
-        let m = get_node(&*t, 1);
-        check_values(m.keys, [Some(6)]);
-        check_values(m.nodes, [leaf(6), leaf(10)]);
+    #[test]
+    fn test_try_insert() {
+        let mut t = BTree::new();
 
-        let r = get_node(&*t, 2);
-        check_values(r.keys, [Some(17), Some(21)]);
-        check_values(r.nodes, [leaf(17), leaf(21)]);
+        for k in range(0, 1000) {
+            assert_eq!(t.try_insert(k, k), Ok(()));
+        }
+
+        for k in range(0, 1000) {
+            assert_eq!(t.try_insert(k, -k), Err((k, -k)));
+            assert_eq!(t.find(&k).unwrap(), &k);
+        }
+
+        assert_eq!(t.len(), 1000);
+        check_invariants(&*t, true);
     }
 
-    // NB The following test will only work when BTREE_MIN_DEGREE = 2;
     #[test]
-    fn test_insert_split_middle_leaf() {
-        assert_eq!(BTREE_MIN_DEGREE, 2);
+    fn test_mangle() {
+        let mut t = BTree::new();
 
-        let l = tree([Some(4), None, None],
-                     [leaf(4), leaf(5), None, None]);
-        let m = tree([Some(6), Some(7), Some(8)],
-                     [leaf(6), leaf(7), leaf(8), leaf(10)]);
-        let r = tree([Some(17), Some(21), None],
-                     [leaf(17), leaf(21), None, None]);
-        let mut t = tree([Some(5), Some(10), None],
-                         [node(l), node(m), node(r), None]);
+        for k in range(0, 3000) {
+            t.mangle(k % 1000, k, |_, a| ~[a], |_, v, a| v.push(a));
+        }
 
-        assert!(t.insert(9, 9));
+        for k in range(0, 1000) {
+            assert_eq!(t.find(&k).unwrap(), &~[k, k + 1000, k + 2000]);
+        }
 
-        assert_eq!(t.used, 3);
+        let v = t.mangle(5000, 1, |k, a| ~[*k + a], |_, _, _| fail!());
+        assert_eq!(*v, ~[5001]);
+    }
 
-        check_values(t.keys, [Some(5), Some(7), Some(10)]);
-        check_used(t.nodes, [true, true, true, true]);
+    #[test]
+    fn test_index() {
+        let t = range_tree(0, 1000);
 
-        let t0 = get_node(&*t, 0);
-        check_values(t0.keys, [Some(4)]);
-        check_values(t0.nodes, [leaf(4), leaf(5)]);
+        for k in range(0, 1000) {
+            assert_eq!((*t)[k], k);
+        }
+    }
 
-        let t1 = get_node(&*t, 1);
-        check_values(t1.keys, [Some(6)]);
-        check_values(t1.nodes, [leaf(6), leaf(7)]);
+    #[test]
+    #[should_fail]
+    fn test_index_missing_key() {
+        let t = range_tree(0, 1000);
+        (*t)[1000];
+    }
 
-        let t2 = get_node(&*t, 2);
-        check_values(t2.keys, [Some(8), Some(9)]);
-        check_values(t2.nodes, [leaf(8), leaf(9), leaf(10)]);
+    fn count_words<M: MutableMap<int, int>>(map: &mut M, words: &[int]) {
+        for w in words.iter() {
+            let seen = match map.find_mut(w) {
+                Some(count) => { *count += 1; true }
+                None => false,
+            };
 
-        let t3 = get_node(&*t, 3);
-        check_values(t3.keys, [Some(17), Some(21)]);
-        check_values(t3.nodes, [leaf(17), leaf(21)]);
+            if !seen {
+                map.insert(*w, 1);
+            }
+        }
+    }
+
+    fn lookup<'a, M: Map<int, int>>(map: &'a M, key: &int) -> Option<&'a int> {
+        map.find(key)
     }
-    */
 
     #[test]
-    fn test_insert_split_random() {
-        let iterations = 100000;
+    fn test_map_traits() {
+        let words: ~[int] = range(0, 3000).map(|k| k % 1000).collect();
 
         let mut t = BTree::new();
-        let mut rng = IsaacRng::new();
-        rng.reseed([42u32]);
+        let mut reference = TreeMap::new();
 
-        let mut random_keys = ~[];
-        for k in range(0, iterations) { random_keys.push(k); }
-        rng.shuffle_mut(random_keys);
+        count_words(&mut *t, words);
+        count_words(&mut reference, words);
 
-        for &key in random_keys.iter() {
-            t.insert(key, key);
-        }
+        assert_eq!(t.len(), reference.len());
 
-        for &k in random_keys.iter() {
-            assert_eq!(t.find(k).unwrap(), &k);
+        for (k, v) in reference.iter() {
+            assert_eq!(lookup(&*t, k), Some(v));
         }
+
+        let m = &mut *t as &mut MutableMap<int, int>;
+        assert_eq!(m.swap(1, 10), Some(3));
+        assert_eq!(m.pop(&1), Some(10));
+        assert!(!m.remove(&1));
+        assert!(!m.contains_key(&1));
     }
 }
@@ -26,6 +26,6 @@ fn main() {
     }
 
     for &k in random_keys.iter() {
-        assert_eq!(t.find(k).unwrap(), &k);
+        assert_eq!(t.find(&k).unwrap(), &k);
     }
 }
@@ -0,0 +1,72 @@
+extern mod btree;
+use btree::BTree;
+
+use std::io::File;
+use std::io::buffered::BufferedReader;
+use std::os;
+
+/// Load `key,value` rows from a CSV file into a tree, look up the keys given
+/// on the command line, and write the rows back out sorted by key. A later row
+/// replaces the value of an earlier row with the same key.
+fn main() {
+    let args = os::args();
+
+    if args.len() < 3 {
+        println!("usage: {} <input.csv> <output.csv> [key ...]", args[0]);
+        os::set_exit_status(1);
+        return;
+    }
+
+    let input = match File::open(&Path::new(args[1].clone())) {
+        Some(file) => file,
+        None => {
+            println!("cannot open {}", args[1]);
+            os::set_exit_status(1);
+            return;
+        }
+    };
+
+    let mut t = BTree::new();
+    let mut reader = BufferedReader::new(input);
+    let mut line_no = 0;
+
+    for line in reader.lines() {
+        line_no += 1;
+
+        let line = line.trim_right();
+
+        if line.is_empty() {
+            continue;
+        }
+
+        match line.find(',') {
+            Some(i) => {
+                t.insert(line.slice_to(i).to_owned(),
+                         line.slice_from(i + 1).to_owned());
+            }
+            None => println!("line {}: no comma, skipped", line_no),
+        }
+    }
+
+    println!("loaded {} keys, tree height {}", t.len(), t.height());
+
+    for key in args.slice_from(3).iter() {
+        match t.find(key) {
+            Some(value) => println!("{} = {}", *key, *value),
+            None => println!("{} not found", *key),
+        }
+    }
+
+    let mut out = match File::create(&Path::new(args[2].clone())) {
+        Some(file) => file,
+        None => {
+            println!("cannot create {}", args[2]);
+            os::set_exit_status(1);
+            return;
+        }
+    };
+
+    for (key, value) in t.iter() {
+        out.write_str(format!("{},{}\n", *key, *value));
+    }
+}